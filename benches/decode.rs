@@ -0,0 +1,69 @@
+//! Compares the owned decoding of the received messages with the in-place one.
+//! Run with `cargo bench`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+// peerko is a binary crate, the message format is pulled in as a module.
+// Checking all targets builds it with cfg(test) but without the test harness.
+#[allow(dead_code, unused_imports)]
+#[path = "../src/message/format.rs"]
+mod format;
+#[allow(dead_code, unused_imports)]
+#[path = "../src/message/tlv.rs"]
+mod tlv;
+
+use format::{Chat, ChatRef, Header, Integrity, MemberRequest, Message, MessageRef, MessageType, PROTOCOL_VERSION};
+
+fn chat() -> Message<Chat> {
+    let chat = Chat::new("peer-A".to_string(), "rendezvous", 42, 3, "see you at the usual place at eight").unwrap();
+    Message::new(Header::new(PROTOCOL_VERSION, MessageType::Chat, 0), Some(chat))
+}
+
+fn member_request() -> Message<MemberRequest> {
+    Message::new(Header::new(PROTOCOL_VERSION, MessageType::MemberReq, 0), Some(MemberRequest::new("peer-A", "rendezvous", 0, 0).unwrap()))
+}
+
+fn decode(c: &mut Criterion) {
+    let chat = chat().encode(&Integrity::Checksum, false).unwrap();
+    let request = member_request().encode(&Integrity::Checksum, false).unwrap();
+    // The datagram as it sits in the receive buffer
+    let mut datagram = [0; 1024];
+    datagram[..chat.len()].copy_from_slice(&chat);
+
+    let mut group = c.benchmark_group("decode");
+    group.bench_function("chat/vec", |b| b.iter(|| {
+        // The receive path copies the datagram out of the buffer first
+        let data = datagram[..chat.len()].to_vec();
+        Message::<Chat>::decode(black_box(data), &Integrity::Checksum).unwrap()
+    }));
+    group.bench_function("chat/ref", |b| b.iter(|| {
+        let msg = MessageRef::parse(black_box(&datagram[..chat.len()]), &Integrity::Checksum).unwrap();
+        msg.decode::<ChatRef>().unwrap().msg_id()
+    }));
+    group.bench_function("member_request/vec", |b| b.iter(|| {
+        Message::<MemberRequest>::decode(black_box(request.clone()), &Integrity::Checksum).unwrap()
+    }));
+    group.bench_function("member_request/slice", |b| b.iter(|| {
+        MessageRef::parse(black_box(&request), &Integrity::Checksum).unwrap().into_message::<MemberRequest>().unwrap()
+    }));
+    group.finish();
+}
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    group.bench_function("chat/vec", |b| b.iter(|| {
+        black_box(chat()).encode(&Integrity::Checksum, false).unwrap()
+    }));
+    let mut buf = Vec::with_capacity(1024);
+    group.bench_function("chat/into", |b| b.iter(|| {
+        buf.clear();
+        black_box(chat()).encode_into(&mut buf, &Integrity::Checksum, false).unwrap();
+        buf.len()
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, decode, encode);
+criterion_main!(benches);
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// peerko is a binary crate, the message format is pulled in as a module
+#[allow(dead_code)]
+#[path = "../../src/message/format.rs"]
+mod format;
+#[allow(dead_code)]
+#[path = "../../src/message/tlv.rs"]
+mod tlv;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = format::decode(data);
+});
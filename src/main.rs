@@ -1,4 +1,4 @@
-use std::{net::SocketAddr, io::Stdout, sync::{Arc, Mutex}};
+use std::{collections::{BTreeMap, BTreeSet}, io::Stdout, net::Ipv4Addr, ops::Range, path::PathBuf, sync::{Arc, Mutex}};
 
 use crossbeam_channel::{Receiver, Sender};
 use crossterm::{
@@ -10,33 +10,113 @@ use std::{error::Error, io};
 use tui::{
     backend::{Backend, CrosstermBackend},
     layout::{Constraint, Direction, Layout},
-    style::Style,
+    style::{Color, Modifier, Style},
     text::{Span, Spans, Text},
-    widgets::{Block, Borders, List, ListItem, Paragraph},
+    widgets::{Block, Borders, List, ListItem, Paragraph, Tabs},
     Frame, Terminal,
 };
 use unicode_width::UnicodeWidthStr;
 
 use clap::Parser;
-use peer::Peer;
+use message::{format::{Chat, ChatAction, ChatActionKind, TextStyle}, markup};
+use peer::{Peer, PeerError, PeerEvent, MemberInfo};
+use transport::common::TransportError;
 
 mod transport;
 mod message;
 mod peer;
 
-/// The application that holds the current input and messages
+/// Chat message with the later edits, deletes and reactions applied
+struct ChatLine {
+    chat: Chat,
+    edited: bool,
+    deleted: bool,
+    /// Peers that reacted, by reaction
+    reactions: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl ChatLine {
+    fn new(chat: Chat) -> ChatLine {
+        ChatLine { chat, edited: false, deleted: false, reactions: BTreeMap::new() }
+    }
+
+    /// Applies the action to the message if it references it
+    fn apply(&mut self, action: &ChatAction) -> bool {
+        if self.chat.peer_id() != action.author() || self.chat.msg_id() != action.msg_id() {
+            return false;
+        }
+        match action.kind() {
+            ChatActionKind::Edit(edited) if !self.deleted => {
+                // The edit keeps the message it replies to
+                let reply_to = self.chat.reply_to().map(|(peer_id, msg_id)| (peer_id.to_string(), msg_id));
+                self.chat = edited.clone();
+                if let Some((peer_id, msg_id)) = reply_to {
+                    let _ = self.chat.set_reply_to(&peer_id, msg_id);
+                }
+                self.edited = true;
+            },
+            ChatActionKind::Edit(_) => {},
+            ChatActionKind::Delete => {
+                self.deleted = true;
+                self.reactions.clear();
+            },
+            ChatActionKind::React(reaction) if !self.deleted => {
+                self.reactions.entry(reaction.clone()).or_default().insert(action.peer_id().to_string());
+            },
+            ChatActionKind::React(_) => {},
+        }
+        true
+    }
+}
+
+/// Line of the chat
+enum Line {
+    Chat(ChatLine),
+    /// Notice about the group or the answer to a command
+    Notice(String),
+}
+
+/// Messages and members of a single group
+struct Channel {
+    group: String,
+    messages: Vec<Line>,
+    members: Vec<MemberInfo>,
+}
+
+impl Channel {
+    fn new(group: &str) -> Channel {
+        Channel { group: group.to_string(), messages: vec![], members: vec![] }
+    }
+}
+
+/// The application that holds the current input and the channels of the joined groups
 struct App {
     input: String,
-    messages: Arc<Mutex<Vec<String>>>,
+    channels: Arc<Mutex<Vec<Channel>>>,
+    /// Index of the channel shown in the chat
+    active: usize,
+    /// Group to switch to once the peer joins it
+    joining: Option<String>,
 }
 
-impl Default for App {
-    fn default() -> App {
+impl App {
+    fn new(group: &str) -> App {
         App {
             input: String::new(),
-            messages: Arc::new(Mutex::new(Vec::new())),
+            channels: Arc::new(Mutex::new(vec![Channel::new(group)])),
+            active: 0,
+            joining: None,
         }
     }
+
+    fn active_group(&self) -> String {
+        self.channels.lock().unwrap()[self.active].group.clone()
+    }
+
+    /// The user is typing a message, commands don't count
+    fn is_typing(&self) -> bool {
+        !self.input.is_empty() && !self.input.starts_with('/')
+    }
 }
 
 #[derive(Clone, Parser, Debug)]
@@ -50,27 +130,136 @@ struct CliArgs {
     #[clap(long, value_parser, short = 'p')]
     port: u16,
 
+    /// Bootstrap server as IP:PORT or HOST:PORT, can be repeated.
+    /// Servers are tried in order, a hostname is used with all of its addresses.
     #[clap(long, value_parser, short = 'b')]
-    bootstrap: Option<SocketAddr>,
+    bootstrap: Vec<String>,
 
     #[clap(long, value_parser, short = 's')]
     server_mode: Option<bool>,
+
+    /// Rendezvous server to share the group membership with, can be repeated
+    #[clap(long, value_parser, short = 'f')]
+    federate: Vec<String>,
+
+    /// Find the groups in a DHT formed by the peers instead of on a server.
+    /// Any peer in DHT mode can be used as the bootstrap.
+    #[clap(long, value_parser, short = 'd')]
+    dht: Option<bool>,
+
+    /// Find the peers on the LAN through multicast on the interface with this address,
+    /// 0.0.0.0 for the default interface
+    #[clap(long, value_parser, short = 'l')]
+    lan: Option<Ipv4Addr>,
+
+    /// File for keeping the known peers between runs, for reconnecting quickly
+    #[clap(long, value_parser, short = 'c')]
+    cache: Option<PathBuf>,
+
+    /// Store messages for offline group members on the server
+    #[clap(long, value_parser, short = 'm')]
+    mailbox: Option<bool>,
+
+    /// Shared secret of the group. Its messages are signed with it and messages
+    /// without a valid signature are dropped, so every member needs the same secret.
+    #[clap(long, value_parser, short = 'k')]
+    secret: Option<String>,
+
+    /// Compress chat messages and member lists sent to peers that support it, on by default
+    #[clap(long, value_parser, short = 'z')]
+    compress: Option<bool>,
 }
 
 type AppTerminal = Terminal<CrosstermBackend<Stdout>>;
 
-fn setup_app() -> Result<(AppTerminal, App), Box<dyn Error>> {
+fn setup_app(group: &str) -> Result<(AppTerminal, App), Box<dyn Error>> {
     enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
     let backend = CrosstermBackend::new(stdout);
     let terminal = Terminal::new(backend)?;
-    let app = App::default();
+    let app = App::new(group);
 
     Ok((terminal, app))
 }
 
-fn draw_ui<B: Backend>(f: &mut Frame<B>, app: &App) {
+/// Style of the marked up parts of the messages
+fn text_style(style: TextStyle) -> Style {
+    match style {
+        TextStyle::Bold => Style::default().add_modifier(Modifier::BOLD),
+        TextStyle::Italic => Style::default().add_modifier(Modifier::ITALIC),
+        TextStyle::Code => Style::default().fg(Color::Green),
+    }
+}
+
+/// Splits the text of the chat into spans, at the edges of the styled parts and the mentions
+fn chat_spans(chat: &Chat) -> Vec<Span<'_>> {
+    let text = chat.msg();
+    let mut ranges: Vec<(Range<usize>, Style)> = chat.styles().iter()
+        .map(|s| (s.range(), text_style(s.style())))
+        .collect();
+    for (range, name) in markup::mention_ranges(text) {
+        if chat.mentions().iter().any(|mention| mention == name) {
+            ranges.push((range, Style::default().fg(Color::Cyan)));
+        }
+    }
+
+    let mut edges: Vec<usize> = ranges.iter().flat_map(|(r, _)| [r.start, r.end]).chain([0, text.len()]).collect();
+    edges.sort_unstable();
+    edges.dedup();
+    edges.windows(2)
+        .map(|part| {
+            let style = ranges.iter()
+                .filter(|(r, _)| r.start <= part[0] && part[1] <= r.end)
+                .fold(Style::default(), |style, (_, s)| style.patch(*s));
+            Span::styled(&text[part[0]..part[1]], style)
+        })
+        .collect()
+}
+
+/// Returns the lines of a chat message, with the start of the message it replies to above it.
+/// Messages mentioning the user are highlighted, the reactions are counted below the message.
+fn chat_item<'a>(line: &'a ChatLine, messages: &[Line], name: &str) -> ListItem<'a> {
+    let chat = &line.chat;
+    let dim = Style::default().fg(Color::DarkGray);
+    let mut lines = vec![];
+    if let Some((peer_id, msg_id)) = chat.reply_to() {
+        let quoted = messages.iter().find_map(|line| match line {
+            Line::Chat(c) if c.chat.msg_id() == msg_id && c.chat.peer_id() == peer_id => Some(if c.deleted { "(deleted)" } else { c.chat.msg() }),
+            _ => None,
+        });
+        let quote = match quoted {
+            Some(text) if text.chars().count() > 40 => format!("> {}: {}...", peer_id, text.chars().take(40).collect::<String>()),
+            Some(text) => format!("> {}: {}", peer_id, text),
+            None => format!("> reply to {}", peer_id),
+        };
+        lines.push(Spans::from(Span::styled(quote, dim)));
+    }
+
+    let mut spans = vec![Span::raw(format!("{}: ", chat.peer_id()))];
+    if line.deleted {
+        spans.push(Span::styled("(deleted)", dim.add_modifier(Modifier::ITALIC)));
+        return ListItem::new(Spans::from(spans));
+    }
+    spans.extend(chat_spans(chat));
+    if line.edited {
+        spans.push(Span::styled(" (edited)", dim));
+    }
+    lines.push(Spans::from(spans));
+    if !line.reactions.is_empty() {
+        let counts: Vec<String> = line.reactions.iter().map(|(reaction, peers)| format!("{} {}", reaction, peers.len())).collect();
+        lines.push(Spans::from(Span::styled(format!("  {}", counts.join("  ")), dim)));
+    }
+
+    let item = ListItem::new(lines);
+    if chat.peer_id() != name && chat.mentions().iter().any(|m| m == name) {
+        item.style(Style::default().fg(Color::Yellow))
+    } else {
+        item
+    }
+}
+
+fn draw_ui<B: Backend>(f: &mut Frame<B>, app: &App, name: &str) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
         .margin(2)
@@ -89,9 +278,14 @@ fn draw_ui<B: Backend>(f: &mut Frame<B>, app: &App) {
     let help_message = Paragraph::new(text);
     f.render_widget(help_message, chunks[0]);
 
-    let text = Text::from("Esc: exit");
-    let help_message = Paragraph::new(text);
-    f.render_widget(help_message, chunks[1]);
+    let channels = app.channels.lock().unwrap();
+
+    let titles: Vec<Spans> = channels.iter().map(|c| Spans::from(c.group.as_str())).collect();
+    let groups = Tabs::new(titles)
+        .select(app.active)
+        .highlight_style(Style::default().add_modifier(Modifier::BOLD))
+        .block(Block::default().borders(Borders::ALL).title("Groups - Esc: exit, Tab: switch group"));
+    f.render_widget(groups, chunks[1]);
 
     let input = Paragraph::new(app.input.as_ref())
         .style(Style::default())
@@ -102,45 +296,130 @@ fn draw_ui<B: Backend>(f: &mut Frame<B>, app: &App) {
         chunks[2].y + 1,
     );
 
-    let messages = app.messages.lock().unwrap();
+    let channel = &channels[app.active];
 
-    let messages: Vec<ListItem> = messages
+    let messages: Vec<ListItem> = channel.messages
         .iter()
-        .enumerate()
         .rev()
-        .map(|(_, m)| {
-            let content = vec![Spans::from(Span::raw(m))];
-            ListItem::new(content)
+        .map(|line| match line {
+            Line::Chat(chat) => chat_item(chat, &channel.messages, name),
+            Line::Notice(notice) => ListItem::new(Spans::from(Span::raw(format!("* {}", notice)))),
         })
         .collect();
     let messages =
-        List::new(messages).block(Block::default().borders(Borders::ALL).title("Messages"));
-    f.render_widget(messages, chunks[3]);
+        List::new(messages).block(Block::default().borders(Borders::ALL).title(format!("Messages - {}", channel.group)));
+
+    let members: Vec<ListItem> = channel.members
+        .iter()
+        .map(|m| {
+            let mut lines = vec![Spans::from(vec![
+                Span::styled(m.id.as_str(), Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(format!(" [{}]", if m.suspect { "suspect".to_string() } else { m.status.to_string() })),
+                Span::raw(if m.typing { " typing..." } else { "" }),
+            ])];
+            if !m.text.is_empty() {
+                lines.push(Spans::from(Span::styled(format!("  {}", m.text), Style::default().add_modifier(Modifier::ITALIC))));
+            }
+            ListItem::new(lines)
+        })
+        .collect();
+    let members = List::new(members).block(Block::default().borders(Borders::ALL).title("Peers"));
+
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Min(1), Constraint::Length(32)].as_ref())
+        .split(chunks[3]);
+    f.render_widget(messages, columns[0]);
+    f.render_widget(members, columns[1]);
 }
 
-fn run_chat(peer_name: &str, msg_sender: Sender<String>, msg_receiver: Receiver<(String, String)>) -> Result<(), Box<dyn Error>> {
-    let (mut terminal, mut app) = setup_app()?;
+fn run_chat(peer_name: &str, group: &str, msg_sender: Sender<(String, String)>, typing_sender: Sender<(String, bool)>, msg_receiver: Receiver<PeerEvent>) -> Result<(), Box<dyn Error>> {
+    let (mut terminal, mut app) = setup_app(group)?;
 
-    let thread_messages = app.messages.clone();
-    // Thread which receives the messages from the peer instance and prints them
+    let thread_channels = app.channels.clone();
+    // Thread which receives the messages from the peer instance and prints them in the channel of the group
     std::thread::spawn(move || {
         loop {
-            if let Ok((id, msg)) = msg_receiver.recv() {
-                thread_messages.lock().unwrap().push(format!("{}: {}", id, msg));
-            }
+            let (group, line) = match msg_receiver.recv() {
+                Ok(PeerEvent::Message(group, chat)) => (group, Line::Chat(ChatLine::new(chat))),
+                Ok(PeerEvent::Action(group, action)) => {
+                    // Past lines are updated in place, actions on messages not shown are dropped
+                    let mut channels = thread_channels.lock().unwrap();
+                    if let Some(channel) = channels.iter_mut().find(|c| c.group == group) {
+                        let _ = channel.messages.iter_mut().rev().any(|line| match line {
+                            Line::Chat(chat) => chat.apply(&action),
+                            Line::Notice(_) => false,
+                        });
+                    }
+                    continue;
+                },
+                Ok(PeerEvent::Notice(group, notice)) => (group, Line::Notice(notice)),
+                Ok(PeerEvent::Joined(group)) => {
+                    let mut channels = thread_channels.lock().unwrap();
+                    if !channels.iter().any(|c| c.group == group) {
+                        channels.push(Channel::new(&group));
+                    }
+                    continue;
+                },
+                Ok(PeerEvent::Members(group, members)) => {
+                    let mut channels = thread_channels.lock().unwrap();
+                    if let Some(channel) = channels.iter_mut().find(|c| c.group == group) {
+                        channel.members = members;
+                    }
+                    continue;
+                },
+                Err(_) => break,
+            };
+            let mut channels = thread_channels.lock().unwrap();
+            // Leftovers of a group that was left end up in the first channel
+            let index = channels.iter().position(|c| c.group == group).unwrap_or(0);
+            channels[index].messages.push(line);
         }
     });
     
     loop {
-        terminal.draw(|f| draw_ui(f, &app))?;
+        if let Some(group) = app.joining.as_deref() {
+            if let Some(index) = app.channels.lock().unwrap().iter().position(|c| c.group == group) {
+                app.active = index;
+                app.joining = None;
+            }
+        }
+        terminal.draw(|f| draw_ui(f, &app, peer_name))?;
 
         if event::poll(std::time::Duration::from_millis(100))? {
             if let Event::Key(key) = event::read()? {
+                let typed_group = app.active_group();
+                let was_typing = app.is_typing();
+
                 match key.code {
                     KeyCode::Enter => {
                         let line: String = app.input.drain(..).collect();
-                        msg_sender.send(line.clone()).unwrap();
-                        app.messages.lock().unwrap().push(format!("{}: {}", peer_name, line));
+                        match line.split_once(' ') {
+                            // The channel is added once the peer joins the group
+                            Some(("/join", new_group)) => {
+                                app.joining = new_group.split_whitespace().next().map(str::to_string);
+                            },
+                            Some(("/part", old_group)) => {
+                                let old_group = old_group.trim();
+                                let mut channels = app.channels.lock().unwrap();
+                                if channels.len() == 1 && channels[0].group == old_group {
+                                    channels[0].messages.push(Line::Notice("can't leave the last group".to_string()));
+                                    continue;
+                                }
+                                let count = channels.len();
+                                channels.retain(|c| c.group != old_group);
+                                if channels.len() != count {
+                                    app.active = 0;
+                                }
+                            },
+                            // Sent messages are shown once the peer sends them
+                            _ => (),
+                        }
+                        msg_sender.send((app.active_group(), line)).unwrap();
+                    },
+                    KeyCode::Tab => {
+                        let count = app.channels.lock().unwrap().len();
+                        app.active = (app.active + 1) % count;
                     },
                     KeyCode::Char(c) => {
                         app.input.push(c);
@@ -153,6 +432,16 @@ fn run_chat(peer_name: &str, msg_sender: Sender<String>, msg_receiver: Receiver<
                     }
                     _ => {}
                 }
+
+                // Let the group know while a message is being typed
+                let group = app.active_group();
+                let switched = group != typed_group;
+                if was_typing && (!app.is_typing() || switched) {
+                    typing_sender.send((typed_group, false)).unwrap();
+                }
+                if app.is_typing() && (!was_typing || switched) {
+                    typing_sender.send((group, true)).unwrap();
+                }
             }
         }
     }
@@ -169,25 +458,63 @@ fn run_chat(peer_name: &str, msg_sender: Sender<String>, msg_receiver: Receiver<
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = CliArgs::parse();
-    
+
     // Run peer app
-    let mut peer = Peer::new(args.name.clone(), args.group, args.port, args.bootstrap).unwrap();
+    let bootstraps = peer::bootstrap::resolve(&args.bootstrap)?;
+    let mut peer = match Peer::new(args.name.clone(), args.group.clone(), args.port, bootstraps) {
+        Ok(peer) => peer,
+        Err(PeerError::Transport(TransportError::Bind(io::ErrorKind::AddrInUse))) => {
+            eprintln!("Error: port {} is already in use", args.port);
+            std::process::exit(1);
+        },
+        Err(err) => return Err(err.into()),
+    };
+    if args.compress == Some(false) {
+        peer.set_compression(false);
+    }
+    if let Some(secret) = &args.secret {
+        peer.set_secret(secret);
+    }
+    peer.set_mailbox(args.mailbox.unwrap_or(false));
+    peer.set_dht(args.dht.unwrap_or(false));
+    if let Some(path) = &args.cache {
+        peer.set_cache(path)?;
+    }
+    if let Some(interface) = args.lan {
+        peer.set_lan(interface)?;
+    }
+    peer.set_federation(peer::bootstrap::resolve(&args.federate)?);
+    // Fail before the chat starts if the name is taken
+    if let Err(err) = peer.check_name() {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
 
     // Get the chat sender and receiver
     let msg_sender = peer.msg_sender();
     let msg_receiver = peer.msg_receiver();
+    let shutdown_sender = peer.shutdown_sender();
+    let typing_sender = peer.typing_sender();
 
     // Run the peer in a separate thread
-    let peer_thread = std::thread::spawn(move||{
-        peer.run();
-    });
+    let peer_thread = std::thread::spawn(move || peer.run());
 
     let server_mode = args.server_mode.unwrap_or(false);
 
     if !server_mode {
-        run_chat(&args.name, msg_sender, msg_receiver).unwrap();
+        run_chat(&args.name, &args.group, msg_sender, typing_sender, msg_receiver).unwrap();
+        // Let the group know we're leaving before exiting
+        shutdown_sender.send(()).unwrap();
     } else {
-        peer_thread.join().unwrap();
+        // Print the group notices, so the server doesn't pile them up
+        std::thread::spawn(move || {
+            while let Ok(event) = msg_receiver.recv() {
+                if let PeerEvent::Notice(group, notice) = event {
+                    println!("{}: {}", group, notice);
+                }
+            }
+        });
     }
+    peer_thread.join().unwrap()?;
     Ok(())
 }
@@ -1,522 +1,3962 @@
-use std::{fmt::Display, net::{SocketAddr, IpAddr}, io::{Cursor, Read}, error::Error};
-
-use byteorder::{WriteBytesExt, BigEndian, ReadBytesExt};
-
-const MAGIC_HEADER: u8 = 0x9D;
-
-#[derive(Clone, Debug)]
-pub struct FormatError {
-    pub error: String,
-}
-
-impl Error for FormatError {}
-
-impl Display for FormatError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Format err: {}", self.error)
-    }
-}
-
-/// Market trait for types that wrap the content of a message
-pub trait MessageContent: Clone + Into<Vec<u8>> + TryFrom<Vec<u8>> {}
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub struct Header {
-    magic_bytes: u8,
-    version: u8,
-    msg_type: MessageType,
-    size: u16,
-}
-
-impl Header {
-    pub fn new(version: u8, r#type: MessageType, size: u16) -> Header {
-        Header {
-            magic_bytes: MAGIC_HEADER,
-            version,
-            msg_type: r#type,
-            size,
-        }
-    }
-
-    pub fn msg_type(&self) -> MessageType {
-        self.msg_type
-    }
-}
-
-impl Into<Vec<u8>> for Header {
-    fn into(self) -> Vec<u8> {
-        let mut buf = vec![];
-        buf.write_u8(self.magic_bytes).unwrap();
-        buf.write_u8((self.version << 4) | self.msg_type as u8).unwrap();
-        buf.write_u16::<BigEndian>(self.size).unwrap();
-        buf
-    }
-}
-
-impl TryFrom<Vec<u8>> for Header {
-    type Error = FormatError;
-
-    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
-        let mut reader = Cursor::new(value);
-        let magic_bytes = reader.read_u8()
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-
-        if magic_bytes != MAGIC_HEADER {
-            return Err(FormatError { error: String::from("magic header mismatch") });
-        }
-
-        let version_type = reader.read_u8()
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-        let size = reader.read_u16::<BigEndian>()
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-        Ok(Header {
-            magic_bytes,
-            version: version_type & 0xF0,
-            msg_type: MessageType::from(version_type & 0x0F),
-            size,
-        })
-    }
-}
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-#[repr(u8)]
-pub enum MessageType {
-    Alive = 0x01,
-    MemberReq = 0x02,
-    MemberRes = 0x04,
-    Chat = 0x08,
-}
-
-impl From<u8> for MessageType {
-    fn from(val: u8) -> Self {
-        match val {
-            0x01 => MessageType::Alive,
-            0x02 => MessageType::MemberReq,
-            0x04 => MessageType::MemberRes,
-            0x08 => MessageType::Chat,
-            _ => panic!("Wrong message type supplied")
-        }
-    }
-}
-
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct Alive {
-    peer_id: String,
-}
-
-impl MessageContent for Alive {}
-
-impl Alive {
-    pub fn new(peer_id: String) -> Alive {
-        Alive { peer_id }
-    }
-
-    pub fn peer_id(&self) -> &str {
-        &self.peer_id
-    }
-}
-
-impl Into<Vec<u8>> for Alive {
-    fn into(self) -> Vec<u8> {
-        let mut buf = vec![0u8; 32];
-        buf[0..self.peer_id.len()].copy_from_slice(self.peer_id.as_bytes());
-        buf
-    }
-}
-
-impl TryFrom<Vec<u8>> for Alive {
-    type Error = FormatError;
-
-    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
-        let mut reader = Cursor::new(&value);
-
-        let mut peer_id_buf = vec![0; 32];
-        reader.read_exact(&mut peer_id_buf)
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-
-        let peer_id = String::from_utf8(peer_id_buf.into_iter().filter(|s| *s != 0).collect())
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-
-        Ok(Alive {
-            peer_id,
-        })
-    }
-}
-
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct MemberRequest {
-    peer_id: String,
-    group: String,
-}
-
-impl MessageContent for MemberRequest {}
-
-impl MemberRequest {
-    pub fn new(peer_id: &str, group: &str) -> Result<MemberRequest, FormatError> {
-        if group.len() > 32 {
-            return Err(FormatError{error: String::from("Group name exceeds 32.")});
-        }
-
-        if peer_id.len() > 32 {
-            return Err(FormatError{error: String::from("Peer id exceeds 32.")});
-        }
-        
-        Ok(MemberRequest { group: group.to_string(),  peer_id: peer_id.to_string() })
-    }
-
-    pub fn group_name(&self) -> &str {
-        &self.group
-    }
-
-    pub fn peer_id(&self) -> String {
-        self.peer_id.clone()
-    }
-}
-
-impl Into<Vec<u8>> for MemberRequest {
-    fn into(self) -> Vec<u8> {
-        let mut buf = vec![0u8; 64];
-        buf[0..self.group.len()].copy_from_slice(self.group.as_bytes());
-        buf[32..32+self.peer_id.len()].copy_from_slice(self.peer_id.as_bytes());
-        buf
-    }
-}
-
-impl TryFrom<Vec<u8>> for MemberRequest {
-    type Error = FormatError;
-
-    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
-        let mut reader = Cursor::new(&value);
-
-        let mut grp_buf = vec![0; 32];
-        let mut peer_id_buf = vec![0; 32];
-        reader.read_exact(&mut grp_buf)
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-        reader.read_exact(&mut peer_id_buf)
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-
-        let group = String::from_utf8(grp_buf.into_iter().filter(|s| *s != 0).collect())
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-
-        let peer_id = String::from_utf8(peer_id_buf.into_iter().filter(|s| *s != 0).collect())
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-
-        Ok(MemberRequest {
-            group,
-            peer_id,
-        })
-    }
-}
-
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct MemberResponse {
-    group: String,
-    member_number: u8,
-    peers: Vec<(String, SocketAddr)>,
-}
-
-impl MessageContent for MemberResponse {}
-
-impl MemberResponse {
-    pub fn new(group: &str, peers: Vec<(String, SocketAddr)>) -> Result<MemberResponse, FormatError> {
-        if peers.len() > 5 {
-            return Err(FormatError{error: String::from("More than 5 peer addresses.")});
-        }
-
-        if group.len() > 32 {
-            return Err(FormatError{error: String::from("Group name exceeds 32.")});
-        }
-        
-        let member_number = peers.len().try_into().expect("Failed to get member count");
-
-        Ok(MemberResponse { group: group.to_string(), member_number, peers })
-    }
-
-    pub fn group_name(&self) -> String {
-        self.group.clone()
-    }
-
-    pub fn peers(&self) -> &Vec<(String, SocketAddr)> {
-        &self.peers
-    }
-
-}
-
-impl Into<Vec<u8>> for MemberResponse {
-    fn into(self) -> Vec<u8> {
-        // group_name + member_count + peer_id + IP(4) + Port(2)
-        let msg_size = 32 + 1 + (32 + 4 + 2) * self.peers.len();
-        let mut buf = vec![0; msg_size];
-
-        let grp_name_len = self.group.len();
-        buf[0..grp_name_len].copy_from_slice(self.group.as_bytes());
-
-        buf[32] = self.peers.len() as u8;
-
-        for i in 0..self.peers.len() {
-            let (peer_id, peer_addr) = &self.peers[i];
-            let offset = 33 + 38*i;
-            buf[offset..offset+peer_id.len()].copy_from_slice(peer_id.as_bytes());
-
-            let ip_bytes = match peer_addr.ip() {
-                IpAddr::V4(ip) => ip.octets(),
-                _ => panic!("Only IPv4 supported"),
-            };
-
-            buf[offset+32..offset+36].copy_from_slice(&ip_bytes);
-            buf[offset+36..offset+38].copy_from_slice(&peer_addr.port().to_be_bytes());
-        }
-
-        buf
-    }
-}
-
-impl TryFrom<Vec<u8>> for MemberResponse {
-    type Error = FormatError;
-
-    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
-        let mut reader = Cursor::new(value);
-        let mut grp_buf = vec![0; 32];
-        
-        reader.read_exact(&mut grp_buf)
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-
-        let group = String::from_utf8(grp_buf.into_iter().filter(|s| *s != 0).collect())
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-
-        let member_number = reader.read_u8()
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-
-        let mut peers: Vec<(String, SocketAddr)> = Vec::new();
-
-        for _ in 0..member_number {
-            let mut peer_id_buf = vec![0; 32];
-            reader.read_exact(&mut peer_id_buf)
-                .map_err(|err| FormatError{ error: err.to_string() })?;
-
-            let peer_id = String::from_utf8(peer_id_buf.into_iter().filter(|s| *s != 0).collect())
-                .map_err(|err| FormatError{ error: err.to_string() })?;
-
-            let mut ip_buf = [0; 4];
-            reader.read_exact(&mut ip_buf)
-                .map_err(|err| FormatError{ error: err.to_string() })?;
-
-            let port = reader.read_u16::<BigEndian>()
-                .map_err(|err| FormatError{ error: err.to_string() })?;
-
-            peers.push((peer_id, SocketAddr::new(IpAddr::from(ip_buf), port)));
-        }
-
-        Ok(MemberResponse{
-            group,
-            member_number,
-            peers,
-        })
-    }
-}
-
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct Chat {
-    peer_id: String,
-    msg: String,
-}
-
-
-impl Into<Vec<u8>> for Chat {
-    fn into(self) -> Vec<u8> {
-        let msg_size = 33 + self.msg.len();
-        let mut buf = vec![0; msg_size];
-
-        buf[0..self.peer_id.len()].copy_from_slice(self.peer_id.as_bytes());
-        buf[32] = self.msg.len() as u8;
-        buf[33..msg_size].copy_from_slice(self.msg.as_bytes());
-        buf
-    }
-}
-
-impl TryFrom<Vec<u8>> for Chat {
-    type Error = FormatError;
-
-    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
-        let mut reader = Cursor::new(value);
-        let mut peer_id_buf = vec![0; 32];
-        
-        reader.read_exact(&mut peer_id_buf).map_err(|err| FormatError{ error: err.to_string() })?;
-
-        let peer_id = String::from_utf8(peer_id_buf.into_iter().filter(|s| *s != 0).collect())
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-
-        let msg_len = reader.read_u8()
-            .map_err(|err| FormatError{ error: err.to_string() })? as usize;
-
-        let mut msg_buf = vec![0; msg_len];
-
-        reader.read_exact(&mut msg_buf).map_err(|err| FormatError{ error: err.to_string() })?;
-
-        let msg = String::from_utf8(msg_buf.into_iter().filter(|s| *s != 0).collect())
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-
-        Ok(Chat{
-            peer_id,
-            msg,
-        })
-    }
-}
-
-impl MessageContent for Chat {}
-
-impl Chat {
-    pub fn new(peer_id: String, msg: &str) -> Chat {
-        Chat{
-            peer_id,
-            msg: msg.to_owned(),
-        }
-    }
-
-    pub fn msg(&self) -> &str {
-        &self.msg
-    }
-
-    pub fn peer_id(&self) -> String {
-        self.peer_id.clone()
-    }
-}
-
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub struct Message<T> 
-    where T: MessageContent {
-    header: Header,
-    content: Option<T>,
-}
-
-impl<T> Message<T> where T: MessageContent {
-    pub fn new(header: Header, content: Option<T>) -> Message<T> {
-        Message { header, content }
-    }
-
-    pub fn content(&self) -> Option<&T> {
-        self.content.as_ref()
-    }
-}
-
-impl<T> From<Message<T>> for Vec<u8> where T: MessageContent {
-    fn from(val: Message<T>) -> Self {
-        // let mut buf = [0; 576];
-        // self.encode_as_be_bytes(&mut buf);
-        // buf.to_vec()
-        let mut msg_bytes: Vec<u8> = val.header.into();
-        if let Some(content) = val.content {
-            msg_bytes.extend(content.into());
-        }
-        msg_bytes
-    }
-}
-
-impl<T> TryFrom<Vec<u8>> for Message<T> where T: MessageContent {
-    type Error = FormatError;
-
-    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
-        let mut reader = Cursor::new(&value);
-
-        let mut header_bytes: [u8; 4] = [0; 4];
-        reader.read_exact(&mut header_bytes)
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-
-        let header = Header::try_from(header_bytes.to_vec())
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-
-        let content_size = value.len() - 4;
-
-        let mut content_bytes: Vec<u8> = vec![0; content_size];
-        reader.read_exact(&mut content_bytes)
-            .map_err(|err| FormatError{ error: err.to_string() })?;
-
-        let content = T::try_from(content_bytes)
-            .map_err(|_err| FormatError { error: "Error converting message content".to_string() })?;
-
-        Ok(Message {
-            header,
-            content: Some(content),
-        })
-    }
-}
-
-mod tests {
-    use super::*;
-
-    #[test]
-    fn header_serialization() {
-        let mut header = Header::new(12, MessageType::Alive, 501);
-        let mut expected: Vec<u8> = vec![MAGIC_HEADER, 0xC1, 0x01, 0xF5];
-        assert_eq!(<Header as Into<Vec<u8>>>::into(header), expected);
-
-        header = Header::new(5, MessageType::Chat, 113);
-        expected = vec![MAGIC_HEADER, 0x58, 0x00, 0x71];
-        assert_eq!(<Header as Into<Vec<u8>>>::into(header), expected);
-    }
-
-    #[test]
-    fn member_request_serialization() {
-        let req = MemberRequest::new("peer-A", "my-group").unwrap();
-        let buf: Vec<u8> = req.into();
-        assert_eq!(buf[0..8], *"my-group".as_bytes());
-    }
-
-    #[test]
-    fn member_request_deserialization() {
-        let req = MemberRequest::new("peer1", "my-group").unwrap();
-
-        let bytes: Vec<u8> = req.into();
-
-        let req2 = MemberRequest::try_from(bytes).unwrap();
-        assert_eq!(req2.group, "my-group");
-        assert_eq!(req2.peer_id, "peer1");
-    }
-
-    #[test]
-    fn member_response_serialization() {
-        let peers = vec![
-            ("peer-A".to_string(), "11.22.33.44:1234".parse().unwrap()),
-            ("peer-B".to_string(), "255.0.0.1:65511".parse().unwrap()),
-        ];
-        let res = MemberResponse::new("my-group", peers).unwrap();
-        let buf: Vec<u8> = res.into();
-
-        let res2 = MemberResponse::try_from(buf).unwrap();
-
-        assert_eq!(res2.group, "my-group".to_string());
-
-        assert_eq!(res2.member_number, 2);
-
-        assert_eq!(res2.peers[0], ("peer-A".to_string(), "11.22.33.44:1234".parse().unwrap()));
-        assert_eq!(res2.peers[1],  ("peer-B".to_string(), "255.0.0.1:65511".parse().unwrap()));
-    }
-
-    #[test]
-    fn member_response_deserialization() {
-        let data = [
-            // group name
-            'g' as u8, 'r' as u8, 'p' as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            // member count
-            2,
-            // First peer name
-            'p' as u8, 'e' as u8, 'e' as u8, 'r' as u8, 'A' as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            // First IP and port
-            11, 22, 255, 0, 0x04, 0xD2,
-            // Second peer name
-            'p' as u8, 'e' as u8, 'e' as u8, 'r' as u8, 'B' as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            // Second IP and port
-            255, 0, 1, 1, 0xFD, 0xFE,
-        ];
-        let res = MemberResponse::try_from(data.to_vec()).unwrap();
-
-        assert_eq!(res.group, "grp".to_string());
-
-        // Member count
-        assert_eq!(res.member_number, 2);
-
-        let peers = res.peers();
-        assert_eq!(peers[0], ("peerA".to_string(), SocketAddr::new("11.22.255.0".parse().unwrap(), 1234)));
-        assert_eq!(peers[1], ("peerB".to_string(), SocketAddr::new("255.0.1.1".parse().unwrap(), 65022)));
-    }
-}
\ No newline at end of file
+use std::{borrow::Cow, fmt::Display, net::{SocketAddr, IpAddr}, io::{Cursor, Read}, error::Error, ops::Range, str::Utf8Error, string::FromUtf8Error, collections::{HashSet, VecDeque}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::tlv::{TlvReader, TlvValue, TlvWriter};
+
+const MAGIC_HEADER: u8 = 0x9D;
+
+/// Length of the header in front of every message
+pub const HEADER_SIZE: usize = 4;
+
+/// Type nibble of the messages whose type doesn't fit in 4 bits.
+/// Their header has a fifth byte with the type, from 0x10 on.
+const EXTENDED_TYPE: u8 = 0x0F;
+
+/// Returns the length of the header starting with the given version and type byte
+fn header_len(version_type: u8) -> usize {
+    match version_type & 0x0F {
+        EXTENDED_TYPE => HEADER_SIZE + 1,
+        _ => HEADER_SIZE,
+    }
+}
+
+/// The low 12 bits of the header size field hold the content length, the high 4 bits are flags
+const SIZE_MASK: u16 = 0x0FFF;
+
+/// Longest content a message can have
+pub const MAX_CONTENT: usize = SIZE_MASK as usize;
+
+/// Flag of the size field, the message ends with a CRC32C of the header and the content
+const FLAG_CHECKSUM: u16 = 0x8000;
+
+/// Flag of the size field, the message ends with the send time and an HMAC-SHA256 of the header, the content and the send time
+const FLAG_MAC: u16 = 0x4000;
+
+/// Flag of the size field, the content is compressed with LZ4. Trailers cover the compressed bytes.
+const FLAG_COMPRESSED: u16 = 0x2000;
+
+/// Flags of the trailer, at most one is set
+const TRAILER_FLAGS: u16 = FLAG_CHECKSUM | FLAG_MAC;
+
+const CHECKSUM_LEN: usize = 4;
+
+/// The MAC is cut to 128 bits to keep the messages short
+const MAC_LEN: usize = 16;
+
+/// Send time in front of the MAC, milliseconds since the Unix epoch
+const STAMP_LEN: usize = 8;
+
+/// Trailer appended to the messages for catching corrupted or forged datagrams
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Integrity {
+    /// No trailer
+    None,
+    /// CRC32C trailer, catches corrupted datagrams
+    Checksum,
+    /// MAC with the shared secret of the group. Messages without a valid MAC are dropped.
+    Mac(Vec<u8>),
+}
+
+/// Trailers of the joined groups and the MACs received lately.
+/// Owned by the peer, which encodes the messages of a group with its trailer and opens the received ones with all of them.
+#[derive(Debug, Default)]
+pub struct Keyring {
+    /// Groups that weren't joined use checksums
+    groups: Vec<(String, Integrity)>,
+    seen: SeenMacs,
+}
+
+impl Keyring {
+    pub fn new() -> Keyring {
+        Keyring::default()
+    }
+
+    /// Sets the trailer of the messages of a joined group, a MAC for groups with a shared secret
+    pub fn set(&mut self, group: &str, integrity: Integrity) {
+        self.groups.retain(|(g, _)| g != group);
+        self.groups.push((group.to_string(), integrity));
+    }
+
+    /// Forgets the trailer of a group that was left
+    pub fn remove(&mut self, group: &str) {
+        self.groups.retain(|(g, _)| g != group);
+    }
+
+    /// Returns the trailer of the group's messages.
+    /// Messages without a group are sent with the trailer of the group of the neighbour, None takes the first joined group.
+    pub fn integrity(&self, group: Option<&str>) -> Integrity {
+        let found = match group {
+            Some(group) => self.groups.iter().find(|(g, _)| g == group),
+            None => self.groups.first(),
+        };
+        found.map(|(_, integrity)| integrity.clone()).unwrap_or(Integrity::Checksum)
+    }
+
+    /// Checks the length and the trailer of a received message before its content is decompressed.
+    /// A MAC is checked against the keys of all joined groups, a signed message is accepted once.
+    /// Whether the trailer fits the group of the content is checked when it's decoded.
+    pub fn open<'a>(&mut self, buf: &'a [u8]) -> Result<MessageRef<'a>, FormatError> {
+        let (sealed, data) = MessageRef::split(buf)?;
+        let seal = match sealed.header.flags & TRAILER_FLAGS {
+            FLAG_MAC => {
+                let mut result = Err(FormatError::Integrity(IntegrityError::UnexpectedMac));
+                for (group, integrity) in self.groups.iter().filter(|(_, integrity)| matches!(integrity, Integrity::Mac(_))) {
+                    result = sealed.check(integrity).map(|stamp| (group, stamp));
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+                let (group, stamp) = result?;
+                let tag = <[u8; MAC_LEN]>::try_from(sealed.trailer).unwrap();
+                if !self.seen.insert(stamp.unwrap(), tag, now_millis()) {
+                    return Err(FormatError::Integrity(IntegrityError::Replayed));
+                }
+                Seal::Signed(group.clone())
+            },
+            _ => Seal::Unsigned {
+                secret_groups: self.groups.iter().filter(|(_, integrity)| matches!(integrity, Integrity::Mac(_))).map(|(group, _)| group.clone()).collect(),
+                open_groups: self.groups.is_empty() || self.groups.iter().any(|(_, integrity)| !matches!(integrity, Integrity::Mac(_))),
+            },
+        };
+        MessageRef::inflate(sealed.header, data, seal)
+    }
+}
+
+fn mac(key: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac
+}
+
+/// Signed messages sent longer ago than this are dropped, so a captured message can't be replayed later.
+/// The clocks of the peers sharing a secret have to be this close.
+pub const REPLAY_WINDOW: Duration = Duration::from_secs(60);
+
+/// Most MACs remembered for catching replays within the window, the oldest are forgotten first
+const MAX_SEEN_MACS: usize = 65536;
+
+/// MACs of the signed messages received within the replay window.
+/// Every signed message has its own send time, so a message seen twice was replayed.
+#[derive(Debug, Default)]
+struct SeenMacs {
+    tags: HashSet<[u8; MAC_LEN]>,
+    order: VecDeque<(u64, [u8; MAC_LEN])>,
+}
+
+impl SeenMacs {
+    /// Remembers the MAC of a message sent at `stamp`, returns false if it was already seen
+    fn insert(&mut self, stamp: u64, tag: [u8; MAC_LEN], now: u64) -> bool {
+        let window = REPLAY_WINDOW.as_millis() as u64;
+        while let Some((oldest, old_tag)) = self.order.front().copied() {
+            if oldest + window >= now && self.order.len() < MAX_SEEN_MACS {
+                break;
+            }
+            self.order.pop_front();
+            self.tags.remove(&old_tag);
+        }
+        if !self.tags.insert(tag) {
+            return false;
+        }
+        self.order.push_back((stamp, tag));
+        true
+    }
+}
+
+/// Returns the wall clock time in milliseconds, the send time of the signed messages
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Returns the length of the trailer, None if the flags are unknown.
+/// The MAC trailer starts with the send time.
+fn trailer_len(size_field: u16) -> Option<usize> {
+    match size_field & TRAILER_FLAGS {
+        0 => Some(0),
+        FLAG_CHECKSUM => Some(CHECKSUM_LEN),
+        FLAG_MAC => Some(STAMP_LEN + MAC_LEN),
+        _ => None,
+    }
+}
+
+/// Returns the length of a message with the given header version and type byte and size field, None if the flags are unknown
+fn message_len(version_type: u8, size_field: u16) -> Option<usize> {
+    if size_field & !(SIZE_MASK | TRAILER_FLAGS | FLAG_COMPRESSED) != 0 {
+        return None;
+    }
+    Some(header_len(version_type) + (size_field & SIZE_MASK) as usize + trailer_len(size_field)?)
+}
+
+/// Feature bit of `Alive` and `MemberRequest`, the peer reads compressed contents
+pub const FEATURE_COMPRESSION: u8 = 0x01;
+
+/// Features of this build, advertised unless turned off with `set_features`
+pub const FEATURES: u8 = FEATURE_COMPRESSION;
+
+/// Dictionary shared by all peers for compressing the short contents, which don't repeat enough
+/// on their own. Holds the field headers of chats and member lists, private address prefixes
+/// and frequent words. Changing it breaks the decoding of compressed contents of older peers.
+const COMPRESSION_DICT: &[u8] = b"\x02\x00\x06\x7F\x00\x00\x01\x02\x00\x06\xC0\xA8\x00\x02\x00\x06\xC0\xA8\x01\x02\x00\x06\x0A\x00\x00\
+    \x04\x00\x01\x00\x05\x00\x03\x00\x04\x00\x00\x00\x04\x00\x01\x04\x01\x00\
+    https://www. http://.com/ .org/ the and that this with have you for not are was but what just \
+    like know there they about would will your from when can't don't it's I'm thanks \
+    fn let mut pub use impl self return if else match Some(None) Ok(Err(\n    \n\n";
+
+/// Compresses a content, None if it doesn't get shorter. The compressed content starts with
+/// the original length, so a lying sender can't make the decoder allocate more than 64 KiB.
+fn compress_content(content: &[u8]) -> Option<Vec<u8>> {
+    let len = u16::try_from(content.len()).ok()?;
+    let mut buf = len.to_be_bytes().to_vec();
+    buf.extend(lz4_flex::block::compress_with_dict(content, COMPRESSION_DICT));
+    (buf.len() < content.len()).then_some(buf)
+}
+
+fn decompress_content(content: &[u8]) -> Result<Vec<u8>, FormatError> {
+    let (len, block) = match content {
+        [len_hi, len_lo, block @ ..] => (u16::from_be_bytes([*len_hi, *len_lo]) as usize, block),
+        _ => return Err(FormatError::Truncated),
+    };
+    let raw = lz4_flex::block::decompress_with_dict(block, len, COMPRESSION_DICT)
+        .map_err(|_| FormatError::Decompression)?;
+    if raw.len() != len {
+        return Err(FormatError::Decompression);
+    }
+    Ok(raw)
+}
+
+/// Version of the protocol put in the header of the sent messages.
+///
+/// Versions stay compatible as long as the layouts only grow: fixed layouts get new fields appended,
+/// TLV bodies get new tags that older decoders skip. A change that can't follow these rules raises
+/// the version and `MIN_PROTOCOL_VERSION`.
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Oldest version whose messages can still be read, and which can read the messages of this one.
+///
+/// Version 1 is the first release. Version 2 replaced its layouts: TLV bodies for `Alive`, `MemberRequest`,
+/// `MemberResponse` and `Chat`, flags for the trailer and the compression in the header size field,
+/// the version range in `MemberRequest` and the extended types. The messages of version 1 peers are
+/// dropped and counted, and they get no answer: the first release panics on the `Reject` type it doesn't
+/// know, so their requests time out. All peers and servers of a group move to version 2 together.
+pub const MIN_PROTOCOL_VERSION: u8 = 2;
+
+/// Returns true if the peer supporting the range of versions can talk to this one
+pub fn compatible(versions: (u8, u8)) -> bool {
+    versions.0 <= versions.1 && versions.0 <= PROTOCOL_VERSION && versions.1 >= MIN_PROTOCOL_VERSION
+}
+
+/// Error of encoding or decoding a message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FormatError {
+    /// The content ends in the middle of a field
+    Truncated,
+    /// The datagram doesn't start with the magic byte, it isn't a peerko message
+    BadMagic(u8),
+    /// Message type unknown to this version
+    UnknownType(u8),
+    /// Flags of the header size field unknown to this version
+    UnknownFlags(u16),
+    /// A name or a text isn't valid UTF-8
+    InvalidUtf8,
+    /// A name, text or list is longer than the protocol allows
+    TooLong { field: &'static str, max: usize },
+    /// A field every sender has to fill in is missing from a TLV body
+    MissingField(u8),
+    /// A field has the wrong length for its value, e.g. 3 bytes for a `u32`
+    WrongLength { value: &'static str, len: usize },
+    /// A field holds a value the message doesn't allow, e.g. an unknown reject reason
+    InvalidValue { field: &'static str, value: String },
+    /// The compressed content doesn't decompress to its original length
+    Decompression,
+    /// The sizes in the header don't match the datagram
+    Framing(FrameError),
+    /// The checksum or the MAC doesn't match, the datagram is corrupted or forged
+    Integrity(IntegrityError),
+}
+
+impl Error for FormatError {}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Format err: ")?;
+        match self {
+            FormatError::Truncated => write!(f, "the content ends in the middle of a field"),
+            FormatError::BadMagic(byte) => write!(f, "not a message, it starts with 0x{:02X}", byte),
+            FormatError::UnknownType(msg_type) => write!(f, "unknown message type 0x{:02X}", msg_type),
+            FormatError::UnknownFlags(flags) => write!(f, "unknown flags 0x{:04X}", flags),
+            FormatError::InvalidUtf8 => write!(f, "text isn't valid UTF-8"),
+            FormatError::TooLong { field, max } => write!(f, "{} exceeds {}", field, max),
+            FormatError::MissingField(tag) => write!(f, "missing field 0x{:02X}", tag),
+            FormatError::WrongLength { value, len } => write!(f, "field of {} bytes isn't a {}", len, value),
+            FormatError::InvalidValue { field, value } => write!(f, "invalid {} {}", field, value),
+            FormatError::Decompression => write!(f, "the content can't be decompressed"),
+            FormatError::Framing(err) => write!(f, "{}", err),
+            FormatError::Integrity(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Reading the content only fails at its end
+impl From<std::io::Error> for FormatError {
+    fn from(_: std::io::Error) -> Self {
+        FormatError::Truncated
+    }
+}
+
+impl From<FromUtf8Error> for FormatError {
+    fn from(_: FromUtf8Error) -> Self {
+        FormatError::InvalidUtf8
+    }
+}
+
+impl From<Utf8Error> for FormatError {
+    fn from(_: Utf8Error) -> Self {
+        FormatError::InvalidUtf8
+    }
+}
+
+/// Trailer of a received message that doesn't match its policy or its bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntegrityError {
+    ChecksumMismatch,
+    MacMismatch,
+    /// A shared secret is set and the message isn't signed
+    MissingMac,
+    /// The message is signed and no shared secret is set
+    UnexpectedMac,
+    /// The signed message was sent outside the replay window
+    Stale,
+    /// The signed message was already received, someone sent it again
+    Replayed,
+}
+
+impl Display for IntegrityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntegrityError::ChecksumMismatch => write!(f, "checksum mismatch"),
+            IntegrityError::MacMismatch => write!(f, "MAC mismatch"),
+            IntegrityError::MissingMac => write!(f, "message without a MAC"),
+            IntegrityError::UnexpectedMac => write!(f, "MAC without a shared secret"),
+            IntegrityError::Stale => write!(f, "signed message outside the replay window"),
+            IntegrityError::Replayed => write!(f, "signed message received twice"),
+        }
+    }
+}
+
+/// Contents that belong to a group, their messages carry the trailer of the group
+pub trait InGroup {
+    /// Returns the group of the content, None for the messages between neighbours like the pings
+    fn group(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Implements `InGroup` for contents with a `group_name`, or with `none:` for the contents without a group
+macro_rules! in_group {
+    (none: $($content:ty),*) => {$(
+        impl InGroup for $content {}
+    )*};
+    ($($content:ty),*) => {$(
+        impl InGroup for $content {
+            fn group(&self) -> Option<&str> {
+                Some(self.group_name())
+            }
+        }
+    )*};
+}
+
+in_group!(none: Alive, Ack, PingReq, MemberSync, Challenge, DhtMessage);
+in_group!(MemberRequest, MemberRequestRef<'_>, Chat, ChatRef<'_>, ChatAction, Mail, Bye, Announce, Reject, Presence);
+
+impl InGroup for MemberResponse {
+    fn group(&self) -> Option<&str> {
+        Some(&self.group)
+    }
+}
+
+/// Market trait for types that wrap the content of a message
+pub trait MessageContent: Clone + TryFrom<Vec<u8>, Error = FormatError> + InGroup {
+    /// Appends the encoded content to the buffer. Contents sent often write straight into it.
+    /// Fails if a field doesn't fit its length, the buffer may then hold a part of the content.
+    fn encode_into(self, buf: &mut Vec<u8>) -> Result<(), FormatError>;
+}
+
+/// Contents with a fixed layout, their constructors check the fields so encoding them can't fail
+macro_rules! fixed_content {
+    ($($content:ty),*) => {$(
+        impl MessageContent for $content {
+            fn encode_into(self, buf: &mut Vec<u8>) -> Result<(), FormatError> {
+                buf.extend(Vec::<u8>::from(self));
+                Ok(())
+            }
+        }
+    )*};
+}
+
+fixed_content!(Ack, PingReq, Mail, Bye, MemberSync, Announce, Challenge, Reject, Presence, DhtMessage);
+
+/// Reads a zero padded name field of 32 bytes
+fn read_name<R: Read>(reader: &mut R) -> Result<String, FormatError> {
+    let mut name_buf = vec![0; 32];
+    reader.read_exact(&mut name_buf)?;
+
+    Ok(String::from_utf8(name_buf.into_iter().filter(|s| *s != 0).collect())?)
+}
+
+/// Writes the name as a zero padded field of 32 bytes
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+    let mut name_buf = [0u8; 32];
+    name_buf[0..name.len()].copy_from_slice(name.as_bytes());
+    buf.extend_from_slice(&name_buf);
+}
+
+/// Reads a required name field of a TLV body, names are still limited to 32 bytes
+fn read_tlv_name<'a>(reader: &TlvReader<'a>, tag: u8) -> Result<&'a str, FormatError> {
+    let name: &str = reader.require(tag)?;
+    if name.len() > 32 {
+        return Err(FormatError::TooLong { field: "name", max: 32 });
+    }
+    Ok(name)
+}
+
+/// Reads an IPv4 address and port (6 bytes)
+fn read_addr<R: Read>(reader: &mut R) -> Result<SocketAddr, FormatError> {
+    let mut ip_buf = [0; 4];
+    reader.read_exact(&mut ip_buf)?;
+
+    let port = reader.read_u16::<BigEndian>()?;
+
+    Ok(SocketAddr::new(IpAddr::from(ip_buf), port))
+}
+
+/// The fixed layouts only hold IPv4 addresses, so their constructors reject the other ones
+fn check_addr(addr: &SocketAddr) -> Result<(), FormatError> {
+    match addr {
+        SocketAddr::V4(_) => Ok(()),
+        SocketAddr::V6(_) => Err(FormatError::InvalidValue { field: "address", value: addr.to_string() }),
+    }
+}
+
+/// Writes an IPv4 address and port (6 bytes), the address was checked by `check_addr`
+fn write_addr(buf: &mut Vec<u8>, addr: &SocketAddr) {
+    if let IpAddr::V4(ip) = addr.ip() {
+        buf.extend_from_slice(&ip.octets());
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Mismatch between the sizes in the headers and the bytes of a datagram
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The datagram doesn't start with a header
+    NotMessage,
+    /// The datagram ends before the header or the content given by its size
+    Truncated { expected: usize, available: usize },
+    /// Bytes that don't form a message follow the last message
+    Trailing { bytes: usize },
+}
+
+impl Error for FrameError {}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::NotMessage => write!(f, "not a message"),
+            FrameError::Truncated { expected, available } => write!(f, "truncated message, {} of {} bytes", available, expected),
+            FrameError::Trailing { bytes } => write!(f, "{} trailing bytes", bytes),
+        }
+    }
+}
+
+impl From<FrameError> for FormatError {
+    fn from(err: FrameError) -> Self {
+        FormatError::Framing(err)
+    }
+}
+
+/// Iterator over the messages coalesced into a datagram, see `frames`
+pub struct Frames<'a> {
+    rest: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = Result<&'a [u8], FrameError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // An empty datagram isn't a message either
+        if self.done || (self.rest.is_empty() && self.offset > 0) {
+            return None;
+        }
+
+        let available = self.rest.len();
+        let frame = match self.rest {
+            [MAGIC_HEADER, version_type, size_hi, size_lo, ..] => match message_len(*version_type, u16::from_be_bytes([*size_hi, *size_lo])) {
+                Some(expected) => self.rest.get(..expected).ok_or(FrameError::Truncated { expected, available }),
+                // The trailer length of unknown flags isn't known
+                None => Err(FrameError::NotMessage),
+            },
+            [MAGIC_HEADER, ..] => Err(FrameError::Truncated { expected: HEADER_SIZE, available }),
+            _ if self.offset == 0 => Err(FrameError::NotMessage),
+            _ => Err(FrameError::Trailing { bytes: available }),
+        };
+
+        match frame {
+            Ok(frame) => {
+                self.rest = &self.rest[frame.len()..];
+                self.offset += frame.len();
+            },
+            // Nothing after a broken frame can be trusted
+            Err(_) => self.done = true,
+        }
+        Some(frame)
+    }
+}
+
+/// Splits a datagram into its messages by the sizes in their headers
+pub fn frames(buf: &[u8]) -> Frames<'_> {
+    Frames { rest: buf, offset: 0, done: false }
+}
+
+/// Datagrams of at most `max` bytes that messages are encoded into one after another, keeping their order.
+/// The messages are written in place, a message longer than `max` is sent alone.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Datagrams {
+    datagrams: Vec<Vec<u8>>,
+    max: usize,
+}
+
+impl Datagrams {
+    pub fn new(max: usize) -> Datagrams {
+        Datagrams { datagrams: vec![], max }
+    }
+
+    /// Appends the message written by `encode`, e.g. `|buf| msg.encode_into(buf, &integrity, compress)`.
+    /// A message that doesn't fit in the last datagram is moved to a new one.
+    /// `encode` has to leave the buffer as it was when it fails, like `Message::encode_into`.
+    pub fn push(&mut self, encode: impl FnOnce(&mut Vec<u8>) -> Result<(), FormatError>) -> Result<(), FormatError> {
+        if self.datagrams.last().is_none_or(|last| last.len() >= self.max) {
+            self.datagrams.push(Vec::with_capacity(self.max));
+        }
+        let last = self.datagrams.last_mut().unwrap();
+        let start = last.len();
+        encode(last)?;
+        if last.len() > self.max && start > 0 {
+            let msg = last.split_off(start);
+            self.datagrams.push(msg);
+        }
+        Ok(())
+    }
+
+    /// Returns the filled datagrams
+    pub fn finish(self) -> Vec<Vec<u8>> {
+        self.datagrams.into_iter().filter(|datagram| !datagram.is_empty()).collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    magic_bytes: u8,
+    version: u8,
+    msg_type: MessageType,
+    /// Length of the content, set when the message is encoded
+    size: u16,
+    /// Flags in the high bits of the size field
+    flags: u16,
+}
+
+impl Header {
+    pub fn new(version: u8, r#type: MessageType, size: u16) -> Header {
+        Header {
+            magic_bytes: MAGIC_HEADER,
+            version,
+            msg_type: r#type,
+            size: size & SIZE_MASK,
+            flags: 0,
+        }
+    }
+
+    pub fn msg_type(&self) -> MessageType {
+        self.msg_type
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Length of the encoded header, `HEADER_SIZE` or one more for the extended types
+    pub fn encoded_len(&self) -> usize {
+        header_len(self.version_type())
+    }
+
+    fn version_type(&self) -> u8 {
+        (self.version << 4) | (self.msg_type as u8).min(EXTENDED_TYPE)
+    }
+
+    /// Writes the header to the start of the buffer, which is `encoded_len` long
+    fn write(&self, buf: &mut [u8]) {
+        let [size_hi, size_lo] = (self.size | self.flags).to_be_bytes();
+        buf[..HEADER_SIZE].copy_from_slice(&[self.magic_bytes, self.version_type(), size_hi, size_lo]);
+        if let Some(ext) = buf.get_mut(HEADER_SIZE) {
+            *ext = self.msg_type as u8;
+        }
+    }
+}
+
+#[allow(clippy::from_over_into)]
+impl Into<Vec<u8>> for Header {
+    fn into(self) -> Vec<u8> {
+        let mut buf = vec![0; self.encoded_len()];
+        self.write(&mut buf);
+        buf
+    }
+}
+
+impl TryFrom<Vec<u8>> for Header {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        Header::try_from(value.as_slice())
+    }
+}
+
+impl TryFrom<&[u8]> for Header {
+    type Error = FormatError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let mut reader = Cursor::new(value);
+        let magic_bytes = reader.read_u8()?;
+
+        if magic_bytes != MAGIC_HEADER {
+            return Err(FormatError::BadMagic(magic_bytes));
+        }
+
+        let version_type = reader.read_u8()?;
+        let size = reader.read_u16::<BigEndian>()?;
+        if message_len(version_type, size).is_none() {
+            return Err(FormatError::UnknownFlags(size & !SIZE_MASK));
+        }
+        let msg_type = match version_type & 0x0F {
+            EXTENDED_TYPE => match reader.read_u8()? {
+                ext if ext > EXTENDED_TYPE => ext,
+                ext => return Err(FormatError::UnknownType(ext)),
+            },
+            msg_type => msg_type,
+        };
+
+        Ok(Header {
+            magic_bytes,
+            version: version_type >> 4,
+            msg_type: MessageType::try_from(msg_type)?,
+            size: size & SIZE_MASK,
+            flags: size & !SIZE_MASK,
+        })
+    }
+}
+
+/// Type of the message. Types from 0x10 on are extended types, see `EXTENDED_TYPE`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    Alive = 0x01,
+    MemberReq = 0x02,
+    MemberRes = 0x04,
+    Chat = 0x08,
+    Mail = 0x03,
+    Ack = 0x05,
+    PingReq = 0x06,
+    Bye = 0x07,
+    Sync = 0x09,
+    Dht = 0x0A,
+    Announce = 0x0B,
+    Challenge = 0x0C,
+    Reject = 0x0D,
+    Presence = 0x0E,
+    ChatAction = 0x10,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = FormatError;
+
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0x01 => Ok(MessageType::Alive),
+            0x02 => Ok(MessageType::MemberReq),
+            0x04 => Ok(MessageType::MemberRes),
+            0x08 => Ok(MessageType::Chat),
+            0x03 => Ok(MessageType::Mail),
+            0x05 => Ok(MessageType::Ack),
+            0x06 => Ok(MessageType::PingReq),
+            0x07 => Ok(MessageType::Bye),
+            0x09 => Ok(MessageType::Sync),
+            0x0A => Ok(MessageType::Dht),
+            0x0B => Ok(MessageType::Announce),
+            0x0C => Ok(MessageType::Challenge),
+            0x0D => Ok(MessageType::Reject),
+            0x0E => Ok(MessageType::Presence),
+            0x10 => Ok(MessageType::ChatAction),
+            _ => Err(FormatError::UnknownType(val)),
+        }
+    }
+}
+
+/// Membership state of a peer as seen by the failure detector
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MemberState {
+    Alive = 0x00,
+    Suspect = 0x01,
+    Dead = 0x02,
+}
+
+impl TryFrom<u8> for MemberState {
+    type Error = FormatError;
+
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0x00 => Ok(MemberState::Alive),
+            0x01 => Ok(MemberState::Suspect),
+            0x02 => Ok(MemberState::Dead),
+            _ => Err(FormatError::InvalidValue { field: "member state", value: val.to_string() }),
+        }
+    }
+}
+
+/// Membership change piggybacked on the failure detector messages
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemberUpdate {
+    group: String,
+    peer_id: String,
+    addr: SocketAddr,
+    state: MemberState,
+    incarnation: u32,
+}
+
+impl MemberUpdate {
+    pub fn new(group: &str, peer_id: &str, addr: SocketAddr, state: MemberState, incarnation: u32) -> Result<MemberUpdate, FormatError> {
+        if group.len() > 32 {
+            return Err(FormatError::TooLong { field: "group name", max: 32 });
+        }
+
+        if peer_id.len() > 32 {
+            return Err(FormatError::TooLong { field: "peer id", max: 32 });
+        }
+
+        check_addr(&addr)?;
+
+        Ok(MemberUpdate { group: group.to_string(), peer_id: peer_id.to_string(), addr, state, incarnation })
+    }
+
+    pub fn group_name(&self) -> &str {
+        &self.group
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    pub fn state(&self) -> MemberState {
+        self.state
+    }
+
+    pub fn incarnation(&self) -> u32 {
+        self.incarnation
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        // group_name + peer_id + IP(4) + Port(2) + state(1) + incarnation(4)
+        write_name(buf, &self.group);
+        write_name(buf, &self.peer_id);
+        write_addr(buf, &self.addr);
+        buf.push(self.state as u8);
+        buf.extend_from_slice(&self.incarnation.to_be_bytes());
+    }
+
+    fn read<R: Read>(reader: &mut R) -> Result<MemberUpdate, FormatError> {
+        let group = read_name(reader)?;
+        let peer_id = read_name(reader)?;
+        let addr = read_addr(reader)?;
+        let state = MemberState::try_from(reader.read_u8()?)?;
+        let incarnation = reader.read_u32::<BigEndian>()?;
+
+        Ok(MemberUpdate { group, peer_id, addr, state, incarnation })
+    }
+}
+
+/// Writes the update count followed by the updates
+fn write_updates(buf: &mut Vec<u8>, updates: &[MemberUpdate]) {
+    buf.push(updates.len() as u8);
+    for update in updates {
+        update.write(buf);
+    }
+}
+
+fn read_updates<R: Read>(reader: &mut R) -> Result<Vec<MemberUpdate>, FormatError> {
+    let count = reader.read_u8()?;
+
+    (0..count).map(|_| MemberUpdate::read(reader)).collect()
+}
+
+/// Direct ping of the failure detector. Also keeps the NAT mapping open.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Alive {
+    peer_id: String,
+    /// Random id of the sending process, tells a moved peer from another one with the same name
+    session: u64,
+    seq: u32,
+    updates: Vec<MemberUpdate>,
+    /// Features supported by the sender, `FEATURE_*` bits
+    features: u8,
+}
+
+impl MessageContent for Alive {
+    fn encode_into(self, buf: &mut Vec<u8>) -> Result<(), FormatError> {
+        let mut writer = TlvWriter::with_buffer(std::mem::take(buf));
+        let written = self.write_fields(&mut writer);
+        *buf = writer.finish();
+        written
+    }
+}
+
+impl Alive {
+    fn write_fields(&self, writer: &mut TlvWriter) -> Result<(), FormatError> {
+        writer.put(Alive::PEER_ID, &self.peer_id)?
+            .put(Alive::SEQ, &self.seq)?
+            .put(Alive::SESSION, &self.session)?
+            .put(Alive::FEATURES, &self.features)?;
+        let mut update_buf = vec![];
+        for update in self.updates.iter() {
+            update_buf.clear();
+            update.write(&mut update_buf);
+            writer.put_bytes(Alive::UPDATE, &update_buf)?;
+        }
+        Ok(())
+    }
+
+    // Field tags
+    const PEER_ID: u8 = 0x01;
+    const SEQ: u8 = 0x02;
+    /// Repeated, one member update in its fixed layout per field
+    const UPDATE: u8 = 0x03;
+    const SESSION: u8 = 0x04;
+    const FEATURES: u8 = 0x05;
+
+    pub fn new(peer_id: String, session: u64, seq: u32, updates: Vec<MemberUpdate>) -> Alive {
+        Alive { peer_id, session, seq, updates, features: FEATURES }
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    pub fn updates(&self) -> &[MemberUpdate] {
+        &self.updates
+    }
+
+    pub fn features(&self) -> u8 {
+        self.features
+    }
+
+    /// Advertises the given features instead of all the features of this build
+    pub fn set_features(&mut self, features: u8) {
+        self.features = features;
+    }
+}
+
+impl TryFrom<Alive> for Vec<u8> {
+    type Error = FormatError;
+
+    fn try_from(val: Alive) -> Result<Self, Self::Error> {
+        let mut buf = vec![];
+        val.encode_into(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl TryFrom<Vec<u8>> for Alive {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        let reader = TlvReader::parse(&value)?;
+
+        let peer_id = read_tlv_name(&reader, Alive::PEER_ID)?.to_string();
+        let seq = reader.get(Alive::SEQ)?.unwrap_or(0);
+        let session = reader.get(Alive::SESSION)?.unwrap_or(0);
+        let features = reader.get(Alive::FEATURES)?.unwrap_or(0);
+        let updates = reader.all(Alive::UPDATE)
+            .map(|update| MemberUpdate::read(&mut Cursor::new(update)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Alive {
+            peer_id,
+            session,
+            seq,
+            updates,
+            features,
+        })
+    }
+}
+
+/// Answer to a direct or indirect ping
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ack {
+    peer_id: String,
+    seq: u32,
+    updates: Vec<MemberUpdate>,
+}
+
+
+impl Ack {
+    pub fn new(peer_id: String, seq: u32, updates: Vec<MemberUpdate>) -> Result<Ack, FormatError> {
+        if peer_id.len() > 32 {
+            return Err(FormatError::TooLong { field: "peer id", max: 32 });
+        }
+        if updates.len() > u8::MAX as usize {
+            return Err(FormatError::TooLong { field: "member updates", max: u8::MAX as usize });
+        }
+
+        Ok(Ack { peer_id, seq, updates })
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    pub fn updates(&self) -> &[MemberUpdate] {
+        &self.updates
+    }
+
+    /// Returns the same answer with another sequence number, used when relaying an indirect ping.
+    /// The name was checked when the answer was created or decoded.
+    pub fn with_seq(&self, seq: u32) -> Ack {
+        Ack { peer_id: self.peer_id.clone(), seq, updates: vec![] }
+    }
+}
+
+impl From<Ack> for Vec<u8> {
+    fn from(val: Ack) -> Self {
+        // peer_id + seq(4) + update_count(1) + updates
+        let mut buf = vec![];
+        write_name(&mut buf, &val.peer_id);
+        buf.extend_from_slice(&val.seq.to_be_bytes());
+        write_updates(&mut buf, &val.updates);
+        buf
+    }
+}
+
+impl TryFrom<Vec<u8>> for Ack {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        let mut reader = Cursor::new(&value);
+
+        let peer_id = read_name(&mut reader)?;
+        let seq = reader.read_u32::<BigEndian>()?;
+        let updates = read_updates(&mut reader)?;
+
+        Ok(Ack {
+            peer_id,
+            seq,
+            updates,
+        })
+    }
+}
+
+/// Asks a member to ping the target on behalf of the requester
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PingReq {
+    peer_id: String,
+    target_id: String,
+    target_addr: SocketAddr,
+    seq: u32,
+}
+
+
+impl PingReq {
+    pub fn new(peer_id: String, target_id: String, target_addr: SocketAddr, seq: u32) -> Result<PingReq, FormatError> {
+        if peer_id.len() > 32 || target_id.len() > 32 {
+            return Err(FormatError::TooLong { field: "peer id", max: 32 });
+        }
+        check_addr(&target_addr)?;
+
+        Ok(PingReq { peer_id, target_id, target_addr, seq })
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub fn target_id(&self) -> &str {
+        &self.target_id
+    }
+
+    pub fn target_addr(&self) -> &SocketAddr {
+        &self.target_addr
+    }
+
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+}
+
+impl From<PingReq> for Vec<u8> {
+    fn from(val: PingReq) -> Self {
+        // peer_id + target_id + IP(4) + Port(2) + seq(4)
+        let mut buf = vec![];
+        write_name(&mut buf, &val.peer_id);
+        write_name(&mut buf, &val.target_id);
+        write_addr(&mut buf, &val.target_addr);
+        buf.extend_from_slice(&val.seq.to_be_bytes());
+        buf
+    }
+}
+
+impl TryFrom<Vec<u8>> for PingReq {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        let mut reader = Cursor::new(&value);
+
+        let peer_id = read_name(&mut reader)?;
+        let target_id = read_name(&mut reader)?;
+        let target_addr = read_addr(&mut reader)?;
+        let seq = reader.read_u32::<BigEndian>()?;
+
+        Ok(PingReq {
+            peer_id,
+            target_id,
+            target_addr,
+            seq,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemberRequest {
+    peer_id: String,
+    group: String,
+    /// Index of the first member to return, for fetching the next page of a large group
+    cursor: u16,
+    /// Random id of the requesting process, tells a moved peer from another one with the same name
+    session: u64,
+    /// Oldest and newest protocol version supported by the requester
+    versions: (u8, u8),
+    /// Features supported by the requester, `FEATURE_*` bits
+    features: u8,
+}
+
+impl MessageContent for MemberRequest {
+    fn encode_into(self, buf: &mut Vec<u8>) -> Result<(), FormatError> {
+        let mut writer = TlvWriter::with_buffer(std::mem::take(buf));
+        let written = self.write_fields(&mut writer);
+        *buf = writer.finish();
+        written
+    }
+}
+
+impl MemberRequest {
+    fn write_fields(&self, writer: &mut TlvWriter) -> Result<(), FormatError> {
+        writer.put(MemberRequest::GROUP, &self.group)?
+            .put(MemberRequest::PEER_ID, &self.peer_id)?
+            .put(MemberRequest::CURSOR, &self.cursor)?
+            .put(MemberRequest::SESSION, &self.session)?
+            .put(MemberRequest::MIN_VERSION, &self.versions.0)?
+            .put(MemberRequest::MAX_VERSION, &self.versions.1)?
+            .put(MemberRequest::FEATURES, &self.features)?;
+        Ok(())
+    }
+
+    // Field tags
+    const GROUP: u8 = 0x01;
+    const PEER_ID: u8 = 0x02;
+    const CURSOR: u8 = 0x03;
+    const SESSION: u8 = 0x04;
+    const MIN_VERSION: u8 = 0x05;
+    const MAX_VERSION: u8 = 0x06;
+    const FEATURES: u8 = 0x07;
+
+    pub fn new(peer_id: &str, group: &str, cursor: u16, session: u64) -> Result<MemberRequest, FormatError> {
+        if group.len() > 32 {
+            return Err(FormatError::TooLong { field: "group name", max: 32 });
+        }
+
+        if peer_id.len() > 32 {
+            return Err(FormatError::TooLong { field: "peer id", max: 32 });
+        }
+        
+        Ok(MemberRequest {
+            group: group.to_string(),
+            peer_id: peer_id.to_string(),
+            cursor,
+            session,
+            versions: (MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+            features: FEATURES,
+        })
+    }
+
+    pub fn group_name(&self) -> &str {
+        &self.group
+    }
+
+    pub fn peer_id(&self) -> String {
+        self.peer_id.clone()
+    }
+
+    pub fn cursor(&self) -> u16 {
+        self.cursor
+    }
+
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    /// Oldest and newest protocol version supported by the requester
+    pub fn versions(&self) -> (u8, u8) {
+        self.versions
+    }
+
+    pub fn features(&self) -> u8 {
+        self.features
+    }
+
+    /// Advertises the given features instead of all the features of this build
+    pub fn set_features(&mut self, features: u8) {
+        self.features = features;
+    }
+}
+
+impl TryFrom<MemberRequest> for Vec<u8> {
+    type Error = FormatError;
+
+    fn try_from(val: MemberRequest) -> Result<Self, Self::Error> {
+        let mut buf = vec![];
+        val.encode_into(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl TryFrom<Vec<u8>> for MemberRequest {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        MemberRequestRef::try_from(value.as_slice()).map(MemberRequest::from)
+    }
+}
+
+/// Member request decoded in place, the names borrow from the received content.
+/// Every peer of the group sends one on joining and on each page, so the server doesn't copy them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemberRequestRef<'a> {
+    peer_id: &'a str,
+    group: &'a str,
+    cursor: u16,
+    session: u64,
+    versions: (u8, u8),
+    features: u8,
+}
+
+impl<'a> MemberRequestRef<'a> {
+    pub fn group_name(&self) -> &'a str {
+        self.group
+    }
+
+    pub fn peer_id(&self) -> &'a str {
+        self.peer_id
+    }
+
+    pub fn cursor(&self) -> u16 {
+        self.cursor
+    }
+
+    pub fn session(&self) -> u64 {
+        self.session
+    }
+
+    /// Oldest and newest protocol version supported by the requester
+    pub fn versions(&self) -> (u8, u8) {
+        self.versions
+    }
+
+    pub fn features(&self) -> u8 {
+        self.features
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for MemberRequestRef<'a> {
+    type Error = FormatError;
+
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        let reader = TlvReader::parse(value)?;
+
+        let group = read_tlv_name(&reader, MemberRequest::GROUP)?;
+        let peer_id = read_tlv_name(&reader, MemberRequest::PEER_ID)?;
+        let cursor = reader.get(MemberRequest::CURSOR)?.unwrap_or(0);
+        let session = reader.get(MemberRequest::SESSION)?.unwrap_or(0);
+        // A requester that doesn't say speaks only the oldest version
+        let min_version = reader.get(MemberRequest::MIN_VERSION)?.unwrap_or(MIN_PROTOCOL_VERSION);
+        let max_version = reader.get(MemberRequest::MAX_VERSION)?.unwrap_or(min_version);
+        let features = reader.get(MemberRequest::FEATURES)?.unwrap_or(0);
+
+        Ok(MemberRequestRef {
+            group,
+            peer_id,
+            cursor,
+            session,
+            versions: (min_version, max_version),
+            features,
+        })
+    }
+}
+
+impl From<MemberRequestRef<'_>> for MemberRequest {
+    fn from(val: MemberRequestRef<'_>) -> Self {
+        MemberRequest {
+            group: val.group.to_string(),
+            peer_id: val.peer_id.to_string(),
+            cursor: val.cursor,
+            session: val.session,
+            versions: val.versions,
+            features: val.features,
+        }
+    }
+}
+
+/// Size of the datagrams the messages are sent in, the `MAX_DATAGRAM` of the UDP transport
+const DATAGRAM_SIZE: usize = 1024;
+
+/// Encoded length of a member response without its members, with the longest group name
+const MEMBER_PAGE_FIXED_LEN: usize = (3 + 32) + (3 + 2) + (3 + 2);
+
+/// Encoded length of a member of the page with the longest peer id: the nested field, the id and the IPv4 address
+const MEMBER_ENTRY_LEN: usize = 3 + (3 + 32) + (3 + 6);
+
+/// Maximum number of peers inside a single member response, so a full page with a MAC trailer fits in a datagram
+pub const MEMBER_PAGE_SIZE: usize =
+    (DATAGRAM_SIZE - HEADER_SIZE - STAMP_LEN - MAC_LEN - MEMBER_PAGE_FIXED_LEN) / MEMBER_ENTRY_LEN;
+
+/// One page of the group member list.
+/// Large groups are split over several responses, the requester asks for
+/// the next page by sending a `MemberRequest` with the next cursor.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemberResponse {
+    group: String,
+    /// Number of members in the whole list
+    total: u16,
+    /// Index of the first member in this page
+    cursor: u16,
+    member_number: u8,
+    peers: Vec<(String, SocketAddr)>,
+}
+
+impl MessageContent for MemberResponse {
+    fn encode_into(self, buf: &mut Vec<u8>) -> Result<(), FormatError> {
+        let mut writer = TlvWriter::with_buffer(std::mem::take(buf));
+        let written = self.write_fields(&mut writer);
+        *buf = writer.finish();
+        written
+    }
+}
+
+impl MemberResponse {
+    fn write_fields(&self, writer: &mut TlvWriter) -> Result<(), FormatError> {
+        writer.put(MemberResponse::GROUP, &self.group)?
+            .put(MemberResponse::TOTAL, &self.total)?
+            .put(MemberResponse::CURSOR, &self.cursor)?;
+        let mut peer = TlvWriter::new();
+        for (peer_id, peer_addr) in self.peers.iter() {
+            let peer_buf = peer
+                .put(MemberResponse::PEER_ID, peer_id)?
+                .put(MemberResponse::PEER_ADDR, peer_addr)?
+                .finish();
+            writer.put_bytes(MemberResponse::PEER, &peer_buf)?;
+        }
+        Ok(())
+    }
+
+    // Field tags
+    const GROUP: u8 = 0x01;
+    const TOTAL: u8 = 0x02;
+    const CURSOR: u8 = 0x03;
+    /// Repeated, one nested body with the peer id and address per member
+    const PEER: u8 = 0x04;
+    const PEER_ID: u8 = 0x01;
+    const PEER_ADDR: u8 = 0x02;
+
+    pub fn new(group: &str, total: u16, cursor: u16, peers: Vec<(String, SocketAddr)>) -> Result<MemberResponse, FormatError> {
+        if peers.len() > MEMBER_PAGE_SIZE {
+            return Err(FormatError::TooLong { field: "member page", max: MEMBER_PAGE_SIZE });
+        }
+
+        if group.len() > 32 {
+            return Err(FormatError::TooLong { field: "group name", max: 32 });
+        }
+
+        if cursor as usize + peers.len() > total as usize {
+            return Err(FormatError::InvalidValue { field: "cursor", value: cursor.to_string() });
+        }
+        
+        let member_number = peers.len().try_into().expect("Failed to get member count");
+
+        Ok(MemberResponse { group: group.to_string(), total, cursor, member_number, peers })
+    }
+
+    /// Returns the requested page of the member list
+    pub fn page(group: &str, members: &[(String, SocketAddr)], cursor: u16) -> Result<MemberResponse, FormatError> {
+        let total: u16 = members.len().try_into()
+            .map_err(|_| FormatError::TooLong { field: "member list", max: u16::MAX as usize })?;
+        let start = (cursor as usize).min(members.len());
+        let end = (start + MEMBER_PAGE_SIZE).min(members.len());
+
+        MemberResponse::new(group, total, start as u16, members[start..end].to_vec())
+    }
+
+    pub fn group_name(&self) -> String {
+        self.group.clone()
+    }
+
+    pub fn peers(&self) -> &Vec<(String, SocketAddr)> {
+        &self.peers
+    }
+
+    pub fn total(&self) -> u16 {
+        self.total
+    }
+
+    pub fn cursor(&self) -> u16 {
+        self.cursor
+    }
+
+    /// Returns the cursor of the next page, if there is one
+    pub fn next_cursor(&self) -> Option<u16> {
+        let next = self.cursor.saturating_add(self.peers.len() as u16);
+        if next < self.total && !self.peers.is_empty() {
+            Some(next)
+        } else {
+            None
+        }
+    }
+}
+
+impl TryFrom<MemberResponse> for Vec<u8> {
+    type Error = FormatError;
+
+    fn try_from(val: MemberResponse) -> Result<Self, Self::Error> {
+        let mut buf = vec![];
+        val.encode_into(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl TryFrom<Vec<u8>> for MemberResponse {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        let reader = TlvReader::parse(&value)?;
+
+        let group = read_tlv_name(&reader, MemberResponse::GROUP)?;
+        let total = reader.require(MemberResponse::TOTAL)?;
+        let cursor = reader.get(MemberResponse::CURSOR)?.unwrap_or(0);
+
+        let mut peers: Vec<(String, SocketAddr)> = Vec::new();
+        for peer in reader.all(MemberResponse::PEER) {
+            let peer = TlvReader::parse(peer)?;
+            let peer_id = read_tlv_name(&peer, MemberResponse::PEER_ID)?.to_string();
+            let peer_addr: SocketAddr = peer.require(MemberResponse::PEER_ADDR)?;
+            // The other messages still carry IPv4 addresses only
+            if !peer_addr.is_ipv4() {
+                return Err(FormatError::InvalidValue { field: "member address", value: peer_addr.to_string() });
+            }
+            peers.push((peer_id, peer_addr));
+        }
+
+        MemberResponse::new(group, total, cursor, peers)
+    }
+}
+
+/// Most `@mention`s a chat can carry
+pub const MAX_MENTIONS: usize = 16;
+
+/// Most styled ranges a chat can carry
+pub const MAX_STYLES: usize = 64;
+
+/// Markup of a part of the chat text
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TextStyle {
+    Bold = 0x01,
+    Italic = 0x02,
+    Code = 0x03,
+}
+
+impl TryFrom<u8> for TextStyle {
+    type Error = FormatError;
+
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0x01 => Ok(TextStyle::Bold),
+            0x02 => Ok(TextStyle::Italic),
+            0x03 => Ok(TextStyle::Code),
+            _ => Err(FormatError::InvalidValue { field: "text style", value: val.to_string() }),
+        }
+    }
+}
+
+/// Styled byte range of the chat text, sent as `style(1) + start(2) + length(2)`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StyleRange {
+    style: TextStyle,
+    start: u16,
+    len: u16,
+}
+
+impl StyleRange {
+    pub fn new(style: TextStyle, range: Range<usize>) -> Result<StyleRange, FormatError> {
+        let invalid = || FormatError::InvalidValue { field: "style range", value: format!("{:?}", range) };
+        let start = u16::try_from(range.start).map_err(|_| invalid())?;
+        let len = range.end.checked_sub(range.start).and_then(|len| u16::try_from(len).ok()).ok_or_else(invalid)?;
+        Ok(StyleRange { style, start, len })
+    }
+
+    pub fn style(&self) -> TextStyle {
+        self.style
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.start as usize..self.start as usize + self.len as usize
+    }
+
+    /// Checks that the range covers whole characters of the text
+    fn check(&self, text: &str) -> Result<(), FormatError> {
+        let range = self.range();
+        if range.is_empty() || text.get(range.clone()).is_none() {
+            return Err(FormatError::InvalidValue { field: "style range", value: format!("{:?}", range) });
+        }
+        Ok(())
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) {
+        buf.push(self.style as u8);
+        buf.extend_from_slice(&self.start.to_be_bytes());
+        buf.extend_from_slice(&self.len.to_be_bytes());
+    }
+
+    /// Reads a range of the text. Styles of newer versions are skipped, the text is shown plain.
+    fn read(value: &[u8], text: &str) -> Result<Option<StyleRange>, FormatError> {
+        let [style, start_hi, start_lo, len_hi, len_lo] = value else {
+            return Err(FormatError::WrongLength { value: "style range", len: value.len() });
+        };
+        let start = u16::from_be_bytes([*start_hi, *start_lo]);
+        let len = u16::from_be_bytes([*len_hi, *len_lo]);
+        // Any style is fine for the check
+        StyleRange { style: TextStyle::Bold, start, len }.check(text)?;
+        Ok(TextStyle::try_from(*style).ok().map(|style| StyleRange { style, start, len }))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chat {
+    /// Id of the peer that wrote the message
+    peer_id: String,
+    /// Group the message is sent to
+    group: String,
+    /// Unique message id of the origin peer, used for duplicate suppression
+    msg_id: u32,
+    /// Number of times the message can still be forwarded
+    hops: u8,
+    msg: String,
+    /// Peer and message id of the message this one answers
+    reply_to: Option<(String, u32)>,
+    /// Names of the peers mentioned with `@name`
+    mentions: Vec<String>,
+    /// Bold, italic and code parts of the text
+    styles: Vec<StyleRange>,
+}
+
+impl TryFrom<Chat> for Vec<u8> {
+    type Error = FormatError;
+
+    fn try_from(val: Chat) -> Result<Self, Self::Error> {
+        let mut buf = vec![];
+        val.encode_into(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl TryFrom<Vec<u8>> for Chat {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        ChatRef::try_from(value.as_slice()).map(Chat::from)
+    }
+}
+
+impl MessageContent for Chat {
+    fn encode_into(self, buf: &mut Vec<u8>) -> Result<(), FormatError> {
+        let mut writer = TlvWriter::with_buffer(std::mem::take(buf));
+        let written = self.write_fields(&mut writer);
+        *buf = writer.finish();
+        written
+    }
+}
+
+impl Chat {
+    fn write_fields(&self, writer: &mut TlvWriter) -> Result<(), FormatError> {
+        writer.put(Chat::PEER_ID, &self.peer_id)?
+            .put(Chat::GROUP, &self.group)?
+            .put(Chat::MSG_ID, &self.msg_id)?
+            .put(Chat::HOPS, &self.hops)?
+            .put(Chat::MSG, &self.msg)?;
+        if let Some((peer_id, msg_id)) = &self.reply_to {
+            writer.put(Chat::REPLY_PEER, peer_id)?
+                .put(Chat::REPLY_ID, msg_id)?;
+        }
+        for mention in self.mentions.iter() {
+            writer.put(Chat::MENTION, mention)?;
+        }
+        let mut style_buf = vec![];
+        for style in self.styles.iter() {
+            style_buf.clear();
+            style.write(&mut style_buf);
+            writer.put_bytes(Chat::STYLE, &style_buf)?;
+        }
+        Ok(())
+    }
+
+    // Field tags
+    const PEER_ID: u8 = 0x01;
+    const GROUP: u8 = 0x02;
+    const MSG_ID: u8 = 0x03;
+    const HOPS: u8 = 0x04;
+    const MSG: u8 = 0x05;
+    /// Sent together with `REPLY_ID`
+    const REPLY_PEER: u8 = 0x06;
+    const REPLY_ID: u8 = 0x07;
+    /// Repeated, one mentioned name per field
+    const MENTION: u8 = 0x08;
+    /// Repeated, one style range per field
+    const STYLE: u8 = 0x09;
+
+    pub fn new(peer_id: String, group: &str, msg_id: u32, hops: u8, msg: &str) -> Result<Chat, FormatError> {
+        if peer_id.len() > 32 || group.len() > 32 {
+            return Err(FormatError::TooLong { field: "name", max: 32 });
+        }
+        Ok(Chat{
+            peer_id,
+            group: group.to_string(),
+            msg_id,
+            hops,
+            msg: msg.to_owned(),
+            reply_to: None,
+            mentions: vec![],
+            styles: vec![],
+        })
+    }
+
+    /// Makes the chat an answer to the message `msg_id` of the peer
+    pub fn set_reply_to(&mut self, peer_id: &str, msg_id: u32) -> Result<(), FormatError> {
+        if peer_id.len() > 32 {
+            return Err(FormatError::TooLong { field: "name", max: 32 });
+        }
+        self.reply_to = Some((peer_id.to_string(), msg_id));
+        Ok(())
+    }
+
+    pub fn set_mentions(&mut self, mentions: Vec<String>) -> Result<(), FormatError> {
+        if mentions.len() > MAX_MENTIONS {
+            return Err(FormatError::TooLong { field: "mentions", max: MAX_MENTIONS });
+        }
+        if mentions.iter().any(|name| name.len() > 32) {
+            return Err(FormatError::TooLong { field: "name", max: 32 });
+        }
+        self.mentions = mentions;
+        Ok(())
+    }
+
+    /// Sets the styled ranges, they have to cover whole characters of the text
+    pub fn set_styles(&mut self, styles: Vec<StyleRange>) -> Result<(), FormatError> {
+        if styles.len() > MAX_STYLES {
+            return Err(FormatError::TooLong { field: "styles", max: MAX_STYLES });
+        }
+        for style in styles.iter() {
+            style.check(&self.msg)?;
+        }
+        self.styles = styles;
+        Ok(())
+    }
+
+    pub fn group_name(&self) -> &str {
+        &self.group
+    }
+
+    pub fn msg_id(&self) -> u32 {
+        self.msg_id
+    }
+
+    pub fn hops(&self) -> u8 {
+        self.hops
+    }
+
+    /// Returns the copy of the message to be forwarded to other peers, if the hop limit allows it.
+    pub fn forwarded(&self) -> Option<Chat> {
+        if self.hops == 0 {
+            return None;
+        }
+
+        let mut chat = self.clone();
+        chat.hops -= 1;
+        Some(chat)
+    }
+
+    pub fn msg(&self) -> &str {
+        &self.msg
+    }
+
+    pub fn peer_id(&self) -> String {
+        self.peer_id.clone()
+    }
+
+    pub fn reply_to(&self) -> Option<(&str, u32)> {
+        self.reply_to.as_ref().map(|(peer_id, msg_id)| (peer_id.as_str(), *msg_id))
+    }
+
+    pub fn mentions(&self) -> &[String] {
+        &self.mentions
+    }
+
+    pub fn styles(&self) -> &[StyleRange] {
+        &self.styles
+    }
+}
+
+/// Chat decoded in place, the names and the text borrow from the received content.
+/// Lets the handler drop duplicates before anything is copied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChatRef<'a> {
+    peer_id: &'a str,
+    group: &'a str,
+    msg_id: u32,
+    hops: u8,
+    msg: &'a str,
+    reply_to: Option<(&'a str, u32)>,
+    /// Fields of the mentions and the styles, checked when the chat was parsed
+    reader: TlvReader<'a>,
+}
+
+impl<'a> ChatRef<'a> {
+    pub fn peer_id(&self) -> &'a str {
+        self.peer_id
+    }
+
+    pub fn group_name(&self) -> &'a str {
+        self.group
+    }
+
+    pub fn msg_id(&self) -> u32 {
+        self.msg_id
+    }
+
+    pub fn msg(&self) -> &'a str {
+        self.msg
+    }
+
+    pub fn mentions(&self) -> impl Iterator<Item = &'a str> {
+        self.reader.all(Chat::MENTION).filter_map(|value| <&str>::decode(value).ok())
+    }
+
+    pub fn styles(&self) -> impl Iterator<Item = StyleRange> + 'a {
+        let msg = self.msg;
+        self.reader.all(Chat::STYLE).filter_map(move |value| StyleRange::read(value, msg).ok().flatten())
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for ChatRef<'a> {
+    type Error = FormatError;
+
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        let reader = TlvReader::parse(value)?;
+        let msg = reader.require(Chat::MSG)?;
+
+        let reply_to = match reader.get(Chat::REPLY_ID)? {
+            Some(msg_id) => Some((read_tlv_name(&reader, Chat::REPLY_PEER)?, msg_id)),
+            None => None,
+        };
+        if reader.all(Chat::MENTION).count() > MAX_MENTIONS {
+            return Err(FormatError::TooLong { field: "mentions", max: MAX_MENTIONS });
+        }
+        for mention in reader.all(Chat::MENTION) {
+            if <&str>::decode(mention)?.len() > 32 {
+                return Err(FormatError::TooLong { field: "name", max: 32 });
+            }
+        }
+        if reader.all(Chat::STYLE).count() > MAX_STYLES {
+            return Err(FormatError::TooLong { field: "styles", max: MAX_STYLES });
+        }
+        for style in reader.all(Chat::STYLE) {
+            StyleRange::read(style, msg)?;
+        }
+
+        Ok(ChatRef {
+            peer_id: read_tlv_name(&reader, Chat::PEER_ID)?,
+            group: read_tlv_name(&reader, Chat::GROUP)?,
+            msg_id: reader.require(Chat::MSG_ID)?,
+            // Messages without a hop limit aren't forwarded
+            hops: reader.get(Chat::HOPS)?.unwrap_or(0),
+            msg,
+            reply_to,
+            reader,
+        })
+    }
+}
+
+impl From<ChatRef<'_>> for Chat {
+    fn from(val: ChatRef<'_>) -> Self {
+        Chat {
+            peer_id: val.peer_id.to_string(),
+            group: val.group.to_string(),
+            msg_id: val.msg_id,
+            hops: val.hops,
+            msg: val.msg.to_string(),
+            reply_to: val.reply_to.map(|(peer_id, msg_id)| (peer_id.to_string(), msg_id)),
+            mentions: val.mentions().map(str::to_string).collect(),
+            styles: val.styles().collect(),
+        }
+    }
+}
+
+/// Longest reaction, e.g. an emoji or a short word
+pub const MAX_REACTION: usize = 16;
+
+/// Change of an earlier chat message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChatActionKind {
+    /// Replaces the text of the message, only the author can edit it
+    Edit(Chat),
+    /// Takes the message back, only the author can delete it
+    Delete,
+    /// Reaction of any member to the message
+    React(String),
+}
+
+impl ChatActionKind {
+    fn code(&self) -> u8 {
+        match self {
+            ChatActionKind::Edit(_) => 0x01,
+            ChatActionKind::Delete => 0x02,
+            ChatActionKind::React(_) => 0x03,
+        }
+    }
+}
+
+/// Edit, delete or reaction referencing an earlier chat message by its author and message id.
+/// Flooded through the group like the chat messages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChatAction {
+    /// Id of the peer taking the action
+    peer_id: String,
+    group: String,
+    /// Id of the action from the message ids of the sender, used for duplicate suppression
+    action_id: u32,
+    /// Number of times the action can still be forwarded
+    hops: u8,
+    /// Author and message id of the changed message
+    author: String,
+    msg_id: u32,
+    kind: ChatActionKind,
+}
+
+
+impl ChatAction {
+    // Field tags
+    const PEER_ID: u8 = 0x01;
+    const GROUP: u8 = 0x02;
+    const ACTION_ID: u8 = 0x03;
+    const HOPS: u8 = 0x04;
+    const KIND: u8 = 0x05;
+    const AUTHOR: u8 = 0x06;
+    const MSG_ID: u8 = 0x07;
+    /// Body of the edited chat, with the author and the id of the changed message
+    const EDITED: u8 = 0x08;
+    const REACTION: u8 = 0x09;
+
+    /// Replaces the message with the edited chat of the same author and message id
+    pub fn edit(action_id: u32, hops: u8, edited: Chat) -> ChatAction {
+        ChatAction {
+            peer_id: edited.peer_id.clone(),
+            group: edited.group.clone(),
+            action_id,
+            hops,
+            author: edited.peer_id.clone(),
+            msg_id: edited.msg_id,
+            kind: ChatActionKind::Edit(edited),
+        }
+    }
+
+    /// Deletes the own message `msg_id`
+    pub fn delete(peer_id: &str, group: &str, action_id: u32, hops: u8, msg_id: u32) -> Result<ChatAction, FormatError> {
+        if peer_id.len() > 32 || group.len() > 32 {
+            return Err(FormatError::TooLong { field: "name", max: 32 });
+        }
+        Ok(ChatAction {
+            peer_id: peer_id.to_string(),
+            group: group.to_string(),
+            action_id,
+            hops,
+            author: peer_id.to_string(),
+            msg_id,
+            kind: ChatActionKind::Delete,
+        })
+    }
+
+    /// Reacts to the message `msg_id` of the author
+    pub fn react(peer_id: &str, group: &str, action_id: u32, hops: u8, author: &str, msg_id: u32, reaction: &str) -> Result<ChatAction, FormatError> {
+        if peer_id.len() > 32 || group.len() > 32 || author.len() > 32 {
+            return Err(FormatError::TooLong { field: "name", max: 32 });
+        }
+        if reaction.len() > MAX_REACTION {
+            return Err(FormatError::TooLong { field: "reaction", max: MAX_REACTION });
+        }
+        if reaction.trim().is_empty() {
+            return Err(FormatError::InvalidValue { field: "reaction", value: reaction.to_string() });
+        }
+        Ok(ChatAction {
+            peer_id: peer_id.to_string(),
+            group: group.to_string(),
+            action_id,
+            hops,
+            author: author.to_string(),
+            msg_id,
+            kind: ChatActionKind::React(reaction.to_string()),
+        })
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub fn group_name(&self) -> &str {
+        &self.group
+    }
+
+    pub fn action_id(&self) -> u32 {
+        self.action_id
+    }
+
+    pub fn author(&self) -> &str {
+        &self.author
+    }
+
+    pub fn msg_id(&self) -> u32 {
+        self.msg_id
+    }
+
+    pub fn kind(&self) -> &ChatActionKind {
+        &self.kind
+    }
+
+    /// Returns the copy of the action to be forwarded to other peers, if the hop limit allows it.
+    pub fn forwarded(&self) -> Option<ChatAction> {
+        if self.hops == 0 {
+            return None;
+        }
+
+        let mut action = self.clone();
+        action.hops -= 1;
+        Some(action)
+    }
+}
+
+impl MessageContent for ChatAction {
+    fn encode_into(self, buf: &mut Vec<u8>) -> Result<(), FormatError> {
+        let mut writer = TlvWriter::with_buffer(std::mem::take(buf));
+        let written = self.write_fields(&mut writer);
+        *buf = writer.finish();
+        written
+    }
+}
+
+impl ChatAction {
+    fn write_fields(&self, writer: &mut TlvWriter) -> Result<(), FormatError> {
+        writer.put(ChatAction::PEER_ID, &self.peer_id)?
+            .put(ChatAction::GROUP, &self.group)?
+            .put(ChatAction::ACTION_ID, &self.action_id)?
+            .put(ChatAction::HOPS, &self.hops)?
+            .put(ChatAction::KIND, &self.kind.code())?
+            .put(ChatAction::AUTHOR, &self.author)?
+            .put(ChatAction::MSG_ID, &self.msg_id)?;
+        match &self.kind {
+            ChatActionKind::Edit(edited) => {
+                writer.put_bytes(ChatAction::EDITED, &Vec::try_from(edited.clone())?)?;
+            },
+            ChatActionKind::Delete => (),
+            ChatActionKind::React(reaction) => {
+                writer.put(ChatAction::REACTION, reaction)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<ChatAction> for Vec<u8> {
+    type Error = FormatError;
+
+    fn try_from(val: ChatAction) -> Result<Self, Self::Error> {
+        let mut buf = vec![];
+        val.encode_into(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl TryFrom<Vec<u8>> for ChatAction {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        let reader = TlvReader::parse(&value)?;
+
+        let peer_id = read_tlv_name(&reader, ChatAction::PEER_ID)?.to_string();
+        let group = read_tlv_name(&reader, ChatAction::GROUP)?.to_string();
+        let author = read_tlv_name(&reader, ChatAction::AUTHOR)?.to_string();
+        let msg_id = reader.require(ChatAction::MSG_ID)?;
+        let kind = match reader.require::<u8>(ChatAction::KIND)? {
+            0x01 => {
+                let edited = reader.all(ChatAction::EDITED).next()
+                    .ok_or(FormatError::MissingField(ChatAction::EDITED))?;
+                let edited = Chat::from(ChatRef::try_from(edited)?);
+                if edited.peer_id != author || edited.group != group || edited.msg_id != msg_id {
+                    return Err(FormatError::InvalidValue { field: "edited message", value: format!("{} {}", edited.peer_id, edited.msg_id) });
+                }
+                ChatActionKind::Edit(edited)
+            },
+            0x02 => ChatActionKind::Delete,
+            0x03 => {
+                let reaction: String = reader.require(ChatAction::REACTION)?;
+                if reaction.len() > MAX_REACTION {
+                    return Err(FormatError::TooLong { field: "reaction", max: MAX_REACTION });
+                }
+                ChatActionKind::React(reaction)
+            },
+            kind => return Err(FormatError::InvalidValue { field: "chat action", value: kind.to_string() }),
+        };
+        // An edit or a delete has to claim to come from the author. Both names are filled in by the sender,
+        // so this catches confused peers, not forged actions.
+        if !matches!(kind, ChatActionKind::React(_)) && peer_id != author {
+            return Err(FormatError::InvalidValue { field: "author", value: peer_id });
+        }
+
+        Ok(ChatAction {
+            peer_id,
+            group,
+            action_id: reader.require(ChatAction::ACTION_ID)?,
+            // Actions without a hop limit aren't forwarded
+            hops: reader.get(ChatAction::HOPS)?.unwrap_or(0),
+            author,
+            msg_id,
+            kind,
+        })
+    }
+}
+
+/// Message stored on the rendezvous server for a group member that is offline.
+/// The payload is opaque to the server, so it can hold an encrypted blob.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mail {
+    group: String,
+    from: String,
+    to: String,
+    payload: Vec<u8>,
+}
+
+
+impl Mail {
+    pub fn new(group: &str, from: &str, to: &str, payload: Vec<u8>) -> Result<Mail, FormatError> {
+        if group.len() > 32 {
+            return Err(FormatError::TooLong { field: "group name", max: 32 });
+        }
+
+        if from.len() > 32 || to.len() > 32 {
+            return Err(FormatError::TooLong { field: "peer id", max: 32 });
+        }
+
+        if payload.len() > u16::MAX as usize {
+            return Err(FormatError::TooLong { field: "mail payload", max: u16::MAX as usize });
+        }
+
+        Ok(Mail { group: group.to_string(), from: from.to_string(), to: to.to_string(), payload })
+    }
+
+    pub fn group_name(&self) -> &str {
+        &self.group
+    }
+
+    pub fn sender(&self) -> &str {
+        &self.from
+    }
+
+    pub fn recipient(&self) -> &str {
+        &self.to
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+impl From<Mail> for Vec<u8> {
+    fn from(val: Mail) -> Self {
+        // group_name + from + to + payload_len(2) + payload
+        let msg_size = 32 * 3 + 2 + val.payload.len();
+        let mut buf = vec![0; msg_size];
+
+        buf[0..val.group.len()].copy_from_slice(val.group.as_bytes());
+        buf[32..32+val.from.len()].copy_from_slice(val.from.as_bytes());
+        buf[64..64+val.to.len()].copy_from_slice(val.to.as_bytes());
+        buf[96..98].copy_from_slice(&(val.payload.len() as u16).to_be_bytes());
+        buf[98..msg_size].copy_from_slice(&val.payload);
+        buf
+    }
+}
+
+impl TryFrom<Vec<u8>> for Mail {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        let mut reader = Cursor::new(value);
+
+        let mut fields = Vec::with_capacity(3);
+        for _ in 0..3 {
+            let mut field_buf = vec![0; 32];
+            reader.read_exact(&mut field_buf)?;
+
+            let field = String::from_utf8(field_buf.into_iter().filter(|s| *s != 0).collect())?;
+            fields.push(field);
+        }
+
+        let payload_len = reader.read_u16::<BigEndian>()? as usize;
+
+        let mut payload = vec![0; payload_len];
+        reader.read_exact(&mut payload)?;
+
+        let to = fields.pop().unwrap_or_default();
+        let from = fields.pop().unwrap_or_default();
+        let group = fields.pop().unwrap_or_default();
+
+        Ok(Mail {
+            group,
+            from,
+            to,
+            payload,
+        })
+    }
+}
+
+/// Sent to the neighbours and the server when the peer leaves the group
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bye {
+    peer_id: String,
+    group: String,
+}
+
+
+impl Bye {
+    pub fn new(peer_id: &str, group: &str) -> Result<Bye, FormatError> {
+        if group.len() > 32 {
+            return Err(FormatError::TooLong { field: "group name", max: 32 });
+        }
+
+        if peer_id.len() > 32 {
+            return Err(FormatError::TooLong { field: "peer id", max: 32 });
+        }
+
+        Ok(Bye { peer_id: peer_id.to_string(), group: group.to_string() })
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub fn group_name(&self) -> &str {
+        &self.group
+    }
+}
+
+impl From<Bye> for Vec<u8> {
+    fn from(val: Bye) -> Self {
+        // peer_id + group_name
+        let mut buf = vec![];
+        write_name(&mut buf, &val.peer_id);
+        write_name(&mut buf, &val.group);
+        buf
+    }
+}
+
+impl TryFrom<Vec<u8>> for Bye {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        let mut reader = Cursor::new(&value);
+
+        let peer_id = read_name(&mut reader)?;
+        let group = read_name(&mut reader)?;
+
+        Ok(Bye {
+            peer_id,
+            group,
+        })
+    }
+}
+
+/// Maximum number of entries in a single `MemberSync` message, so it fits in a 1024 byte packet
+pub const SYNC_MAX_ENTRIES: usize = 12;
+
+/// Group member known to a rendezvous server, exchanged between federated servers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncEntry {
+    group: String,
+    peer_id: String,
+    addr: SocketAddr,
+    /// Last time the member was seen by any server, in milliseconds since the Unix epoch
+    last_seen: u64,
+    /// The member left the group
+    left: bool,
+}
+
+impl SyncEntry {
+    pub fn new(group: &str, peer_id: &str, addr: SocketAddr, last_seen: u64, left: bool) -> Result<SyncEntry, FormatError> {
+        if group.len() > 32 {
+            return Err(FormatError::TooLong { field: "group name", max: 32 });
+        }
+
+        if peer_id.len() > 32 {
+            return Err(FormatError::TooLong { field: "peer id", max: 32 });
+        }
+
+        check_addr(&addr)?;
+
+        Ok(SyncEntry { group: group.to_string(), peer_id: peer_id.to_string(), addr, last_seen, left })
+    }
+
+    pub fn group_name(&self) -> &str {
+        &self.group
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    pub fn last_seen(&self) -> u64 {
+        self.last_seen
+    }
+
+    pub fn left(&self) -> bool {
+        self.left
+    }
+}
+
+/// Membership changes exchanged between federated rendezvous servers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemberSync {
+    entries: Vec<SyncEntry>,
+}
+
+
+impl MemberSync {
+    pub fn new(entries: Vec<SyncEntry>) -> Result<MemberSync, FormatError> {
+        if entries.len() > SYNC_MAX_ENTRIES {
+            return Err(FormatError::TooLong { field: "member sync", max: SYNC_MAX_ENTRIES });
+        }
+
+        Ok(MemberSync { entries })
+    }
+
+    pub fn entries(&self) -> &[SyncEntry] {
+        &self.entries
+    }
+}
+
+impl From<MemberSync> for Vec<u8> {
+    fn from(val: MemberSync) -> Self {
+        // entry_count(1) + entries of group_name + peer_id + IP(4) + Port(2) + last_seen(8) + left(1)
+        let mut buf = vec![val.entries.len() as u8];
+        for entry in val.entries {
+            write_name(&mut buf, &entry.group);
+            write_name(&mut buf, &entry.peer_id);
+            write_addr(&mut buf, &entry.addr);
+            buf.extend_from_slice(&entry.last_seen.to_be_bytes());
+            buf.push(entry.left as u8);
+        }
+        buf
+    }
+}
+
+impl TryFrom<Vec<u8>> for MemberSync {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        let mut reader = Cursor::new(&value);
+
+        let count = reader.read_u8()?;
+
+        let mut entries = vec![];
+        for _ in 0..count {
+            let group = read_name(&mut reader)?;
+            let peer_id = read_name(&mut reader)?;
+            let addr = read_addr(&mut reader)?;
+            let last_seen = reader.read_u64::<BigEndian>()?;
+            let left = reader.read_u8()? != 0;
+            entries.push(SyncEntry { group, peer_id, addr, last_seen, left });
+        }
+
+        MemberSync::new(entries)
+    }
+}
+
+/// Multicast on the LAN to let the local peers know about the group member
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Announce {
+    peer_id: String,
+    group: String,
+    /// Port of the peer's transport, the announcement is sent from another socket
+    port: u16,
+}
+
+
+impl Announce {
+    pub fn new(peer_id: &str, group: &str, port: u16) -> Result<Announce, FormatError> {
+        if group.len() > 32 {
+            return Err(FormatError::TooLong { field: "group name", max: 32 });
+        }
+
+        if peer_id.len() > 32 {
+            return Err(FormatError::TooLong { field: "peer id", max: 32 });
+        }
+
+        Ok(Announce { peer_id: peer_id.to_string(), group: group.to_string(), port })
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub fn group_name(&self) -> &str {
+        &self.group
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl From<Announce> for Vec<u8> {
+    fn from(val: Announce) -> Self {
+        // peer_id + group_name + port(2)
+        let mut buf = vec![];
+        write_name(&mut buf, &val.peer_id);
+        write_name(&mut buf, &val.group);
+        buf.extend_from_slice(&val.port.to_be_bytes());
+        buf
+    }
+}
+
+impl TryFrom<Vec<u8>> for Announce {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        let mut reader = Cursor::new(&value);
+
+        let peer_id = read_name(&mut reader)?;
+        let group = read_name(&mut reader)?;
+        let port = reader.read_u16::<BigEndian>()?;
+
+        Ok(Announce {
+            peer_id,
+            group,
+            port,
+        })
+    }
+}
+
+/// Step of the path challenge
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ChallengeKind {
+    /// Token sent to the new address of a peer
+    Request = 0x00,
+    /// The token echoed from the new address, with the proof made with the key of the challenger
+    Response = 0x01,
+    /// Key handed to a peer at the address it joined from, it proves the peer's identity after a move
+    Bind = 0x02,
+}
+
+impl TryFrom<u8> for ChallengeKind {
+    type Error = FormatError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(ChallengeKind::Request),
+            0x01 => Ok(ChallengeKind::Response),
+            0x02 => Ok(ChallengeKind::Bind),
+            _ => Err(FormatError::InvalidValue { field: "challenge kind", value: value.to_string() }),
+        }
+    }
+}
+
+/// Path challenge for validating the new address of a peer.
+/// The token sent to the new address has to come back from it in the response, along with a proof
+/// that only the peer holding the key handed out at its old address can make.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Challenge {
+    peer_id: String,
+    token: u64,
+    kind: ChallengeKind,
+    proof: u64,
+}
+
+
+impl Challenge {
+    pub fn new(peer_id: &str, kind: ChallengeKind, token: u64, proof: u64) -> Result<Challenge, FormatError> {
+        if peer_id.len() > 32 {
+            return Err(FormatError::TooLong { field: "peer id", max: 32 });
+        }
+
+        Ok(Challenge { peer_id: peer_id.to_string(), token, kind, proof })
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    /// Token of the request and the response, or the key of the bind
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    pub fn kind(&self) -> ChallengeKind {
+        self.kind
+    }
+
+    pub fn proof(&self) -> u64 {
+        self.proof
+    }
+}
+
+impl From<Challenge> for Vec<u8> {
+    fn from(val: Challenge) -> Self {
+        // peer_id + token(8) + kind(1) + proof(8)
+        let mut buf = vec![];
+        write_name(&mut buf, &val.peer_id);
+        buf.extend_from_slice(&val.token.to_be_bytes());
+        buf.push(val.kind as u8);
+        buf.extend_from_slice(&val.proof.to_be_bytes());
+        buf
+    }
+}
+
+impl TryFrom<Vec<u8>> for Challenge {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        let mut reader = Cursor::new(&value);
+
+        let peer_id = read_name(&mut reader)?;
+        let token = reader.read_u64::<BigEndian>()?;
+        let kind = ChallengeKind::try_from(reader.read_u8()?)?;
+        let proof = reader.read_u64::<BigEndian>()?;
+
+        Ok(Challenge { peer_id, token, kind, proof })
+    }
+}
+
+/// Reason for rejecting a peer from the group
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RejectReason {
+    /// Another peer in the group already uses the name
+    NameTaken = 0x01,
+    /// The peers have no protocol version in common
+    VersionMismatch = 0x02,
+}
+
+impl TryFrom<u8> for RejectReason {
+    type Error = FormatError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(RejectReason::NameTaken),
+            0x02 => Ok(RejectReason::VersionMismatch),
+            _ => Err(FormatError::InvalidValue { field: "reject reason", value: value.to_string() }),
+        }
+    }
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::NameTaken => write!(f, "name is already taken"),
+            RejectReason::VersionMismatch => write!(f, "no common protocol version"),
+        }
+    }
+}
+
+/// Tells the peer that it can't be a member of the group under its name
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reject {
+    peer_id: String,
+    group: String,
+    reason: RejectReason,
+    /// Oldest and newest protocol version supported by the rejecting peer
+    versions: (u8, u8),
+}
+
+
+impl Reject {
+    pub fn new(peer_id: &str, group: &str, reason: RejectReason) -> Result<Reject, FormatError> {
+        if group.len() > 32 {
+            return Err(FormatError::TooLong { field: "group name", max: 32 });
+        }
+
+        if peer_id.len() > 32 {
+            return Err(FormatError::TooLong { field: "peer id", max: 32 });
+        }
+
+        Ok(Reject { peer_id: peer_id.to_string(), group: group.to_string(), reason, versions: (MIN_PROTOCOL_VERSION, PROTOCOL_VERSION) })
+    }
+
+    /// Name of the rejected peer
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub fn group_name(&self) -> &str {
+        &self.group
+    }
+
+    pub fn reason(&self) -> RejectReason {
+        self.reason
+    }
+
+    /// Oldest and newest protocol version supported by the rejecting peer
+    pub fn versions(&self) -> (u8, u8) {
+        self.versions
+    }
+}
+
+impl Display for Reject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.reason {
+            RejectReason::VersionMismatch => write!(f, "{}, the peer supports versions {}-{} and this one {}-{}",
+                self.reason, self.versions.0, self.versions.1, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+            _ => write!(f, "{}", self.reason),
+        }
+    }
+}
+
+impl From<Reject> for Vec<u8> {
+    fn from(val: Reject) -> Self {
+        // peer_id + group_name + reason(1) + min_version(1) + max_version(1)
+        let mut buf = vec![];
+        write_name(&mut buf, &val.peer_id);
+        write_name(&mut buf, &val.group);
+        buf.push(val.reason as u8);
+        buf.push(val.versions.0);
+        buf.push(val.versions.1);
+        buf
+    }
+}
+
+impl TryFrom<Vec<u8>> for Reject {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        let mut reader = Cursor::new(&value);
+
+        let peer_id = read_name(&mut reader)?;
+        let group = read_name(&mut reader)?;
+        let reason = reader.read_u8()?
+            .try_into()?;
+        let mut version_buf = [0; 2];
+        reader.read_exact(&mut version_buf)?;
+        let versions = (version_buf[0], version_buf[1]);
+
+        Ok(Reject { peer_id, group, reason, versions })
+    }
+}
+
+/// Longest status line of a peer, in bytes
+pub const STATUS_TEXT_MAX: usize = 64;
+
+/// Availability of a peer chosen by its user
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PresenceStatus {
+    Online = 0x00,
+    Away = 0x01,
+    Busy = 0x02,
+}
+
+impl TryFrom<u8> for PresenceStatus {
+    type Error = FormatError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(PresenceStatus::Online),
+            0x01 => Ok(PresenceStatus::Away),
+            0x02 => Ok(PresenceStatus::Busy),
+            _ => Err(FormatError::InvalidValue { field: "presence status", value: value.to_string() }),
+        }
+    }
+}
+
+impl std::str::FromStr for PresenceStatus {
+    type Err = FormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "online" => Ok(PresenceStatus::Online),
+            "away" => Ok(PresenceStatus::Away),
+            "busy" => Ok(PresenceStatus::Busy),
+            _ => Err(FormatError::InvalidValue { field: "presence status", value: s.to_string() }),
+        }
+    }
+}
+
+impl Display for PresenceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PresenceStatus::Online => write!(f, "online"),
+            PresenceStatus::Away => write!(f, "away"),
+            PresenceStatus::Busy => write!(f, "busy"),
+        }
+    }
+}
+
+/// Presence of the group member, sent to the neighbours when it changes and periodically
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Presence {
+    peer_id: String,
+    group: String,
+    status: PresenceStatus,
+    /// The user is writing a message in the group
+    typing: bool,
+    text: String,
+}
+
+
+impl Presence {
+    pub fn new(peer_id: &str, group: &str, status: PresenceStatus, typing: bool, text: &str) -> Result<Presence, FormatError> {
+        if group.len() > 32 {
+            return Err(FormatError::TooLong { field: "group name", max: 32 });
+        }
+
+        if peer_id.len() > 32 {
+            return Err(FormatError::TooLong { field: "peer id", max: 32 });
+        }
+
+        if text.len() > STATUS_TEXT_MAX {
+            return Err(FormatError::TooLong { field: "status text", max: STATUS_TEXT_MAX });
+        }
+
+        Ok(Presence { peer_id: peer_id.to_string(), group: group.to_string(), status, typing, text: text.to_string() })
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub fn group_name(&self) -> &str {
+        &self.group
+    }
+
+    pub fn status(&self) -> PresenceStatus {
+        self.status
+    }
+
+    pub fn is_typing(&self) -> bool {
+        self.typing
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+impl From<Presence> for Vec<u8> {
+    fn from(val: Presence) -> Self {
+        // peer_id + group_name + status(1) + typing(1) + text_len(1) + text
+        let mut buf = vec![];
+        write_name(&mut buf, &val.peer_id);
+        write_name(&mut buf, &val.group);
+        buf.push(val.status as u8);
+        buf.push(val.typing as u8);
+        buf.push(val.text.len() as u8);
+        buf.extend_from_slice(val.text.as_bytes());
+        buf
+    }
+}
+
+impl TryFrom<Vec<u8>> for Presence {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        let mut reader = Cursor::new(&value);
+
+        let peer_id = read_name(&mut reader)?;
+        let group = read_name(&mut reader)?;
+        let status = reader.read_u8()?
+            .try_into()?;
+        let typing = reader.read_u8()? != 0;
+        let text_len = reader.read_u8()? as usize;
+        if text_len > STATUS_TEXT_MAX {
+            return Err(FormatError::TooLong { field: "status text", max: STATUS_TEXT_MAX });
+        }
+
+        let mut text_buf = vec![0; text_len];
+        reader.read_exact(&mut text_buf)?;
+        let text = String::from_utf8(text_buf)?;
+
+        Ok(Presence { peer_id, group, status, typing, text })
+    }
+}
+
+/// Maximum number of nodes or records in a single `DhtMessage`, so it fits in a 1024 byte packet
+pub const DHT_MAX_ITEMS: usize = 10;
+
+/// Operation of a DHT message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DhtOp {
+    Ping = 0x00,
+    Pong = 0x01,
+    /// Asks for the nodes closest to the key
+    FindNode = 0x02,
+    /// Asks for the records stored under the key, or the closest nodes if there are none
+    FindValue = 0x03,
+    Nodes = 0x04,
+    Values = 0x05,
+    /// Stores the record under the key, the address of the record is the source of the message
+    Store = 0x06,
+}
+
+impl TryFrom<u8> for DhtOp {
+    type Error = FormatError;
+
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0x00 => Ok(DhtOp::Ping),
+            0x01 => Ok(DhtOp::Pong),
+            0x02 => Ok(DhtOp::FindNode),
+            0x03 => Ok(DhtOp::FindValue),
+            0x04 => Ok(DhtOp::Nodes),
+            0x05 => Ok(DhtOp::Values),
+            0x06 => Ok(DhtOp::Store),
+            _ => Err(FormatError::InvalidValue { field: "DHT operation", value: val.to_string() }),
+        }
+    }
+}
+
+/// Group membership record stored in the DHT
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DhtRecord {
+    group: String,
+    peer_id: String,
+    addr: SocketAddr,
+}
+
+impl DhtRecord {
+    pub fn new(group: &str, peer_id: &str, addr: SocketAddr) -> Result<DhtRecord, FormatError> {
+        if group.len() > 32 {
+            return Err(FormatError::TooLong { field: "group name", max: 32 });
+        }
+
+        if peer_id.len() > 32 {
+            return Err(FormatError::TooLong { field: "peer id", max: 32 });
+        }
+
+        check_addr(&addr)?;
+
+        Ok(DhtRecord { group: group.to_string(), peer_id: peer_id.to_string(), addr })
+    }
+
+    pub fn group_name(&self) -> &str {
+        &self.group
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    /// Returns the record with another address
+    pub fn with_addr(&self, addr: SocketAddr) -> DhtRecord {
+        DhtRecord { group: self.group.clone(), peer_id: self.peer_id.clone(), addr }
+    }
+}
+
+/// Message of the Kademlia overlay used for finding group members without a server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DhtMessage {
+    op: DhtOp,
+    /// Node id of the sender
+    sender: u64,
+    /// Transaction id, copied from the request to the answer
+    txn: u32,
+    key: u64,
+    nodes: Vec<(u64, SocketAddr)>,
+    records: Vec<DhtRecord>,
+}
+
+
+impl DhtMessage {
+    pub fn new(op: DhtOp, sender: u64, txn: u32, key: u64, nodes: Vec<(u64, SocketAddr)>, records: Vec<DhtRecord>) -> Result<DhtMessage, FormatError> {
+        if nodes.len() > DHT_MAX_ITEMS || records.len() > DHT_MAX_ITEMS {
+            return Err(FormatError::TooLong { field: "DHT message", max: DHT_MAX_ITEMS });
+        }
+
+        for (_, addr) in nodes.iter() {
+            check_addr(addr)?;
+        }
+
+        Ok(DhtMessage { op, sender, txn, key, nodes, records })
+    }
+
+    pub fn op(&self) -> DhtOp {
+        self.op
+    }
+
+    pub fn sender(&self) -> u64 {
+        self.sender
+    }
+
+    pub fn txn(&self) -> u32 {
+        self.txn
+    }
+
+    pub fn key(&self) -> u64 {
+        self.key
+    }
+
+    pub fn nodes(&self) -> &[(u64, SocketAddr)] {
+        &self.nodes
+    }
+
+    pub fn records(&self) -> &[DhtRecord] {
+        &self.records
+    }
+}
+
+impl From<DhtMessage> for Vec<u8> {
+    fn from(val: DhtMessage) -> Self {
+        // op(1) + sender(8) + txn(4) + key(8)
+        // + node_count(1) + nodes of id(8) + IP(4) + Port(2)
+        // + record_count(1) + records of group_name + peer_id + IP(4) + Port(2)
+        let mut buf = vec![val.op as u8];
+        buf.extend_from_slice(&val.sender.to_be_bytes());
+        buf.extend_from_slice(&val.txn.to_be_bytes());
+        buf.extend_from_slice(&val.key.to_be_bytes());
+
+        buf.push(val.nodes.len() as u8);
+        for (id, addr) in val.nodes.iter() {
+            buf.extend_from_slice(&id.to_be_bytes());
+            write_addr(&mut buf, addr);
+        }
+
+        buf.push(val.records.len() as u8);
+        for record in val.records.iter() {
+            write_name(&mut buf, &record.group);
+            write_name(&mut buf, &record.peer_id);
+            write_addr(&mut buf, &record.addr);
+        }
+        buf
+    }
+}
+
+impl TryFrom<Vec<u8>> for DhtMessage {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        let mut reader = Cursor::new(&value);
+
+        let op = DhtOp::try_from(reader.read_u8()?)?;
+        let sender = reader.read_u64::<BigEndian>()?;
+        let txn = reader.read_u32::<BigEndian>()?;
+        let key = reader.read_u64::<BigEndian>()?;
+
+        let node_count = reader.read_u8()?;
+        let mut nodes = vec![];
+        for _ in 0..node_count {
+            let id = reader.read_u64::<BigEndian>()?;
+            nodes.push((id, read_addr(&mut reader)?));
+        }
+
+        let record_count = reader.read_u8()?;
+        let mut records = vec![];
+        for _ in 0..record_count {
+            let group = read_name(&mut reader)?;
+            let peer_id = read_name(&mut reader)?;
+            let addr = read_addr(&mut reader)?;
+            records.push(DhtRecord { group, peer_id, addr });
+        }
+
+        DhtMessage::new(op, sender, txn, key, nodes, records)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Message<T> 
+    where T: MessageContent {
+    header: Header,
+    content: Option<T>,
+}
+
+impl<T> Message<T> where T: MessageContent {
+    pub fn new(header: Header, content: Option<T>) -> Message<T> {
+        Message { header, content }
+    }
+
+    pub fn content(&self) -> Option<&T> {
+        self.content.as_ref()
+    }
+
+    /// Encodes the message with the given trailer, the header gets the content size and the flags.
+    /// With `compress` the content is compressed, unless it would get longer.
+    pub fn encode(self, integrity: &Integrity, compress: bool) -> Result<Vec<u8>, FormatError> {
+        let mut buf = vec![];
+        self.encode_into(&mut buf, integrity, compress)?;
+        Ok(buf)
+    }
+
+    /// Appends the encoded message to the buffer, e.g. after the messages coalesced before it.
+    /// The content is written in place, the header is filled in once its size is known.
+    /// If the content can't be encoded or is longer than `MAX_CONTENT`, the buffer is left as it was.
+    pub fn encode_into(self, buf: &mut Vec<u8>, integrity: &Integrity, compress: bool) -> Result<(), FormatError> {
+        let start = buf.len();
+        let header_len = self.header.encoded_len();
+        buf.resize(start + header_len, 0);
+        if let Some(content) = self.content {
+            if let Err(err) = content.encode_into(buf) {
+                buf.truncate(start);
+                return Err(err);
+            }
+        }
+
+        let mut header = self.header;
+        header.flags = match integrity {
+            Integrity::None => 0,
+            Integrity::Checksum => FLAG_CHECKSUM,
+            Integrity::Mac(_) => FLAG_MAC,
+        };
+        if let Some(compressed) = compress.then(|| compress_content(&buf[start + header_len..])).flatten() {
+            buf.truncate(start + header_len);
+            buf.extend(compressed);
+            header.flags |= FLAG_COMPRESSED;
+        }
+        let content_len = buf.len() - start - header_len;
+        if content_len > MAX_CONTENT {
+            buf.truncate(start);
+            return Err(FormatError::TooLong { field: "content", max: MAX_CONTENT });
+        }
+        header.size = content_len as u16;
+        header.write(&mut buf[start..start + header_len]);
+
+        match integrity {
+            Integrity::None => (),
+            Integrity::Checksum => {
+                let checksum = crc32c::crc32c(&buf[start..]);
+                buf.extend_from_slice(&checksum.to_be_bytes());
+            },
+            Integrity::Mac(key) => {
+                // The send time is signed along, so the message can't be replayed once the window is over
+                buf.extend_from_slice(&now_millis().to_be_bytes());
+                let tag = mac(key, &buf[start..]).finalize().into_bytes();
+                buf.extend_from_slice(&tag[..MAC_LEN]);
+            },
+        }
+        Ok(())
+    }
+
+    /// Decodes a message, checking its trailer against the given policy before the content is parsed.
+    /// With a shared secret only messages with a valid MAC are accepted, otherwise the trailer is optional.
+    pub fn decode(value: Vec<u8>, integrity: &Integrity) -> Result<Message<T>, FormatError> {
+        MessageRef::parse(&value, integrity)?.into_message()
+    }
+}
+
+/// Header and trailer of a message, and the bytes the trailer covers
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Sealed<'a> {
+    header: Header,
+    signed: &'a [u8],
+    trailer: &'a [u8],
+}
+
+impl Sealed<'_> {
+    /// Checks the MAC against the policy, returns the send time of a signed message.
+    /// Checksums are checked when the message is split.
+    fn check(&self, integrity: &Integrity) -> Result<Option<u64>, FormatError> {
+        match (self.header.flags & TRAILER_FLAGS, integrity) {
+            (FLAG_MAC, Integrity::Mac(key)) => {
+                mac(key, self.signed).verify_truncated_left(self.trailer)
+                    .map_err(|_| FormatError::Integrity(IntegrityError::MacMismatch))?;
+                // The send time follows the content
+                let stamp = self.signed.get(self.header.encoded_len() + self.header.size as usize..)
+                    .and_then(|stamp| <[u8; STAMP_LEN]>::try_from(stamp).ok())
+                    .map(u64::from_be_bytes)
+                    .ok_or(FormatError::Integrity(IntegrityError::Stale))?;
+                if stamp.abs_diff(now_millis()) > REPLAY_WINDOW.as_millis() as u64 {
+                    return Err(FormatError::Integrity(IntegrityError::Stale));
+                }
+                Ok(Some(stamp))
+            },
+            (_, Integrity::Mac(_)) => Err(FormatError::Integrity(IntegrityError::MissingMac)),
+            (FLAG_MAC, _) => Err(FormatError::Integrity(IntegrityError::UnexpectedMac)),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Groups the content of a received message may belong to, by its trailer
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Seal {
+    /// Checked against the trailer given to `MessageRef::parse`, any content passes
+    Checked,
+    /// Signed with the key of the group, content of other groups doesn't pass
+    Signed(String),
+    /// Not signed, content of the groups with a secret doesn't pass.
+    /// Neither does content without a group, unless a joined group has no secret.
+    Unsigned { secret_groups: Vec<String>, open_groups: bool },
+}
+
+impl Seal {
+    fn admit(&self, group: Option<&str>) -> Result<(), FormatError> {
+        match (self, group) {
+            (Seal::Signed(signer), Some(group)) if signer != group => Err(FormatError::Integrity(IntegrityError::MacMismatch)),
+            (Seal::Unsigned { secret_groups, .. }, Some(group)) if secret_groups.iter().any(|g| g == group) => {
+                Err(FormatError::Integrity(IntegrityError::MissingMac))
+            },
+            (Seal::Unsigned { open_groups: false, .. }, None) => Err(FormatError::Integrity(IntegrityError::MissingMac)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Message split in place. The content borrows from the received datagram,
+/// it is only copied when it was compressed.
+/// The trailer is checked before the content is decompressed, see `Keyring::open` and `MessageRef::parse`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageRef<'a> {
+    header: Header,
+    content: Cow<'a, [u8]>,
+    seal: Seal,
+}
+
+impl<'a> MessageRef<'a> {
+    /// Checks the length and the trailer of a single message, see `Message::decode`
+    pub fn parse(buf: &'a [u8], integrity: &Integrity) -> Result<MessageRef<'a>, FormatError> {
+        let (sealed, data) = MessageRef::split(buf)?;
+        sealed.check(integrity)?;
+        MessageRef::inflate(sealed.header, data, Seal::Checked)
+    }
+
+    /// Checks the length and the checksum of a single message and splits off its trailer.
+    /// Returns the raw content, still compressed.
+    fn split(buf: &'a [u8]) -> Result<(Sealed<'a>, &'a [u8]), FormatError> {
+        if buf.len() < HEADER_SIZE {
+            return Err(FrameError::Truncated { expected: HEADER_SIZE, available: buf.len() }.into());
+        }
+        let header = Header::try_from(buf)?;
+        let header_len = header.encoded_len();
+
+        // The size in the header is authoritative, coalesced datagrams are split with `frames` first
+        let expected = message_len(header.version_type(), header.size | header.flags).unwrap();
+        if buf.len() < expected {
+            return Err(FrameError::Truncated { expected, available: buf.len() }.into());
+        }
+        if buf.len() > expected {
+            return Err(FrameError::Trailing { bytes: buf.len() - expected }.into());
+        }
+
+        let data = &buf[header_len..header_len + header.size as usize];
+        // The MAC covers the send time in front of it
+        let (signed, trailer) = match header.flags & TRAILER_FLAGS {
+            FLAG_MAC => buf.split_at(buf.len() - MAC_LEN),
+            _ => buf.split_at(header_len + data.len()),
+        };
+        if header.flags & TRAILER_FLAGS == FLAG_CHECKSUM && trailer != crc32c::crc32c(signed).to_be_bytes() {
+            return Err(FormatError::Integrity(IntegrityError::ChecksumMismatch));
+        }
+        Ok((Sealed { header, signed, trailer }, data))
+    }
+
+    /// Decompresses the content of a message whose trailer was checked
+    fn inflate(header: Header, data: &'a [u8], seal: Seal) -> Result<MessageRef<'a>, FormatError> {
+        let content = match header.flags & FLAG_COMPRESSED {
+            0 => Cow::Borrowed(data),
+            _ => Cow::Owned(decompress_content(data)?),
+        };
+        Ok(MessageRef { header, content, seal })
+    }
+
+    /// Decodes the plain content in place, it was decompressed if sent compressed.
+    /// Fails if the trailer doesn't fit the group of the content.
+    pub fn decode<'b, R>(&'b self) -> Result<R, FormatError>
+        where R: TryFrom<&'b [u8], Error = FormatError> + InGroup {
+        let content = R::try_from(&self.content)?;
+        self.seal.admit(content.group())?;
+        Ok(content)
+    }
+
+    /// Decodes the content into an owned message, see `decode`
+    pub fn into_message<T: MessageContent>(self) -> Result<Message<T>, FormatError> {
+        let content = T::try_from(self.content.into_owned())?;
+        self.seal.admit(content.group())?;
+        Ok(Message {
+            header: self.header,
+            content: Some(content),
+        })
+    }
+}
+
+/// Encodes the message with a checksum, e.g. for the tests.
+/// The peer encodes its messages with the trailer of their group, see `Keyring::integrity`.
+impl<T> TryFrom<Message<T>> for Vec<u8> where T: MessageContent {
+    type Error = FormatError;
+
+    fn try_from(val: Message<T>) -> Result<Self, Self::Error> {
+        val.encode(&Integrity::Checksum, false)
+    }
+}
+
+/// Decodes a message with a checksum or without a trailer, see `Message::decode`
+impl<T> TryFrom<Vec<u8>> for Message<T> where T: MessageContent {
+    type Error = FormatError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        Message::decode(value, &Integrity::Checksum)
+    }
+}
+
+impl<T> TryFrom<&[u8]> for Message<T> where T: MessageContent {
+    type Error = FormatError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        MessageRef::parse(value, &Integrity::Checksum)?.into_message()
+    }
+}
+
+/// Decodes a datagram of any messages and returns their types.
+/// Used by the fuzz target and the tests to check that no input makes the decoder panic.
+#[cfg(any(test, fuzzing))]
+pub fn decode(buf: &[u8]) -> Result<Vec<MessageType>, FormatError> {
+    frames(buf)
+        .map(|frame| decode_message(frame?))
+        .collect()
+}
+
+#[cfg(any(test, fuzzing))]
+fn decode_message(buf: &[u8]) -> Result<MessageType, FormatError> {
+    let header = Header::try_from(buf)?;
+    let data = buf;
+    match header.msg_type() {
+        MessageType::Alive => Message::<Alive>::try_from(data).map(|_| ()),
+        MessageType::MemberReq => Message::<MemberRequest>::try_from(data).map(|_| ()),
+        MessageType::MemberRes => Message::<MemberResponse>::try_from(data).map(|_| ()),
+        MessageType::Chat => Message::<Chat>::try_from(data).map(|_| ()),
+        MessageType::Mail => Message::<Mail>::try_from(data).map(|_| ()),
+        MessageType::Ack => Message::<Ack>::try_from(data).map(|_| ()),
+        MessageType::PingReq => Message::<PingReq>::try_from(data).map(|_| ()),
+        MessageType::Bye => Message::<Bye>::try_from(data).map(|_| ()),
+        MessageType::Sync => Message::<MemberSync>::try_from(data).map(|_| ()),
+        MessageType::Dht => Message::<DhtMessage>::try_from(data).map(|_| ()),
+        MessageType::Announce => Message::<Announce>::try_from(data).map(|_| ()),
+        MessageType::Challenge => Message::<Challenge>::try_from(data).map(|_| ()),
+        MessageType::Reject => Message::<Reject>::try_from(data).map(|_| ()),
+        MessageType::Presence => Message::<Presence>::try_from(data).map(|_| ()),
+        MessageType::ChatAction => Message::<ChatAction>::try_from(data).map(|_| ()),
+    }?;
+    Ok(header.msg_type())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_serialization() {
+        let mut header = Header::new(12, MessageType::Alive, 501);
+        let mut expected: Vec<u8> = vec![MAGIC_HEADER, 0xC1, 0x01, 0xF5];
+        assert_eq!(<Header as Into<Vec<u8>>>::into(header), expected);
+
+        header = Header::new(5, MessageType::Chat, 113);
+        expected = vec![MAGIC_HEADER, 0x58, 0x00, 0x71];
+        assert_eq!(<Header as Into<Vec<u8>>>::into(header), expected);
+
+        let decoded = Header::try_from(expected).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(decoded.version(), 5);
+
+        // Extended types put the type after the size
+        header = Header::new(6, MessageType::ChatAction, 7);
+        expected = vec![MAGIC_HEADER, 0x6F, 0x00, 0x07, 0x10];
+        assert_eq!(header.encoded_len(), HEADER_SIZE + 1);
+        assert_eq!(<Header as Into<Vec<u8>>>::into(header), expected);
+        assert_eq!(Header::try_from(expected).unwrap(), header);
+    }
+
+    #[test]
+    fn framing() {
+        let chat = Vec::try_from(Message::new(Header::new(PROTOCOL_VERSION, MessageType::Chat, 999), Some(Chat::new("peer-A".to_string(), "grp", 1, 2, "hi").unwrap()))).unwrap();
+        let bye = Vec::try_from(Message::new(Header::new(PROTOCOL_VERSION, MessageType::Bye, 0), Some(Bye::new("peer-A", "grp").unwrap()))).unwrap();
+        // The encoder sets the size, the checksum follows the content
+        assert_eq!(Header::try_from(chat[0..4].to_vec()).unwrap().size() as usize, chat.len() - HEADER_SIZE - CHECKSUM_LEN);
+
+        let mut datagrams = Datagrams::new(chat.len() + bye.len());
+        for msg in [&chat, &bye, &chat] {
+            datagrams.push(|buf| {
+                buf.extend_from_slice(msg);
+                Ok(())
+            }).unwrap();
+        }
+        let datagrams = datagrams.finish();
+        assert_eq!(datagrams.len(), 2);
+        let split: Vec<&[u8]> = frames(&datagrams[0]).collect::<Result<_, _>>().unwrap();
+        assert_eq!(split, vec![&chat[..], &bye[..]]);
+        assert_eq!(decode(&datagrams[0]).unwrap(), vec![MessageType::Chat, MessageType::Bye]);
+
+        // The longer header of the extended types is counted in
+        let action = Vec::try_from(Message::new(Header::new(PROTOCOL_VERSION, MessageType::ChatAction, 0), Some(ChatAction::delete("peer-A", "grp", 2, 1, 1).unwrap()))).unwrap();
+        assert_eq!(decode(&[action.clone(), bye.clone()].concat()).unwrap(), vec![MessageType::ChatAction, MessageType::Bye]);
+        assert_eq!(Message::<ChatAction>::try_from(action).unwrap().content().unwrap().msg_id(), 1);
+
+        let mut trailing = chat.clone();
+        trailing.extend_from_slice(&[0, 0]);
+        assert_eq!(frames(&trailing).last(), Some(Err(FrameError::Trailing { bytes: 2 })));
+        assert!(Message::<Chat>::try_from(trailing).is_err());
+
+        let truncated = &datagrams[0][..datagrams[0].len() - 1];
+        assert_eq!(frames(truncated).last(), Some(Err(FrameError::Truncated { expected: bye.len(), available: bye.len() - 1 })));
+        assert!(Message::<Chat>::try_from(chat[..chat.len() - 1].to_vec()).is_err());
+        assert_eq!(frames(&chat[..2]).next(), Some(Err(FrameError::Truncated { expected: HEADER_SIZE, available: 2 })));
+
+        assert_eq!(frames(&[1, 2, 3, 4]).collect::<Vec<_>>(), vec![Err(FrameError::NotMessage)]);
+        assert_eq!(frames(&[]).collect::<Vec<_>>(), vec![Err(FrameError::NotMessage)]);
+    }
+
+    #[test]
+    fn integrity_trailers() {
+        let bye = || Message::new(Header::new(PROTOCOL_VERSION, MessageType::Bye, 0), Some(Bye::new("peer-A", "grp").unwrap()));
+        let key = Integrity::Mac(b"secret".to_vec());
+
+        let plain = bye().encode(&Integrity::None, false).unwrap();
+        let checked = bye().encode(&Integrity::Checksum, false).unwrap();
+        let signed = bye().encode(&key, false).unwrap();
+        assert_eq!(checked.len(), plain.len() + CHECKSUM_LEN);
+        assert_eq!(signed.len(), plain.len() + STAMP_LEN + MAC_LEN);
+        assert_eq!(checked[2] & 0xF0, 0x80);
+
+        // The trailer is optional without a shared secret
+        assert!(Message::<Bye>::decode(plain.clone(), &Integrity::Checksum).is_ok());
+        assert!(Message::<Bye>::decode(checked.clone(), &Integrity::None).is_ok());
+        assert!(Message::<Bye>::decode(signed.clone(), &key).is_ok());
+
+        let error = |buf: &Vec<u8>, integrity: &Integrity| Message::<Bye>::decode(buf.clone(), integrity).unwrap_err();
+        for i in 4..checked.len() {
+            let mut corrupted = checked.clone();
+            corrupted[i] ^= 0x10;
+            assert_eq!(error(&corrupted, &Integrity::Checksum), FormatError::Integrity(IntegrityError::ChecksumMismatch));
+        }
+        let mut corrupted = signed.clone();
+        corrupted[10] ^= 0x01;
+        assert_eq!(error(&corrupted, &key), FormatError::Integrity(IntegrityError::MacMismatch));
+        assert_eq!(error(&signed, &Integrity::Mac(b"other".to_vec())), FormatError::Integrity(IntegrityError::MacMismatch));
+        assert_eq!(error(&signed, &Integrity::Checksum), FormatError::Integrity(IntegrityError::UnexpectedMac));
+        assert_eq!(error(&checked, &key), FormatError::Integrity(IntegrityError::MissingMac));
+        assert_eq!(error(&plain, &key), FormatError::Integrity(IntegrityError::MissingMac));
+
+        // Messages sent before the replay window are dropped
+        assert_eq!(signed.len(), plain.len() + STAMP_LEN + MAC_LEN);
+        let mut old = signed[..plain.len()].to_vec();
+        old.extend_from_slice(&(now_millis() - 2 * REPLAY_WINDOW.as_millis() as u64).to_be_bytes());
+        let tag = mac(b"secret", &old).finalize().into_bytes();
+        old.extend_from_slice(&tag[..MAC_LEN]);
+        assert_eq!(error(&old, &key), FormatError::Integrity(IntegrityError::Stale));
+
+        // Unknown flags can't be framed
+        let mut flagged = plain;
+        flagged[2] |= 0x10;
+        assert_eq!(error(&flagged, &Integrity::None), FormatError::UnknownFlags(0x1000));
+        assert_eq!(frames(&flagged).next(), Some(Err(FrameError::NotMessage)));
+    }
+
+    #[test]
+    fn compression() {
+        let chat = |text: &str| Message::new(Header::new(PROTOCOL_VERSION, MessageType::Chat, 0), Some(Chat::new("peer-A".to_string(), "grp", 1, 0, text).unwrap()));
+        let paste = "let mut buf = vec![];\n".repeat(30);
+
+        let raw = chat(&paste).encode(&Integrity::Checksum, false).unwrap();
+        let compressed = chat(&paste).encode(&Integrity::Checksum, true).unwrap();
+        assert!(compressed.len() < raw.len() / 4);
+        assert_eq!(compressed[2] & 0xF0, 0xA0);
+        let msg = Message::<Chat>::decode(compressed.clone(), &Integrity::Checksum).unwrap();
+        assert_eq!(msg.content().unwrap().msg(), paste);
+        assert_eq!(frames(&compressed).count(), 1);
+
+        // Short contents don't get shorter and are sent raw
+        assert_eq!(chat("hi").encode(&Integrity::None, true).unwrap(), chat("hi").encode(&Integrity::None, false).unwrap());
+
+        // The trailer covers the compressed bytes
+        let mut corrupted = compressed.clone();
+        corrupted[12] ^= 0x01;
+        assert_eq!(Message::<Chat>::decode(corrupted, &Integrity::Checksum), Err(FormatError::Integrity(IntegrityError::ChecksumMismatch)));
+
+        // A wrong original length or a cut block can't be decompressed
+        let mut plain = chat(&paste).encode(&Integrity::None, true).unwrap();
+        plain[HEADER_SIZE + 1] ^= 0x01;
+        assert_eq!(Message::<Chat>::decode(plain, &Integrity::None), Err(FormatError::Decompression));
+        let plain = chat(&paste).encode(&Integrity::None, true).unwrap();
+        let size = plain.len() - HEADER_SIZE - 3;
+        let mut cut = plain[..plain.len() - 3].to_vec();
+        cut[2..4].copy_from_slice(&(size as u16 | FLAG_COMPRESSED).to_be_bytes());
+        assert_eq!(Message::<Chat>::decode(cut, &Integrity::None), Err(FormatError::Decompression));
+
+        // The peers advertise compression unless it's turned off
+        let mut req = MemberRequest::new("peer-A", "grp", 0, 0).unwrap();
+        assert_eq!(req.features(), FEATURE_COMPRESSION);
+        req.set_features(0);
+        assert_eq!(req.features(), 0);
+    }
+
+    #[test]
+    fn in_place_decoding() {
+        let chat = Chat::new("peer-A".to_string(), "grp", 7, 2, "hello").unwrap();
+        let buf = Message::new(Header::new(PROTOCOL_VERSION, MessageType::Chat, 0), Some(chat.clone())).encode(&Integrity::Checksum, false).unwrap();
+
+        // Raw contents are borrowed, compressed ones are decompressed into a buffer of their own
+        let msg = MessageRef::parse(&buf, &Integrity::Checksum).unwrap();
+        assert!(matches!(msg.content, Cow::Borrowed(_)));
+        let view = msg.decode::<ChatRef>().unwrap();
+        assert_eq!((view.peer_id(), view.group_name(), view.msg_id(), view.msg()), ("peer-A", "grp", 7, "hello"));
+        assert_eq!(Chat::from(view), chat);
+        assert_eq!(msg.into_message::<Chat>(), Message::<Chat>::decode(buf.clone(), &Integrity::Checksum));
+
+        let paste = "let mut buf = vec![];\n".repeat(30);
+        let compressed = Message::new(Header::new(PROTOCOL_VERSION, MessageType::Chat, 0), Some(Chat::new("peer-A".to_string(), "grp", 8, 0, &paste).unwrap()))
+            .encode(&Integrity::Checksum, true).unwrap();
+        let msg = MessageRef::parse(&compressed, &Integrity::Checksum).unwrap();
+        assert!(matches!(msg.content, Cow::Owned(_)));
+        assert_eq!(msg.decode::<ChatRef>().unwrap().msg(), paste);
+
+        // Same checks as the owned decoding
+        assert_eq!(MessageRef::parse(&buf[..buf.len() - 1], &Integrity::Checksum).err(), Some(FormatError::Framing(FrameError::Truncated { expected: buf.len(), available: buf.len() - 1 })));
+        assert_eq!(MessageRef::parse(&buf, &Integrity::Mac(b"secret".to_vec())).err(), Some(FormatError::Integrity(IntegrityError::MissingMac)));
+    }
+
+    #[test]
+    fn encode_into_buffer() {
+        let bye = Message::new(Header::new(PROTOCOL_VERSION, MessageType::Bye, 0), Some(Bye::new("peer-A", "grp").unwrap()));
+        let req = Message::new(Header::new(PROTOCOL_VERSION, MessageType::MemberReq, 0), Some(MemberRequest::new("peer-A", "grp", 0, 0).unwrap()));
+        let key = Integrity::Mac(b"secret".to_vec());
+
+        // Messages are appended after the ones already in the buffer, the same as encoded alone
+        let mut buf = vec![];
+        bye.clone().encode_into(&mut buf, &Integrity::Checksum, false).unwrap();
+        req.clone().encode_into(&mut buf, &key, true).unwrap();
+        let first = bye.encode(&Integrity::Checksum, false).unwrap();
+        assert_eq!(buf[..first.len()], first);
+
+        let messages: Vec<&[u8]> = frames(&buf).collect::<Result<_, _>>().unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(Message::<MemberRequest>::decode(messages[1].to_vec(), &key).is_ok());
+
+        // Contents longer than the size field can hold leave the buffer as it was
+        let text = "x".repeat(MAX_CONTENT);
+        let long = Message::new(Header::new(PROTOCOL_VERSION, MessageType::Chat, 0), Some(Chat::new("peer-A".to_string(), "grp", 1, 0, &text).unwrap()));
+        let len = buf.len();
+        assert_eq!(long.encode_into(&mut buf, &key, false), Err(FormatError::TooLong { field: "content", max: MAX_CONTENT }));
+        assert_eq!(buf.len(), len);
+    }
+
+    #[test]
+    fn group_secrets() {
+        let mut keyring = Keyring::new();
+        keyring.set("open-grp", Integrity::Checksum);
+        keyring.set("keyed-grp", Integrity::Mac(b"secret".to_vec()));
+        assert_eq!(keyring.integrity(Some("other-grp")), Integrity::Checksum);
+
+        let bye = |group: &str, integrity: &Integrity| Message::new(Header::new(PROTOCOL_VERSION, MessageType::Bye, 0), Some(Bye::new("peer-A", group).unwrap()))
+            .encode(integrity, false).unwrap();
+        let keyed = bye("keyed-grp", &keyring.integrity(Some("keyed-grp")));
+        let open = bye("open-grp", &keyring.integrity(Some("open-grp")));
+        assert_eq!(keyed[2] & 0xF0, 0x40);
+        assert_eq!(open[2] & 0xF0, 0x80);
+        assert!(keyring.open(&open).and_then(|msg| msg.into_message::<Bye>()).is_ok());
+
+        // A signed message is only accepted once
+        assert!(keyring.open(&keyed).and_then(|msg| msg.into_message::<Bye>()).is_ok());
+        assert_eq!(keyring.open(&keyed).err(), Some(FormatError::Integrity(IntegrityError::Replayed)));
+
+        // The messages of the group need its MAC
+        let unsigned = bye("keyed-grp", &Integrity::Checksum);
+        assert_eq!(keyring.open(&unsigned).and_then(|msg| msg.into_message::<Bye>()).err(), Some(FormatError::Integrity(IntegrityError::MissingMac)));
+        let other_key = bye("keyed-grp", &Integrity::Mac(b"other".to_vec()));
+        assert_eq!(keyring.open(&other_key).err(), Some(FormatError::Integrity(IntegrityError::MacMismatch)));
+        keyring.set("second-grp", Integrity::Mac(b"second".to_vec()));
+        let crossed = bye("keyed-grp", &keyring.integrity(Some("second-grp")));
+        assert_eq!(keyring.open(&crossed).and_then(|msg| msg.into_message::<Bye>()).err(), Some(FormatError::Integrity(IntegrityError::MacMismatch)));
+
+        // Messages without a group pass with the trailer of any joined group
+        let ack = |integrity: &Integrity| Message::new(Header::new(PROTOCOL_VERSION, MessageType::Ack, 0), Some(Ack::new("peer-A".to_string(), 1, vec![]).unwrap()))
+            .encode(integrity, false).unwrap();
+        assert!(keyring.open(&ack(&keyring.integrity(Some("keyed-grp")))).and_then(|msg| msg.into_message::<Ack>()).is_ok());
+        assert!(keyring.open(&ack(&Integrity::Checksum)).and_then(|msg| msg.into_message::<Ack>()).is_ok());
+        assert!(keyring.open(&ack(&Integrity::Mac(b"other".to_vec()))).is_err());
+        // Unless every joined group has a secret
+        keyring.remove("open-grp");
+        assert_eq!(keyring.open(&ack(&Integrity::Checksum)).and_then(|msg| msg.into_message::<Ack>()).err(), Some(FormatError::Integrity(IntegrityError::MissingMac)));
+
+        // The trailer is checked before the content is decompressed
+        let paste = "let mut buf = vec![];\n".repeat(30);
+        let chat = |integrity: &Integrity| Message::new(Header::new(PROTOCOL_VERSION, MessageType::Chat, 0), Some(Chat::new("peer-A".to_string(), "keyed-grp", 1, 0, &paste).unwrap()))
+            .encode(integrity, true).unwrap();
+        for integrity in [Integrity::Checksum, Integrity::Mac(b"other".to_vec())] {
+            let mut broken = chat(&integrity);
+            broken[HEADER_SIZE + 1] ^= 0x01;
+            assert!(matches!(keyring.open(&broken), Err(FormatError::Integrity(_))));
+        }
+
+        // The conversions don't know any group, they use checksums
+        let msg = Message::new(Header::new(PROTOCOL_VERSION, MessageType::Bye, 0), Some(Bye::new("peer-A", "keyed-grp").unwrap()));
+        assert_eq!(Vec::try_from(msg).unwrap(), unsigned);
+        assert!(Message::<Bye>::try_from(unsigned).is_ok());
+        assert!(Message::<Bye>::try_from(keyed).is_err());
+    }
+
+    #[test]
+    fn version_compatibility() {
+        assert!(compatible((MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)));
+        assert!(compatible((MIN_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION)));
+        // The first release
+        assert!(!compatible((1, 1)));
+        assert!(compatible((PROTOCOL_VERSION, PROTOCOL_VERSION + 3)));
+        assert!(!compatible((PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 3)));
+        assert!(!compatible((0, 0)));
+        assert!(!compatible((2, 1)));
+    }
+
+    #[test]
+    fn tlv_fields() {
+        // Only the names are required
+        let buf = TlvWriter::new().put(0x01, &String::from("grp")).unwrap().put(0x02, &String::from("peer-A")).unwrap().finish();
+        let req = MemberRequest::try_from(buf).unwrap();
+        assert_eq!(req.peer_id(), "peer-A");
+        assert_eq!((req.cursor(), req.session()), (0, 0));
+        assert_eq!(req.versions(), (MIN_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION));
+        assert_eq!(req.features(), 0);
+
+        let buf = TlvWriter::new().put(0x01, &String::from("peer-A")).unwrap().finish();
+        let alive = Alive::try_from(buf).unwrap();
+        assert_eq!((alive.seq(), alive.session(), alive.features()), (0, 0, 0));
+        assert!(alive.updates().is_empty());
+
+        // Fields added by newer versions are skipped
+        let alive = Alive::new("peer-A".to_string(), 7, 42, vec![]);
+        let mut buf = Vec::try_from(alive.clone()).unwrap();
+        buf.extend(TlvWriter::new().put(0xEE, &String::from("from the future")).unwrap().finish());
+        assert_eq!(Alive::try_from(buf).unwrap(), alive);
+
+        // Names keep their zero bytes, but are still limited to 32 bytes
+        let chat = Chat::new("pe\0er".to_string(), "my\0group", 1, 0, "hi\0").unwrap();
+        assert_eq!(Chat::try_from(Vec::try_from(chat.clone()).unwrap()).unwrap(), chat);
+        assert_eq!(Chat::new("p".repeat(33), "my-group", 1, 0, "hi"), Err(FormatError::TooLong { field: "name", max: 32 }));
+        assert_eq!(Chat::new("peer-A".to_string(), &"g".repeat(33), 1, 0, "hi"), Err(FormatError::TooLong { field: "name", max: 32 }));
+        let buf = TlvWriter::new()
+            .put(Chat::PEER_ID, &"p".repeat(33)).unwrap()
+            .put(Chat::GROUP, &"my-group").unwrap()
+            .put(Chat::MSG_ID, &1u32).unwrap()
+            .put(Chat::HOPS, &0u8).unwrap()
+            .put(Chat::MSG, &"hi").unwrap()
+            .finish();
+        assert_eq!(Chat::try_from(buf), Err(FormatError::TooLong { field: "name", max: 32 }));
+        let buf = TlvWriter::new().put_bytes(0x01, &[0xC3, 0x28]).unwrap().finish();
+        assert_eq!(Alive::try_from(buf), Err(FormatError::InvalidUtf8));
+        assert_eq!(Alive::try_from(vec![]), Err(FormatError::MissingField(0x01)));
+
+        // A field cut in half is an error
+        let buf = Vec::try_from(MemberRequest::new("peer-A", "grp", 1, 2).unwrap()).unwrap();
+        assert_eq!(MemberRequest::try_from(buf[..buf.len() - 1].to_vec()), Err(FormatError::Truncated));
+    }
+
+    #[test]
+    fn member_request_serialization() {
+        let req = MemberRequest::new("peer-A", "my-group", 0x0102, 0x0304).unwrap();
+        let buf = Vec::try_from(req).unwrap();
+        assert_eq!(buf[0..3], [0x01, 0x00, 0x08]);
+        assert_eq!(buf[3..11], *"my-group".as_bytes());
+        assert_eq!(buf[11..14], [0x02, 0x00, 0x06]);
+        assert_eq!(buf[20..25], [0x03, 0x00, 0x02, 0x01, 0x02]);
+        assert_eq!(buf[25..36], [0x04, 0x00, 0x08, 0, 0, 0, 0, 0, 0, 0x03, 0x04]);
+        assert_eq!(buf[36..44], [0x05, 0x00, 0x01, MIN_PROTOCOL_VERSION, 0x06, 0x00, 0x01, PROTOCOL_VERSION]);
+        assert_eq!(buf[44..], [0x07, 0x00, 0x01, FEATURE_COMPRESSION]);
+    }
+
+    #[test]
+    fn member_request_deserialization() {
+        let req = MemberRequest::new("peer1", "my-group", 10, 77).unwrap();
+
+        let bytes = Vec::try_from(req).unwrap();
+
+        let view = MemberRequestRef::try_from(bytes.as_slice()).unwrap();
+        assert_eq!((view.group_name(), view.peer_id(), view.versions()), ("my-group", "peer1", (MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)));
+
+        let req2 = MemberRequest::try_from(bytes.clone()).unwrap();
+        assert_eq!(MemberRequest::from(view), req2);
+        assert_eq!(req2.group, "my-group");
+        assert_eq!(req2.peer_id, "peer1");
+        assert_eq!(req2.cursor, 10);
+        assert_eq!(req2.session, 77);
+    }
+
+    #[test]
+    fn member_response_serialization() {
+        let peers = vec![
+            ("peer-A".to_string(), "11.22.33.44:1234".parse().unwrap()),
+            ("peer-B".to_string(), "255.0.0.1:65511".parse().unwrap()),
+        ];
+        let res = MemberResponse::new("my-group", 2, 0, peers).unwrap();
+        let buf = Vec::try_from(res).unwrap();
+
+        let res2 = MemberResponse::try_from(buf).unwrap();
+
+        assert_eq!(res2.group, "my-group".to_string());
+
+        assert_eq!(res2.member_number, 2);
+
+        assert_eq!(res2.peers[0], ("peer-A".to_string(), "11.22.33.44:1234".parse().unwrap()));
+        assert_eq!(res2.peers[1],  ("peer-B".to_string(), "255.0.0.1:65511".parse().unwrap()));
+    }
+
+    #[test]
+    fn member_response_deserialization() {
+        let data = [
+            // group name
+            0x01, 0, 3, b'g', b'r', b'p',
+            // total and cursor
+            0x02, 0, 2, 0, 7,
+            0x03, 0, 2, 0, 5,
+            // First peer name, IP and port
+            0x04, 0, 17,
+            0x01, 0, 5, b'p', b'e', b'e', b'r', b'A',
+            0x02, 0, 6, 11, 22, 255, 0, 0x04, 0xD2,
+            // Field of a newer version
+            0x7F, 0, 1, 0xAA,
+            // Second peer name, IP and port
+            0x04, 0, 17,
+            0x01, 0, 5, b'p', b'e', b'e', b'r', b'B',
+            0x02, 0, 6, 255, 0, 1, 1, 0xFD, 0xFE,
+        ];
+        let res = MemberResponse::try_from(data.to_vec()).unwrap();
+
+        assert_eq!(res.group, "grp".to_string());
+
+        // Member count
+        assert_eq!(res.member_number, 2);
+        assert_eq!(res.total(), 7);
+        assert_eq!(res.next_cursor(), None);
+
+        let peers = res.peers();
+        assert_eq!(peers[0], ("peerA".to_string(), SocketAddr::new("11.22.255.0".parse().unwrap(), 1234)));
+        assert_eq!(peers[1], ("peerB".to_string(), SocketAddr::new("255.0.1.1".parse().unwrap(), 65022)));
+    }
+
+    #[test]
+    fn mail_serialization() {
+        let mail = Mail::new("my-group", "peer-A", "peer-B", "see you later".as_bytes().to_vec()).unwrap();
+        let buf: Vec<u8> = mail.clone().into();
+        assert_eq!(buf.len(), 98 + 13);
+
+        let mail2 = Mail::try_from(buf).unwrap();
+        assert_eq!(mail2, mail);
+        assert_eq!(mail2.payload(), "see you later".as_bytes());
+    }
+
+    #[test]
+    fn chat_serialization() {
+        let chat = Chat::new("peer-A".to_string(), "my-group", 0xDEADBEEF, 3, "hello there").unwrap();
+        let buf = Vec::try_from(chat.clone()).unwrap();
+        assert_eq!(buf[12..20], *"my-group".as_bytes());
+        assert_eq!(buf[20..31], [0x03, 0, 4, 0xDE, 0xAD, 0xBE, 0xEF, 0x04, 0, 1, 3]);
+
+        let chat2 = Chat::try_from(buf).unwrap();
+        assert_eq!(chat2, chat);
+
+        let fwd = chat2.forwarded().unwrap();
+        assert_eq!(fwd.hops(), 2);
+        assert_eq!(fwd.msg_id(), 0xDEADBEEF);
+        assert!(Chat::new("peer-A".to_string(), "my-group", 1, 0, "last hop").unwrap().forwarded().is_none());
+    }
+
+    #[test]
+    fn rich_chat() {
+        let mut chat = Chat::new("peer-A".to_string(), "my-group", 7, 3, "ask @bob about the code").unwrap();
+        chat.set_reply_to("bob", 0xCAFE).unwrap();
+        chat.set_mentions(vec!["bob".to_string()]).unwrap();
+        chat.set_styles(vec![StyleRange::new(TextStyle::Bold, 0..3).unwrap(), StyleRange::new(TextStyle::Code, 19..23).unwrap()]).unwrap();
+        let buf = Vec::try_from(chat.clone()).unwrap();
+        let chat2 = Chat::try_from(buf.clone()).unwrap();
+        assert_eq!(chat2, chat);
+        assert_eq!(chat2.reply_to(), Some(("bob", 0xCAFE)));
+        assert_eq!(chat2.forwarded().unwrap().styles(), chat.styles());
+
+        let view = ChatRef::try_from(buf.as_slice()).unwrap();
+        assert_eq!(view.mentions().collect::<Vec<_>>(), vec!["bob"]);
+        assert_eq!(view.styles().map(|s| &view.msg()[s.range()]).collect::<Vec<_>>(), vec!["ask", "code"]);
+
+        // Ranges have to cover whole characters of the text
+        let mut chat = Chat::new("peer-A".to_string(), "my-group", 7, 3, "čaj").unwrap();
+        assert!(chat.set_styles(vec![StyleRange::new(TextStyle::Italic, 0..1).unwrap()]).is_err());
+        assert!(chat.set_styles(vec![StyleRange::new(TextStyle::Italic, 2..5).unwrap()]).is_err());
+        assert!(chat.set_styles(vec![StyleRange::new(TextStyle::Italic, 2..2).unwrap()]).is_err());
+        assert!(StyleRange::new(TextStyle::Italic, 0..70_000).is_err());
+        assert_eq!(chat.set_mentions(vec!["p".to_string(); MAX_MENTIONS + 1]), Err(FormatError::TooLong { field: "mentions", max: MAX_MENTIONS }));
+
+        let plain = Vec::try_from(Chat::new("peer-A".to_string(), "my-group", 7, 3, "čaj").unwrap()).unwrap();
+        let with_style = |style: &[u8]| {
+            let mut buf = plain.clone();
+            buf.extend(TlvWriter::new().put_bytes(Chat::STYLE, style).unwrap().finish());
+            Chat::try_from(buf)
+        };
+        assert_eq!(with_style(&[0x02, 0, 2, 0, 2]).unwrap().styles(), [StyleRange::new(TextStyle::Italic, 2..4).unwrap()]);
+        assert_eq!(with_style(&[0x02, 0, 1, 0, 1]), Err(FormatError::InvalidValue { field: "style range", value: "1..2".to_string() }));
+        assert_eq!(with_style(&[0x02, 0, 1]), Err(FormatError::WrongLength { value: "style range", len: 3 }));
+        // Styles of newer versions are skipped
+        assert!(with_style(&[0x7F, 0, 0, 0, 4]).unwrap().styles().is_empty());
+
+        // A reply needs the peer of the message
+        let mut buf = plain.clone();
+        buf.extend(TlvWriter::new().put(Chat::REPLY_ID, &1u32).unwrap().finish());
+        assert_eq!(Chat::try_from(buf), Err(FormatError::MissingField(Chat::REPLY_PEER)));
+    }
+
+    #[test]
+    fn chat_action() {
+        let mut edited = Chat::new("peer-A".to_string(), "my-group", 7, 0, "fixed *typo*").unwrap();
+        edited.set_styles(vec![StyleRange::new(TextStyle::Bold, 6..10).unwrap()]).unwrap();
+        let actions = [
+            ChatAction::edit(20, 3, edited.clone()),
+            ChatAction::delete("peer-A", "my-group", 21, 3, 7).unwrap(),
+            ChatAction::react("peer-B", "my-group", 5, 0, "peer-A", 7, "👍").unwrap(),
+        ];
+        for action in actions {
+            let buf = Vec::try_from(action.clone()).unwrap();
+            assert_eq!(ChatAction::try_from(buf).unwrap(), action);
+        }
+        assert_eq!(ChatAction::edit(20, 3, edited.clone()).kind(), &ChatActionKind::Edit(edited.clone()));
+        assert_eq!(ChatAction::edit(20, 1, edited.clone()).forwarded().unwrap().forwarded(), None);
+        assert!(ChatAction::react("peer-B", "my-group", 5, 0, "peer-A", 7, " ").is_err());
+        assert!(ChatAction::react("peer-B", "my-group", 5, 0, "peer-A", 7, &"x".repeat(MAX_REACTION + 1)).is_err());
+
+        // Only the author can edit or delete the message
+        let forged = TlvWriter::new()
+            .put(ChatAction::PEER_ID, &"peer-B").unwrap()
+            .put(ChatAction::GROUP, &"my-group").unwrap()
+            .put(ChatAction::ACTION_ID, &1u32).unwrap()
+            .put(ChatAction::KIND, &0x02u8).unwrap()
+            .put(ChatAction::AUTHOR, &"peer-A").unwrap()
+            .put(ChatAction::MSG_ID, &7u32).unwrap()
+            .finish();
+        assert_eq!(ChatAction::try_from(forged), Err(FormatError::InvalidValue { field: "author", value: "peer-B".to_string() }));
+
+        // The edited chat has to be the referenced message
+        let other = Chat::new("peer-A".to_string(), "my-group", 8, 0, "other").unwrap();
+        let mismatch = TlvWriter::new()
+            .put(ChatAction::PEER_ID, &"peer-A").unwrap()
+            .put(ChatAction::GROUP, &"my-group").unwrap()
+            .put(ChatAction::ACTION_ID, &1u32).unwrap()
+            .put(ChatAction::KIND, &0x01u8).unwrap()
+            .put(ChatAction::AUTHOR, &"peer-A").unwrap()
+            .put(ChatAction::MSG_ID, &7u32).unwrap()
+            .put_bytes(ChatAction::EDITED, &Vec::try_from(other).unwrap()).unwrap()
+            .finish();
+        assert_eq!(ChatAction::try_from(mismatch), Err(FormatError::InvalidValue { field: "edited message", value: "peer-A 8".to_string() }));
+
+        let unknown = TlvWriter::new()
+            .put(ChatAction::PEER_ID, &"peer-A").unwrap()
+            .put(ChatAction::GROUP, &"my-group").unwrap()
+            .put(ChatAction::ACTION_ID, &1u32).unwrap()
+            .put(ChatAction::KIND, &0x7Fu8).unwrap()
+            .put(ChatAction::AUTHOR, &"peer-A").unwrap()
+            .put(ChatAction::MSG_ID, &7u32).unwrap()
+            .finish();
+        assert_eq!(ChatAction::try_from(unknown), Err(FormatError::InvalidValue { field: "chat action", value: "127".to_string() }));
+    }
+
+    #[test]
+    fn alive_serialization() {
+        let updates = vec![
+            MemberUpdate::new("grp", "peer-B", "11.22.33.44:1234".parse().unwrap(), MemberState::Suspect, 7).unwrap(),
+            MemberUpdate::new("grp", "peer-C", "1.2.3.4:5".parse().unwrap(), MemberState::Dead, 0).unwrap(),
+        ];
+        let alive = Alive::new("peer-A".to_string(), 99, 42, updates);
+        let buf = Vec::try_from(alive.clone()).unwrap();
+        // Every field has a tag and a length of 3 bytes
+        assert_eq!(buf.len(), 3 + 6 + 3 + 4 + 3 + 8 + 3 + 1 + 2 * (3 + 75));
+
+        let alive2 = Alive::try_from(buf).unwrap();
+        assert_eq!(alive2, alive);
+        assert_eq!(alive2.updates()[0].state(), MemberState::Suspect);
+        assert_eq!(alive2.updates()[0].incarnation(), 7);
+    }
+
+    #[test]
+    fn ping_req_serialization() {
+        let req = PingReq::new("peer-A".to_string(), "peer-B".to_string(), "11.22.33.44:1234".parse().unwrap(), 9).unwrap();
+        let buf: Vec<u8> = req.clone().into();
+
+        assert_eq!(PingReq::try_from(buf).unwrap(), req);
+
+        // The fixed layouts can't hold IPv6 addresses
+        let v6: SocketAddr = "[::1]:1234".parse().unwrap();
+        assert_eq!(PingReq::new("peer-A".to_string(), "peer-B".to_string(), v6, 9), Err(FormatError::InvalidValue { field: "address", value: "[::1]:1234".to_string() }));
+        assert!(MemberUpdate::new("grp", "peer-B", v6, MemberState::Alive, 0).is_err());
+        assert!(DhtMessage::new(DhtOp::Nodes, 1, 2, 3, vec![(1, v6)], vec![]).is_err());
+    }
+
+    #[test]
+    fn fixed_layout_names() {
+        // The fixed layouts hold names of up to 32 bytes
+        let addr: SocketAddr = "11.22.33.44:1234".parse().unwrap();
+        let long = "p".repeat(33);
+        assert_eq!(Ack::new(long.clone(), 1, vec![]), Err(FormatError::TooLong { field: "peer id", max: 32 }));
+        assert_eq!(PingReq::new(long.clone(), "peer-B".to_string(), addr, 9), Err(FormatError::TooLong { field: "peer id", max: 32 }));
+        assert_eq!(PingReq::new("peer-A".to_string(), long, addr, 9), Err(FormatError::TooLong { field: "peer id", max: 32 }));
+
+        let ack = Ack::new("p".repeat(32), 1, vec![]).unwrap();
+        let buf: Vec<u8> = ack.with_seq(2).into();
+        assert_eq!(Ack::try_from(buf).unwrap().seq(), 2);
+    }
+
+    #[test]
+    fn wrong_member_state() {
+        let mut buf: Vec<u8> = Ack::new("peer-A".to_string(), 1, vec![
+            MemberUpdate::new("grp", "peer-B", "1.2.3.4:5".parse().unwrap(), MemberState::Alive, 0).unwrap(),
+        ]).unwrap().into();
+        buf[32 + 4 + 1 + 70] = 0x07;
+
+        assert!(Ack::try_from(buf).is_err());
+    }
+
+    #[test]
+    fn bye_serialization() {
+        let bye = Bye::new("peer-A", "my-group").unwrap();
+        let buf: Vec<u8> = bye.clone().into();
+        assert_eq!(buf.len(), 64);
+        assert_eq!(buf[32..40], *"my-group".as_bytes());
+
+        assert_eq!(Bye::try_from(buf).unwrap(), bye);
+    }
+
+    #[test]
+    fn sync_serialization() {
+        let sync = MemberSync::new(vec![
+            SyncEntry::new("grp", "peer-A", "1.2.3.4:5".parse().unwrap(), 1_650_000_000_000, false).unwrap(),
+            SyncEntry::new("grp", "peer-B", "1.2.3.4:6".parse().unwrap(), 1_650_000_000_001, true).unwrap(),
+        ]).unwrap();
+        let buf: Vec<u8> = sync.clone().into();
+        assert_eq!(buf.len(), 1 + 2 * 79);
+        assert_eq!(buf[0], 2);
+
+        assert_eq!(MemberSync::try_from(buf.clone()).unwrap(), sync);
+        assert!(MemberSync::try_from(buf[0..100].to_vec()).is_err());
+
+        let entries = vec![sync.entries()[0].clone(); SYNC_MAX_ENTRIES];
+        let buf = Vec::try_from(Message::new(Header::new(1, MessageType::Sync, 0), Some(MemberSync::new(entries.clone()).unwrap()))).unwrap();
+        assert!(buf.len() <= 1024);
+
+        let entries = vec![sync.entries()[0].clone(); SYNC_MAX_ENTRIES + 1];
+        assert!(MemberSync::new(entries).is_err());
+    }
+
+    #[test]
+    fn dht_serialization() {
+        let nodes = vec![(0xDEAD_BEEF_u64, "1.2.3.4:5".parse().unwrap())];
+        let records = vec![DhtRecord::new("grp", "peer-A", "1.2.3.4:6".parse().unwrap()).unwrap()];
+        let msg = DhtMessage::new(DhtOp::Values, 7, 42, 99, nodes, records).unwrap();
+        let buf: Vec<u8> = msg.clone().into();
+        assert_eq!(buf.len(), 21 + 1 + 14 + 1 + 70);
+
+        assert_eq!(DhtMessage::try_from(buf.clone()).unwrap(), msg);
+        assert!(DhtMessage::try_from(buf[0..40].to_vec()).is_err());
+
+        let mut wrong_op = buf;
+        wrong_op[0] = 0x0F;
+        assert!(DhtMessage::try_from(wrong_op).is_err());
+
+        let nodes = vec![(1, "1.2.3.4:5".parse().unwrap()); DHT_MAX_ITEMS];
+        let records = vec![DhtRecord::new("grp", "peer-A", "1.2.3.4:6".parse().unwrap()).unwrap(); DHT_MAX_ITEMS];
+        let full = DhtMessage::new(DhtOp::Values, 7, 42, 99, nodes.clone(), records).unwrap();
+        let buf = Vec::try_from(Message::new(Header::new(1, MessageType::Dht, 0), Some(full))).unwrap();
+        assert!(buf.len() <= 1024);
+
+        let nodes = vec![(1, "1.2.3.4:5".parse().unwrap()); DHT_MAX_ITEMS + 1];
+        assert!(DhtMessage::new(DhtOp::Nodes, 7, 42, 99, nodes, vec![]).is_err());
+    }
+
+    #[test]
+    fn announce_serialization() {
+        let announce = Announce::new("peer-A", "my-group", 8000).unwrap();
+        let buf: Vec<u8> = announce.clone().into();
+        assert_eq!(buf.len(), 66);
+        assert_eq!(buf[64..66], [0x1F, 0x40]);
+
+        assert_eq!(Announce::try_from(buf).unwrap(), announce);
+    }
+
+    #[test]
+    fn challenge_serialization() {
+        let challenge = Challenge::new("peer-A", ChallengeKind::Response, 0x0102030405060708, 9).unwrap();
+        let buf: Vec<u8> = challenge.clone().into();
+        assert_eq!(buf.len(), 49);
+        assert_eq!(buf[32..49], [1, 2, 3, 4, 5, 6, 7, 8, 1, 0, 0, 0, 0, 0, 0, 0, 9]);
+
+        assert_eq!(Challenge::try_from(buf.clone()).unwrap(), challenge);
+        assert!(Challenge::try_from(vec![0; 40]).is_err());
+
+        // The proof is required
+        assert!(Challenge::try_from(buf[..41].to_vec()).is_err());
+        let mut wrong_kind = buf.clone();
+        wrong_kind[40] = 0x07;
+        assert!(Challenge::try_from(wrong_kind).is_err());
+    }
+
+    #[test]
+    fn reject_serialization() {
+        let reject = Reject::new("peer-A", "my-group", RejectReason::NameTaken).unwrap();
+        let buf: Vec<u8> = reject.clone().into();
+        assert_eq!(buf.len(), 67);
+        assert_eq!(buf[64], 0x01);
+        assert_eq!(Reject::try_from(buf.clone()).unwrap(), reject);
+
+        let mut unknown = buf;
+        unknown[64] = 0xFF;
+        assert!(Reject::try_from(unknown).is_err());
+    }
+
+    #[test]
+    fn presence_serialization() {
+        let presence = Presence::new("peer-A", "my-group", PresenceStatus::Away, true, "lunch").unwrap();
+        let buf: Vec<u8> = presence.clone().into();
+        assert_eq!(buf.len(), 67 + 5);
+        assert_eq!(buf[64..67], [0x01, 0x01, 0x05]);
+        assert_eq!(Presence::try_from(buf).unwrap(), presence);
+
+        assert!(Presence::new("peer-A", "my-group", PresenceStatus::Busy, false, &"x".repeat(STATUS_TEXT_MAX + 1)).is_err());
+        assert_eq!("busy".parse::<PresenceStatus>().unwrap(), PresenceStatus::Busy);
+        assert!("sleeping".parse::<PresenceStatus>().is_err());
+    }
+
+    #[test]
+    fn member_response_pages() {
+        let members: Vec<(String, SocketAddr)> = (0..MEMBER_PAGE_SIZE as u16 + 2)
+            .map(|i| (format!("peer-{}", i), SocketAddr::new("10.0.0.1".parse().unwrap(), 2000 + i)))
+            .collect();
+
+        let first = MemberResponse::page("grp", &members, 0).unwrap();
+        assert_eq!(first.peers().len(), MEMBER_PAGE_SIZE);
+        assert_eq!(first.next_cursor(), Some(MEMBER_PAGE_SIZE as u16));
+
+        let last = MemberResponse::page("grp", &members, MEMBER_PAGE_SIZE as u16).unwrap();
+        assert_eq!(last.peers().len(), members.len() - MEMBER_PAGE_SIZE);
+        assert_eq!(last.peers().last().unwrap().0, members.last().unwrap().0);
+        assert_eq!(last.next_cursor(), None);
+
+        // Cursor past the end returns an empty page instead of failing
+        let past = MemberResponse::page("grp", &members, 40).unwrap();
+        assert!(past.peers().is_empty());
+        assert_eq!(past.next_cursor(), None);
+
+        assert!(MemberResponse::new("grp", 3, 0, members[0..6].to_vec()).is_err());
+        assert!(MemberResponse::new("grp", 3, 1, members[0..3].to_vec()).is_err());
+        assert!(MemberResponse::new("grp", u16::MAX, 0, members[..MEMBER_PAGE_SIZE + 1].to_vec()).is_err());
+
+        // A full page with the longest names still fits in a datagram
+        let longest: Vec<(String, SocketAddr)> = (0..MEMBER_PAGE_SIZE)
+            .map(|i| (format!("{:0>32}", i), "255.255.255.255:65535".parse().unwrap()))
+            .collect();
+        let full = MemberResponse::new(&"g".repeat(32), u16::MAX, u16::MAX - MEMBER_PAGE_SIZE as u16, longest).unwrap();
+        let buf = Message::new(Header::new(PROTOCOL_VERSION, MessageType::MemberRes, 0), Some(full))
+            .encode(&Integrity::Mac(b"secret".to_vec()), false).unwrap();
+        assert!(buf.len() <= DATAGRAM_SIZE);
+        assert!(buf.len() + MEMBER_ENTRY_LEN > DATAGRAM_SIZE);
+    }
+
+    #[test]
+    fn unknown_types() {
+        assert!(MessageType::try_from(0x00).is_err());
+        assert_eq!(MessageType::try_from(0x08).unwrap(), MessageType::Chat);
+
+        // Known magic byte with an unknown type is an error, not a panic
+        assert_eq!(Header::try_from(vec![MAGIC_HEADER, 0x10, 0x00, 0x00]), Err(FormatError::UnknownType(0x00)));
+        assert_eq!(Header::try_from(vec![MAGIC_HEADER, 0x18, 0x10, 0x00]), Err(FormatError::UnknownFlags(0x1000)));
+        assert_eq!(Header::try_from(vec![0x00, 0x1F, 0x00, 0x00]), Err(FormatError::BadMagic(0x00)));
+        assert_eq!(Header::try_from(vec![MAGIC_HEADER, 0x18]), Err(FormatError::Truncated));
+
+        // The extended type can't repeat a basic one, and new extended types are unknown too
+        assert_eq!(Header::try_from(vec![MAGIC_HEADER, 0x6F, 0x00, 0x00, 0x08]), Err(FormatError::UnknownType(0x08)));
+        assert_eq!(Header::try_from(vec![MAGIC_HEADER, 0x6F, 0x00, 0x00, 0x11]), Err(FormatError::UnknownType(0x11)));
+        assert_eq!(Header::try_from(vec![MAGIC_HEADER, 0x6F, 0x00, 0x00]), Err(FormatError::Truncated));
+    }
+
+    /// Returns valid packets of every type, as seeds for mutating
+    fn seed_packets() -> Vec<Vec<u8>> {
+        let addr: SocketAddr = "1.2.3.4:5".parse().unwrap();
+        let update = MemberUpdate::new("grp", "peer-B", addr, MemberState::Suspect, 3).unwrap();
+        let record = DhtRecord::new("grp", "peer-A", addr).unwrap();
+        vec![
+            Vec::try_from(Message::new(Header::new(1, MessageType::Alive, 0), Some(Alive::new("peer-A".to_string(), 1, 2, vec![update.clone()])))).unwrap(),
+            Vec::try_from(Message::new(Header::new(1, MessageType::MemberReq, 0), Some(MemberRequest::new("peer-A", "grp", 1, 2).unwrap()))).unwrap(),
+            Vec::try_from(Message::new(Header::new(1, MessageType::MemberRes, 0), Some(MemberResponse::new("grp", 1, 0, vec![("peer-A".to_string(), addr)]).unwrap()))).unwrap(),
+            Vec::try_from(Message::new(Header::new(1, MessageType::Chat, 0), Some(Chat::new("peer-A".to_string(), "grp", 1, 2, "hello").unwrap()))).unwrap(),
+            Vec::try_from(Message::new(Header::new(1, MessageType::Mail, 0), Some(Mail::new("grp", "peer-A", "peer-B", vec![1, 2, 3]).unwrap()))).unwrap(),
+            Vec::try_from(Message::new(Header::new(1, MessageType::Ack, 0), Some(Ack::new("peer-A".to_string(), 1, vec![update]).unwrap()))).unwrap(),
+            Vec::try_from(Message::new(Header::new(1, MessageType::PingReq, 0), Some(PingReq::new("peer-A".to_string(), "peer-B".to_string(), addr, 1).unwrap()))).unwrap(),
+            Vec::try_from(Message::new(Header::new(1, MessageType::Bye, 0), Some(Bye::new("peer-A", "grp").unwrap()))).unwrap(),
+            Vec::try_from(Message::new(Header::new(1, MessageType::Sync, 0), Some(MemberSync::new(vec![SyncEntry::new("grp", "peer-A", addr, 1, false).unwrap()]).unwrap()))).unwrap(),
+            Vec::try_from(Message::new(Header::new(1, MessageType::Dht, 0), Some(DhtMessage::new(DhtOp::Values, 1, 2, 3, vec![(1, addr)], vec![record]).unwrap()))).unwrap(),
+            Vec::try_from(Message::new(Header::new(1, MessageType::Announce, 0), Some(Announce::new("peer-A", "grp", 8000).unwrap()))).unwrap(),
+            Vec::try_from(Message::new(Header::new(1, MessageType::Challenge, 0), Some(Challenge::new("peer-A", ChallengeKind::Request, 1, 0).unwrap()))).unwrap(),
+            Vec::try_from(Message::new(Header::new(1, MessageType::Reject, 0), Some(Reject::new("peer-A", "grp", RejectReason::NameTaken).unwrap()))).unwrap(),
+            Vec::try_from(Message::new(Header::new(1, MessageType::Presence, 0), Some(Presence::new("peer-A", "grp", PresenceStatus::Away, true, "brb").unwrap()))).unwrap(),
+            Vec::try_from(Message::new(Header::new(6, MessageType::ChatAction, 0), Some(ChatAction::react("peer-A", "grp", 2, 1, "peer-B", 1, "+1").unwrap()))).unwrap(),
+        ]
+    }
+
+    #[test]
+    fn decode_mutated_packets() {
+        let seeds = seed_packets();
+        for seed in seeds.iter() {
+            assert!(decode(seed).is_ok());
+        }
+
+        // Same inputs on every run, xorshift is enough to spread the mutations
+        let mut state = 0x2545F4914F6CDD1Du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for round in 0..20_000 {
+            let mut buf = seeds[round % seeds.len()].clone();
+            for _ in 0..(next() % 4) {
+                let index = next() as usize % buf.len();
+                buf[index] = next() as u8;
+            }
+            buf.truncate(next() as usize % (buf.len() + 1));
+            let _ = decode(&buf);
+
+            let noise: Vec<u8> = (0..next() % 64).map(|_| next() as u8).collect();
+            let _ = decode(&noise);
+        }
+
+        // Every prefix of a valid packet
+        for seed in seeds.iter() {
+            for len in 0..seed.len() {
+                let _ = decode(&seed[..len]);
+            }
+        }
+        assert!(decode(&[]).is_err());
+    }
+
+    mod round_trip {
+        use proptest::prelude::*;
+
+        use super::*;
+
+        /// Names with zero bytes and multi-byte characters, up to 30 bytes
+        fn name() -> impl Strategy<Value = String> {
+            "[\\x00-\\x7F\u{e9}\u{4e16}]{0,10}"
+        }
+
+        fn addr() -> impl Strategy<Value = SocketAddr> {
+            (any::<[u8; 4]>(), any::<u16>()).prop_map(|(ip, port)| SocketAddr::new(IpAddr::from(ip), port))
+        }
+
+        fn update() -> impl Strategy<Value = MemberUpdate> {
+            (any::<bool>(), addr(), any::<u32>())
+                .prop_map(|(dead, addr, incarnation)| {
+                    let state = if dead { MemberState::Dead } else { MemberState::Alive };
+                    MemberUpdate::new("grp", "peer", addr, state, incarnation).unwrap()
+                })
+        }
+
+        proptest! {
+            #[test]
+            fn alive(peer_id in name(), session: u64, seq: u32, updates in prop::collection::vec(update(), 0..4)) {
+                let alive = Alive::new(peer_id, session, seq, updates);
+                prop_assert_eq!(Alive::try_from(Vec::try_from(alive.clone()).unwrap()).unwrap(), alive);
+            }
+
+            #[test]
+            fn member_request(peer_id in name(), group in name(), cursor: u16, session: u64) {
+                let req = MemberRequest::new(&peer_id, &group, cursor, session).unwrap();
+                prop_assert_eq!(MemberRequest::try_from(Vec::try_from(req.clone()).unwrap()).unwrap(), req);
+            }
+
+            #[test]
+            fn member_response(group in name(), peers in prop::collection::vec((name(), addr()), 0..=MEMBER_PAGE_SIZE), extra: u16) {
+                let total = (peers.len() as u16).saturating_add(extra % 100);
+                let res = MemberResponse::new(&group, total, 0, peers).unwrap();
+                prop_assert_eq!(MemberResponse::try_from(Vec::try_from(res.clone()).unwrap()).unwrap(), res);
+            }
+
+            #[test]
+            fn chat(peer_id in name(), group in name(), msg_id: u32, hops: u8, msg in ".{0,200}", reply_to in proptest::option::of((name(), any::<u32>())),
+                mentions in proptest::collection::vec(name(), 0..4)) {
+                let mut chat = Chat::new(peer_id, &group, msg_id, hops, &msg).unwrap();
+                if let Some((reply_peer, reply_id)) = reply_to {
+                    chat.set_reply_to(&reply_peer, reply_id).unwrap();
+                }
+                chat.set_mentions(mentions).unwrap();
+                prop_assert_eq!(Chat::try_from(Vec::try_from(chat.clone()).unwrap()).unwrap(), chat);
+            }
+
+            #[test]
+            fn compressed(peer_id in name(), group in name(), peers in prop::collection::vec((name(), addr()), 0..=MEMBER_PAGE_SIZE), msg in ".{0,400}") {
+                let chat = Chat::new(peer_id, &group, 1, 0, &msg).unwrap();
+                let buf = Message::new(Header::new(PROTOCOL_VERSION, MessageType::Chat, 0), Some(chat.clone())).encode(&Integrity::Checksum, true).unwrap();
+                prop_assert_eq!(Message::<Chat>::decode(buf, &Integrity::Checksum).unwrap().content().cloned(), Some(chat));
+
+                let res = MemberResponse::new(&group, peers.len() as u16, 0, peers).unwrap();
+                let buf = Message::new(Header::new(PROTOCOL_VERSION, MessageType::MemberRes, 0), Some(res.clone())).encode(&Integrity::None, true).unwrap();
+                prop_assert_eq!(Message::<MemberResponse>::decode(buf, &Integrity::None).unwrap().content().cloned(), Some(res));
+            }
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::ops::Range;
+
+use super::format::{StyleRange, TextStyle, MAX_MENTIONS, MAX_STYLES};
+
+/// Chat text as typed by the user, with the markers of the styled parts taken out.
+///
+/// `*bold*`, `_italic_` and `` `code` `` are styled when the markers sit at the edges of words,
+/// so `snake_case_names` and `2*3*4` stay as they are. Styles don't nest, markers inside
+/// a styled part are kept. `@name` mentions the peer with that name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Markup {
+    text: String,
+    styles: Vec<StyleRange>,
+    mentions: Vec<String>,
+}
+
+impl Markup {
+    pub fn parse(input: &str) -> Markup {
+        let mut text = String::with_capacity(input.len());
+        let mut styles = vec![];
+        let mut prev: Option<char> = None;
+        let mut rest = input;
+
+        while let Some(c) = rest.chars().next() {
+            let styled = marker_style(c)
+                .filter(|_| styles.len() < MAX_STYLES && prev.is_none_or(opens_after))
+                .and_then(|style| closing_marker(rest, c).map(|end| (style, end)));
+            match styled {
+                Some((style, end)) => {
+                    let start = text.len();
+                    text.push_str(&rest[1..end]);
+                    // Texts are limited far below the range of the offsets
+                    if let Ok(range) = StyleRange::new(style, start..text.len()) {
+                        styles.push(range);
+                    }
+                    rest = &rest[end + 1..];
+                },
+                None => {
+                    text.push(c);
+                    rest = &rest[c.len_utf8()..];
+                },
+            }
+            prev = Some(c);
+        }
+
+        let mentions = mentions(&text);
+        Markup { text, styles, mentions }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn styles(&self) -> &[StyleRange] {
+        &self.styles
+    }
+
+    pub fn mentions(&self) -> &[String] {
+        &self.mentions
+    }
+}
+
+fn marker_style(c: char) -> Option<TextStyle> {
+    match c {
+        '*' => Some(TextStyle::Bold),
+        '_' => Some(TextStyle::Italic),
+        '`' => Some(TextStyle::Code),
+        _ => None,
+    }
+}
+
+/// A marker opens a styled part at the start of a word
+fn opens_after(prev: char) -> bool {
+    prev.is_whitespace() || "([{\"'".contains(prev)
+}
+
+/// Returns the position of the marker closing the one `text` starts with.
+/// The styled part can't be empty or start or end with a space, and the closing marker has to end a word.
+fn closing_marker(text: &str, marker: char) -> Option<usize> {
+    let inner = &text[1..];
+    if inner.starts_with(|c: char| c.is_whitespace() || c == marker) {
+        return None;
+    }
+    inner.match_indices(marker)
+        .map(|(pos, _)| pos + 1)
+        .find(|&end| {
+            let before = text[..end].chars().next_back();
+            let after = text[end + 1..].chars().next();
+            before.is_some_and(|c| !c.is_whitespace()) && after.is_none_or(|c| !c.is_alphanumeric() && c != marker)
+        })
+}
+
+/// Returns the `@name` words of the text with their names, without the punctuation ending a sentence.
+/// `@bobby` doesn't mention `bob`, and neither does `mail@bob`.
+pub fn mention_ranges(text: &str) -> impl Iterator<Item = (Range<usize>, &str)> {
+    let mut start = 0;
+    text.split_inclusive(char::is_whitespace).filter_map(move |word| {
+        let pos = start;
+        start += word.len();
+        let name = word.trim_end().strip_prefix('@')?.trim_end_matches(|c: char| ",.:;!?)".contains(c));
+        Some((pos..pos + 1 + name.len(), name)).filter(|_| !name.is_empty())
+    })
+}
+
+fn mentions(text: &str) -> Vec<String> {
+    let mut mentions: Vec<String> = vec![];
+    for (_, name) in mention_ranges(text) {
+        if name.len() > 32 || mentions.iter().any(|m| m == name) {
+            continue;
+        }
+        if mentions.len() == MAX_MENTIONS {
+            break;
+        }
+        mentions.push(name.to_string());
+    }
+    mentions
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::message::format::Chat;
+
+    use super::*;
+
+    fn styled(markup: &Markup) -> Vec<(TextStyle, &str)> {
+        markup.styles().iter().map(|s| (s.style(), &markup.text()[s.range()])).collect()
+    }
+
+    #[test]
+    fn styles() {
+        let markup = Markup::parse("this is *very* important, _really_: run `cargo test` (or *not*)");
+        assert_eq!(markup.text(), "this is very important, really: run cargo test (or not)");
+        assert_eq!(styled(&markup), vec![
+            (TextStyle::Bold, "very"),
+            (TextStyle::Italic, "really"),
+            (TextStyle::Code, "cargo test"),
+            (TextStyle::Bold, "not"),
+        ]);
+
+        // Markers inside words, unclosed or around spaces stay in the text
+        for plain in ["snake_case_name", "2*3*4 = 24", "a * b * c", "*open", "**", "_ spaced _", "ünï_cödé_"] {
+            let markup = Markup::parse(plain);
+            assert_eq!(markup.text(), plain);
+            assert!(markup.styles().is_empty(), "{}", plain);
+        }
+
+        // Styles don't nest, code keeps its markers
+        let markup = Markup::parse("`*ptr = 1` and *bold _word_*");
+        assert_eq!(markup.text(), "*ptr = 1 and bold _word_");
+        assert_eq!(styled(&markup), vec![(TextStyle::Code, "*ptr = 1"), (TextStyle::Bold, "bold _word_")]);
+
+        // Multibyte characters keep the ranges on their boundaries
+        let markup = Markup::parse("žena *čaj* ☕");
+        assert_eq!(styled(&markup), vec![(TextStyle::Bold, "čaj")]);
+    }
+
+    #[test]
+    fn mentions() {
+        let markup = Markup::parse("@bob, ask @peer-A.x: @bob! mail@example.com @ @");
+        assert_eq!(markup.mentions(), ["bob", "peer-A.x"]);
+        let text = "@bob, @bobby mail@bob\t@bob";
+        let ranges: Vec<_> = mention_ranges(text).map(|(range, name)| (&text[range], name)).collect();
+        assert_eq!(ranges, [("@bob", "bob"), ("@bobby", "bobby"), ("@bob", "bob")]);
+
+        let many: Vec<String> = (0..MAX_MENTIONS + 2).map(|i| format!("@p{}", i)).collect();
+        assert_eq!(Markup::parse(&many.join(" ")).mentions().len(), MAX_MENTIONS);
+
+        let styles = "*a* ".repeat(MAX_STYLES + 1);
+        assert_eq!(Markup::parse(&styles).styles().len(), MAX_STYLES);
+    }
+
+    proptest! {
+        #[test]
+        fn sent_markup(input in "[a-z *_`@(\u{10d}\u{17e}]{0,100}") {
+            // The parsed markup is always valid for the chat
+            let markup = Markup::parse(&input);
+            let mut chat = Chat::new("peer-A".to_string(), "grp", 1, 0, markup.text()).unwrap();
+            chat.set_styles(markup.styles().to_vec()).unwrap();
+            chat.set_mentions(markup.mentions().to_vec()).unwrap();
+            prop_assert_eq!(Chat::try_from(Vec::try_from(chat.clone()).unwrap()).unwrap(), chat);
+        }
+    }
+}
@@ -1 +1,3 @@
-pub mod format;
+pub mod format;
+pub mod tlv;
+pub mod markup;
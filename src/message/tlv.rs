@@ -0,0 +1,249 @@
+use std::net::{SocketAddr, IpAddr};
+
+use super::format::FormatError;
+
+/// Longest value a single field can hold, the length is sent as 2 bytes
+pub const FIELD_MAX: usize = u16::MAX as usize;
+
+/// Value that can be stored in a field of a TLV body. Values can borrow from the decoded body.
+pub trait TlvValue<'a>: Sized {
+    fn encode(&self, buf: &mut Vec<u8>);
+    fn decode(value: &'a [u8]) -> Result<Self, FormatError>;
+}
+
+/// Decodes integers of an exact width
+macro_rules! tlv_int {
+    ($($int:ty),*) => {$(
+        impl TlvValue<'_> for $int {
+            fn encode(&self, buf: &mut Vec<u8>) {
+                buf.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode(value: &[u8]) -> Result<Self, FormatError> {
+                let bytes = value.try_into()
+                    .map_err(|_| FormatError::WrongLength { value: stringify!($int), len: value.len() })?;
+                Ok(<$int>::from_be_bytes(bytes))
+            }
+        }
+    )*};
+}
+
+tlv_int!(u8, u16, u32, u64);
+
+impl TlvValue<'_> for bool {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.push(*self as u8);
+    }
+
+    fn decode(value: &[u8]) -> Result<Self, FormatError> {
+        Ok(u8::decode(value)? != 0)
+    }
+}
+
+/// Strings are stored as plain UTF-8, the field length is the string length
+impl<'a> TlvValue<'a> for &'a str {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(value: &'a [u8]) -> Result<Self, FormatError> {
+        Ok(std::str::from_utf8(value)?)
+    }
+}
+
+impl TlvValue<'_> for String {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        self.as_str().encode(buf);
+    }
+
+    fn decode(value: &[u8]) -> Result<Self, FormatError> {
+        <&str>::decode(value).map(str::to_string)
+    }
+}
+
+/// Addresses are the IP followed by the port, 6 bytes for IPv4 and 18 for IPv6
+impl TlvValue<'_> for SocketAddr {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self.ip() {
+            IpAddr::V4(ip) => buf.extend_from_slice(&ip.octets()),
+            IpAddr::V6(ip) => buf.extend_from_slice(&ip.octets()),
+        }
+        buf.extend_from_slice(&self.port().to_be_bytes());
+    }
+
+    fn decode(value: &[u8]) -> Result<Self, FormatError> {
+        let ip = match value.len() {
+            6 => IpAddr::from(<[u8; 4]>::try_from(&value[0..4]).unwrap()),
+            18 => IpAddr::from(<[u8; 16]>::try_from(&value[0..16]).unwrap()),
+            len => return Err(FormatError::WrongLength { value: "address", len }),
+        };
+        let port = u16::decode(&value[value.len() - 2..])?;
+        Ok(SocketAddr::new(ip, port))
+    }
+}
+
+/// Builds a body out of `tag(1) + length(2) + value` fields
+pub struct TlvWriter {
+    buf: Vec<u8>,
+}
+
+impl TlvWriter {
+    pub fn new() -> TlvWriter {
+        TlvWriter { buf: vec![] }
+    }
+
+    /// Appends the fields to the end of the buffer, e.g. after the header of the message
+    pub fn with_buffer(buf: Vec<u8>) -> TlvWriter {
+        TlvWriter { buf }
+    }
+
+    /// Appends a field, the value is encoded in place.
+    /// Fails on values longer than `FIELD_MAX`, the buffer is left as it was.
+    pub fn put<'v, T: TlvValue<'v>>(&mut self, tag: u8, value: &T) -> Result<&mut TlvWriter, FormatError> {
+        let tag_at = self.buf.len();
+        self.buf.push(tag);
+        self.buf.extend_from_slice(&[0, 0]);
+        value.encode(&mut self.buf);
+
+        let len = self.buf.len() - tag_at - 3;
+        if len > FIELD_MAX {
+            self.buf.truncate(tag_at);
+            return Err(FormatError::TooLong { field: "field", max: FIELD_MAX });
+        }
+        self.buf[tag_at + 1..tag_at + 3].copy_from_slice(&(len as u16).to_be_bytes());
+        Ok(self)
+    }
+
+    /// Appends a field holding raw bytes, e.g. a nested body. Fails on values longer than `FIELD_MAX`.
+    pub fn put_bytes(&mut self, tag: u8, value: &[u8]) -> Result<&mut TlvWriter, FormatError> {
+        if value.len() > FIELD_MAX {
+            return Err(FormatError::TooLong { field: "field", max: FIELD_MAX });
+        }
+        self.buf.push(tag);
+        self.buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        self.buf.extend_from_slice(value);
+        Ok(self)
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+/// Fields of a received body. Tags the message doesn't ask for are skipped,
+/// so newer peers can add fields without breaking the older ones.
+/// The body is checked once and walked again for every lookup, nothing is copied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TlvReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> TlvReader<'a> {
+    /// Checks that the body splits into fields, fails if a field is cut short
+    pub fn parse(buf: &'a [u8]) -> Result<TlvReader<'a>, FormatError> {
+        let mut fields = Fields { rest: buf };
+        while fields.next_field()?.is_some() {}
+        Ok(TlvReader { buf })
+    }
+
+    fn fields(&self) -> impl Iterator<Item = (u8, &'a [u8])> {
+        let mut fields = Fields { rest: self.buf };
+        // The body was checked by `parse`
+        std::iter::from_fn(move || fields.next_field().ok().flatten())
+    }
+
+    /// Returns the value of an optional field, the first one if it is repeated
+    pub fn get<T: TlvValue<'a>>(&self, tag: u8) -> Result<Option<T>, FormatError> {
+        self.fields()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, value)| T::decode(value))
+            .transpose()
+    }
+
+    /// Returns the value of a field every sender has to fill in
+    pub fn require<T: TlvValue<'a>>(&self, tag: u8) -> Result<T, FormatError> {
+        self.get(tag)?
+            .ok_or(FormatError::MissingField(tag))
+    }
+
+    /// Returns the raw values of a repeated field, in the sent order
+    pub fn all(&self, tag: u8) -> impl Iterator<Item = &'a [u8]> {
+        self.fields().filter(move |(t, _)| *t == tag).map(|(_, value)| value)
+    }
+}
+
+/// Rest of a body being split into fields
+struct Fields<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn next_field(&mut self) -> Result<Option<(u8, &'a [u8])>, FormatError> {
+        if self.rest.is_empty() {
+            return Ok(None);
+        }
+        let (tag, len) = match self.rest {
+            [tag, len_hi, len_lo, ..] => (*tag, u16::from_be_bytes([*len_hi, *len_lo]) as usize),
+            _ => return Err(FormatError::Truncated),
+        };
+        let value = self.rest.get(3..3 + len)
+            .ok_or(FormatError::Truncated)?;
+        self.rest = &self.rest[3 + len..];
+        Ok(Some((tag, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn fields() {
+        let v6: SocketAddr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 2000);
+        let v4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 3000);
+        let buf = TlvWriter::new()
+            .put(0x01, &String::from("pe\0er")).unwrap()
+            .put(0x02, &0x0102u16).unwrap()
+            .put(0x7F, &true).unwrap()
+            .put(0x03, &v4).unwrap()
+            .put(0x03, &v6).unwrap()
+            .finish();
+        assert_eq!(buf[0..8], [0x01, 0x00, 0x05, b'p', b'e', 0x00, b'e', b'r']);
+
+        let reader = TlvReader::parse(&buf).unwrap();
+        assert_eq!(reader.require::<String>(0x01).unwrap(), "pe\0er");
+        assert_eq!(reader.get::<u16>(0x02).unwrap(), Some(0x0102));
+        assert_eq!(reader.get::<u32>(0x04).unwrap(), None);
+        assert_eq!(reader.require::<u32>(0x04), Err(FormatError::MissingField(0x04)));
+        // Wrong width
+        assert_eq!(reader.get::<u32>(0x02), Err(FormatError::WrongLength { value: "u32", len: 2 }));
+
+        let addrs: Vec<SocketAddr> = reader.all(0x03).map(|v| SocketAddr::decode(v).unwrap()).collect();
+        assert_eq!(addrs, vec![v4, v6]);
+    }
+
+    #[test]
+    fn truncated() {
+        let buf = TlvWriter::new().put(0x01, &7u64).unwrap().finish();
+        for len in 1..buf.len() {
+            assert!(TlvReader::parse(&buf[..len]).is_err());
+        }
+        assert!(TlvReader::parse(&[]).unwrap().get::<u8>(0x01).unwrap().is_none());
+    }
+
+    #[test]
+    fn too_long() {
+        let long = "x".repeat(FIELD_MAX + 1);
+        let mut writer = TlvWriter::new();
+        writer.put(0x01, &1u8).unwrap();
+        assert_eq!(writer.put(0x02, &long.as_str()).err(), Some(FormatError::TooLong { field: "field", max: FIELD_MAX }));
+        assert!(writer.put_bytes(0x02, long.as_bytes()).is_err());
+        // Nothing of the failed fields is left behind
+        assert_eq!(writer.finish(), vec![0x01, 0x00, 0x01, 0x01]);
+
+        let buf = TlvWriter::new().put(0x01, &&long[1..]).unwrap().finish();
+        assert_eq!(TlvReader::parse(&buf).unwrap().require::<&str>(0x01).unwrap().len(), FIELD_MAX);
+    }
+}
@@ -0,0 +1,211 @@
+use std::{net::{SocketAddr, ToSocketAddrs}, time::{Duration, Instant}, ops::Add, io};
+
+/// Time a bootstrap server has to answer a member request before the request counts as failed
+pub static BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Time a failed server is skipped after the first failure, doubled after every next one
+static MIN_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest time a failed server is skipped
+static MAX_RETRY_INTERVAL: Duration = Duration::from_secs(120);
+
+/// Resolves the bootstrap endpoints given as `IP:PORT` or `HOST:PORT`.
+/// A hostname with several addresses gives a server for each address, in the resolved order.
+/// The socket is bound to an IPv4 address, so IPv6 addresses are skipped.
+pub fn resolve<T: AsRef<str>>(endpoints: &[T]) -> io::Result<Vec<SocketAddr>> {
+    let mut addrs = vec![];
+    for endpoint in endpoints {
+        let resolved: Vec<SocketAddr> = endpoint.as_ref().to_socket_addrs()?.filter(|addr| addr.is_ipv4()).collect();
+        if resolved.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, format!("{} has no IPv4 address", endpoint.as_ref())));
+        }
+        for addr in resolved {
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+    Ok(addrs)
+}
+
+struct BootstrapServer {
+    addr: SocketAddr,
+    /// Number of unanswered requests in a row
+    failures: u32,
+    /// Time of the oldest unanswered request
+    pending: Option<Instant>,
+    /// The server is skipped until this time
+    down_until: Option<Instant>,
+}
+
+impl BootstrapServer {
+    fn is_live(&self, now: Instant) -> bool {
+        self.down_until.map(|until| until <= now).unwrap_or(true)
+    }
+}
+
+/// List of bootstrap servers with their health.
+/// Servers that don't answer are skipped for a while and the requests fail over to the next one.
+pub struct Bootstraps {
+    servers: Vec<BootstrapServer>,
+    /// Index of the server used by the next discovery round
+    next: usize,
+}
+
+impl Bootstraps {
+    pub fn new(addrs: Vec<SocketAddr>) -> Bootstraps {
+        let servers = addrs
+            .into_iter()
+            .map(|addr| BootstrapServer { addr, failures: 0, pending: None, down_until: None })
+            .collect();
+        Bootstraps { servers, next: 0 }
+    }
+
+    /// Returns all the servers, live or not
+    pub fn all(&self) -> Vec<SocketAddr> {
+        self.servers.iter().map(|s| s.addr).collect()
+    }
+
+    /// Returns the first live server in the configured order.
+    /// If all the servers are down, the one that comes back first is returned.
+    pub fn current(&self) -> Option<SocketAddr> {
+        let now = Instant::now();
+        self.servers
+            .iter()
+            .find(|s| s.is_live(now))
+            .or_else(|| self.servers.iter().min_by_key(|s| s.down_until))
+            .map(|s| s.addr)
+    }
+
+    /// Returns the next live server for a discovery round, rotating between the live ones.
+    /// The server is expected to answer the request of the round.
+    pub fn rotate(&mut self) -> Option<SocketAddr> {
+        let now = Instant::now();
+        let count = self.servers.len();
+        let index = (0..count)
+            .map(|i| (self.next + i) % count)
+            .find(|index| self.servers[*index].is_live(now));
+
+        let addr = match index {
+            Some(index) => {
+                self.next = (index + 1) % count;
+                self.servers[index].addr
+            },
+            None => self.current()?,
+        };
+        self.sent(addr);
+        Some(addr)
+    }
+
+    /// Marks a request sent to the server, which is expected to answer
+    pub fn sent(&mut self, addr: SocketAddr) {
+        if let Some(server) = self.servers.iter_mut().find(|s| s.addr == addr) {
+            server.pending.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// Marks the server healthy after getting a message from it
+    pub fn answered(&mut self, addr: &SocketAddr) {
+        if let Some(server) = self.servers.iter_mut().find(|s| s.addr == *addr) {
+            server.failures = 0;
+            server.pending = None;
+            server.down_until = None;
+        }
+    }
+
+    /// Takes down the servers that didn't answer in time and returns them
+    pub fn expire(&mut self, timeout: Duration) -> Vec<SocketAddr> {
+        let now = Instant::now();
+        let mut failed = vec![];
+        for server in self.servers.iter_mut() {
+            match server.pending {
+                Some(sent) if now.duration_since(sent) > timeout => {
+                    let retry = (MIN_RETRY_INTERVAL * 2u32.saturating_pow(server.failures)).min(MAX_RETRY_INTERVAL);
+                    server.failures += 1;
+                    server.pending = None;
+                    server.down_until = Some(now.add(retry));
+                    failed.push(server.addr);
+                },
+                _ => (),
+            }
+        }
+        failed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn servers() -> Vec<SocketAddr> {
+        vec!["10.0.0.1:8000".parse().unwrap(), "10.0.0.2:8000".parse().unwrap(), "10.0.0.3:8000".parse().unwrap()]
+    }
+
+    #[test]
+    fn failover() {
+        let addrs = servers();
+        let mut bootstraps = Bootstraps::new(addrs.clone());
+        assert_eq!(bootstraps.current(), Some(addrs[0]));
+
+        bootstraps.sent(addrs[0]);
+        bootstraps.sent(addrs[1]);
+        std::thread::sleep(Duration::from_millis(20));
+        bootstraps.answered(&addrs[1]);
+        assert_eq!(bootstraps.expire(Duration::from_millis(10)), vec![addrs[0]]);
+        assert_eq!(bootstraps.current(), Some(addrs[1]));
+
+        // Back in the list after answering
+        bootstraps.answered(&addrs[0]);
+        assert_eq!(bootstraps.current(), Some(addrs[0]));
+    }
+
+    #[test]
+    fn all_down() {
+        let addrs = servers();
+        let mut bootstraps = Bootstraps::new(addrs.clone());
+        for _ in 0..2 {
+            bootstraps.sent(addrs[0]);
+        }
+        bootstraps.sent(addrs[1]);
+        bootstraps.sent(addrs[2]);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(bootstraps.expire(Duration::from_millis(10)).len(), 3);
+
+        // The server that failed again stays down longer
+        bootstraps.sent(addrs[0]);
+        std::thread::sleep(Duration::from_millis(20));
+        bootstraps.expire(Duration::from_millis(10));
+        assert_eq!(bootstraps.current(), Some(addrs[1]));
+        assert!(bootstraps.servers[0].down_until > bootstraps.servers[1].down_until);
+    }
+
+    #[test]
+    fn rotation() {
+        let addrs = servers();
+        let mut bootstraps = Bootstraps::new(addrs.clone());
+        assert_eq!(bootstraps.rotate(), Some(addrs[0]));
+        assert_eq!(bootstraps.rotate(), Some(addrs[1]));
+
+        bootstraps.sent(addrs[2]);
+        std::thread::sleep(Duration::from_millis(20));
+        bootstraps.answered(&addrs[0]);
+        bootstraps.answered(&addrs[1]);
+        assert_eq!(bootstraps.expire(Duration::from_millis(10)), vec![addrs[2]]);
+        assert_eq!(bootstraps.rotate(), Some(addrs[0]));
+        assert_eq!(bootstraps.rotate(), Some(addrs[1]));
+
+        assert_eq!(Bootstraps::new(vec![]).rotate(), None);
+    }
+
+    #[test]
+    fn resolving() {
+        let addrs = resolve(&["127.0.0.1:8000", "localhost:8001", "127.0.0.1:8000"]).unwrap();
+        assert_eq!(addrs[0], "127.0.0.1:8000".parse().unwrap());
+        assert!(addrs.iter().all(|a| a.ip().is_loopback() && a.is_ipv4()));
+        assert!(addrs.iter().any(|a| a.port() == 8001));
+        assert_eq!(addrs.iter().filter(|a| a.port() == 8000).count(), 1);
+
+        assert!(resolve(&["not an address"]).is_err());
+        assert_eq!(resolve(&["[::1]:8000"]).unwrap_err().kind(), io::ErrorKind::AddrNotAvailable);
+    }
+}
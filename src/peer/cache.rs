@@ -0,0 +1,167 @@
+use std::{collections::HashMap, net::SocketAddr, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}, fs, io};
+
+use crate::message::format::MemberState;
+
+use super::structures::{PeerId, NeighbourMap};
+
+/// Period between two saves of the cache
+pub static CACHE_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Peers that weren't seen for longer than this are dropped from the cache
+static CACHE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+const CACHE_HEADER: &str = "# peerko peer cache: group, peer id, address, last seen (seconds since the Unix epoch)";
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CachedPeer {
+    addr: SocketAddr,
+    last_seen: u64,
+}
+
+/// Last known neighbours of every group, kept in a state file between runs.
+/// On startup the cached addresses are probed, so reconnecting doesn't wait for the bootstrap.
+pub struct PeerCache {
+    path: PathBuf,
+    peers: HashMap<(String, PeerId), CachedPeer>,
+    /// Cached peers that were sent a member request and didn't answer yet
+    probes: Vec<(String, PeerId, SocketAddr)>,
+}
+
+impl PeerCache {
+    /// Loads the cache from the file, a missing file gives an empty cache
+    pub fn load(path: &Path) -> io::Result<PeerCache> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+
+        let oldest = now_secs().saturating_sub(CACHE_MAX_AGE.as_secs());
+        let peers = content
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| {
+                let mut fields = line.split('\t');
+                let group = fields.next()?.to_string();
+                let peer_id = fields.next()?.to_string();
+                let addr = fields.next()?.parse().ok()?;
+                let last_seen = fields.next()?.parse().ok()?;
+                Some(((group, peer_id), CachedPeer { addr, last_seen }))
+            })
+            .filter(|(_, peer)| peer.last_seen >= oldest)
+            .collect();
+
+        Ok(PeerCache { path: path.to_path_buf(), peers, probes: vec![] })
+    }
+
+    /// Writes the cache to the file
+    pub fn save(&self) -> io::Result<()> {
+        let mut entries: Vec<_> = self.peers.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut content = String::from(CACHE_HEADER);
+        content.push('\n');
+        for ((group, peer_id), peer) in entries {
+            content.push_str(&format!("{}\t{}\t{}\t{}\n", group, peer_id, peer.addr, peer.last_seen));
+        }
+
+        // Replace the file at once, so a crash doesn't leave half of it
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    /// Records the live neighbours of the group as seen now
+    pub fn update(&mut self, group: &str, peer_list: &NeighbourMap) {
+        let now = now_secs();
+        for peer in peer_list.iter().filter(|p| p.state() == MemberState::Alive) {
+            self.peers.insert((group.to_string(), peer.id().clone()), CachedPeer { addr: *peer.addr(), last_seen: now });
+        }
+    }
+
+    /// Returns the cached peers of the group to probe, most recently seen first
+    pub fn start_probes(&mut self, group: &str) -> Vec<SocketAddr> {
+        let mut peers: Vec<_> = self.peers
+            .iter()
+            .filter(|((g, _), _)| g == group)
+            .collect();
+        peers.sort_by_key(|(_, peer)| std::cmp::Reverse(peer.last_seen));
+
+        let probes: Vec<_> = peers.into_iter().map(|((g, id), peer)| (g.clone(), id.clone(), peer.addr)).collect();
+        self.probes.extend(probes.iter().cloned());
+        probes.into_iter().map(|(_, _, addr)| addr).collect()
+    }
+
+    /// Returns the id of the cached peer that answered the probe
+    pub fn probe_answered(&mut self, group: &str, addr: &SocketAddr) -> Option<PeerId> {
+        let index = self.probes.iter().position(|(g, _, a)| g == group && a == addr)?;
+        Some(self.probes.remove(index).1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{time::Instant, ops::Add};
+
+    use crate::peer::structures::NeighbourEntry;
+
+    use super::*;
+
+    fn cache_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("peerko-{}-{}.cache", name, std::process::id()))
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = cache_path("save");
+        let mut cache = PeerCache::load(&path).unwrap();
+        assert!(cache.peers.is_empty());
+
+        let ttl = Instant::now().add(Duration::from_secs(30));
+        let mut peer_list = NeighbourMap::new();
+        peer_list.insert(NeighbourEntry::new("peer-a".to_string(), "10.0.0.1:2000".parse().unwrap(), ttl));
+        peer_list.insert(NeighbourEntry::new("peer-b".to_string(), "10.0.0.2:2000".parse().unwrap(), ttl));
+        cache.update("grp", &peer_list);
+        cache.save().unwrap();
+
+        let loaded = PeerCache::load(&path).unwrap();
+        assert_eq!(loaded.peers, cache.peers);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn old_and_broken_entries() {
+        let path = cache_path("old");
+        let recent = now_secs() - 60;
+        let old = now_secs() - CACHE_MAX_AGE.as_secs() - 60;
+        let content = format!("{}\ngrp\tpeer-a\t10.0.0.1:2000\t{}\ngrp\tpeer-b\t10.0.0.2:2000\t{}\ngrp\tpeer-c\tnot an address\t{}\n", CACHE_HEADER, recent, old, recent);
+        fs::write(&path, content).unwrap();
+
+        let cache = PeerCache::load(&path).unwrap();
+        assert_eq!(cache.peers.len(), 1);
+        assert!(cache.peers.contains_key(&("grp".to_string(), "peer-a".to_string())));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn probes() {
+        let mut cache = PeerCache::load(&cache_path("probes")).unwrap();
+        let addr_a: SocketAddr = "10.0.0.1:2000".parse().unwrap();
+        let addr_b: SocketAddr = "10.0.0.2:2000".parse().unwrap();
+        cache.peers.insert(("grp".to_string(), "peer-a".to_string()), CachedPeer { addr: addr_a, last_seen: 10 });
+        cache.peers.insert(("grp".to_string(), "peer-b".to_string()), CachedPeer { addr: addr_b, last_seen: 20 });
+        cache.peers.insert(("other".to_string(), "peer-c".to_string()), CachedPeer { addr: addr_b, last_seen: 20 });
+
+        assert_eq!(cache.start_probes("grp"), vec![addr_b, addr_a]);
+        assert_eq!(cache.probe_answered("grp", &addr_a), Some("peer-a".to_string()));
+        assert_eq!(cache.probe_answered("grp", &addr_a), None);
+        assert_eq!(cache.probe_answered("other", &addr_b), None);
+    }
+}
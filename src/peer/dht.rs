@@ -0,0 +1,532 @@
+use std::{collections::HashMap, net::SocketAddr, time::{Duration, Instant}, ops::Add};
+
+use crate::message::format::{DhtMessage, DhtOp, DhtRecord, DHT_MAX_ITEMS};
+
+use super::{random_u64, structures::PeerId};
+
+/// ID of a node in the DHT, also the key space of the records
+pub type NodeId = u64;
+
+/// Period between two announcements and lookups of the joined groups
+pub static DHT_REFRESH: Duration = Duration::from_secs(60);
+
+/// Time to wait for the answer to a query
+static QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time after which an unfinished lookup is dropped
+static LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time a record is kept without being announced again
+static RECORD_TTL: Duration = Duration::from_secs(300);
+
+/// Time after which a node that wasn't heard from can be replaced in a full bucket
+static NODE_EXPIRY: Duration = Duration::from_secs(600);
+
+/// Number of nodes in a bucket, also the number of nodes a record is stored on
+const BUCKET_SIZE: usize = 8;
+
+/// Number of queries a lookup has in flight
+const ALPHA: usize = 3;
+
+/// Returns the key of the group records, the FNV-1a hash of the group name
+pub fn key_for(group: &str) -> NodeId {
+    group.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, b| (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+struct Node {
+    id: NodeId,
+    addr: SocketAddr,
+    last_seen: Instant,
+}
+
+/// Kademlia routing table, with a bucket for every bit of distance from the own id
+struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    fn new(own: NodeId) -> RoutingTable {
+        RoutingTable { own, buckets: (0..64).map(|_| vec![]).collect() }
+    }
+
+    fn bucket_index(&self, id: NodeId) -> Option<usize> {
+        match self.own ^ id {
+            0 => None,
+            distance => Some(63 - distance.leading_zeros() as usize),
+        }
+    }
+
+    /// Adds the node or refreshes it. Returns false if its bucket is full of live nodes.
+    fn insert(&mut self, id: NodeId, addr: SocketAddr) -> bool {
+        let bucket = match self.bucket_index(id) {
+            Some(index) => &mut self.buckets[index],
+            None => return false,
+        };
+
+        // Recently seen nodes go to the end of the bucket
+        if let Some(pos) = bucket.iter().position(|n| n.id == id) {
+            bucket.remove(pos);
+        } else if bucket.len() >= BUCKET_SIZE {
+            match bucket.iter().position(|n| n.last_seen.elapsed() > NODE_EXPIRY) {
+                Some(stale) => { bucket.remove(stale); },
+                None => return false,
+            }
+        }
+        bucket.push(Node { id, addr, last_seen: Instant::now() });
+        true
+    }
+
+    fn remove(&mut self, id: NodeId) {
+        if let Some(index) = self.bucket_index(id) {
+            self.buckets[index].retain(|n| n.id != id);
+        }
+    }
+
+    /// Returns the nodes closest to the target
+    fn closest(&self, target: NodeId, count: usize) -> Vec<(NodeId, SocketAddr)> {
+        let mut nodes: Vec<(NodeId, SocketAddr)> = self.buckets
+            .iter()
+            .flatten()
+            .map(|n| (n.id, n.addr))
+            .collect();
+        nodes.sort_by_key(|(id, _)| id ^ target);
+        nodes.truncate(count);
+        nodes
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+}
+
+/// Records stored on this node on behalf of the others
+struct RecordStore {
+    records: HashMap<NodeId, Vec<(DhtRecord, Instant)>>,
+}
+
+impl RecordStore {
+    fn store(&mut self, key: NodeId, record: DhtRecord) {
+        let records = self.records.entry(key).or_default();
+        records.retain(|(r, _)| r.group_name() != record.group_name() || r.peer_id() != record.peer_id());
+        records.push((record, Instant::now().add(RECORD_TTL)));
+    }
+
+    fn get(&self, key: NodeId) -> Vec<DhtRecord> {
+        let now = Instant::now();
+        self.records
+            .get(&key)
+            .map(|records| records.iter().rev().filter(|(_, expiry)| *expiry > now).map(|(r, _)| r.clone()).take(DHT_MAX_ITEMS).collect())
+            .unwrap_or_default()
+    }
+
+    fn remove_expired(&mut self) {
+        let now = Instant::now();
+        for records in self.records.values_mut() {
+            records.retain(|(_, expiry)| *expiry > now);
+        }
+        self.records.retain(|_, records| !records.is_empty());
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum LookupKind {
+    /// Fills the routing table
+    Nodes,
+    /// Stores the own record of the group on the closest nodes
+    Announce(String),
+    /// Looks for the records of the group
+    Find(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CandidateState {
+    Waiting,
+    Queried,
+    Answered,
+    Failed,
+}
+
+struct Candidate {
+    id: NodeId,
+    addr: SocketAddr,
+    state: CandidateState,
+}
+
+/// Iterative lookup of the nodes closest to the target
+struct Lookup {
+    kind: LookupKind,
+    target: NodeId,
+    candidates: Vec<Candidate>,
+    /// Group members collected from the answers
+    found: Vec<(PeerId, SocketAddr)>,
+    started: Instant,
+}
+
+struct PendingQuery {
+    lookup: Option<u32>,
+    addr: SocketAddr,
+    sent: Instant,
+}
+
+/// Result of a lookup
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DhtEvent {
+    /// Members of the group found in the DHT
+    Found(String, Vec<(PeerId, SocketAddr)>),
+}
+
+/// Messages to send, with their destination
+pub type DhtOutput = Vec<(SocketAddr, DhtMessage)>;
+
+/// Kademlia node storing the group membership records.
+/// Doesn't do any I/O, the messages to send are returned to the caller.
+pub struct Dht {
+    name: PeerId,
+    table: RoutingTable,
+    records: RecordStore,
+    lookups: HashMap<u32, Lookup>,
+    pending: HashMap<u32, PendingQuery>,
+    next_txn: u32,
+}
+
+impl Dht {
+    pub fn new(name: &str) -> Dht {
+        let own = random_u64();
+        Dht {
+            name: name.to_string(),
+            table: RoutingTable::new(own),
+            records: RecordStore { records: HashMap::new() },
+            lookups: HashMap::new(),
+            pending: HashMap::new(),
+            next_txn: random_u64() as u32,
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.table.own
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.table.len()
+    }
+
+    /// Joins the overlay through the seed nodes by looking up the own id
+    pub fn bootstrap(&mut self, seeds: &[SocketAddr]) -> DhtOutput {
+        let lookup = self.start(LookupKind::Nodes, self.id());
+        let mut out: DhtOutput = seeds.iter().map(|seed| self.query(Some(lookup), *seed, DhtOp::FindNode, self.id())).collect();
+        out.extend(self.advance(lookup).0);
+        out
+    }
+
+    /// Stores the own membership record of the group on the nodes closest to its key
+    pub fn announce(&mut self, group: &str) -> DhtOutput {
+        let lookup = self.start(LookupKind::Announce(group.to_string()), key_for(group));
+        self.advance(lookup).0
+    }
+
+    /// Looks for the members of the group
+    pub fn find(&mut self, group: &str) -> DhtOutput {
+        let lookup = self.start(LookupKind::Find(group.to_string()), key_for(group));
+        self.advance(lookup).0
+    }
+
+    fn start(&mut self, kind: LookupKind, target: NodeId) -> u32 {
+        let candidates = self.table
+            .closest(target, BUCKET_SIZE)
+            .into_iter()
+            .map(|(id, addr)| Candidate { id, addr, state: CandidateState::Waiting })
+            .collect();
+        let id = self.txn();
+        self.lookups.insert(id, Lookup { kind, target, candidates, found: vec![], started: Instant::now() });
+        id
+    }
+
+    fn txn(&mut self) -> u32 {
+        let txn = self.next_txn;
+        self.next_txn = self.next_txn.wrapping_add(1);
+        txn
+    }
+
+    fn message(&self, op: DhtOp, txn: u32, key: NodeId, nodes: Vec<(NodeId, SocketAddr)>, records: Vec<DhtRecord>) -> DhtMessage {
+        // Both lists are capped below the limit
+        DhtMessage::new(op, self.id(), txn, key, nodes, records).unwrap()
+    }
+
+    fn query(&mut self, lookup: Option<u32>, addr: SocketAddr, op: DhtOp, key: NodeId) -> (SocketAddr, DhtMessage) {
+        let txn = self.txn();
+        self.pending.insert(txn, PendingQuery { lookup, addr, sent: Instant::now() });
+        (addr, self.message(op, txn, key, vec![], vec![]))
+    }
+
+    /// Queries the next closest candidates, or finishes the lookup when the closest ones answered
+    fn advance(&mut self, id: u32) -> (DhtOutput, Vec<DhtEvent>) {
+        // Queries to the seed nodes aren't tracked by the candidates
+        let mut in_flight = self.pending.values().filter(|q| q.lookup == Some(id)).count();
+        let lookup = match self.lookups.get_mut(&id) {
+            Some(lookup) => lookup,
+            None => return (vec![], vec![]),
+        };
+
+        let target = lookup.target;
+        lookup.candidates.retain(|c| c.state != CandidateState::Failed);
+        lookup.candidates.sort_by_key(|c| c.id ^ target);
+        lookup.candidates.truncate(BUCKET_SIZE * 2);
+
+        let mut to_query = vec![];
+        for candidate in lookup.candidates.iter_mut().take(BUCKET_SIZE) {
+            if in_flight >= ALPHA {
+                break;
+            }
+            if candidate.state == CandidateState::Waiting {
+                candidate.state = CandidateState::Queried;
+                to_query.push(candidate.addr);
+                in_flight += 1;
+            }
+        }
+
+        if in_flight > 0 {
+            let op = match lookup.kind {
+                LookupKind::Find(_) => DhtOp::FindValue,
+                _ => DhtOp::FindNode,
+            };
+            let out = to_query.into_iter().map(|addr| self.query(Some(id), addr, op, target)).collect();
+            return (out, vec![]);
+        }
+
+        // The closest candidates all answered
+        let lookup = self.lookups.remove(&id).unwrap();
+        let closest: Vec<SocketAddr> = lookup.candidates.iter().take(BUCKET_SIZE).map(|c| c.addr).collect();
+        match lookup.kind {
+            LookupKind::Announce(group) => {
+                let record = match DhtRecord::new(&group, &self.name, SocketAddr::new([0, 0, 0, 0].into(), 0)) {
+                    Ok(record) => record,
+                    Err(_) => return (vec![], vec![]),
+                };
+                let out = closest
+                    .into_iter()
+                    .map(|addr| {
+                        let txn = self.txn();
+                        self.pending.insert(txn, PendingQuery { lookup: None, addr, sent: Instant::now() });
+                        (addr, self.message(DhtOp::Store, txn, target, vec![], vec![record.clone()]))
+                    })
+                    .collect();
+                (out, vec![])
+            },
+            LookupKind::Find(group) => (vec![], vec![DhtEvent::Found(group, lookup.found)]),
+            LookupKind::Nodes => (vec![], vec![]),
+        }
+    }
+
+    /// Handles the message received from the address
+    pub fn handle(&mut self, from: SocketAddr, msg: &DhtMessage) -> (DhtOutput, Vec<DhtEvent>) {
+        if msg.sender() != self.id() {
+            self.table.insert(msg.sender(), from);
+        }
+
+        let closest = |dht: &Dht| -> Vec<(NodeId, SocketAddr)> {
+            dht.table
+                .closest(msg.key(), BUCKET_SIZE + 1)
+                .into_iter()
+                .filter(|(id, _)| *id != msg.sender())
+                .take(BUCKET_SIZE)
+                .collect()
+        };
+
+        match msg.op() {
+            DhtOp::Ping => (vec![(from, self.message(DhtOp::Pong, msg.txn(), msg.key(), vec![], vec![]))], vec![]),
+            DhtOp::FindNode => (vec![(from, self.message(DhtOp::Nodes, msg.txn(), msg.key(), closest(self), vec![]))], vec![]),
+            DhtOp::FindValue => {
+                let records = self.records.get(msg.key());
+                let answer = match records.is_empty() {
+                    true => self.message(DhtOp::Nodes, msg.txn(), msg.key(), closest(self), vec![]),
+                    false => self.message(DhtOp::Values, msg.txn(), msg.key(), vec![], records),
+                };
+                (vec![(from, answer)], vec![])
+            },
+            DhtOp::Store => {
+                // The record is stored with the address it came from, like a rendezvous server does
+                for record in msg.records().iter().filter(|r| key_for(r.group_name()) == msg.key()) {
+                    self.records.store(msg.key(), record.with_addr(from));
+                }
+                (vec![(from, self.message(DhtOp::Pong, msg.txn(), msg.key(), vec![], vec![]))], vec![])
+            },
+            DhtOp::Pong => {
+                self.pending.remove(&msg.txn());
+                (vec![], vec![])
+            },
+            DhtOp::Nodes | DhtOp::Values => self.handle_answer(from, msg),
+        }
+    }
+
+    fn handle_answer(&mut self, from: SocketAddr, msg: &DhtMessage) -> (DhtOutput, Vec<DhtEvent>) {
+        // Only answers to our own queries are accepted
+        let lookup_id = match self.pending.get(&msg.txn()) {
+            Some(query) if query.addr == from => query.lookup,
+            _ => return (vec![], vec![]),
+        };
+        self.pending.remove(&msg.txn());
+
+        let own = self.id();
+        let lookup = match lookup_id.and_then(|id| self.lookups.get_mut(&id)) {
+            Some(lookup) => lookup,
+            None => return (vec![], vec![]),
+        };
+
+        match lookup.candidates.iter_mut().find(|c| c.addr == from) {
+            Some(candidate) => candidate.state = CandidateState::Answered,
+            // Seed node that wasn't in the routing table
+            None => lookup.candidates.push(Candidate { id: msg.sender(), addr: from, state: CandidateState::Answered }),
+        }
+
+        for (id, addr) in msg.nodes() {
+            if *id != own && !lookup.candidates.iter().any(|c| c.id == *id) {
+                lookup.candidates.push(Candidate { id: *id, addr: *addr, state: CandidateState::Waiting });
+            }
+        }
+
+        // The closest nodes may each know only a part of the group, the records are collected from all of them
+        if let LookupKind::Find(group) = &lookup.kind {
+            for record in msg.records().iter().filter(|r| r.group_name() == group) {
+                if !lookup.found.iter().any(|(id, _)| id == record.peer_id()) {
+                    lookup.found.push((record.peer_id().to_string(), *record.addr()));
+                }
+            }
+        }
+
+        self.advance(lookup_id.unwrap())
+    }
+
+    /// Fails the queries that didn't get an answer in time, moving their lookups on,
+    /// and drops the stale lookups and records.
+    pub fn expire(&mut self) -> (DhtOutput, Vec<DhtEvent>) {
+        let expired: Vec<u32> = self.pending
+            .iter()
+            .filter(|(_, q)| q.sent.elapsed() > QUERY_TIMEOUT)
+            .map(|(txn, _)| *txn)
+            .collect();
+
+        let mut out = vec![];
+        let mut events = vec![];
+        for txn in expired {
+            let query = self.pending.remove(&txn).unwrap();
+            let lookup = match query.lookup.and_then(|id| self.lookups.get_mut(&id)) {
+                Some(lookup) => lookup,
+                None => continue,
+            };
+
+            let mut failed = None;
+            for candidate in lookup.candidates.iter_mut().filter(|c| c.addr == query.addr) {
+                candidate.state = CandidateState::Failed;
+                failed = Some(candidate.id);
+            }
+            if let Some(id) = failed {
+                self.table.remove(id);
+            }
+
+            let (o, e) = self.advance(query.lookup.unwrap());
+            out.extend(o);
+            events.extend(e);
+        }
+
+        self.lookups.retain(|_, l| l.started.elapsed() < LOOKUP_TIMEOUT);
+        self.records.remove_expired();
+        (out, events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(i: usize) -> SocketAddr {
+        SocketAddr::new("10.0.0.1".parse().unwrap(), 2000 + i as u16)
+    }
+
+    /// Delivers the messages between the nodes until there are none left
+    fn deliver(nodes: &mut [Dht], mut out: Vec<(usize, DhtOutput)>) -> Vec<(usize, DhtEvent)> {
+        let mut events = vec![];
+        while let Some((from, messages)) = out.pop() {
+            for (to, msg) in messages {
+                let to = (to.port() - 2000) as usize;
+                let (o, e) = nodes[to].handle(addr(from), &msg);
+                out.push((to, o));
+                events.extend(e.into_iter().map(|e| (to, e)));
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn group_key() {
+        assert_eq!(key_for("grp"), key_for("grp"));
+        assert_ne!(key_for("grp"), key_for("grq"));
+        assert_eq!(key_for(""), 0xcbf2_9ce4_8422_2325);
+    }
+
+    #[test]
+    fn routing_table() {
+        let mut table = RoutingTable::new(0);
+        assert!(!table.insert(0, addr(0)));
+
+        // Ids 16..32 share the same bucket
+        for id in 16..16 + BUCKET_SIZE as u64 {
+            assert!(table.insert(id, addr(id as usize)));
+        }
+        assert!(!table.insert(31, addr(31)));
+        assert!(table.insert(16, addr(16)));
+        assert!(table.insert(3, addr(3)));
+        assert_eq!(table.len(), BUCKET_SIZE + 1);
+
+        assert_eq!(table.closest(0, 2), vec![(3, addr(3)), (16, addr(16))]);
+        table.remove(3);
+        assert_eq!(table.closest(0, 1), vec![(16, addr(16))]);
+    }
+
+    #[test]
+    fn find_members() {
+        let mut nodes: Vec<Dht> = (0..20).map(|i| Dht::new(&format!("peer-{}", i))).collect();
+
+        // Everyone joins through the first node
+        for i in 1..nodes.len() {
+            let out = nodes[i].bootstrap(&[addr(0)]);
+            deliver(&mut nodes, vec![(i, out)]);
+        }
+        assert!(nodes.iter().all(|n| n.node_count() > 0));
+
+        for i in [3, 7, 11] {
+            let out = nodes[i].announce("grp");
+            deliver(&mut nodes, vec![(i, out)]);
+        }
+
+        let out = nodes[19].find("grp");
+        let events = deliver(&mut nodes, vec![(19, out)]);
+        let mut found = match &events[..] {
+            [(19, DhtEvent::Found(group, members))] if group == "grp" => members.clone(),
+            _ => panic!("unexpected events {:?}", events),
+        };
+        found.sort();
+        assert_eq!(found, vec![
+            ("peer-11".to_string(), addr(11)),
+            ("peer-3".to_string(), addr(3)),
+            ("peer-7".to_string(), addr(7)),
+        ]);
+
+        let out = nodes[19].find("other");
+        let events = deliver(&mut nodes, vec![(19, out)]);
+        assert_eq!(events, vec![(19, DhtEvent::Found("other".to_string(), vec![]))]);
+    }
+
+    #[test]
+    fn unanswered_queries() {
+        let mut dht = Dht::new("peer-a");
+        let out = dht.bootstrap(&[addr(1)]);
+        assert_eq!(out.len(), 1);
+
+        std::thread::sleep(QUERY_TIMEOUT + Duration::from_millis(50));
+        let (out, events) = dht.expire();
+        assert!(out.is_empty() && events.is_empty());
+        assert!(dht.pending.is_empty());
+        assert!(dht.lookups.is_empty());
+    }
+}
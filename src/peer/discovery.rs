@@ -0,0 +1,220 @@
+use std::{time::{Duration, Instant}, ops::Add, collections::HashMap, net::SocketAddr};
+
+use crate::message::format::MemberResponse;
+
+use super::{random_u64, structures::PeerId};
+
+/// Shortest period between two discovery rounds
+pub static MIN_DISCOVERY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest period between two discovery rounds, reached after rounds without new peers
+pub static MAX_DISCOVERY_INTERVAL: Duration = Duration::from_secs(120);
+
+/// Schedule of the automatic `MemberRequest` rounds.
+/// The interval doubles after every round that doesn't find new peers,
+/// and goes back to the minimum when new peers show up or neighbours are lost.
+pub struct Discovery {
+    min_interval: Duration,
+    max_interval: Duration,
+    interval: Duration,
+    next_round: Instant,
+    /// Neighbour count at the last poll, for noticing lost neighbours
+    last_count: usize,
+    /// Neighbour count at the last round, for noticing new neighbours
+    round_count: usize,
+}
+
+impl Discovery {
+    pub fn new(min_interval: Duration, max_interval: Duration) -> Discovery {
+        Discovery {
+            min_interval,
+            max_interval,
+            interval: min_interval,
+            next_round: Instant::now().add(jittered(min_interval)),
+            last_count: 0,
+            round_count: 0,
+        }
+    }
+
+    /// Returns true if a discovery round should be sent now, given the current neighbour count
+    pub fn poll(&mut self, count: usize) -> bool {
+        let dropped = count < self.last_count;
+        self.last_count = count;
+
+        if dropped {
+            // Lost neighbours, look for others right away
+            self.interval = self.min_interval;
+        } else if Instant::now() < self.next_round {
+            return false;
+        } else if count > self.round_count {
+            self.interval = self.min_interval;
+        } else {
+            self.interval = (self.interval * 2).min(self.max_interval);
+        }
+
+        self.round_count = count;
+        self.next_round = Instant::now().add(jittered(self.interval));
+        true
+    }
+}
+
+/// Period after which a member list that stopped arriving midway is dropped
+pub static PAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of a received member list page
+#[derive(Debug, PartialEq, Eq)]
+pub enum Page {
+    /// The page doesn't answer a request of this peer or arrived out of order
+    Ignored,
+    /// The next page has to be requested from the cursor
+    Next(u16),
+    /// The last page arrived, with the members of all pages
+    Complete(Vec<(PeerId, SocketAddr)>),
+}
+
+struct PendingPages {
+    members: Vec<(PeerId, SocketAddr)>,
+    deadline: Instant,
+}
+
+/// Assembles the member list of a group sent over several `MemberResponse` pages.
+pub struct MemberPages {
+    /// Member lists requested from the responders, by group and responder
+    pending: HashMap<(String, SocketAddr), PendingPages>,
+}
+
+impl MemberPages {
+    pub fn new() -> MemberPages {
+        MemberPages { pending: HashMap::new() }
+    }
+
+    /// Remembers the member request sent to the responder, only the pages of the requested lists are collected.
+    /// Starts the list over if it was already requested.
+    pub fn requested(&mut self, group: &str, responder: SocketAddr, now: Instant) {
+        self.pending.retain(|_, pages| pages.deadline > now);
+        self.pending.insert((group.to_string(), responder), PendingPages { members: vec![], deadline: now.add(PAGE_TIMEOUT) });
+    }
+
+    /// Returns true if the member request for the group sent to the responder is still waiting for an answer,
+    /// and stops waiting for it
+    pub fn rejected(&mut self, group: &str, responder: SocketAddr, now: Instant) -> bool {
+        let key = (group.to_string(), responder);
+        self.pending.remove(&key).map(|pages| pages.deadline > now).unwrap_or(false)
+    }
+
+    /// Adds the page received from the responder.
+    /// Returns the full member list once the last page arrives.
+    pub fn add(&mut self, responder: SocketAddr, page: &MemberResponse, now: Instant) -> Page {
+        let key = (page.group_name(), responder);
+        let pages = match self.pending.get_mut(&key) {
+            Some(pages) if pages.deadline > now => pages,
+            _ => return Page::Ignored,
+        };
+        // A page went missing, wait for the next round to start over
+        if pages.members.len() != page.cursor() as usize {
+            self.pending.remove(&key);
+            return Page::Ignored;
+        }
+
+        pages.members.extend(page.peers().iter().cloned());
+        pages.deadline = now.add(PAGE_TIMEOUT);
+
+        match page.next_cursor() {
+            Some(cursor) => Page::Next(cursor),
+            None => self.pending.remove(&key).map(|pages| Page::Complete(pages.members)).unwrap_or(Page::Ignored),
+        }
+    }
+}
+
+/// Spreads the interval by +-20% so the peers don't send their rounds at the same time
+fn jittered(interval: Duration) -> Duration {
+    let factor = 0.8 + (random_u64() % 1000) as f64 / 1000.0 * 0.4;
+    interval.mul_f64(factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::format::MEMBER_PAGE_SIZE;
+
+    #[test]
+    fn backoff() {
+        let mut discovery = Discovery::new(Duration::from_millis(20), Duration::from_millis(50));
+        assert!(!discovery.poll(0));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(discovery.poll(0));
+        assert_eq!(discovery.interval, Duration::from_millis(40));
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(discovery.poll(0));
+        assert_eq!(discovery.interval, Duration::from_millis(50));
+
+        // New peers found, back to the minimum
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(discovery.poll(3));
+        assert_eq!(discovery.interval, Duration::from_millis(20));
+    }
+
+    #[test]
+    fn neighbour_drop() {
+        let mut discovery = Discovery::new(Duration::from_secs(60), Duration::from_secs(60));
+        assert!(!discovery.poll(3));
+        assert!(discovery.poll(2));
+        assert!(!discovery.poll(2));
+    }
+
+    #[test]
+    fn jitter() {
+        for _ in 0..100 {
+            let interval = jittered(Duration::from_secs(10));
+            assert!(interval >= Duration::from_secs(8) && interval <= Duration::from_secs(12));
+        }
+    }
+
+    #[test]
+    fn member_pages() {
+        // Two full pages and a short one
+        let page = MEMBER_PAGE_SIZE as u16;
+        let members: Vec<(String, SocketAddr)> = (0..2 * page + 2)
+            .map(|i| (format!("peer-{}", i), SocketAddr::new("10.0.0.1".parse().unwrap(), 2000 + i)))
+            .collect();
+        let server = "10.0.0.2:8000".parse().unwrap();
+        let mut pages = MemberPages::new();
+        let now = Instant::now();
+
+        // Unsolicited pages don't trigger requests for the next one
+        assert_eq!(pages.add(server, &MemberResponse::page("grp", &members, 0).unwrap(), now), Page::Ignored);
+
+        pages.requested("grp", server, now);
+        assert_eq!(pages.add(server, &MemberResponse::page("grp", &members, 0).unwrap(), now), Page::Next(page));
+        assert_eq!(pages.add(server, &MemberResponse::page("grp", &members, page).unwrap(), now), Page::Next(2 * page));
+        assert_eq!(pages.add(server, &MemberResponse::page("grp", &members, 2 * page).unwrap(), now), Page::Complete(members.clone()));
+        assert_eq!(pages.add(server, &MemberResponse::page("grp", &members, 0).unwrap(), now), Page::Ignored);
+
+        // Out of order page is dropped
+        pages.requested("grp", server, now);
+        assert_eq!(pages.add(server, &MemberResponse::page("grp", &members, 0).unwrap(), now), Page::Next(page));
+        assert_eq!(pages.add(server, &MemberResponse::page("grp", &members, 2 * page).unwrap(), now), Page::Ignored);
+        assert_eq!(pages.add(server, &MemberResponse::page("grp", &members, page).unwrap(), now), Page::Ignored);
+
+        // Lists that stop arriving are dropped
+        pages.requested("grp", server, now);
+        assert_eq!(pages.add(server, &MemberResponse::page("grp", &members, 0).unwrap(), now), Page::Next(page));
+        assert_eq!(pages.add(server, &MemberResponse::page("grp", &members, page).unwrap(), now.add(PAGE_TIMEOUT)), Page::Ignored);
+        pages.requested("grp", "10.0.0.3:8000".parse().unwrap(), now.add(PAGE_TIMEOUT));
+        assert_eq!(pages.pending.len(), 1);
+
+        // Small groups fit in a single page
+        pages.requested("grp", server, now);
+        assert_eq!(pages.add(server, &MemberResponse::page("grp", &members[0..2], 0).unwrap(), now), Page::Complete(members[0..2].to_vec()));
+
+        // Only a pending request can be rejected, and only once
+        assert!(!pages.rejected("grp", server, now));
+        pages.requested("grp", server, now);
+        assert!(!pages.rejected("other", server, now));
+        assert!(!pages.rejected("grp", "10.0.0.4:8000".parse().unwrap(), now));
+        assert!(pages.rejected("grp", server, now));
+        assert!(!pages.rejected("grp", server, now));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,53 @@
+use std::{fmt::Display, error::Error, io};
+
+use crate::{message::format::{FormatError, Reject}, transport::common::TransportError};
+
+/// Error of starting the peer or of a command it was given
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerError {
+    /// A socket operation failed, e.g. the port is taken
+    Transport(TransportError),
+    /// A message can't be encoded, e.g. a name is too long
+    Format(FormatError),
+    /// A file of the peer can't be read, e.g. the peer cache
+    Io(io::ErrorKind),
+    /// The group name is empty
+    EmptyGroup,
+    /// The group to leave wasn't joined
+    NotJoined,
+    /// The server doesn't let the peer in the group
+    Rejected(Reject),
+}
+
+impl Error for PeerError {}
+
+impl Display for PeerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerError::Transport(err) => write!(f, "{}", err),
+            PeerError::Format(err) => write!(f, "{}", err),
+            PeerError::Io(kind) => write!(f, "Peer err: {}", kind),
+            PeerError::EmptyGroup => write!(f, "Peer err: the group name is empty"),
+            PeerError::NotJoined => write!(f, "Peer err: the group isn't joined"),
+            PeerError::Rejected(reject) => write!(f, "Peer err: can't join {} as {}: {}", reject.group_name(), reject.peer_id(), reject),
+        }
+    }
+}
+
+impl From<TransportError> for PeerError {
+    fn from(err: TransportError) -> Self {
+        PeerError::Transport(err)
+    }
+}
+
+impl From<FormatError> for PeerError {
+    fn from(err: FormatError) -> Self {
+        PeerError::Format(err)
+    }
+}
+
+impl From<io::Error> for PeerError {
+    fn from(err: io::Error) -> Self {
+        PeerError::Io(err.kind())
+    }
+}
@@ -0,0 +1,182 @@
+use std::{collections::HashMap, net::SocketAddr, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::message::format::SyncEntry;
+
+use super::structures::PeerId;
+
+/// Period between two syncs of membership changes with the federated servers
+pub static SYNC_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Period between two syncs of the full member list, so restarted servers catch up
+pub static FULL_SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Time after which a member that wasn't seen by any server is forgotten.
+/// Longer than the slowest discovery round of the clients.
+pub static MEMBER_EXPIRY: Duration = Duration::from_secs(300);
+
+/// Returns the current time in milliseconds since the Unix epoch
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct FederatedMember {
+    addr: SocketAddr,
+    last_seen: u64,
+    left: bool,
+}
+
+/// Group membership shared between federated rendezvous servers.
+/// Conflicting entries about the same member are resolved in favour of the latest seen one.
+pub struct Federation {
+    servers: Vec<SocketAddr>,
+    members: HashMap<(String, PeerId), FederatedMember>,
+    /// Members changed since the last sync
+    changed: Vec<(String, PeerId)>,
+}
+
+impl Federation {
+    pub fn new(servers: Vec<SocketAddr>) -> Federation {
+        Federation { servers, members: HashMap::new(), changed: vec![] }
+    }
+
+    /// Returns the federated servers
+    pub fn servers(&self) -> &[SocketAddr] {
+        &self.servers
+    }
+
+    pub fn is_server(&self, addr: &SocketAddr) -> bool {
+        self.servers.contains(addr)
+    }
+
+    /// Records the member seen by this server
+    pub fn seen(&mut self, group: &str, peer_id: &str, addr: SocketAddr, now: u64) {
+        self.update(group, peer_id, FederatedMember { addr, last_seen: now, left: false });
+    }
+
+    /// Records the member leaving the group
+    pub fn left(&mut self, group: &str, peer_id: &str, addr: SocketAddr, now: u64) {
+        self.update(group, peer_id, FederatedMember { addr, last_seen: now, left: true });
+    }
+
+    /// Merges the entries sent by another server. Returns the entries that changed the member list.
+    pub fn merge(&mut self, entries: &[SyncEntry]) -> Vec<SyncEntry> {
+        entries
+            .iter()
+            .filter(|e| {
+                let member = FederatedMember { addr: *e.addr(), last_seen: e.last_seen(), left: e.left() };
+                self.update(e.group_name(), e.peer_id(), member)
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn update(&mut self, group: &str, peer_id: &str, member: FederatedMember) -> bool {
+        let key = (group.to_string(), peer_id.to_string());
+        let newer = match self.members.get(&key) {
+            Some(known) => member.last_seen > known.last_seen,
+            None => true,
+        };
+
+        if newer {
+            self.members.insert(key.clone(), member);
+            if !self.changed.contains(&key) {
+                self.changed.push(key);
+            }
+        }
+        newer
+    }
+
+    /// Returns the members of the group known to the federation
+    pub fn members(&self, group: &str) -> Vec<(PeerId, SocketAddr)> {
+        let mut members: Vec<(PeerId, SocketAddr)> = self.members
+            .iter()
+            .filter(|((g, _), m)| g == group && !m.left)
+            .map(|((_, id), m)| (id.clone(), m.addr))
+            .collect();
+        // Stable order, so the pages of a member response line up
+        members.sort();
+        members
+    }
+
+    /// Takes the entries changed since the last call, for syncing with the other servers
+    pub fn deltas(&mut self) -> Vec<SyncEntry> {
+        let changed = std::mem::take(&mut self.changed);
+        changed.iter().filter_map(|key| self.entry(key)).collect()
+    }
+
+    /// Returns all the known entries
+    pub fn snapshot(&self) -> Vec<SyncEntry> {
+        self.members.keys().filter_map(|key| self.entry(key)).collect()
+    }
+
+    fn entry(&self, key: &(String, PeerId)) -> Option<SyncEntry> {
+        let member = self.members.get(key)?;
+        SyncEntry::new(&key.0, &key.1, member.addr, member.last_seen, member.left).ok()
+    }
+
+    /// Forgets the members that weren't seen for longer than the expiry
+    pub fn remove_expired(&mut self, now: u64, expiry: Duration) {
+        let expiry = expiry.as_millis() as u64;
+        self.members.retain(|_, m| now.saturating_sub(m.last_seen) <= expiry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_seen_wins() {
+        let addr1: SocketAddr = "10.0.0.1:2000".parse().unwrap();
+        let addr2: SocketAddr = "10.0.0.2:2000".parse().unwrap();
+        let mut federation = Federation::new(vec![]);
+        federation.seen("grp", "peer-a", addr1, 100);
+
+        let stale = SyncEntry::new("grp", "peer-a", addr2, 50, false).unwrap();
+        assert!(federation.merge(&[stale]).is_empty());
+        assert_eq!(federation.members("grp"), vec![("peer-a".to_string(), addr1)]);
+
+        let fresh = SyncEntry::new("grp", "peer-a", addr2, 150, false).unwrap();
+        assert_eq!(federation.merge(std::slice::from_ref(&fresh)), vec![fresh]);
+        assert_eq!(federation.members("grp"), vec![("peer-a".to_string(), addr2)]);
+
+        let left = SyncEntry::new("grp", "peer-a", addr2, 200, true).unwrap();
+        federation.merge(&[left]);
+        assert!(federation.members("grp").is_empty());
+
+        // Seen again after leaving
+        federation.seen("grp", "peer-a", addr1, 300);
+        assert_eq!(federation.members("grp").len(), 1);
+        assert!(federation.members("other").is_empty());
+    }
+
+    #[test]
+    fn deltas() {
+        let addr: SocketAddr = "10.0.0.1:2000".parse().unwrap();
+        let mut federation = Federation::new(vec![]);
+        federation.seen("grp", "peer-a", addr, 100);
+        federation.seen("grp", "peer-b", addr, 100);
+        federation.seen("grp", "peer-a", addr, 110);
+
+        let deltas = federation.deltas();
+        assert_eq!(deltas.len(), 2);
+        assert_eq!(deltas[0].last_seen(), 110);
+        assert!(federation.deltas().is_empty());
+        assert_eq!(federation.snapshot().len(), 2);
+    }
+
+    #[test]
+    fn expiry() {
+        let addr: SocketAddr = "10.0.0.1:2000".parse().unwrap();
+        let mut federation = Federation::new(vec![]);
+        federation.seen("grp", "peer-a", addr, 1_000);
+        federation.seen("grp", "peer-b", addr, 5_000);
+
+        federation.remove_expired(7_000, Duration::from_secs(3));
+        assert_eq!(federation.members("grp"), vec![("peer-b".to_string(), addr)]);
+    }
+}
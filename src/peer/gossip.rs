@@ -0,0 +1,86 @@
+use std::{collections::{HashSet, VecDeque}, time::{SystemTime, UNIX_EPOCH}};
+
+use super::structures::PeerId;
+
+/// Number of times a chat message is forwarded before it's dropped
+pub const GOSSIP_HOP_LIMIT: u8 = 4;
+
+/// Number of message ids remembered for duplicate suppression
+const SEEN_CAPACITY: usize = 1024;
+
+/// Keeps track of the chat messages that were already delivered,
+/// so the re-flooded copies don't show up twice and aren't forwarded in circles.
+pub struct SeenCache {
+    order: VecDeque<(PeerId, u32)>,
+    seen: HashSet<(PeerId, u32)>,
+}
+
+impl SeenCache {
+    pub fn new() -> SeenCache {
+        SeenCache { order: VecDeque::new(), seen: HashSet::new() }
+    }
+
+    /// Marks the message as seen. Returns false if it was seen before.
+    pub fn insert(&mut self, origin: &str, msg_id: u32) -> bool {
+        let key = (origin.to_string(), msg_id);
+        if !self.seen.insert(key.clone()) {
+            return false;
+        }
+
+        self.order.push_back(key);
+        if self.order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// Generator of message ids for the chat messages originating from this peer.
+/// Starts from a time based value so the ids don't repeat after a restart.
+pub struct MessageIdGen {
+    next: u32,
+}
+
+impl MessageIdGen {
+    pub fn new() -> MessageIdGen {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u32)
+            .unwrap_or(0);
+        MessageIdGen { next: seed }
+    }
+
+    pub fn next_id(&mut self) -> u32 {
+        let id = self.next;
+        self.next = self.next.wrapping_add(1);
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_suppression() {
+        let mut cache = SeenCache::new();
+        assert!(cache.insert("peer-a", 1));
+        assert!(cache.insert("peer-b", 1));
+        assert!(!cache.insert("peer-a", 1));
+
+        for i in 0..SEEN_CAPACITY as u32 {
+            cache.insert("peer-c", i);
+        }
+        // The oldest entries are evicted
+        assert!(cache.insert("peer-a", 1));
+    }
+
+    #[test]
+    fn message_ids() {
+        let mut ids = MessageIdGen::new();
+        let first = ids.next_id();
+        assert_eq!(ids.next_id(), first.wrapping_add(1));
+    }
+}
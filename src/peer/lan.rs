@@ -0,0 +1,95 @@
+use std::{net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket}, sync::Mutex, time::Duration, io};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::message::format::{Announce, Header, Integrity, Keyring, Message, MessageType, PROTOCOL_VERSION};
+
+use super::{LockResultExt, PeerError};
+
+/// Multicast group the LAN announcements are sent to
+pub static LAN_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 42), 4242);
+
+/// Period between two announcements of the joined groups
+pub static ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Multicast socket for finding the peers on the same LAN without a server.
+/// Every peer on the host shares the multicast port, the announcements carry the port of the peer's transport.
+pub struct LanDiscovery {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+}
+
+impl LanDiscovery {
+    /// Joins the multicast group on the interface with the given address, `0.0.0.0` picks the default one
+    pub fn new(interface: Ipv4Addr, group: SocketAddrV4) -> io::Result<LanDiscovery> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), group.port()).into())?;
+
+        socket.join_multicast_v4(group.ip(), &interface)?;
+        socket.set_multicast_if_v4(&interface)?;
+        // Announcements stay on the LAN, and reach the other peers on the same host
+        socket.set_multicast_ttl_v4(1)?;
+        socket.set_multicast_loop_v4(true)?;
+
+        let socket: UdpSocket = socket.into();
+        socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+        Ok(LanDiscovery { socket, group })
+    }
+
+    /// Announces the group member to the LAN, with the trailer of the group
+    pub fn announce(&self, announce: Announce, integrity: &Integrity) -> Result<(), PeerError> {
+        let msg = Message::<Announce>::new(Header::new(PROTOCOL_VERSION, MessageType::Announce, 0), Some(announce));
+        let buf = msg.encode(integrity, false)?;
+        self.socket.send_to(&buf, self.group)?;
+        Ok(())
+    }
+
+    /// Waits for the next announcement and returns it with the transport address of the announcing peer.
+    /// Returns None when nothing was announced for a while or the packet isn't an announcement.
+    /// The announcement is opened with the keys of the joined groups, the keyring isn't locked while waiting.
+    pub fn recv(&self, keyring: &Mutex<Keyring>) -> Option<(Announce, SocketAddr)> {
+        let mut buf = [0; 1024];
+        let (size, from) = self.socket.recv_from(&mut buf).ok()?;
+
+        let header = Header::try_from(&buf[..size]).ok()?;
+        if header.msg_type() != MessageType::Announce {
+            return None;
+        }
+
+        let opened = keyring.lock().ignore_poison().open(&buf[..size]);
+        let msg = opened.and_then(|msg| msg.into_message::<Announce>()).ok()?;
+        let announce = msg.content()?.clone();
+        let addr = SocketAddr::new(from.ip(), announce.port());
+        Some((announce, addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loopback_announce() {
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 43), 4243);
+        let lan1 = LanDiscovery::new(Ipv4Addr::LOCALHOST, group).unwrap();
+        let lan2 = LanDiscovery::new(Ipv4Addr::LOCALHOST, group).unwrap();
+
+        let keyring = Mutex::new(Keyring::new());
+
+        lan1.announce(Announce::new("peer-a", "grp", 9000).unwrap(), &Integrity::Checksum).unwrap();
+
+        // Both peers on the host get it, the sender too
+        for lan in [&lan1, &lan2] {
+            let (announce, addr) = lan.recv(&keyring).unwrap();
+            assert_eq!(announce.peer_id(), "peer-a");
+            assert_eq!(announce.group_name(), "grp");
+            assert_eq!(addr, "127.0.0.1:9000".parse().unwrap());
+        }
+
+        // Nothing else announced
+        assert!(lan2.recv(&keyring).is_none());
+    }
+}
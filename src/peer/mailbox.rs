@@ -0,0 +1,147 @@
+use std::{collections::{HashMap, VecDeque}, time::{Duration, Instant}, ops::Add};
+
+use crate::message::format::Mail;
+
+use super::structures::PeerId;
+
+/// Period until a stored mail is dropped if the recipient doesn't show up
+static MAIL_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Maximum number of payload bytes held for a single recipient
+const MAILBOX_QUOTA_BYTES: usize = 16 * 1024;
+
+/// Maximum number of mails held for a single recipient
+const MAILBOX_QUOTA_COUNT: usize = 64;
+
+/// Maximum number of recipients with pending mails, over all groups
+const MAILBOX_MAX_BOXES: usize = 4096;
+
+/// Maximum number of payload bytes held for all recipients
+const MAILBOX_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// Period of dropping the expired mails of recipients that don't show up
+pub static MAIL_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+struct StoredMail {
+    mail: Mail,
+    expiry: Instant,
+}
+
+/// Store-and-forward mailbox kept on the rendezvous server.
+/// Holds mails for group members that are offline until they send the next `MemberRequest`.
+pub struct Mailbox {
+    boxes: HashMap<(String, PeerId), VecDeque<StoredMail>>,
+    /// Payload bytes held in all boxes
+    bytes: usize,
+}
+
+impl Mailbox {
+    pub fn new() -> Mailbox {
+        Mailbox { boxes: HashMap::new(), bytes: 0 }
+    }
+
+    /// Stores the mail for its recipient.
+    /// Returns false if the recipient's quota or the capacity of the whole mailbox is exceeded.
+    pub fn deposit(&mut self, mail: Mail) -> bool {
+        let key = (mail.group_name().to_string(), mail.recipient().to_string());
+        if !self.boxes.contains_key(&key) && self.boxes.len() >= MAILBOX_MAX_BOXES {
+            return false;
+        }
+        if self.bytes + mail.payload().len() > MAILBOX_MAX_BYTES {
+            return false;
+        }
+
+        let queue = self.boxes.entry(key).or_default();
+        let len = mail.payload().len();
+        let used: usize = queue.iter().map(|m| m.mail.payload().len()).sum();
+        if queue.len() >= MAILBOX_QUOTA_COUNT || used + len > MAILBOX_QUOTA_BYTES {
+            return false;
+        }
+
+        queue.push_back(StoredMail { mail, expiry: Instant::now().add(MAIL_EXPIRY) });
+        self.bytes += len;
+        true
+    }
+
+    /// Removes and returns all pending mails of the peer inside the group.
+    pub fn take(&mut self, group: &str, peer_id: &str) -> Vec<Mail> {
+        match self.boxes.remove(&(group.to_string(), peer_id.to_string())) {
+            Some(queue) => {
+                self.bytes -= queue.iter().map(|m| m.mail.payload().len()).sum::<usize>();
+                queue.into_iter()
+                    .filter(|m| m.expiry > Instant::now())
+                    .map(|m| m.mail)
+                    .collect()
+            },
+            None => vec![],
+        }
+    }
+
+    /// Drops the expired mails and the boxes left empty
+    pub fn remove_expired(&mut self, now: Instant) {
+        let mut dropped = 0;
+        self.boxes.retain(|_, queue| {
+            while queue.front().is_some_and(|m| m.expiry <= now) {
+                dropped += queue.pop_front().map(|m| m.mail.payload().len()).unwrap_or(0);
+            }
+            !queue.is_empty()
+        });
+        self.bytes -= dropped;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deposit_and_take() {
+        let mut mailbox = Mailbox::new();
+        assert!(mailbox.deposit(Mail::new("grp", "peer-a", "peer-b", vec![1, 2, 3]).unwrap()));
+        assert!(mailbox.deposit(Mail::new("grp", "peer-c", "peer-b", vec![4]).unwrap()));
+        assert!(mailbox.deposit(Mail::new("grp", "peer-a", "peer-c", vec![5]).unwrap()));
+
+        let mails = mailbox.take("grp", "peer-b");
+        assert_eq!(mails.len(), 2);
+        assert_eq!(mails[0].sender(), "peer-a");
+        assert_eq!(mails[1].payload(), [4]);
+
+        assert!(mailbox.take("grp", "peer-b").is_empty());
+        assert!(mailbox.take("other-grp", "peer-c").is_empty());
+        assert_eq!(mailbox.take("grp", "peer-c").len(), 1);
+    }
+
+    #[test]
+    fn quota() {
+        let mut mailbox = Mailbox::new();
+        assert!(mailbox.deposit(Mail::new("grp", "peer-a", "peer-b", vec![0; MAILBOX_QUOTA_BYTES]).unwrap()));
+        assert!(!mailbox.deposit(Mail::new("grp", "peer-a", "peer-b", vec![0]).unwrap()));
+
+        for _ in 0..MAILBOX_QUOTA_COUNT {
+            mailbox.deposit(Mail::new("grp", "peer-a", "peer-c", vec![0]).unwrap());
+        }
+        assert!(!mailbox.deposit(Mail::new("grp", "peer-a", "peer-c", vec![0]).unwrap()));
+        assert_eq!(mailbox.take("grp", "peer-c").len(), MAILBOX_QUOTA_COUNT);
+    }
+
+    #[test]
+    fn capacity() {
+        let mut mailbox = Mailbox::new();
+        // New recipients can't grow the mailbox without bound
+        for i in 0..MAILBOX_MAX_BOXES {
+            assert!(mailbox.deposit(Mail::new(&format!("grp-{}", i % 16), "peer-a", &format!("peer-{}", i), vec![0; 8]).unwrap()));
+        }
+        assert!(!mailbox.deposit(Mail::new("grp", "peer-a", "new-peer", vec![0]).unwrap()));
+        assert!(mailbox.deposit(Mail::new("grp-0", "peer-a", "peer-0", vec![0]).unwrap()));
+        assert_eq!(mailbox.bytes, MAILBOX_MAX_BOXES * 8 + 1);
+
+        assert_eq!(mailbox.take("grp-0", "peer-0").len(), 2);
+        assert_eq!(mailbox.bytes, (MAILBOX_MAX_BOXES - 1) * 8);
+        assert!(mailbox.deposit(Mail::new("grp", "peer-a", "new-peer", vec![0]).unwrap()));
+
+        // The sweep drops the mails of recipients that never came back
+        mailbox.remove_expired(Instant::now().add(MAIL_EXPIRY));
+        assert!(mailbox.boxes.is_empty());
+        assert_eq!(mailbox.bytes, 0);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{collections::HashMap, net::SocketAddr, time::{Duration, Instant}};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::{random_u64, structures::PeerId};
+
+/// Time the peer has to answer the path challenge before it can be challenged again
+pub static CHALLENGE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Most challenges waiting for an answer, so spoofed packets can't pile them up
+const MAX_PENDING: usize = 64;
+
+/// Most keys handed out by other peers that are kept
+const MAX_KEYS: usize = 1024;
+
+struct PendingChallenge {
+    peer_id: PeerId,
+    addr: SocketAddr,
+    token: u64,
+    session: u64,
+    sent: Instant,
+}
+
+/// Address changes of the neighbours waiting for validation.
+/// A peer heard from a new address is sent a random token there, and its address is switched
+/// only after the token comes back from the new address, so a spoofed source can't take over the session.
+///
+/// Receiving at the new address doesn't prove who the sender is, so every peer is also handed a key
+/// at the address it joined from. The response has to carry a proof made with that key, which
+/// a host claiming the name of the peer from elsewhere never saw.
+pub struct Migrations {
+    pending: Vec<PendingChallenge>,
+    /// Secret the handed out keys are derived from, it never leaves the process
+    secret: [u8; 32],
+    /// Keys handed to this peer by the other ones
+    keys: HashMap<PeerId, u64>,
+}
+
+/// Returns the first 8 bytes of the HMAC of the data
+fn mac(key: &[u8], data: &[u8]) -> u64 {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    let tag = mac.finalize().into_bytes();
+    u64::from_be_bytes(tag[..8].try_into().unwrap())
+}
+
+impl Migrations {
+    pub fn new() -> Migrations {
+        let mut secret = [0; 32];
+        for chunk in secret.chunks_mut(8) {
+            chunk.copy_from_slice(&random_u64().to_be_bytes());
+        }
+        Migrations { pending: vec![], secret, keys: HashMap::new() }
+    }
+
+    /// Returns the key handed to the peer, to be sent only to the address it joined from
+    pub fn key_for(&self, peer_id: &str) -> u64 {
+        mac(&self.secret, peer_id.as_bytes())
+    }
+
+    /// Keeps the key the peer handed to this one
+    pub fn set_key(&mut self, peer_id: &str, key: u64) {
+        if self.keys.len() < MAX_KEYS || self.keys.contains_key(peer_id) {
+            self.keys.insert(peer_id.to_string(), key);
+        }
+    }
+
+    /// Returns the proof answering the token of the challenger, made with the key it handed out
+    pub fn proof(&self, challenger: &str, token: u64) -> u64 {
+        match self.keys.get(challenger) {
+            Some(key) => mac(&key.to_be_bytes(), &token.to_be_bytes()),
+            None => 0,
+        }
+    }
+
+    /// Starts validating the new address of the peer with the given session and returns the token to send there.
+    /// Returns None if the address is already being validated.
+    pub fn challenge(&mut self, peer_id: &str, addr: SocketAddr, session: u64) -> Option<u64> {
+        self.pending.retain(|c| c.sent.elapsed() <= CHALLENGE_TIMEOUT);
+        if self.pending.len() >= MAX_PENDING || self.pending.iter().any(|c| c.peer_id == peer_id && c.addr == addr) {
+            return None;
+        }
+
+        let token = random_u64();
+        self.pending.push(PendingChallenge { peer_id: peer_id.to_string(), addr, token, session, sent: Instant::now() });
+        Some(token)
+    }
+
+    /// Returns the session of the peer if the response completes a pending challenge
+    /// and carries the proof of the key handed to the peer
+    pub fn validate(&mut self, peer_id: &str, addr: SocketAddr, token: u64, proof: u64) -> Option<u64> {
+        if proof != mac(&self.key_for(peer_id).to_be_bytes(), &token.to_be_bytes()) {
+            return None;
+        }
+        let index = self.pending
+            .iter()
+            .position(|c| c.peer_id == peer_id && c.addr == addr && c.token == token && c.sent.elapsed() <= CHALLENGE_TIMEOUT);
+
+        index.map(|index| self.pending.remove(index).session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validation() {
+        let addr: SocketAddr = "10.0.0.1:2000".parse().unwrap();
+        let other: SocketAddr = "10.0.0.2:2000".parse().unwrap();
+        let mut migrations = Migrations::new();
+
+        // Peer a got the key at the address it joined from
+        let mut peer_a = Migrations::new();
+        peer_a.set_key("me", migrations.key_for("peer-a"));
+
+        let token = migrations.challenge("peer-a", addr, 7).unwrap();
+        assert_eq!(migrations.challenge("peer-a", addr, 7), None);
+        let proof = peer_a.proof("me", token);
+
+        // The token has to come back from the same peer and address
+        assert_eq!(migrations.validate("peer-a", addr, token.wrapping_add(1), proof), None);
+        assert_eq!(migrations.validate("peer-a", other, token, proof), None);
+        assert_eq!(migrations.validate("peer-b", addr, token, proof), None);
+        assert_eq!(migrations.validate("peer-a", addr, token, proof), Some(7));
+        assert_eq!(migrations.validate("peer-a", addr, token, proof), None);
+    }
+
+    #[test]
+    fn takeover() {
+        let mut migrations = Migrations::new();
+        let mut victim = Migrations::new();
+        victim.set_key("me", migrations.key_for("victim"));
+
+        // A third party claims the name of the victim from its own address and echoes the token
+        let attacker_addr: SocketAddr = "10.0.0.66:2000".parse().unwrap();
+        let mut attacker = Migrations::new();
+        let token = migrations.challenge("victim", attacker_addr, 7).unwrap();
+        assert_eq!(attacker.proof("me", token), 0);
+        assert_eq!(migrations.validate("victim", attacker_addr, token, 0), None);
+
+        // The key of another peer doesn't help
+        attacker.set_key("me", migrations.key_for("attacker"));
+        assert_eq!(migrations.validate("victim", attacker_addr, token, attacker.proof("me", token)), None);
+
+        // The victim moving to a new address is still let through
+        let new_addr: SocketAddr = "10.0.0.2:2000".parse().unwrap();
+        let token = migrations.challenge("victim", new_addr, 7).unwrap();
+        assert_eq!(migrations.validate("victim", new_addr, token, victim.proof("me", token)), Some(7));
+    }
+
+    #[test]
+    fn pending_limit() {
+        let mut migrations = Migrations::new();
+        for port in 0..MAX_PENDING as u16 {
+            assert!(migrations.challenge("peer-a", SocketAddr::new("10.0.0.1".parse().unwrap(), port), 7).is_some());
+        }
+        assert!(migrations.challenge("peer-b", "10.0.0.2:2000".parse().unwrap(), 7).is_none());
+    }
+}
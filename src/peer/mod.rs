@@ -1,307 +1,1894 @@
-use std::{net::SocketAddr, error::Error, sync::{Arc, Mutex, LockResult}, collections::HashMap, time::{Duration, Instant}, ops::Add};
-
-use crossbeam_channel::{unbounded, Sender, Receiver};
-
-use crate::{transport::{udp::UdpTransport, common::{TransportPacket, Transport}}, message::{format::{Message, Chat, Header, MessageType, MemberRequest, MemberResponse, Alive}, self}};
-
-use self::structures::{PeerId, NeighbourMap, NeighbourEntry};
-
-mod structures;
-
-/// Period until to keep the peer inside the peer list until it sends a keep alive msg
-static TTL_RENEWAL: Duration = std::time::Duration::from_secs(30);
-
-pub trait LockResultExt {
-    type Guard;
-
-    fn ignore_poison(self) -> Self::Guard;
-}
-
-impl<Guard> LockResultExt for LockResult<Guard> {
-    type Guard = Guard;
-
-    fn ignore_poison(self) -> Guard {
-        self.unwrap_or_else(|e| e.into_inner())
-    }
-}
-
-/// Instance of a peer. 
-/// Encapsulates the neighbour map, network transport and manages
-/// communication with other peers inside the group.
-pub struct Peer {
-    name: PeerId,
-    group: String,
-    port: u16,
-    bootstrap: Option<SocketAddr>,
-    transport: UdpTransport,
-    tx: Sender<String>,
-    rx: Receiver<String>,
-    peer_map: Arc<Mutex<HashMap::<String, NeighbourMap>>>,
-
-    msg_tx: Sender<(String, String)>,
-    msg_rx: Receiver<(String, String)>,
-}
-
-impl Peer {
-    pub fn new(name: String, group: String, port: u16, bootstrap: Option<SocketAddr>) -> Result<Peer, Box<dyn Error>> {
-        let (tx, rx) = unbounded();
-        let (msg_tx, msg_rx) = unbounded();
-        let peer_map = Arc::new(Mutex::new(HashMap::new()));
-        Ok(Peer {
-            name,
-            group,
-            port,
-            bootstrap,
-            transport: UdpTransport::new(SocketAddr::new("0.0.0.0".parse().unwrap(), port)).unwrap(),
-            rx, tx,
-            peer_map,
-            msg_tx, msg_rx,
-        })
-    }
-
-    /// Returns a sender for sending commands or messages to the peer.
-    pub fn msg_sender(&self) -> Sender<String> {
-        self.tx.clone()
-    }
-
-    /// Returns the receiver for capturing messages from other peers.
-    pub fn msg_receiver(&self) -> Receiver<(PeerId, String)> {
-        self.msg_rx.clone()
-    }
-
-    fn send_req(&self, peer_socket: SocketAddr) -> Result<(), Box<dyn Error>> {
-        let header = Header::new(1, message::format::MessageType::MemberReq, 64);
-        let msg = Message::<MemberRequest>::new(header, Some(MemberRequest::new(&self.name.clone(), &self.group)?));
-        let buf: Vec<u8> = msg.into();
-        self.transport.send(TransportPacket {
-            socket_addr: peer_socket,
-            data: buf,
-        })?;
-        Ok(())
-    }
-
-    /// After calling this method, the current thread blocks
-    /// The peer listens for incoming messages or commands, sends requests to other peers
-    /// and maintains the connection with neighbours.
-    pub fn run(&mut self) -> ! {
-        let cmd_sock = self.transport.try_clone().unwrap();
-
-        // Thread for sending the Alive message to all neighbours
-        self.run_keep_alive_thread();
-
-        // Handler thread for incoming packets
-        self.run_message_handler_thread();
-
-        if let Some(bootstrap) = self.bootstrap {
-            self.send_req(bootstrap);
-        }
-
-        let cmd_sender = self.msg_tx.clone();
-        
-        // The main thread catches the incoming commands from the msg_sender
-        loop {
-            match self.rx.recv() {
-                Ok(cmd_str) => {
-                    // Matching special commands:
-                    // peers - returns a list of all neighbours
-                    // req - send a MemberRequest to all peers to discover newly added ones
-                    match cmd_str.trim() {
-                        "peers" => {
-                            cmd_sender.send((self.name.clone(), format!("{:?}", self.peer_map.lock().ignore_poison()))).unwrap();
-                            continue;
-                        },
-                        "req" => {
-                            for (_group, peer_list) in self.peer_map.lock().ignore_poison().iter() {
-                                for peer in peer_list.iter() {
-                                    self.send_req(*peer.addr());
-                                }
-                            }
-                            // Send to bootstrap since he has a stable address
-                            // Although this fights the purpose of the bootstrap peer,
-                            // it's easier and faster to get a more stable connection
-                            // The proper way would be to introduce "stable peers"
-                            if let Some(bootstrap) = self.bootstrap {
-                                self.send_req(bootstrap);
-                            }
-                            continue;
-                        },
-                        _ => (),
-                    }
-
-                    let header = Header::new(1, message::format::MessageType::Chat, cmd_str.len().try_into().unwrap());
-                    for (_group, peer_list) in self.peer_map.lock().ignore_poison().iter() {
-                        for peer in peer_list.iter() {
-                            let chat = Chat::new(self.name.clone(), &cmd_str);
-                            let msg = Message::<Chat>::new(header, Some(chat));
-                            cmd_sock.send(TransportPacket {
-                                socket_addr: *peer.addr(),
-                                data: msg.into(),
-                            }).unwrap();
-                        }
-                    }
-                },
-                Err(err) => println!("Error on recv: {}", err),
-            }
-        }
-    }
-
-    /// Sends keep alive messages to peers from the internal list of neighbours
-    fn run_keep_alive_thread(&self) -> std::thread::JoinHandle<()> {
-        let peer_map_lock = self.peer_map.clone();
-
-        let alive_sock = self.transport.try_clone().unwrap();
-        let alive_packet = Alive::new(self.name.clone());
-        // Thread for sending the Alive message to all neighbours
-        std::thread::spawn(move || {
-            loop {
-                std::thread::sleep(std::time::Duration::from_secs(5));
-                let mut peer_map = peer_map_lock.lock().ignore_poison();
-
-                for (_, peer_list) in peer_map.iter_mut() {
-                    peer_list.remove_expired();
-                    for peer in peer_list.iter() {
-                        let msg = Message::<Alive>::new(Header::new(1, MessageType::Alive, 0), Some(alive_packet.clone()));
-                        // TODO: log error
-                        alive_sock.send(TransportPacket { socket_addr: *peer.addr(), data: msg.into() });
-                    }
-                }
-            }
-        })
-    }
-
-    /// Handles messages from other peers
-    fn run_message_handler_thread(&self) -> std::thread::JoinHandle<()> {
-        let peer_map_lock = self.peer_map.clone();
-        let recv_sock = self.transport.try_clone().unwrap();
-        let msg_sender = self.msg_tx.clone();
-
-        // Handler thread for incoming packets
-        std::thread::spawn(move || {
-            loop {
-                // Receive the packet
-                let packet = match recv_sock.recv() {
-                    Ok(p) => p,
-                    Err(_err) => {
-                        // TODO: log error
-                        continue;
-                    },
-                };
-    
-                // Parse the header (first 4 bytes)
-                let header_bytes = &packet.data[0..4];
-                let header = match Header::try_from(header_bytes.to_vec()) {
-                    Ok(h) => h,
-                    Err(_) => {
-                        // TODO: log error
-                        continue;
-                    },
-                };
-    
-                // Route answer based on input
-                match header.msg_type() {
-                    // Alive should update the TTL inside the peer map
-                    MessageType::Alive => {
-                        let msg = match Message::<Alive>::try_from(packet.data) {
-                            Ok(msg) => msg,
-                            Err(_) => {
-                                // TODO: log
-                                continue;
-                            },
-                        };
-
-                        let content = msg.content().unwrap();
-                        let peer_id = content.peer_id();
-
-                        let mut group_map = peer_map_lock.lock().ignore_poison();
-
-                        for (_, peer_list) in group_map.iter_mut() {
-                            if peer_list.contains_peer(peer_id) {
-                                peer_list.find_peer_mut(peer_id).unwrap().update_ttl(TTL_RENEWAL);
-                            }
-                        }
-                    },
-                    MessageType::MemberReq => {
-                        let msg = match Message::<MemberRequest>::try_from(packet.data) {
-                            Ok(msg) => msg,
-                            Err(_) => {
-                                // TODO: log
-                                continue;
-                            }
-                        };
-
-                        let content = msg.content().unwrap();
-                        let group_name = content.group_name();
-                        let peer_id = content.peer_id();
-                        
-                        let mut group_map = peer_map_lock.lock().ignore_poison();
-                        
-                        if !group_map.contains_key(group_name) {
-                            group_map.insert(group_name.to_string(), NeighbourMap::new());
-                        }
-                        
-                        let peer_list = group_map.get_mut(group_name).unwrap();
-                        
-                        if !peer_list.contains_peer(&peer_id) {
-                            // Initial TTL is set to 2 minutes
-                            let ttl = Instant::now().add(TTL_RENEWAL.add(Duration::from_secs(120)));
-                            peer_list.insert(NeighbourEntry::new(peer_id, packet.socket_addr, ttl));
-                        }
-                        
-                        let peer_id = content.peer_id();
-                        let response_peers = peer_list
-                            //.clone()
-                            .iter()
-                            .filter(|s| *s.id() != peer_id.clone())
-                            .map(|e| (e.id().clone(), *e.addr()))
-                            .collect();
-                            
-                        let res_msg = Message::<MemberResponse>::new(
-                            Header::new(1, MessageType::MemberRes, 0),
-                            Some(MemberResponse::new(group_name, response_peers).unwrap())
-                        );
-                        recv_sock.send(TransportPacket { socket_addr: packet.socket_addr, data: res_msg.into() }).unwrap();
-                    },
-                    MessageType::MemberRes => {
-                        let msg = match Message::<MemberResponse>::try_from(packet.data) {
-                            Ok(msg) => msg,
-                            Err(_) => {
-                                // TODO: log
-                                continue;
-                            },
-                        };
-
-                        let content = msg.content().unwrap();
-                        let peers = content.peers();
-                        let group_name = content.group_name();
-    
-                        let mut peer_map = peer_map_lock.lock().ignore_poison();
-                        
-                        if !peer_map.contains_key(&group_name) {
-                            peer_map.insert(group_name.clone(), NeighbourMap::new());
-                        }
-    
-                        let peer_list = peer_map.get_mut(&group_name).unwrap();
-                        
-                        for (peer_id, peer_addr) in peers {
-                            if !peer_list.contains_peer(peer_id) {
-                                let ttl = Instant::now().add(TTL_RENEWAL);
-                                peer_list.insert(NeighbourEntry::new(peer_id.to_string(), *peer_addr, ttl));
-                            }
-                        }
-                    },
-                    MessageType::Chat => {
-                        let msg = match Message::<Chat>::try_from(packet.data) {
-                            Ok(msg) => msg,
-                            Err(_) => {
-                                // TODO: log
-                                continue;
-                            },
-                        };
-                        let content = msg.content().unwrap();
-                        msg_sender.send((content.peer_id(), content.msg().to_string())).unwrap();
-                    },
-                }
-            }
-        })
-    }
-}
\ No newline at end of file
+use std::{net::{SocketAddr, Ipv4Addr}, path::Path, io, sync::{Arc, Mutex, LockResult, OnceLock}, collections::{HashMap, VecDeque, hash_map::RandomState}, hash::{BuildHasher, Hasher}, time::{Duration, Instant}, ops::{Add, Range}};
+
+use crossbeam_channel::{unbounded, Sender, Receiver, select};
+
+use crate::{transport::{udp::{UdpTransport, MAX_DATAGRAM}, common::{TransportPacket, PacketRef, Transport, TransportError}}, message::{markup::Markup, format::{FormatError, Message, MessageContent, Chat, ChatRef, ChatAction, ChatActionKind, Header, MessageType, MemberRequest, MemberRequestRef, MemberResponse, Datagrams, Alive, Mail, Ack, PingReq, MemberUpdate, MemberState, Bye, MemberSync, SYNC_MAX_ENTRIES, DhtMessage, Announce, Challenge, ChallengeKind, Reject, RejectReason, Presence, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, Integrity, Keyring, FEATURES, FEATURE_COMPRESSION}, self}};
+
+use self::{structures::{PeerId, NeighbourMap, NeighbourEntry}, mailbox::Mailbox, gossip::{SeenCache, MessageIdGen, GOSSIP_HOP_LIMIT}, swim::{Swim, Probe}, discovery::{Discovery, MemberPages, Page}, bootstrap::{Bootstraps, BOOTSTRAP_TIMEOUT}, federation::Federation, dht::{Dht, DhtEvent, DhtOutput}, lan::LanDiscovery, cache::PeerCache, migration::Migrations, presence::PresenceMap, stats::DropStats, outbox::{Outbox, OUTBOX_DELAY}};
+
+mod structures;
+mod mailbox;
+mod gossip;
+mod swim;
+mod discovery;
+pub mod bootstrap;
+mod federation;
+mod dht;
+mod lan;
+mod cache;
+mod migration;
+mod presence;
+mod stats;
+mod outbox;
+mod error;
+
+pub use self::{presence::MemberInfo, error::PeerError};
+
+/// Period until to keep the peer inside the peer list until it sends a keep alive msg
+static TTL_RENEWAL: Duration = std::time::Duration::from_secs(30);
+
+/// Period of checking whether a discovery round is due
+static DISCOVERY_TICK: Duration = Duration::from_millis(500);
+
+/// Returns a random number, good enough for picking members and jitter
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Returns the random id of this process, which tells it apart from other peers using the same name
+fn session() -> u64 {
+    static SESSION: OnceLock<u64> = OnceLock::new();
+    *SESSION.get_or_init(random_u64)
+}
+
+pub trait LockResultExt {
+    type Guard;
+
+    fn ignore_poison(self) -> Self::Guard;
+}
+
+impl<Guard> LockResultExt for LockResult<Guard> {
+    type Guard = Guard;
+
+    fn ignore_poison(self) -> Guard {
+        self.unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Events delivered by the peer to the chat. The first field is the group of the event.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// Chat message written by a peer, own messages included once they are sent
+    Message(String, Chat),
+    /// Edit, delete or reaction of an earlier chat message, own actions included
+    Action(String, ChatAction),
+    /// Notice about the group, like a member leaving
+    Notice(String, String),
+    /// The group was joined with `/join`
+    Joined(String),
+    /// Current members of the group with their presence, sent when it changes
+    Members(String, Vec<MemberInfo>),
+}
+
+/// Instance of a peer. 
+/// Encapsulates the neighbour map, network transport and manages
+/// communication with other peers inside the group.
+pub struct Peer {
+    name: PeerId,
+    /// Groups the peer joined. Locked before the peer map when both are held.
+    groups: Arc<Mutex<Vec<String>>>,
+    /// Bootstrap servers with their health
+    bootstraps: Arc<Mutex<Bootstraps>>,
+    transport: UdpTransport,
+    tx: Sender<(String, String)>,
+    rx: Receiver<(String, String)>,
+    peer_map: Arc<Mutex<HashMap::<String, NeighbourMap>>>,
+    mailbox_enabled: bool,
+    mailbox: Arc<Mutex<Mailbox>>,
+    msg_ids: MessageIdGen,
+    seen: Arc<Mutex<SeenCache>>,
+    member_pages: Arc<Mutex<MemberPages>>,
+    /// Id of the last chat message of every member, by group, for replying to it
+    last_chats: Arc<Mutex<HashMap<(String, PeerId), u32>>>,
+    swim: Arc<Mutex<Swim>>,
+    federation: Arc<Mutex<Federation>>,
+    dht_enabled: bool,
+    dht: Arc<Mutex<Dht>>,
+    lan: Option<LanDiscovery>,
+    cache: Option<Arc<Mutex<PeerCache>>>,
+    presence: Arc<Mutex<PresenceMap>>,
+    typing_tx: Sender<(String, bool)>,
+    typing_rx: Receiver<(String, bool)>,
+    drops: Arc<Mutex<DropStats>>,
+    /// Chat messages waiting to share a datagram with the next messages to the same neighbour
+    outbox: Arc<Mutex<Outbox>>,
+    /// Trailers of the joined groups. Locked last, no other lock is taken while it's held.
+    keyring: Arc<Mutex<Keyring>>,
+    /// Features advertised to the other peers, `FEATURE_*` bits
+    features: u8,
+
+    shutdown_tx: Sender<()>,
+    shutdown_rx: Receiver<()>,
+
+    msg_tx: Sender<PeerEvent>,
+    msg_rx: Receiver<PeerEvent>,
+}
+
+impl Peer {
+    pub fn new(name: String, group: String, port: u16, bootstraps: Vec<SocketAddr>) -> Result<Peer, PeerError> {
+        // Checks the length of the names
+        MemberRequest::new(&name, &group, 0, 0)?;
+
+        let (tx, rx) = unbounded();
+        let (msg_tx, msg_rx) = unbounded();
+        let (shutdown_tx, shutdown_rx) = unbounded();
+        let (typing_tx, typing_rx) = unbounded();
+        let peer_map = Arc::new(Mutex::new(HashMap::new()));
+        let dht = Arc::new(Mutex::new(Dht::new(&name)));
+        let mut keyring = Keyring::new();
+        keyring.set(&group, Integrity::Checksum);
+        Ok(Peer {
+            name,
+            groups: Arc::new(Mutex::new(vec![group])),
+            bootstraps: Arc::new(Mutex::new(Bootstraps::new(bootstraps))),
+            transport: UdpTransport::new(SocketAddr::new("0.0.0.0".parse().unwrap(), port))?,
+            rx, tx,
+            peer_map,
+            mailbox_enabled: false,
+            mailbox: Arc::new(Mutex::new(Mailbox::new())),
+            msg_ids: MessageIdGen::new(),
+            seen: Arc::new(Mutex::new(SeenCache::new())),
+            member_pages: Arc::new(Mutex::new(MemberPages::new())),
+            last_chats: Arc::new(Mutex::new(HashMap::new())),
+            swim: Arc::new(Mutex::new(Swim::new())),
+            federation: Arc::new(Mutex::new(Federation::new(vec![]))),
+            dht_enabled: false,
+            dht,
+            lan: None,
+            cache: None,
+            presence: Arc::new(Mutex::new(PresenceMap::new())),
+            typing_tx, typing_rx,
+            drops: Arc::new(Mutex::new(DropStats::new())),
+            outbox: Arc::new(Mutex::new(Outbox::new(MAX_DATAGRAM))),
+            keyring: Arc::new(Mutex::new(keyring)),
+            features: FEATURES,
+            shutdown_tx, shutdown_rx,
+            msg_tx, msg_rx,
+        })
+    }
+
+    /// Signs the messages of the first group with the shared secret, its messages without a valid MAC are dropped
+    pub fn set_secret(&mut self, secret: &str) {
+        let group = self.groups.lock().ignore_poison()[0].clone();
+        self.keyring.lock().ignore_poison().set(&group, Integrity::Mac(secret.as_bytes().to_vec()));
+    }
+
+    /// Sends the chat messages and member lists uncompressed, and tells the other peers to do the same
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.features = if enabled { FEATURES } else { FEATURES & !FEATURE_COMPRESSION };
+    }
+
+    /// Enables the store-and-forward mailbox.
+    /// A server keeps mails for offline members, a client deposits mails for them on the bootstrap server.
+    pub fn set_mailbox(&mut self, enabled: bool) {
+        self.mailbox_enabled = enabled;
+    }
+
+    /// Sets the rendezvous servers to share the group membership with.
+    /// Member requests are then answered with the members known to the whole federation.
+    pub fn set_federation(&mut self, servers: Vec<SocketAddr>) {
+        self.federation = Arc::new(Mutex::new(Federation::new(servers)));
+    }
+
+    /// Enables the serverless mode, in which the groups are found in a Kademlia DHT formed by the peers.
+    /// The bootstrap addresses are then used only for the first contact with the DHT, any peer in DHT mode can be one.
+    pub fn set_dht(&mut self, enabled: bool) {
+        self.dht_enabled = enabled;
+    }
+
+    /// Enables finding the peers on the LAN through multicast on the interface with the given address,
+    /// `0.0.0.0` picks the default interface. Works without any server.
+    pub fn set_lan(&mut self, interface: Ipv4Addr) -> Result<(), PeerError> {
+        self.lan = Some(LanDiscovery::new(interface, lan::LAN_GROUP)?);
+        Ok(())
+    }
+
+    /// Keeps the last known neighbours in the state file, and probes them on startup
+    /// along with the bootstrap, so reconnecting after a restart is fast.
+    pub fn set_cache(&mut self, path: &Path) -> Result<(), PeerError> {
+        self.cache = Some(Arc::new(Mutex::new(PeerCache::load(path)?)));
+        Ok(())
+    }
+
+    /// Asks the bootstrap server whether the name is free in the group, before the peer starts.
+    /// Fails if another peer uses the name, passes if the server doesn't answer in time.
+    pub fn check_name(&self) -> Result<(), PeerError> {
+        let bootstrap = match self.bootstraps.lock().ignore_poison().current() {
+            Some(bootstrap) if !self.dht_enabled => bootstrap,
+            _ => return Ok(()),
+        };
+        let group = self.groups.lock().ignore_poison()[0].clone();
+        send_req(&self.transport, &self.member_pages, &self.keyring, self.features, &self.name, &group, bootstrap)?;
+
+        let deadline = Instant::now().add(BOOTSTRAP_TIMEOUT);
+        let result = loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break Ok(());
+            }
+            self.transport.set_read_timeout(Some(left))?;
+            // The members and mails of the answer are left for the message handler
+            let packet = match self.transport.peek() {
+                Ok(packet) => packet,
+                Err(err) if err.timed_out() => break Ok(()),
+                // An earlier datagram bounced, the error is taken off the socket
+                Err(TransportError::Recv(io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionRefused)) => {
+                    let _ = self.transport.recv();
+                    continue;
+                },
+                Err(err) => break Err(err.into()),
+            };
+            let msg_type = match Header::try_from(&packet.data[..]) {
+                Ok(header) if packet.socket_addr == bootstrap => Some(header.msg_type()),
+                _ => None,
+            };
+            if msg_type == Some(MessageType::MemberRes) {
+                break Ok(());
+            }
+            let _ = self.transport.recv();
+
+            if msg_type != Some(MessageType::Reject) {
+                continue;
+            }
+            let reject = self.keyring.lock().ignore_poison().open(&packet.data).and_then(|msg| msg.into_message::<Reject>());
+            match reject {
+                Ok(msg) if msg.content().map(|r| r.peer_id() == self.name).unwrap_or(false) => {
+                    break Err(PeerError::Rejected(msg.content().unwrap().clone()));
+                },
+                _ => continue,
+            }
+        };
+
+        self.transport.set_read_timeout(None)?;
+        result
+    }
+
+    /// Returns a sender for sending commands or messages to the peer, along with the target group.
+    pub fn msg_sender(&self) -> Sender<(String, String)> {
+        self.tx.clone()
+    }
+
+    /// Returns the receiver for capturing messages from other peers.
+    pub fn msg_receiver(&self) -> Receiver<PeerEvent> {
+        self.msg_rx.clone()
+    }
+
+    /// Returns a sender for stopping the peer. The peer says goodbye to the group before `run` returns.
+    pub fn shutdown_sender(&self) -> Sender<()> {
+        self.shutdown_tx.clone()
+    }
+
+    /// Returns a sender for telling whether the user is typing in the group
+    pub fn typing_sender(&self) -> Sender<(String, bool)> {
+        self.typing_tx.clone()
+    }
+
+    /// Tells the neighbours and the server that the peer is leaving all groups
+    fn send_bye(&self) {
+        let groups: Vec<String> = self.peer_map.lock().ignore_poison().keys().cloned().collect();
+        for group in groups {
+            self.send_group_bye(&group);
+        }
+    }
+
+    /// Tells the neighbours inside the group and the server that the peer is leaving the group
+    fn send_group_bye(&self, group: &str) {
+        let bye = match Bye::new(&self.name, group) {
+            Ok(bye) => bye,
+            Err(_) => return,
+        };
+        let integrity = self.keyring.lock().ignore_poison().integrity(Some(group));
+        let peer_map = self.peer_map.lock().ignore_poison();
+        let servers = self.bootstraps.lock().ignore_poison().all();
+        let addrs = peer_map.get(group).into_iter().flat_map(|l| l.iter()).map(|p| *p.addr()).chain(servers);
+        let mut outbox = self.outbox.lock().ignore_poison();
+        for addr in addrs {
+            let msg = Message::<Bye>::new(Header::new(PROTOCOL_VERSION, MessageType::Bye, 0), Some(bye.clone()));
+            // The chat messages still waiting for the neighbour go before the bye
+            let datagrams = outbox.take_with(addr, Instant::now(), |buf| msg.encode_into(buf, &integrity, false)).unwrap_or_default();
+            for data in datagrams {
+                // TODO: log error
+                let _ = self.transport.send(TransportPacket { socket_addr: addr, data });
+            }
+        }
+    }
+
+    /// Joins the group and asks the bootstrap for its members.
+    /// With a secret the messages of the group are signed, and its messages without a valid MAC are dropped.
+    fn join_group(&self, group: &str, secret: Option<&str>) -> Result<(), PeerError> {
+        if group.is_empty() {
+            return Err(PeerError::EmptyGroup);
+        }
+        // Checks the length of the group name
+        MemberRequest::new(&self.name, group, 0, 0)?;
+
+        let mut groups = self.groups.lock().ignore_poison();
+        let joined = groups.iter().any(|g| g == group);
+        if !joined {
+            groups.push(group.to_string());
+        }
+        if !joined || secret.is_some() {
+            let integrity = secret.map(|secret| Integrity::Mac(secret.as_bytes().to_vec())).unwrap_or(Integrity::Checksum);
+            self.keyring.lock().ignore_poison().set(group, integrity);
+        }
+        self.peer_map.lock().ignore_poison().entry(group.to_string()).or_insert_with(NeighbourMap::new);
+
+        if self.dht_enabled {
+            let mut dht = self.dht.lock().ignore_poison();
+            let mut out = dht.announce(group);
+            out.extend(dht.find(group));
+            let integrity = self.keyring.lock().ignore_poison().integrity(None);
+            send_dht(&self.transport, out, &integrity);
+            return Ok(());
+        }
+
+        let mut bootstraps = self.bootstraps.lock().ignore_poison();
+        if let Some(bootstrap) = bootstraps.current() {
+            bootstraps.sent(bootstrap);
+            send_req(&self.transport, &self.member_pages, &self.keyring, self.features, &self.name, group, bootstrap)?;
+        }
+        Ok(())
+    }
+
+    /// Leaves the group and forgets its members. Fails if the group wasn't joined.
+    fn part_group(&self, group: &str) -> Result<(), PeerError> {
+        if !self.groups.lock().ignore_poison().iter().any(|g| g == group) {
+            return Err(PeerError::NotJoined);
+        }
+        self.send_group_bye(group);
+        self.groups.lock().ignore_poison().retain(|g| g != group);
+        self.keyring.lock().ignore_poison().remove(group);
+        self.peer_map.lock().ignore_poison().remove(group);
+        Ok(())
+    }
+
+    /// Sends the own presence to the neighbours in the group
+    fn send_presence(&self, group: &str) {
+        let integrity = self.keyring.lock().ignore_poison().integrity(Some(group));
+        let peer_map = self.peer_map.lock().ignore_poison();
+        if let Some(peer_list) = peer_map.get(group) {
+            send_presence(&self.transport, &self.name, group, &self.presence.lock().ignore_poison(), peer_list, &integrity);
+        }
+    }
+
+    /// Sends the chat message to the members of the group, optionally as a reply to a message of a member.
+    /// The markup of the text is sent as styled ranges.
+    fn send_chat(&mut self, group: &str, text: &str, reply_to: Option<(&str, u32)>) -> Result<(), FormatError> {
+        let header = Header::new(PROTOCOL_VERSION, message::format::MessageType::Chat, 0);
+        let msg_id = self.msg_ids.next_id();
+        let mut chat = self.marked_up_chat(group, msg_id, GOSSIP_HOP_LIMIT, text)?;
+        if let Some((peer_id, reply_id)) = reply_to {
+            chat.set_reply_to(peer_id, reply_id)?;
+        }
+        let integrity = self.keyring.lock().ignore_poison().integrity(Some(group));
+        check_fits(MessageType::Chat, &chat, &integrity)?;
+
+        // Ignore our own message when it's flooded back
+        self.seen.lock().ignore_poison().insert(&self.name, msg_id);
+        self.last_chats.lock().ignore_poison().insert((group.to_string(), self.name.clone()), msg_id);
+        let _ = self.msg_tx.send(PeerEvent::Message(group.to_string(), chat.clone()));
+
+        let peer_map = self.peer_map.lock().ignore_poison();
+        let peer_list = match peer_map.get(group) {
+            Some(peer_list) => peer_list,
+            None => return Ok(()),
+        };
+
+        for peer in peer_list.iter() {
+            let msg = Message::<Chat>::new(header, Some(chat.clone()));
+            let compress = compresses(self.features, peer.features());
+            queue_for(&self.transport, &self.outbox, *peer.addr(), |buf| msg.encode_into(buf, &integrity, compress));
+        }
+
+        // Leave the message on the server for members that went offline.
+        // The mail carries the text as typed, the recipient parses its markup again. Replies arrive as plain messages.
+        let bootstrap = self.bootstraps.lock().ignore_poison().current();
+        if let (true, Some(bootstrap)) = (self.mailbox_enabled, bootstrap) {
+            // The mails for all offline members share the datagrams
+            let mut mails = Datagrams::new(MAX_DATAGRAM);
+            for mail in peer_list.offline().iter().filter_map(|peer_id| Mail::new(group, &self.name, peer_id, text.as_bytes().to_vec()).ok()) {
+                let msg = Message::<Mail>::new(Header::new(PROTOCOL_VERSION, MessageType::Mail, 0), Some(mail));
+                let _ = mails.push(|buf| msg.encode_into(buf, &integrity, false));
+            }
+            for data in mails.finish() {
+                // TODO: log error
+                let _ = self.transport.send(TransportPacket { socket_addr: bootstrap, data });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the own chat message with the markup of the text turned into styled ranges
+    fn marked_up_chat(&self, group: &str, msg_id: u32, hops: u8, text: &str) -> Result<Chat, FormatError> {
+        let markup = Markup::parse(text);
+        let mut chat = Chat::new(self.name.clone(), group, msg_id, hops, markup.text())?;
+        chat.set_styles(markup.styles().to_vec())?;
+        chat.set_mentions(markup.mentions().to_vec())?;
+        Ok(chat)
+    }
+
+    /// Sends the edit, delete or reaction to the members of the group, the same way as the chat messages
+    fn send_action(&self, action: ChatAction) {
+        let group = action.group_name().to_string();
+        self.seen.lock().ignore_poison().insert(&self.name, action.action_id());
+        let _ = self.msg_tx.send(PeerEvent::Action(group.clone(), action.clone()));
+
+        let header = Header::new(PROTOCOL_VERSION, MessageType::ChatAction, 0);
+        let integrity = self.keyring.lock().ignore_poison().integrity(Some(&group));
+        let peer_map = self.peer_map.lock().ignore_poison();
+        for peer in peer_map.get(&group).into_iter().flat_map(|l| l.iter()) {
+            let msg = Message::<ChatAction>::new(header, Some(action.clone()));
+            let compress = compresses(self.features, peer.features());
+            queue_for(&self.transport, &self.outbox, *peer.addr(), |buf| msg.encode_into(buf, &integrity, compress));
+        }
+    }
+
+    /// Changes the last own message in the group, or reacts to the last message of a member
+    fn act_on_chat(&mut self, group: &str, cmd: &str, args: &str) -> Result<(), String> {
+        let (author, reaction) = match cmd {
+            "/react" => args.split_once(' ').map(|(author, reaction)| (author, reaction.trim()))
+                .ok_or_else(|| "usage: /react PEER REACTION".to_string())?,
+            _ => (self.name.as_str(), ""),
+        };
+        let key = (group.to_string(), author.to_string());
+        let msg_id = match self.last_chats.lock().ignore_poison().get(&key) {
+            Some(msg_id) => *msg_id,
+            None if author == self.name => return Err("no message of yours to change".to_string()),
+            None => return Err(format!("no message of {} to react to", author)),
+        };
+
+        let action_id = self.msg_ids.next_id();
+        let action = match cmd {
+            "/edit" if args.is_empty() => return Err("the edited message is empty".to_string()),
+            "/edit" => self.marked_up_chat(group, msg_id, 0, args).map(|edited| ChatAction::edit(action_id, GOSSIP_HOP_LIMIT, edited)),
+            "/delete" => {
+                self.last_chats.lock().ignore_poison().remove(&key);
+                ChatAction::delete(&self.name, group, action_id, GOSSIP_HOP_LIMIT, msg_id)
+            },
+            _ => ChatAction::react(&self.name, group, action_id, GOSSIP_HOP_LIMIT, author, msg_id, reaction),
+        };
+        let integrity = self.keyring.lock().ignore_poison().integrity(Some(group));
+        let action = action.and_then(|action| check_fits(MessageType::ChatAction, &action, &integrity).map(|_| action));
+        self.send_action(action.map_err(|err| format!("can't send the message: {}", err))?);
+        Ok(())
+    }
+
+    /// After calling this method, the current thread blocks until the peer is shut down
+    /// The peer listens for incoming messages or commands, sends requests to other peers
+    /// and maintains the connection with neighbours. Fails if the socket can't be shared with the background threads.
+    pub fn run(&mut self) -> Result<(), PeerError> {
+        // Thread for probing the neighbours
+        self.run_failure_detector_thread()?;
+
+        // Handler thread for incoming packets
+        self.run_message_handler_thread()?;
+
+        // Thread for sending the queued chat messages
+        self.run_outbox_thread()?;
+
+        if !self.federation.lock().ignore_poison().servers().is_empty() {
+            // Thread for syncing the membership with the other servers
+            self.run_federation_thread()?;
+        }
+
+        if self.mailbox_enabled {
+            // Thread for dropping the mails nobody picked up
+            self.run_mailbox_thread();
+        }
+
+        if let Some(cache) = self.cache.clone() {
+            // Probe the neighbours from the last run
+            for group in self.groups.lock().ignore_poison().iter() {
+                for addr in cache.lock().ignore_poison().start_probes(group) {
+                    let _ = send_req(&self.transport, &self.member_pages, &self.keyring, self.features, &self.name, group, addr);
+                }
+            }
+
+            // Thread for saving the neighbours
+            self.run_cache_thread(cache);
+        }
+
+        if let Some(lan) = self.lan.take() {
+            // Thread for announcing the groups on the LAN
+            self.run_lan_thread(lan)?;
+        }
+
+        let bootstrap = self.bootstraps.lock().ignore_poison().current();
+        if self.dht_enabled {
+            // Thread for finding the group members in the DHT
+            self.run_dht_thread()?;
+        } else if let Some(bootstrap) = bootstrap {
+            self.bootstraps.lock().ignore_poison().sent(bootstrap);
+            for group in self.groups.lock().ignore_poison().iter() {
+                let _ = send_req(&self.transport, &self.member_pages, &self.keyring, self.features, &self.name, group, bootstrap);
+            }
+
+            // Thread for finding new peers in the background
+            self.run_discovery_thread()?;
+        }
+
+        // Thread for sending the presence and tracking the members' one
+        self.run_presence_thread()?;
+
+        let cmd_sender = self.msg_tx.clone();
+        
+        // The main thread catches the incoming commands from the msg_sender
+        loop {
+            let cmd = select! {
+                recv(self.rx) -> cmd => cmd,
+                recv(self.typing_rx) -> typing => {
+                    if let Ok((group, typing)) = typing {
+                        let changed = self.presence.lock().ignore_poison().set_typing(&group, typing);
+                        if changed {
+                            self.send_presence(&group);
+                        }
+                    }
+                    continue;
+                },
+                recv(self.shutdown_rx) -> _ => {
+                    if let Some(cache) = &self.cache {
+                        // TODO: log error
+                        let _ = save_cache(cache, &self.peer_map.lock().ignore_poison());
+                    }
+                    self.send_bye();
+                    return Ok(());
+                },
+            };
+
+            match cmd {
+                Ok((group, cmd_str)) => {
+                    // Matching special commands:
+                    // peers - returns a list of all neighbours
+                    // stats - returns the number of dropped packets
+                    // req - send a MemberRequest to all peers to discover newly added ones
+                    // /join GROUP [SECRET] - joins another group, signing its messages with the secret
+                    // /part GROUP - leaves the group
+                    // /groups - returns a list of joined groups
+                    // /status online|away|busy [TEXT] - sets the presence shown to the other members
+                    // /reply PEER TEXT - answers the last message of the member
+                    // /edit TEXT, /delete - changes the last own message
+                    // /react PEER REACTION - reacts to the last message of the member
+                    let notice = match cmd_str.trim().split_once(' ').unwrap_or((cmd_str.trim(), "")) {
+                        ("peers", _) => format!("{:?}", self.peer_map.lock().ignore_poison()),
+                        ("stats", _) => self.drops.lock().ignore_poison().to_string(),
+                        ("req", _) => {
+                            let groups = self.groups.lock().ignore_poison().clone();
+                            let bootstrap = self.bootstraps.lock().ignore_poison().rotate();
+                            request_members(&self.transport, &self.member_pages, &self.keyring, self.features, &self.name, &groups, &self.peer_map.lock().ignore_poison(), bootstrap);
+                            continue;
+                        },
+                        ("/join", args) => {
+                            let (new_group, secret) = match args.trim().split_once(' ') {
+                                Some((new_group, secret)) => (new_group, Some(secret.trim())),
+                                None => (args.trim(), None),
+                            };
+                            match self.join_group(new_group, secret) {
+                                Ok(_) => {
+                                    let _ = self.msg_tx.send(PeerEvent::Joined(new_group.to_string()));
+                                    format!("joined {}", new_group)
+                                },
+                                Err(err) => format!("can't join {}: {}", new_group, err),
+                            }
+                        },
+                        ("/part", old_group) => match self.part_group(old_group.trim()) {
+                            Ok(_) => format!("left {}", old_group.trim()),
+                            Err(err) => format!("can't leave {}: {}", old_group.trim(), err),
+                        },
+                        ("/groups", _) => {
+                            let peer_map = self.peer_map.lock().ignore_poison();
+                            let groups: Vec<String> = self.groups
+                                .lock()
+                                .ignore_poison()
+                                .iter()
+                                .map(|g| format!("{} ({} peers)", g, peer_map.get(g).map(|l| l.count()).unwrap_or(0)))
+                                .collect();
+                            groups.join(", ")
+                        },
+                        ("/status", args) => {
+                            let (status, text) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+                            let result = status.parse().and_then(|status| self.presence.lock().ignore_poison().set_status(status, text.trim()));
+                            match result {
+                                Ok(_) => {
+                                    let groups = self.groups.lock().ignore_poison().clone();
+                                    for group in groups.iter() {
+                                        self.send_presence(group);
+                                    }
+                                    match text.trim() {
+                                        "" => format!("status set to {}", status),
+                                        text => format!("status set to {}: {}", status, text),
+                                    }
+                                },
+                                Err(err) => format!("can't set the status: {}", err),
+                            }
+                        },
+                        ("/reply", args) => {
+                            let (peer_id, text) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+                            let last = self.last_chats.lock().ignore_poison().get(&(group.clone(), peer_id.to_string())).copied();
+                            match last {
+                                None => format!("no message of {} to reply to", peer_id),
+                                Some(_) if text.trim().is_empty() => format!("the reply to {} is empty", peer_id),
+                                Some(msg_id) => match self.send_chat(&group, text.trim(), Some((peer_id, msg_id))) {
+                                    Ok(_) => continue,
+                                    Err(err) => format!("can't send the message: {}", err),
+                                },
+                            }
+                        },
+                        (cmd @ ("/edit" | "/delete" | "/react"), args) => match self.act_on_chat(&group, cmd, args.trim()) {
+                            Ok(_) => continue,
+                            Err(notice) => notice,
+                        },
+                        _ => match self.send_chat(&group, &cmd_str, None) {
+                            Ok(_) => continue,
+                            Err(err) => format!("can't send the message: {}", err),
+                        },
+                    };
+                    cmd_sender.send(PeerEvent::Notice(group, notice)).unwrap();
+                },
+                Err(err) => println!("Error on recv: {}", err),
+            }
+        }
+    }
+
+    /// Sends rounds of member requests in the background.
+    /// Rounds get less frequent while no new peers show up, and are sent right away when neighbours are lost.
+    /// Rounds rotate between the live bootstrap servers, a server that stops answering is skipped for a while.
+    fn run_discovery_thread(&self) -> Result<std::thread::JoinHandle<()>, PeerError> {
+        let peer_map_lock = self.peer_map.clone();
+        let req_sock = self.transport.try_clone()?;
+        let pages_lock = self.member_pages.clone();
+        let keyring_lock = self.keyring.clone();
+        let features = self.features;
+        let name = self.name.clone();
+        let groups_lock = self.groups.clone();
+        let bootstraps_lock = self.bootstraps.clone();
+
+        Ok(std::thread::spawn(move || {
+            let mut discovery = Discovery::new(discovery::MIN_DISCOVERY_INTERVAL, discovery::MAX_DISCOVERY_INTERVAL);
+            loop {
+                std::thread::sleep(DISCOVERY_TICK);
+
+                let groups = groups_lock.lock().ignore_poison().clone();
+                let peer_map = peer_map_lock.lock().ignore_poison();
+                let count = peer_map.values().map(|peer_list| peer_list.count()).sum();
+
+                let mut bootstraps = bootstraps_lock.lock().ignore_poison();
+                // Fail over to the next server right away
+                let failover = !bootstraps.expire(bootstrap::BOOTSTRAP_TIMEOUT).is_empty();
+                if discovery.poll(count) || failover {
+                    let bootstrap = bootstraps.rotate();
+                    drop(bootstraps);
+                    request_members(&req_sock, &pages_lock, &keyring_lock, features, &name, &groups, &peer_map, bootstrap);
+                }
+            }
+        }))
+    }
+
+    /// Joins the DHT, then announces the joined groups and looks for their members periodically.
+    fn run_dht_thread(&self) -> Result<std::thread::JoinHandle<()>, PeerError> {
+        let dht_lock = self.dht.clone();
+        let dht_sock = self.transport.try_clone()?;
+        let pages_lock = self.member_pages.clone();
+        let keyring_lock = self.keyring.clone();
+        let features = self.features;
+        let groups_lock = self.groups.clone();
+        let bootstraps_lock = self.bootstraps.clone();
+        let peer_map_lock = self.peer_map.clone();
+        let swim_lock = self.swim.clone();
+        let msg_sender = self.msg_tx.clone();
+        let name = self.name.clone();
+
+        Ok(std::thread::spawn(move || {
+            let mut last_bootstrap: Option<Instant> = None;
+            let mut last_refresh: Option<Instant> = None;
+            loop {
+                std::thread::sleep(DISCOVERY_TICK);
+
+                let groups = groups_lock.lock().ignore_poison().clone();
+                let mut dht = dht_lock.lock().ignore_poison();
+                let (mut out, events) = dht.expire();
+
+                if dht.node_count() == 0 {
+                    // Keep trying the seeds until someone answers
+                    if last_bootstrap.map(|t| t.elapsed() > discovery::MIN_DISCOVERY_INTERVAL).unwrap_or(true) {
+                        out.extend(dht.bootstrap(&bootstraps_lock.lock().ignore_poison().all()));
+                        last_bootstrap = Some(Instant::now());
+                    }
+                } else if last_refresh.map(|t| t.elapsed() > dht::DHT_REFRESH).unwrap_or(true) {
+                    // Refresh the routing table, then the records of the groups
+                    out.extend(dht.bootstrap(&[]));
+                    for group in groups.iter() {
+                        out.extend(dht.announce(group));
+                        out.extend(dht.find(group));
+                    }
+                    last_refresh = Some(Instant::now());
+                }
+                drop(dht);
+
+                let integrity = keyring_lock.lock().ignore_poison().integrity(None);
+                send_dht(&dht_sock, out, &integrity);
+                handle_dht_events(&dht_sock, &pages_lock, &keyring_lock, features, &name, events, &groups, &peer_map_lock, &swim_lock, &msg_sender);
+            }
+        }))
+    }
+
+    /// Announces the joined groups on the LAN and adds the local peers that announce them too.
+    fn run_lan_thread(&self, lan: LanDiscovery) -> Result<std::thread::JoinHandle<()>, PeerError> {
+        let lan_sock = self.transport.try_clone()?;
+        let pages_lock = self.member_pages.clone();
+        let keyring_lock = self.keyring.clone();
+        let features = self.features;
+        let port = self.transport.local_addr().map(|addr| addr.port()).unwrap_or(0);
+        let groups_lock = self.groups.clone();
+        let peer_map_lock = self.peer_map.clone();
+        let swim_lock = self.swim.clone();
+        let msg_sender = self.msg_tx.clone();
+        let name = self.name.clone();
+
+        Ok(std::thread::spawn(move || {
+            let mut last_announce: Option<Instant> = None;
+            loop {
+                let groups = groups_lock.lock().ignore_poison().clone();
+                if last_announce.map(|t| t.elapsed() > lan::ANNOUNCE_INTERVAL).unwrap_or(true) {
+                    for group in groups.iter() {
+                        if let Ok(announce) = Announce::new(&name, group, port) {
+                            let integrity = keyring_lock.lock().ignore_poison().integrity(Some(group));
+                            // TODO: log error
+                            let _ = lan.announce(announce, &integrity);
+                        }
+                    }
+                    last_announce = Some(Instant::now());
+                }
+
+                let (announce, addr) = match lan.recv(&keyring_lock) {
+                    Some(received) => received,
+                    None => continue,
+                };
+
+                let group = announce.group_name().to_string();
+                if groups.contains(&group) {
+                    let members = vec![(announce.peer_id().to_string(), addr)];
+                    add_found_members(&lan_sock, &pages_lock, &keyring_lock, features, &name, &group, members, &peer_map_lock, &swim_lock, &msg_sender);
+                }
+            }
+        }))
+    }
+
+    /// Sends the own presence periodically and the typing flags while they're set.
+    /// Lets the chat know when the members or their presence change.
+    fn run_presence_thread(&self) -> Result<std::thread::JoinHandle<()>, PeerError> {
+        let peer_map_lock = self.peer_map.clone();
+        let presence_sock = self.transport.try_clone()?;
+        let name = self.name.clone();
+        let groups_lock = self.groups.clone();
+        let presence_lock = self.presence.clone();
+        let keyring_lock = self.keyring.clone();
+        let msg_sender = self.msg_tx.clone();
+
+        Ok(std::thread::spawn(move || {
+            let mut last_sent = Instant::now();
+            let mut shown: HashMap<String, Vec<MemberInfo>> = HashMap::new();
+            loop {
+                std::thread::sleep(presence::PRESENCE_TICK);
+
+                let groups = groups_lock.lock().ignore_poison().clone();
+                let peer_map = peer_map_lock.lock().ignore_poison();
+                let mut presence = presence_lock.lock().ignore_poison();
+
+                let mut due = presence.typing_due();
+                if last_sent.elapsed() >= presence::PRESENCE_INTERVAL {
+                    last_sent = Instant::now();
+                    due = groups.clone();
+                }
+                for group in due.iter() {
+                    if let Some(peer_list) = peer_map.get(group) {
+                        let integrity = keyring_lock.lock().ignore_poison().integrity(Some(group));
+                        send_presence(&presence_sock, &name, group, &presence, peer_list, &integrity);
+                    }
+                }
+
+                presence.retain(&peer_map);
+                shown.retain(|group, _| groups.contains(group));
+                for group in groups.iter() {
+                    let members = peer_map.get(group).map(|l| presence.members(group, l)).unwrap_or_default();
+                    if shown.get(group) != Some(&members) {
+                        shown.insert(group.clone(), members.clone());
+                        let _ = msg_sender.send(PeerEvent::Members(group.clone(), members));
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Saves the neighbours to the cache file periodically.
+    fn run_cache_thread(&self, cache: Arc<Mutex<PeerCache>>) -> std::thread::JoinHandle<()> {
+        let peer_map_lock = self.peer_map.clone();
+
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(cache::CACHE_SAVE_INTERVAL);
+                // TODO: log error
+                let _ = save_cache(&cache, &peer_map_lock.lock().ignore_poison());
+            }
+        })
+    }
+
+    /// Sends the chat messages that waited for the other messages to their neighbours
+    fn run_outbox_thread(&self) -> Result<std::thread::JoinHandle<()>, PeerError> {
+        let outbox_lock = self.outbox.clone();
+        let outbox_sock = self.transport.try_clone()?;
+
+        Ok(std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(OUTBOX_DELAY);
+                let due = outbox_lock.lock().ignore_poison().due(Instant::now());
+                for (addr, data) in due {
+                    // TODO: log error
+                    let _ = outbox_sock.send(TransportPacket { socket_addr: addr, data });
+                }
+            }
+        }))
+    }
+
+    /// Drops the expired mails periodically, including the ones of recipients that never return.
+    fn run_mailbox_thread(&self) -> std::thread::JoinHandle<()> {
+        let mailbox_lock = self.mailbox.clone();
+
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(mailbox::MAIL_SWEEP_INTERVAL);
+                mailbox_lock.lock().ignore_poison().remove_expired(Instant::now());
+            }
+        })
+    }
+
+    /// Sends the membership changes to the federated servers, and the full member list once in a while.
+    fn run_federation_thread(&self) -> Result<std::thread::JoinHandle<()>, PeerError> {
+        let federation_lock = self.federation.clone();
+        let sync_sock = self.transport.try_clone()?;
+        let keyring_lock = self.keyring.clone();
+
+        Ok(std::thread::spawn(move || {
+            let mut last_full_sync = Instant::now();
+            loop {
+                std::thread::sleep(federation::SYNC_INTERVAL);
+
+                let mut federation = federation_lock.lock().ignore_poison();
+                federation.remove_expired(federation::now_millis(), federation::MEMBER_EXPIRY);
+
+                let mut entries = federation.deltas();
+                if last_full_sync.elapsed() > federation::FULL_SYNC_INTERVAL {
+                    entries = federation.snapshot();
+                    last_full_sync = Instant::now();
+                }
+
+                let integrity = keyring_lock.lock().ignore_poison().integrity(None);
+                for chunk in entries.chunks(SYNC_MAX_ENTRIES) {
+                    let sync = match MemberSync::new(chunk.to_vec()) {
+                        Ok(sync) => sync,
+                        Err(_) => continue,
+                    };
+                    for server in federation.servers() {
+                        let msg = Message::<MemberSync>::new(Header::new(PROTOCOL_VERSION, MessageType::Sync, 0), Some(sync.clone()));
+                        // TODO: log error
+                        if let Ok(data) = msg.encode(&integrity, false) {
+                            let _ = sync_sock.send(TransportPacket { socket_addr: *server, data });
+                        }
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Runs the SWIM failure detector. Pings all neighbours every protocol period,
+    /// which also keeps the NAT mappings open, asks other members to ping the unresponsive ones
+    /// and moves them through the suspect and dead states.
+    fn run_failure_detector_thread(&self) -> Result<std::thread::JoinHandle<()>, PeerError> {
+        let peer_map_lock = self.peer_map.clone();
+        let swim_lock = self.swim.clone();
+        let outbox_lock = self.outbox.clone();
+        let keyring_lock = self.keyring.clone();
+        let features = self.features;
+        let name = self.name.clone();
+        let msg_sender = self.msg_tx.clone();
+
+        let alive_sock = self.transport.try_clone()?;
+        let period_ticks = (swim::PROTOCOL_PERIOD.as_millis() / swim::ACK_TIMEOUT.as_millis()) as u64;
+
+        Ok(std::thread::spawn(move || {
+            let mut tick: u64 = 0;
+            loop {
+                std::thread::sleep(swim::ACK_TIMEOUT);
+                tick += 1;
+
+                let mut peer_map = peer_map_lock.lock().ignore_poison();
+                let mut swim = swim_lock.lock().ignore_poison();
+
+                for probe in swim.expired_probes() {
+                    match probe {
+                        // Target didn't answer, ask other members to ping it
+                        Probe::Direct { group, target } => {
+                            let peer_list = match peer_map.get(&group) {
+                                Some(peer_list) => peer_list,
+                                None => continue,
+                            };
+                            let target_addr = match peer_list.find_peer(&target) {
+                                Some(peer) => *peer.addr(),
+                                None => continue,
+                            };
+                            let helpers: Vec<SocketAddr> = peer_list
+                                .iter()
+                                .filter(|p| *p.id() != target && p.state() == MemberState::Alive)
+                                .map(|p| *p.addr())
+                                .collect();
+
+                            let seq = swim.probe(Probe::Indirect { group: group.clone(), target: target.clone() });
+                            let req = match PingReq::new(name.clone(), target.clone(), target_addr, seq) {
+                                Ok(req) => req,
+                                Err(_) => continue,
+                            };
+                            let integrity = keyring_lock.lock().ignore_poison().integrity(Some(&group));
+                            let offset = if helpers.is_empty() { 0 } else { random_u64() as usize % helpers.len() };
+                            for helper in helpers.iter().cycle().skip(offset).take(helpers.len().min(swim::INDIRECT_PROBES)) {
+                                let msg = Message::<PingReq>::new(Header::new(PROTOCOL_VERSION, MessageType::PingReq, 0), Some(req.clone()));
+                                // TODO: log error
+                                if let Ok(data) = msg.encode(&integrity, false) {
+                                    let _ = alive_sock.send(TransportPacket { socket_addr: *helper, data });
+                                }
+                            }
+                        },
+                        // Nobody could reach the target
+                        Probe::Indirect { group, target } => {
+                            if let Some(peer) = peer_map.get_mut(&group).and_then(|l| l.find_peer_mut(&target)) {
+                                if peer.apply(MemberState::Suspect, peer.incarnation()) {
+                                    if let Ok(update) = MemberUpdate::new(&group, &target, *peer.addr(), MemberState::Suspect, peer.incarnation()) {
+                                        swim.broadcast(update);
+                                    }
+                                }
+                            }
+                        },
+                        // The requester suspects the target on its own
+                        Probe::Relay { .. } => (),
+                    }
+                }
+
+                // Suspects that didn't refute in time are declared dead
+                for (group, peer_list) in peer_map.iter_mut() {
+                    for peer in peer_list.iter_mut() {
+                        if peer.suspicion_expired(swim::SUSPECT_TIMEOUT) && peer.apply(MemberState::Dead, peer.incarnation()) {
+                            if let Ok(update) = MemberUpdate::new(group, peer.id(), *peer.addr(), MemberState::Dead, peer.incarnation()) {
+                                swim.broadcast(update);
+                            }
+                        }
+                    }
+                }
+
+                if !tick.is_multiple_of(period_ticks) {
+                    continue;
+                }
+
+                for (group, peer_list) in peer_map.iter_mut() {
+                    for peer in peer_list.remove_expired() {
+                        let _ = msg_sender.send(PeerEvent::Notice(group.clone(), format!("{} timed out", peer.id())));
+                    }
+                    let integrity = keyring_lock.lock().ignore_poison().integrity(Some(group));
+                    for peer in peer_list.iter() {
+                        if peer.state() == MemberState::Dead {
+                            continue;
+                        }
+                        let seq = swim.probe(Probe::Direct { group: group.clone(), target: peer.id().clone() });
+                        let mut alive = Alive::new(name.clone(), session(), seq, swim.piggyback());
+                        alive.set_features(features);
+                        let msg = Message::<Alive>::new(Header::new(PROTOCOL_VERSION, MessageType::Alive, 0), Some(alive));
+                        // The chat messages waiting for the neighbour go along
+                        let datagrams = outbox_lock.lock().ignore_poison().take_with(*peer.addr(), Instant::now(), |buf| msg.encode_into(buf, &integrity, false));
+                        for data in datagrams.unwrap_or_default() {
+                            // TODO: log error
+                            let _ = alive_sock.send(TransportPacket { socket_addr: *peer.addr(), data });
+                        }
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Handles messages from other peers
+    fn run_message_handler_thread(&self) -> Result<std::thread::JoinHandle<()>, PeerError> {
+        let peer_map_lock = self.peer_map.clone();
+        let recv_sock = self.transport.try_clone()?;
+        let msg_sender = self.msg_tx.clone();
+        let name = self.name.clone();
+        let mailbox_enabled = self.mailbox_enabled;
+        let mailbox_lock = self.mailbox.clone();
+        let seen_lock = self.seen.clone();
+        let member_pages_lock = self.member_pages.clone();
+        let last_chats_lock = self.last_chats.clone();
+        let swim_lock = self.swim.clone();
+        let groups_lock = self.groups.clone();
+        let bootstraps_lock = self.bootstraps.clone();
+        let federation_lock = self.federation.clone();
+        let dht_enabled = self.dht_enabled;
+        let dht_lock = self.dht.clone();
+        let cache_lock = self.cache.clone();
+        let presence_lock = self.presence.clone();
+        let drops_lock = self.drops.clone();
+        let outbox_lock = self.outbox.clone();
+        let keyring_lock = self.keyring.clone();
+        let features = self.features;
+
+        // Handler thread for incoming packets
+        Ok(std::thread::spawn(move || {
+            let mut migrations = Migrations::new();
+            // Datagrams are read into the same buffer, the messages are decoded in place
+            let mut buf = [0; MAX_DATAGRAM];
+            let mut frames: VecDeque<(SocketAddr, Range<usize>)> = VecDeque::new();
+            loop {
+                // Datagrams can hold several messages, they are handled one by one
+                let packet = match frames.pop_front() {
+                    Some((socket_addr, range)) => PacketRef { socket_addr, data: &buf[range] },
+                    None => {
+                        let datagram = match recv_sock.recv_into(&mut buf) {
+                            Ok(p) => p,
+                            Err(err) => {
+                                drops_lock.lock().ignore_poison().record_socket(&err);
+                                continue;
+                            },
+                        };
+
+                        // Any message from a bootstrap server shows it's up
+                        bootstraps_lock.lock().ignore_poison().answered(&datagram.socket_addr);
+
+                        // A datagram with a broken frame is dropped whole. The messages follow each other.
+                        match message::format::frames(datagram.data).collect::<Result<Vec<_>, _>>() {
+                            Ok(messages) => {
+                                let mut start = 0;
+                                for data in messages {
+                                    frames.push_back((datagram.socket_addr, start..start + data.len()));
+                                    start += data.len();
+                                }
+                            },
+                            Err(err) => drops_lock.lock().ignore_poison().record_frame(err),
+                        }
+                        continue;
+                    },
+                };
+    
+                // Parse the header (first 4 bytes, 5 for the extended types)
+                let header = match Header::try_from(packet.data) {
+                    Ok(h) => h,
+                    Err(err) => {
+                        // Garbage and types of newer versions are skipped
+                        drops_lock.lock().ignore_poison().record_error(&err);
+                        continue;
+                    },
+                };
+                // Version 1 peers get no answer, see `MIN_PROTOCOL_VERSION`
+                if header.version() < MIN_PROTOCOL_VERSION {
+                    drops_lock.lock().ignore_poison().record_old_version(header.version());
+                    continue;
+                }
+
+                // The trailer is checked before the content is decompressed or parsed
+                let opened = keyring_lock.lock().ignore_poison().open(packet.data);
+                let msg = match opened {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        drops_lock.lock().ignore_poison().record_error(&err);
+                        continue;
+                    },
+                };
+    
+                // Route answer based on input
+                match header.msg_type() {
+                    // Alive is a direct ping, it should update the TTL inside the peer map and get an Ack
+                    MessageType::Alive => {
+                        let msg = match msg.into_message::<Alive>() {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                drops_lock.lock().ignore_poison().record_error(&err);
+                                continue;
+                            },
+                        };
+
+                        let content = msg.content().unwrap();
+                        let peer_id = content.peer_id();
+
+                        let mut group_map = peer_map_lock.lock().ignore_poison();
+                        let mut swim = swim_lock.lock().ignore_poison();
+
+                        let mut moved = false;
+                        let mut first_contact = false;
+                        for (group, peer_list) in group_map.iter_mut() {
+                            if let Some(peer) = peer_list.find_peer_mut(peer_id) {
+                                if *peer.addr() == packet.socket_addr {
+                                    first_contact |= peer.session().is_none();
+                                    peer.set_session(content.session());
+                                    peer.set_features(content.features());
+                                    peer.update_ttl(TTL_RENEWAL);
+                                    peer.confirm_alive();
+                                } else if peer.clashes_with(content.session()) {
+                                    // Only the servers can reject, a member could be the one using the name of another peer
+                                    let notice = format!("{} at {} uses the name of another peer", peer_id, packet.socket_addr);
+                                    let _ = msg_sender.send(PeerEvent::Notice(group.clone(), notice));
+                                } else {
+                                    // The address is switched only after the path challenge
+                                    moved = true;
+                                }
+                            }
+                        }
+                        let integrity = keyring_lock.lock().ignore_poison().integrity(group_of(&group_map, peer_id));
+                        if moved {
+                            send_challenge(&recv_sock, &name, &mut migrations, &integrity, peer_id, packet.socket_addr, content.session());
+                        }
+                        if first_contact {
+                            send_bind(&recv_sock, &name, &migrations, &integrity, peer_id, packet.socket_addr);
+                        }
+                        for (group, peer_id) in apply_updates(&name, content.updates(), &mut group_map, &mut swim) {
+                            let _ = msg_sender.send(PeerEvent::Notice(group, format!("{} joined the group", peer_id)));
+                        }
+
+                        let ack_msg = Ack::new(name.clone(), content.seq(), swim.piggyback())
+                            .map(|ack| Message::<Ack>::new(Header::new(PROTOCOL_VERSION, MessageType::Ack, 0), Some(ack)));
+                        // TODO: log error
+                        if let Ok(data) = ack_msg.and_then(|msg| msg.encode(&integrity, false)) {
+                            let _ = recv_sock.send(TransportPacket { socket_addr: packet.socket_addr, data });
+                        }
+                    },
+                    MessageType::Ack => {
+                        let msg = match msg.into_message::<Ack>() {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                drops_lock.lock().ignore_poison().record_error(&err);
+                                continue;
+                            },
+                        };
+
+                        let content = msg.content().unwrap();
+
+                        let mut group_map = peer_map_lock.lock().ignore_poison();
+                        let mut swim = swim_lock.lock().ignore_poison();
+
+                        match swim.ack(content.seq()) {
+                            Some(Probe::Direct { group, target }) | Some(Probe::Indirect { group, target }) if target == content.peer_id() => {
+                                if let Some(peer) = group_map.get_mut(&group).and_then(|l| l.find_peer_mut(&target)) {
+                                    peer.update_ttl(TTL_RENEWAL);
+                                    peer.confirm_alive();
+                                }
+                            },
+                            // Answer to the ping sent on behalf of another member
+                            Some(Probe::Relay { requester, seq }) => {
+                                // The requester and the target share the group the request was checked against
+                                let ack_msg = Message::<Ack>::new(Header::new(PROTOCOL_VERSION, MessageType::Ack, 0), Some(content.with_seq(seq)));
+                                // TODO: log error
+                                let integrity = keyring_lock.lock().ignore_poison().integrity(group_of(&group_map, content.peer_id()));
+                                if let Ok(data) = ack_msg.encode(&integrity, false) {
+                                    let _ = recv_sock.send(TransportPacket { socket_addr: requester, data });
+                                }
+                            },
+                            _ => (),
+                        }
+                        for (group, peer_id) in apply_updates(&name, content.updates(), &mut group_map, &mut swim) {
+                            let _ = msg_sender.send(PeerEvent::Notice(group, format!("{} joined the group", peer_id)));
+                        }
+                    },
+                    MessageType::PingReq => {
+                        let msg = match msg.into_message::<PingReq>() {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                drops_lock.lock().ignore_poison().record_error(&err);
+                                continue;
+                            },
+                        };
+
+                        let content = msg.content().unwrap();
+
+                        // Only relay between members of a shared group, so the peer can't be used to reflect traffic.
+                        // The ping to the target gets the trailer of that group.
+                        let group: Option<String> = peer_map_lock.lock().ignore_poison().iter()
+                            .find(|(_, peer_list)| {
+                                peer_list.find_peer(content.peer_id()).is_some_and(|p| *p.addr() == packet.socket_addr)
+                                    && peer_list.find_peer(content.target_id()).is_some_and(|p| p.addr() == content.target_addr())
+                            })
+                            .map(|(group, _)| group.clone());
+                        let integrity = match group {
+                            Some(group) => keyring_lock.lock().ignore_poison().integrity(Some(&group)),
+                            None => continue,
+                        };
+
+                        let mut swim = swim_lock.lock().ignore_poison();
+
+                        // Ping the target on behalf of the requester
+                        let seq = swim.probe(Probe::Relay { requester: packet.socket_addr, seq: content.seq() });
+                        let mut alive = Alive::new(name.clone(), session(), seq, swim.piggyback());
+                        alive.set_features(features);
+                        let alive_msg = Message::<Alive>::new(Header::new(PROTOCOL_VERSION, MessageType::Alive, 0), Some(alive));
+                        // TODO: log error
+                        if let Ok(data) = alive_msg.encode(&integrity, false) {
+                            let _ = recv_sock.send(TransportPacket { socket_addr: *content.target_addr(), data });
+                        }
+                    },
+                    MessageType::MemberReq => {
+                        // The names borrow from the datagram, only new members copy theirs
+                        let content = match msg.decode::<MemberRequestRef>() {
+                            Ok(content) => content,
+                            Err(err) => {
+                                drops_lock.lock().ignore_poison().record_error(&err);
+                                continue;
+                            }
+                        };
+                        let group_name = content.group_name();
+                        let peer_id = content.peer_id();
+
+                        let integrity = keyring_lock.lock().ignore_poison().integrity(Some(group_name));
+
+                        // Peers without a common version can't understand each other
+                        if !message::format::compatible(content.versions()) {
+                            send_reject(&recv_sock, peer_id, group_name, RejectReason::VersionMismatch, packet.socket_addr, &integrity);
+                            let (min, max) = content.versions();
+                            let notice = format!("{} at {} was rejected, it supports versions {}-{}", peer_id, packet.socket_addr, min, max);
+                            let _ = msg_sender.send(PeerEvent::Notice(group_name.to_string(), notice));
+                            continue;
+                        }
+                        
+                        let mut group_map = peer_map_lock.lock().ignore_poison();
+                        
+                        if !group_map.contains_key(group_name) {
+                            group_map.insert(group_name.to_string(), NeighbourMap::new());
+                        }
+                        
+                        let peer_list = group_map.get_mut(group_name).unwrap();
+                        
+                        // The mails only go to the address the member is known at, a moved member gets them after the challenge
+                        let validated = match peer_list.find_peer_mut(peer_id) {
+                            Some(peer) if *peer.addr() == packet.socket_addr => {
+                                peer.set_session(content.session());
+                                peer.set_features(content.features());
+                                send_bind(&recv_sock, &name, &migrations, &integrity, peer_id, packet.socket_addr);
+                                true
+                            },
+                            // Another peer already uses the name, the requester isn't let in
+                            Some(peer) if peer.clashes_with(content.session()) => {
+                                send_reject(&recv_sock, peer_id, group_name, RejectReason::NameTaken, packet.socket_addr, &integrity);
+                                let notice = format!("{} at {} was rejected, the name is taken", peer_id, packet.socket_addr);
+                                let _ = msg_sender.send(PeerEvent::Notice(group_name.to_string(), notice));
+                                continue;
+                            },
+                            Some(_) => {
+                                send_challenge(&recv_sock, &name, &mut migrations, &integrity, peer_id, packet.socket_addr, content.session());
+                                false
+                            },
+                            None => {
+                                // Initial TTL is set to 2 minutes
+                                let ttl = Instant::now().add(TTL_RENEWAL.add(Duration::from_secs(120)));
+                                let _ = msg_sender.send(PeerEvent::Notice(group_name.to_string(), format!("{} joined the group", peer_id)));
+                                let mut entry = NeighbourEntry::new(peer_id.to_string(), packet.socket_addr, ttl);
+                                entry.set_session(content.session());
+                                entry.set_features(content.features());
+                                peer_list.insert(entry);
+                                send_bind(&recv_sock, &name, &migrations, &integrity, peer_id, packet.socket_addr);
+                                true
+                            },
+                        };
+                        
+                        let mut response_peers: Vec<(PeerId, SocketAddr)> = peer_list
+                            //.clone()
+                            .iter()
+                            .filter(|s| s.id() != peer_id)
+                            .map(|e| (e.id().clone(), *e.addr()))
+                            .collect();
+
+                        // Add the members registered on the other servers of the federation
+                        let mut federation = federation_lock.lock().ignore_poison();
+                        if !federation.servers().is_empty() {
+                            federation.seen(group_name, peer_id, packet.socket_addr, federation::now_millis());
+                            for (id, addr) in federation.members(group_name) {
+                                if id != peer_id && !peer_list.contains_peer(&id) {
+                                    response_peers.push((id, addr));
+                                }
+                            }
+                        }
+                        drop(federation);
+
+                        // Large groups are sent page by page, the requester asks for the next one
+                        let page = match MemberResponse::page(group_name, &response_peers, content.cursor()) {
+                            Ok(page) => page,
+                            Err(_) => {
+                                // TODO: log
+                                continue;
+                            },
+                        };
+                        let res_msg = Message::<MemberResponse>::new(Header::new(PROTOCOL_VERSION, MessageType::MemberRes, 0), Some(page));
+                        let mut replies = Datagrams::new(MAX_DATAGRAM);
+                        let _ = replies.push(|buf| res_msg.encode_into(buf, &integrity, compresses(features, content.features())));
+
+                        // Deliver the mails stored while the peer was offline, along with the members
+                        if mailbox_enabled && validated {
+                            for mail in mailbox_lock.lock().ignore_poison().take(group_name, peer_id) {
+                                let msg = Message::<Mail>::new(Header::new(PROTOCOL_VERSION, MessageType::Mail, 0), Some(mail));
+                                let _ = replies.push(|buf| msg.encode_into(buf, &integrity, false));
+                            }
+                        }
+                        for data in replies.finish() {
+                            // TODO: log error
+                            let _ = recv_sock.send(TransportPacket { socket_addr: packet.socket_addr, data });
+                        }
+                    },
+                    MessageType::MemberRes => {
+                        let msg = match msg.into_message::<MemberResponse>() {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                drops_lock.lock().ignore_poison().record_error(&err);
+                                continue;
+                            },
+                        };
+
+                        let content = msg.content().unwrap();
+                        let group_name = content.group_name();
+
+                        // Ignore the members of groups the peer isn't part of
+                        if !groups_lock.lock().ignore_poison().contains(&group_name) {
+                            continue;
+                        }
+
+                        // A neighbour from the last run answered the probe
+                        let probed = cache_lock.as_ref().and_then(|c| c.lock().ignore_poison().probe_answered(&group_name, &packet.socket_addr));
+                        if let Some(peer_id) = probed {
+                            let mut peer_map = peer_map_lock.lock().ignore_poison();
+                            let mut swim = swim_lock.lock().ignore_poison();
+                            insert_members(&group_name, vec![(peer_id, packet.socket_addr)], &mut peer_map, &mut swim, &msg_sender);
+                        }
+
+                        // Only the lists this peer asked for are collected, so unsolicited pages don't trigger requests
+                        let page = member_pages_lock.lock().ignore_poison().add(packet.socket_addr, content, Instant::now());
+                        let peers = match page {
+                            Page::Complete(peers) => peers,
+                            // Ask for the rest of the list before adding the members
+                            Page::Next(cursor) => {
+                                let _ = send_req_page(&recv_sock, &keyring_lock, features, &name, &group_name, cursor, packet.socket_addr);
+                                continue;
+                            },
+                            Page::Ignored => continue,
+                        };
+    
+                        let mut peer_map = peer_map_lock.lock().ignore_poison();
+                        let mut swim = swim_lock.lock().ignore_poison();
+                        insert_members(&group_name, peers, &mut peer_map, &mut swim, &msg_sender);
+                    },
+                    MessageType::Chat => {
+                        // Decoded in place, so the copies of messages that were already delivered are dropped without allocating
+                        let chat = match msg.decode::<ChatRef>() {
+                            Ok(chat) => chat,
+                            Err(err) => {
+                                drops_lock.lock().ignore_poison().record_error(&err);
+                                continue;
+                            },
+                        };
+                        if !seen_lock.lock().ignore_poison().insert(chat.peer_id(), chat.msg_id()) {
+                            continue;
+                        }
+                        let content = &Chat::from(chat);
+
+                        let group_name = content.group_name().to_string();
+                        if groups_lock.lock().ignore_poison().contains(&group_name) {
+                            last_chats_lock.lock().ignore_poison().insert((group_name.clone(), content.peer_id()), content.msg_id());
+                            msg_sender.send(PeerEvent::Message(group_name.clone(), content.clone())).unwrap();
+                        }
+
+                        if let Some(fwd) = content.forwarded() {
+                            let integrity = keyring_lock.lock().ignore_poison().integrity(Some(&group_name));
+                            let peer_map = peer_map_lock.lock().ignore_poison();
+                            flood(&recv_sock, &outbox_lock, peer_map.get(&group_name), MessageType::Chat, fwd, packet.socket_addr, &content.peer_id(), &integrity, features);
+                        }
+                    },
+                    MessageType::ChatAction => {
+                        let msg = match msg.into_message::<ChatAction>() {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                drops_lock.lock().ignore_poison().record_error(&err);
+                                continue;
+                            },
+                        };
+                        let content = msg.content().unwrap();
+
+                        // Actions are flooded like the chat messages, the copies are dropped the same way
+                        if !seen_lock.lock().ignore_poison().insert(content.peer_id(), content.action_id()) {
+                            continue;
+                        }
+
+                        let group_name = content.group_name().to_string();
+                        if groups_lock.lock().ignore_poison().contains(&group_name) {
+                            if let ChatActionKind::Delete = content.kind() {
+                                let key = (group_name.clone(), content.author().to_string());
+                                let mut last_chats = last_chats_lock.lock().ignore_poison();
+                                if last_chats.get(&key) == Some(&content.msg_id()) {
+                                    last_chats.remove(&key);
+                                }
+                            }
+                            let _ = msg_sender.send(PeerEvent::Action(group_name.clone(), content.clone()));
+                        }
+
+                        if let Some(fwd) = content.forwarded() {
+                            let integrity = keyring_lock.lock().ignore_poison().integrity(Some(&group_name));
+                            let peer_map = peer_map_lock.lock().ignore_poison();
+                            flood(&recv_sock, &outbox_lock, peer_map.get(&group_name), MessageType::ChatAction, fwd, packet.socket_addr, content.peer_id(), &integrity, features);
+                        }
+                    },
+                    MessageType::Bye => {
+                        let msg = match msg.into_message::<Bye>() {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                drops_lock.lock().ignore_poison().record_error(&err);
+                                continue;
+                            },
+                        };
+
+                        let content = msg.content().unwrap();
+                        let mut group_map = peer_map_lock.lock().ignore_poison();
+
+                        // Only the peer itself can say goodbye
+                        let left = group_map
+                            .get_mut(content.group_name())
+                            .filter(|peer_list| peer_list.find_peer(content.peer_id()).map(|p| *p.addr()) == Some(packet.socket_addr))
+                            .and_then(|peer_list| peer_list.remove(content.peer_id()));
+
+                        if let Some(peer) = &left {
+                            let mut federation = federation_lock.lock().ignore_poison();
+                            if !federation.servers().is_empty() {
+                                federation.left(content.group_name(), content.peer_id(), *peer.addr(), federation::now_millis());
+                            }
+                        }
+
+                        if left.is_some() {
+                            let _ = msg_sender.send(PeerEvent::Notice(content.group_name().to_string(), format!("{} left the group", content.peer_id())));
+                        }
+                    },
+                    MessageType::Sync => {
+                        let msg = match msg.into_message::<MemberSync>() {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                drops_lock.lock().ignore_poison().record_error(&err);
+                                continue;
+                            },
+                        };
+
+                        // Only the federated servers can change the shared membership,
+                        // the merged changes are passed on in the next sync
+                        let mut federation = federation_lock.lock().ignore_poison();
+                        if federation.is_server(&packet.socket_addr) {
+                            federation.merge(msg.content().unwrap().entries());
+                        }
+                    },
+                    MessageType::Dht => {
+                        if !dht_enabled {
+                            continue;
+                        }
+
+                        let msg = match msg.into_message::<DhtMessage>() {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                drops_lock.lock().ignore_poison().record_error(&err);
+                                continue;
+                            },
+                        };
+
+                        let (out, events) = dht_lock.lock().ignore_poison().handle(packet.socket_addr, msg.content().unwrap());
+                        let integrity = keyring_lock.lock().ignore_poison().integrity(None);
+                        send_dht(&recv_sock, out, &integrity);
+
+                        let groups = groups_lock.lock().ignore_poison().clone();
+                        handle_dht_events(&recv_sock, &member_pages_lock, &keyring_lock, features, &name, events, &groups, &peer_map_lock, &swim_lock, &msg_sender);
+                    },
+                    // Announcements only arrive on the LAN socket
+                    MessageType::Announce => continue,
+                    MessageType::Reject => {
+                        let msg = match msg.into_message::<Reject>() {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                drops_lock.lock().ignore_poison().record_error(&err);
+                                continue;
+                            },
+                        };
+
+                        let content = msg.content().unwrap();
+                        let group = content.group_name();
+                        if content.peer_id() != name || !groups_lock.lock().ignore_poison().iter().any(|g| g == group) {
+                            continue;
+                        }
+
+                        // Only a bootstrap server answering our own member request can reject us,
+                        // the members of the group can't tell which of two peers with the same name came first
+                        let asked = bootstraps_lock.lock().ignore_poison().all().contains(&packet.socket_addr)
+                            && member_pages_lock.lock().ignore_poison().rejected(group, packet.socket_addr, Instant::now());
+                        if !asked {
+                            continue;
+                        }
+
+                        // Leave the group instead of getting mixed up with the other peer, the same way as `part_group`
+                        groups_lock.lock().ignore_poison().retain(|g| g != group);
+                        keyring_lock.lock().ignore_poison().remove(group);
+                        peer_map_lock.lock().ignore_poison().remove(group);
+                        let _ = msg_sender.send(PeerEvent::Notice(group.to_string(), format!("left {}: {}", group, content)));
+                    },
+                    MessageType::Presence => {
+                        let msg = match msg.into_message::<Presence>() {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                drops_lock.lock().ignore_poison().record_error(&err);
+                                continue;
+                            },
+                        };
+
+                        // Only the members of the group can set their presence in it
+                        let content = msg.content().unwrap();
+                        let group_map = peer_map_lock.lock().ignore_poison();
+                        let member = group_map
+                            .get(content.group_name())
+                            .and_then(|l| l.find_peer(content.peer_id()))
+                            .map(|p| *p.addr() == packet.socket_addr)
+                            .unwrap_or(false);
+                        if member {
+                            presence_lock.lock().ignore_poison().update(content);
+                        }
+                    },
+                    MessageType::Challenge => {
+                        let msg = match msg.into_message::<Challenge>() {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                drops_lock.lock().ignore_poison().record_error(&err);
+                                continue;
+                            },
+                        };
+
+                        let content = msg.content().unwrap();
+
+                        match content.kind() {
+                            // Echo the token to show that we're reachable on this address,
+                            // with the proof of the key the challenger handed out
+                            ChallengeKind::Request => {
+                                let proof = migrations.proof(content.peer_id(), content.token());
+                                if let Ok(response) = Challenge::new(&name, ChallengeKind::Response, content.token(), proof) {
+                                    let response_msg = Message::<Challenge>::new(Header::new(PROTOCOL_VERSION, MessageType::Challenge, 0), Some(response));
+                                    // TODO: log error
+                                    let peer_map = peer_map_lock.lock().ignore_poison();
+                                    let integrity = keyring_lock.lock().ignore_poison().integrity(group_of(&peer_map, content.peer_id()));
+                                    drop(peer_map);
+                                    if let Ok(data) = response_msg.encode(&integrity, false) {
+                                        let _ = recv_sock.send(TransportPacket { socket_addr: packet.socket_addr, data });
+                                    }
+                                }
+                                continue;
+                            },
+                            // The key is only taken from the address the peer is known at, or from a bootstrap server
+                            ChallengeKind::Bind => {
+                                let known = peer_map_lock.lock().ignore_poison().values().any(|peer_list| {
+                                    peer_list.find_peer(content.peer_id()).is_some_and(|p| *p.addr() == packet.socket_addr)
+                                });
+                                if known || bootstraps_lock.lock().ignore_poison().all().contains(&packet.socket_addr) {
+                                    migrations.set_key(content.peer_id(), content.token());
+                                }
+                                continue;
+                            },
+                            ChallengeKind::Response => (),
+                        }
+
+                        let session = match migrations.validate(content.peer_id(), packet.socket_addr, content.token(), content.proof()) {
+                            Some(session) => session,
+                            None => continue,
+                        };
+
+                        let mut group_map = peer_map_lock.lock().ignore_poison();
+                        for (group, peer_list) in group_map.iter_mut() {
+                            if let Some(peer) = peer_list.find_peer_mut(content.peer_id()) {
+                                let old_addr = *peer.addr();
+                                peer.set_addr(packet.socket_addr);
+                                peer.set_session(session);
+                                peer.update_ttl(TTL_RENEWAL);
+                                peer.confirm_alive();
+                                let notice = format!("{} moved from {} to {}", content.peer_id(), old_addr, packet.socket_addr);
+                                let _ = msg_sender.send(PeerEvent::Notice(group.clone(), notice));
+                            }
+                        }
+                    },
+                    MessageType::Mail => {
+                        let msg = match msg.into_message::<Mail>() {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                drops_lock.lock().ignore_poison().record_error(&err);
+                                continue;
+                            },
+                        };
+                        let mail = msg.content().unwrap().clone();
+
+                        if mail.recipient() == name {
+                            // Mail delivered by the server, sent while this peer was offline.
+                            // Only the bootstrap servers hold mails, and only the ones of the joined groups are shown.
+                            let from_server = bootstraps_lock.lock().ignore_poison().all().contains(&packet.socket_addr);
+                            if !from_server || !groups_lock.lock().ignore_poison().iter().any(|g| g == mail.group_name()) {
+                                continue;
+                            }
+                            let markup = Markup::parse(&String::from_utf8_lossy(mail.payload()));
+                            let chat = Chat::new(mail.sender().to_string(), mail.group_name(), 0, 0, &format!("{} (sent while offline)", markup.text()))
+                                .and_then(|mut chat| {
+                                    chat.set_styles(markup.styles().to_vec())?;
+                                    chat.set_mentions(markup.mentions().to_vec())?;
+                                    Ok(chat)
+                                });
+                            if let Ok(chat) = chat {
+                                msg_sender.send(PeerEvent::Message(mail.group_name().to_string(), chat)).unwrap();
+                            }
+                        } else if mailbox_enabled {
+                            // TODO: log dropped mail over quota
+                            mailbox_lock.lock().ignore_poison().deposit(mail);
+                        }
+                    },
+                }
+            }
+        }))
+    }
+}
+
+/// Sends a member request for the group to the peer.
+/// The answer is expected, so its pages are collected.
+fn send_req(transport: &UdpTransport, pages: &Mutex<MemberPages>, keyring: &Mutex<Keyring>, features: u8, name: &str, group: &str, peer_socket: SocketAddr) -> Result<(), PeerError> {
+    pages.lock().ignore_poison().requested(group, peer_socket, Instant::now());
+    send_req_page(transport, keyring, features, name, group, 0, peer_socket)
+}
+
+/// Sends a member request for the page of the member list starting at the cursor.
+/// The features tell the peer whether it can compress the answer.
+fn send_req_page(transport: &UdpTransport, keyring: &Mutex<Keyring>, features: u8, name: &str, group: &str, cursor: u16, peer_socket: SocketAddr) -> Result<(), PeerError> {
+    let header = Header::new(PROTOCOL_VERSION, message::format::MessageType::MemberReq, 0);
+    let mut req = MemberRequest::new(name, group, cursor, session())?;
+    req.set_features(features);
+    let msg = Message::<MemberRequest>::new(header, Some(req));
+    let integrity = keyring.lock().ignore_poison().integrity(Some(group));
+    let buf = msg.encode(&integrity, false)?;
+    transport.send(TransportPacket {
+        socket_addr: peer_socket,
+        data: buf,
+    })?;
+    Ok(())
+}
+
+/// Fails if the message doesn't fit in a datagram of its own. The encoded size counts, so the names,
+/// the markup and the trailer of the group take room along with the text. Compressed contents only get shorter.
+fn check_fits<T: MessageContent>(msg_type: MessageType, content: &T, integrity: &Integrity) -> Result<(), FormatError> {
+    let data = Message::new(Header::new(PROTOCOL_VERSION, msg_type, 0), Some(content.clone())).encode(integrity, false)?;
+    if data.len() > MAX_DATAGRAM {
+        return Err(FormatError::TooLong { field: "message", max: MAX_DATAGRAM });
+    }
+    Ok(())
+}
+
+/// Returns a group the neighbour is in. The messages without a group, like the pings, get the trailer of this group.
+fn group_of<'a>(peer_map: &'a HashMap<String, NeighbourMap>, peer_id: &str) -> Option<&'a str> {
+    peer_map.iter()
+        .find(|(_, peer_list)| peer_list.find_peer(peer_id).is_some())
+        .map(|(group, _)| group.as_str())
+}
+
+/// Sends a path challenge to the new address of the peer, unless one is already pending
+fn send_challenge(transport: &UdpTransport, name: &str, migrations: &mut Migrations, integrity: &Integrity, peer_id: &str, addr: SocketAddr, session: u64) {
+    let token = match migrations.challenge(peer_id, addr, session) {
+        Some(token) => token,
+        None => return,
+    };
+
+    if let Ok(challenge) = Challenge::new(name, ChallengeKind::Request, token, 0) {
+        let msg = Message::<Challenge>::new(Header::new(PROTOCOL_VERSION, MessageType::Challenge, 0), Some(challenge));
+        // TODO: log error
+        if let Ok(data) = msg.encode(integrity, false) {
+            let _ = transport.send(TransportPacket { socket_addr: addr, data });
+        }
+    }
+}
+
+/// Hands the peer its key for proving later address changes, sent to the address it joined from
+fn send_bind(transport: &UdpTransport, name: &str, migrations: &Migrations, integrity: &Integrity, peer_id: &str, addr: SocketAddr) {
+    if let Ok(bind) = Challenge::new(name, ChallengeKind::Bind, migrations.key_for(peer_id), 0) {
+        let msg = Message::<Challenge>::new(Header::new(PROTOCOL_VERSION, MessageType::Challenge, 0), Some(bind));
+        // TODO: log error
+        if let Ok(data) = msg.encode(integrity, false) {
+            let _ = transport.send(TransportPacket { socket_addr: addr, data });
+        }
+    }
+}
+
+/// Sends the own presence in the group to its members
+fn send_presence(transport: &UdpTransport, name: &str, group: &str, presence: &PresenceMap, peer_list: &NeighbourMap, integrity: &Integrity) {
+    let own = match presence.own(name, group) {
+        Ok(own) => own,
+        Err(_) => return,
+    };
+
+    for peer in peer_list.iter() {
+        let msg = Message::<Presence>::new(Header::new(PROTOCOL_VERSION, MessageType::Presence, 0), Some(own.clone()));
+        // TODO: log error
+        if let Ok(data) = msg.encode(integrity, false) {
+            let _ = transport.send(TransportPacket { socket_addr: *peer.addr(), data });
+        }
+    }
+}
+
+/// Tells the peer at the address that it can't be in the group
+fn send_reject(transport: &UdpTransport, peer_id: &str, group: &str, reason: RejectReason, addr: SocketAddr, integrity: &Integrity) {
+    if let Ok(reject) = Reject::new(peer_id, group, reason) {
+        let msg = Message::<Reject>::new(Header::new(PROTOCOL_VERSION, MessageType::Reject, 0), Some(reject));
+        // TODO: log error
+        if let Ok(data) = msg.encode(integrity, false) {
+            let _ = transport.send(TransportPacket { socket_addr: addr, data });
+        }
+    }
+}
+
+/// Records the current neighbours in the cache and writes it to the file
+fn save_cache(cache: &Mutex<PeerCache>, peer_map: &HashMap<String, NeighbourMap>) -> std::io::Result<()> {
+    let mut cache = cache.lock().ignore_poison();
+    for (group, peer_list) in peer_map.iter() {
+        cache.update(group, peer_list);
+    }
+    cache.save()
+}
+
+/// Sends the DHT messages, with the trailer of the first joined group
+fn send_dht(transport: &UdpTransport, out: DhtOutput, integrity: &Integrity) {
+    for (addr, dht_msg) in out {
+        let msg = Message::<DhtMessage>::new(Header::new(PROTOCOL_VERSION, MessageType::Dht, 0), Some(dht_msg));
+        // TODO: log error
+        if let Ok(data) = msg.encode(integrity, false) {
+            let _ = transport.send(TransportPacket { socket_addr: addr, data });
+        }
+    }
+}
+
+/// Re-floods a chat message or action to the neighbours in the group, so it reaches peers the origin can't reach directly.
+/// The neighbour it came from and the origin are skipped.
+#[allow(clippy::too_many_arguments)]
+fn flood<T: MessageContent>(transport: &UdpTransport, outbox: &Mutex<Outbox>, peer_list: Option<&NeighbourMap>, msg_type: MessageType, content: T, from: SocketAddr, origin: &str, integrity: &Integrity, features: u8) {
+    let header = Header::new(PROTOCOL_VERSION, msg_type, 0);
+    for peer in peer_list.into_iter().flat_map(|l| l.iter()) {
+        if *peer.addr() == from || peer.id() == origin {
+            continue;
+        }
+        let msg = Message::<T>::new(header, Some(content.clone()));
+        let compress = compresses(features, peer.features());
+        queue_for(transport, outbox, *peer.addr(), |buf| msg.encode_into(buf, integrity, compress));
+    }
+}
+
+/// Returns true if the messages to the neighbour are compressed, both peers have to read compressed contents
+fn compresses(features: u8, peer_features: u8) -> bool {
+    features & peer_features & FEATURE_COMPRESSION != 0
+}
+
+/// Queues the message written by `encode` for the neighbour, to be sent together with the next messages to it
+fn queue_for(transport: &UdpTransport, outbox: &Mutex<Outbox>, addr: SocketAddr, encode: impl FnOnce(&mut Vec<u8>) -> Result<(), FormatError>) {
+    // TODO: log error
+    for data in outbox.lock().ignore_poison().push(addr, Instant::now(), encode).unwrap_or_default() {
+        // TODO: log error
+        let _ = transport.send(TransportPacket { socket_addr: addr, data });
+    }
+}
+
+/// Adds the group members found in the DHT
+#[allow(clippy::too_many_arguments)]
+fn handle_dht_events(transport: &UdpTransport, pages: &Mutex<MemberPages>, keyring: &Mutex<Keyring>, features: u8, name: &str, events: Vec<DhtEvent>, groups: &[String], peer_map_lock: &Mutex<HashMap<String, NeighbourMap>>, swim_lock: &Mutex<Swim>, msg_sender: &Sender<PeerEvent>) {
+    for DhtEvent::Found(group, members) in events {
+        if groups.contains(&group) {
+            add_found_members(transport, pages, keyring, features, name, &group, members, peer_map_lock, swim_lock, msg_sender);
+        }
+    }
+}
+
+/// Adds the group members found without a server and sends them a member request,
+/// so they add this peer too and share the rest of the group.
+#[allow(clippy::too_many_arguments)]
+fn add_found_members(transport: &UdpTransport, pages: &Mutex<MemberPages>, keyring: &Mutex<Keyring>, features: u8, name: &str, group: &str, members: Vec<(PeerId, SocketAddr)>, peer_map_lock: &Mutex<HashMap<String, NeighbourMap>>, swim_lock: &Mutex<Swim>, msg_sender: &Sender<PeerEvent>) {
+    let members = members.into_iter().filter(|(id, _)| id != name).collect();
+    let added = {
+        let mut peer_map = peer_map_lock.lock().ignore_poison();
+        let mut swim = swim_lock.lock().ignore_poison();
+        insert_members(group, members, &mut peer_map, &mut swim, msg_sender)
+    };
+    for (_, addr) in added {
+        let _ = send_req(transport, pages, keyring, features, name, group, addr);
+    }
+}
+
+/// Adds the members that aren't known yet to the group, and lets the chat and the rest of the group know about them.
+/// Returns the added members.
+fn insert_members(group_name: &str, peers: Vec<(PeerId, SocketAddr)>, peer_map: &mut HashMap<String, NeighbourMap>, swim: &mut Swim, msg_sender: &Sender<PeerEvent>) -> Vec<(PeerId, SocketAddr)> {
+    let peer_list = peer_map.entry(group_name.to_string()).or_insert_with(NeighbourMap::new);
+
+    let mut added = vec![];
+    for (peer_id, peer_addr) in peers {
+        if !peer_list.contains_peer(&peer_id) {
+            let ttl = Instant::now().add(TTL_RENEWAL);
+            peer_list.insert(NeighbourEntry::new(peer_id.to_string(), peer_addr, ttl));
+            let _ = msg_sender.send(PeerEvent::Notice(group_name.to_string(), format!("{} joined the group", peer_id)));
+
+            // Let the rest of the group know about the new member
+            if let Ok(update) = MemberUpdate::new(group_name, &peer_id, peer_addr, MemberState::Alive, 0) {
+                swim.broadcast(update);
+            }
+            added.push((peer_id, peer_addr));
+        }
+    }
+    added
+}
+
+/// Sends a member request for each group to all its peers to discover newly added ones.
+#[allow(clippy::too_many_arguments)]
+fn request_members(transport: &UdpTransport, pages: &Mutex<MemberPages>, keyring: &Mutex<Keyring>, features: u8, name: &str, groups: &[String], peer_map: &HashMap<String, NeighbourMap>, bootstrap: Option<SocketAddr>) {
+    for group in groups {
+        for peer in peer_map.get(group).into_iter().flat_map(|l| l.iter()) {
+            let _ = send_req(transport, pages, keyring, features, name, group, *peer.addr());
+        }
+        // Send to bootstrap since he has a stable address
+        // Although this fights the purpose of the bootstrap peer,
+        // it's easier and faster to get a more stable connection
+        // The proper way would be to introduce "stable peers"
+        if let Some(bootstrap) = bootstrap {
+            let _ = send_req(transport, pages, keyring, features, name, group, bootstrap);
+        }
+    }
+}
+
+/// Merges the piggybacked membership updates into the peer map
+/// and spreads the ones that changed something further.
+/// Returns the ids of the members that weren't known before.
+fn apply_updates(name: &str, updates: &[MemberUpdate], peer_map: &mut HashMap<String, NeighbourMap>, swim: &mut Swim) -> Vec<(String, PeerId)> {
+    let mut joined = vec![];
+    for update in updates {
+        // Refute the suspicion about ourselves
+        if update.peer_id() == name {
+            if update.state() != MemberState::Alive && update.incarnation() >= swim.incarnation() {
+                let incarnation = swim.refute(update.incarnation());
+                let addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+                if let Ok(refutation) = MemberUpdate::new(update.group_name(), name, addr, MemberState::Alive, incarnation) {
+                    swim.broadcast(refutation);
+                }
+            }
+            continue;
+        }
+
+        let peer_list = match peer_map.get_mut(update.group_name()) {
+            Some(peer_list) => peer_list,
+            None => continue,
+        };
+
+        match peer_list.find_peer_mut(update.peer_id()) {
+            Some(peer) => {
+                if !peer.apply(update.state(), update.incarnation()) {
+                    continue;
+                }
+                if update.state() == MemberState::Alive {
+                    peer.update_ttl(TTL_RENEWAL);
+                }
+                if let Ok(update) = MemberUpdate::new(update.group_name(), peer.id(), *peer.addr(), update.state(), update.incarnation()) {
+                    swim.broadcast(update);
+                }
+            },
+            // Member we didn't know about, unless it's a refutation without an address
+            None => {
+                if update.state() == MemberState::Alive && !update.addr().ip().is_unspecified() {
+                    let mut entry = NeighbourEntry::new(update.peer_id().to_string(), *update.addr(), Instant::now().add(TTL_RENEWAL));
+                    entry.apply(MemberState::Alive, update.incarnation());
+                    peer_list.insert(entry);
+                    swim.broadcast(update.clone());
+                    joined.push((update.group_name().to_string(), update.peer_id().to_string()));
+                }
+            },
+        }
+    }
+    joined
+}
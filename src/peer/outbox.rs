@@ -0,0 +1,125 @@
+use std::{collections::HashMap, net::SocketAddr, time::{Duration, Instant}};
+
+use crate::message::format::{Datagrams, FormatError};
+
+/// Time a chat message waits for other messages to the same neighbour before it's sent
+pub static OUTBOX_DELAY: Duration = Duration::from_millis(20);
+
+/// Most messages waiting for a single neighbour, the rest is sent right away
+const OUTBOX_MAX_MESSAGES: usize = 64;
+
+struct Pending {
+    datagrams: Datagrams,
+    messages: usize,
+    /// Time the oldest message was queued
+    since: Instant,
+}
+
+/// Messages waiting to be sent to the neighbours, encoded into datagrams of at most `max` bytes.
+/// Chat messages wait for a moment, so the ones going to the same neighbour share a datagram
+/// and the next `Alive` to the neighbour takes them along.
+pub struct Outbox {
+    pending: HashMap<SocketAddr, Pending>,
+    max: usize,
+}
+
+impl Outbox {
+    pub fn new(max: usize) -> Outbox {
+        Outbox { pending: HashMap::new(), max }
+    }
+
+    /// Queues the message written by `encode` for the neighbour, see `Datagrams::push`.
+    /// Returns the datagrams to send right away if the queue of the neighbour is full.
+    pub fn push(&mut self, addr: SocketAddr, now: Instant, encode: impl FnOnce(&mut Vec<u8>) -> Result<(), FormatError>) -> Result<Vec<Vec<u8>>, FormatError> {
+        let max = self.max;
+        let pending = self.pending.entry(addr).or_insert_with(|| Pending { datagrams: Datagrams::new(max), messages: 0, since: now });
+        let pushed = pending.datagrams.push(encode);
+        if pushed.is_ok() {
+            pending.messages += 1;
+        }
+        match pending.messages {
+            0 => { self.pending.remove(&addr); },
+            messages if messages >= OUTBOX_MAX_MESSAGES => return pushed.map(|_| self.take(addr)),
+            _ => {},
+        }
+        pushed.map(|_| vec![])
+    }
+
+    /// Takes the datagrams waiting for the neighbour
+    pub fn take(&mut self, addr: SocketAddr) -> Vec<Vec<u8>> {
+        self.pending.remove(&addr).map(|pending| pending.datagrams.finish()).unwrap_or_default()
+    }
+
+    /// Writes the message after the ones waiting for the neighbour and takes them all, e.g. an `Alive` taking the chats along
+    pub fn take_with(&mut self, addr: SocketAddr, now: Instant, encode: impl FnOnce(&mut Vec<u8>) -> Result<(), FormatError>) -> Result<Vec<Vec<u8>>, FormatError> {
+        let mut datagrams = self.push(addr, now, encode)?;
+        datagrams.extend(self.take(addr));
+        Ok(datagrams)
+    }
+
+    /// Takes the datagrams of the neighbours whose messages waited for `OUTBOX_DELAY`
+    pub fn due(&mut self, now: Instant) -> Vec<(SocketAddr, Vec<u8>)> {
+        let due: Vec<SocketAddr> = self.pending
+            .iter()
+            .filter(|(_, pending)| now.duration_since(pending.since) >= OUTBOX_DELAY)
+            .map(|(addr, _)| *addr)
+            .collect();
+        due.into_iter()
+            .flat_map(|addr| self.take(addr).into_iter().map(move |data| (addr, data)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(bytes: Vec<u8>) -> impl FnOnce(&mut Vec<u8>) -> Result<(), FormatError> {
+        move |buf| {
+            buf.extend(bytes);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn coalescing() {
+        let mut outbox = Outbox::new(100);
+        let now = Instant::now();
+        let a: SocketAddr = "10.0.0.1:2000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:2000".parse().unwrap();
+
+        assert!(outbox.push(a, now, raw(vec![1; 10])).unwrap().is_empty());
+        assert!(outbox.push(a, now, raw(vec![2; 10])).unwrap().is_empty());
+        assert!(outbox.push(b, now, raw(vec![3; 10])).unwrap().is_empty());
+        assert!(outbox.due(now).is_empty());
+
+        // The alive to the neighbour takes the waiting chats along
+        let datagrams = outbox.take_with(a, now, raw(vec![0; 5])).unwrap();
+        assert_eq!(datagrams, vec![[vec![1; 10], vec![2; 10], vec![0; 5]].concat()]);
+        assert!(outbox.take(a).is_empty());
+
+        // Messages that don't fit together get datagrams of their own
+        assert_eq!(outbox.due(now + OUTBOX_DELAY), vec![(b, vec![3; 10])]);
+        outbox.push(b, now, raw(vec![4; 60])).unwrap();
+        outbox.push(b, now, raw(vec![5; 60])).unwrap();
+        assert_eq!(outbox.due(now + OUTBOX_DELAY), vec![(b, vec![4; 60]), (b, vec![5; 60])]);
+        assert!(outbox.due(now + OUTBOX_DELAY).is_empty());
+
+        // A message that fails to encode isn't queued
+        let err = FormatError::TooLong { field: "content", max: 1 };
+        assert_eq!(outbox.push(a, now, |_| Err(err.clone())), Err(err));
+        assert!(outbox.due(now + OUTBOX_DELAY).is_empty());
+    }
+
+    #[test]
+    fn full_queue() {
+        let mut outbox = Outbox::new(100);
+        let now = Instant::now();
+        let addr: SocketAddr = "10.0.0.1:2000".parse().unwrap();
+        for _ in 1..OUTBOX_MAX_MESSAGES {
+            assert!(outbox.push(addr, now, raw(vec![1])).unwrap().is_empty());
+        }
+        assert_eq!(outbox.push(addr, now, raw(vec![1])).unwrap(), vec![vec![1; OUTBOX_MAX_MESSAGES]]);
+        assert!(outbox.due(now + OUTBOX_DELAY).is_empty());
+    }
+}
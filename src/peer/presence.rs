@@ -0,0 +1,178 @@
+use std::{collections::HashMap, time::{Duration, Instant}, ops::Add};
+
+use crate::message::format::{Presence, PresenceStatus, FormatError, MemberState};
+
+use super::structures::{PeerId, NeighbourMap};
+
+/// Period of checking the presence of the members and the typing flags
+pub static PRESENCE_TICK: Duration = Duration::from_millis(500);
+
+/// Period between two presence messages, so new members learn about the others
+pub static PRESENCE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Period between two presence messages while the user is typing
+pub static TYPING_REFRESH: Duration = Duration::from_secs(2);
+
+/// Time after which a typing flag is dropped if it isn't refreshed
+pub static TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Presence of a group member as shown in the chat
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemberInfo {
+    pub id: PeerId,
+    pub status: PresenceStatus,
+    pub text: String,
+    pub typing: bool,
+    /// The member stopped answering pings
+    pub suspect: bool,
+}
+
+struct KnownPresence {
+    status: PresenceStatus,
+    text: String,
+    typing_until: Option<Instant>,
+}
+
+/// Own presence and the last presence heard from the members of every group
+pub struct PresenceMap {
+    status: PresenceStatus,
+    text: String,
+    /// Groups the user is typing in, with the time of the last sent presence
+    typing: HashMap<String, Instant>,
+    peers: HashMap<(String, PeerId), KnownPresence>,
+}
+
+impl PresenceMap {
+    pub fn new() -> PresenceMap {
+        PresenceMap { status: PresenceStatus::Online, text: String::new(), typing: HashMap::new(), peers: HashMap::new() }
+    }
+
+    /// Sets the own status, fails if the text is too long
+    pub fn set_status(&mut self, status: PresenceStatus, text: &str) -> Result<(), FormatError> {
+        // Checks the length of the text
+        Presence::new("", "", status, false, text)?;
+        self.status = status;
+        self.text = text.to_string();
+        Ok(())
+    }
+
+    /// Sets whether the user is typing in the group. Returns true if it changed.
+    pub fn set_typing(&mut self, group: &str, typing: bool) -> bool {
+        if !typing {
+            return self.typing.remove(group).is_some();
+        }
+        if self.typing.contains_key(group) {
+            return false;
+        }
+        self.typing.insert(group.to_string(), Instant::now());
+        true
+    }
+
+    /// Returns the groups whose typing flag has to be refreshed, and marks them refreshed
+    pub fn typing_due(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        self.typing
+            .iter_mut()
+            .filter(|(_, sent)| now.duration_since(**sent) >= TYPING_REFRESH)
+            .map(|(group, sent)| {
+                *sent = now;
+                group.clone()
+            })
+            .collect()
+    }
+
+    /// Returns the own presence in the group
+    pub fn own(&self, name: &str, group: &str) -> Result<Presence, FormatError> {
+        Presence::new(name, group, self.status, self.typing.contains_key(group), &self.text)
+    }
+
+    /// Records the presence sent by a member
+    pub fn update(&mut self, presence: &Presence) {
+        let typing_until = match presence.is_typing() {
+            true => Some(Instant::now().add(TYPING_TIMEOUT)),
+            false => None,
+        };
+        let known = KnownPresence { status: presence.status(), text: presence.text().to_string(), typing_until };
+        self.peers.insert((presence.group_name().to_string(), presence.peer_id().to_string()), known);
+    }
+
+    /// Forgets the members that are no longer in the group
+    pub fn retain(&mut self, peer_map: &HashMap<String, NeighbourMap>) {
+        self.peers.retain(|(group, id), _| peer_map.get(group).map(|l| l.contains_peer(id)).unwrap_or(false));
+    }
+
+    /// Returns the presence of the group members, sorted by name.
+    /// Members that didn't send their presence yet are shown online.
+    pub fn members(&self, group: &str, peer_list: &NeighbourMap) -> Vec<MemberInfo> {
+        let now = Instant::now();
+        let mut members: Vec<MemberInfo> = peer_list
+            .iter()
+            .filter(|p| p.state() != MemberState::Dead)
+            .map(|p| {
+                let known = self.peers.get(&(group.to_string(), p.id().clone()));
+                MemberInfo {
+                    id: p.id().clone(),
+                    status: known.map(|k| k.status).unwrap_or(PresenceStatus::Online),
+                    text: known.map(|k| k.text.clone()).unwrap_or_default(),
+                    typing: known.and_then(|k| k.typing_until).map(|until| until > now).unwrap_or(false),
+                    suspect: p.state() == MemberState::Suspect,
+                }
+            })
+            .collect();
+        members.sort_by(|a, b| a.id.cmp(&b.id));
+        members
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::peer::structures::NeighbourEntry;
+
+    use super::*;
+
+    #[test]
+    fn member_presence() {
+        let ttl = Instant::now().add(Duration::from_secs(30));
+        let mut peer_list = NeighbourMap::new();
+        peer_list.insert(NeighbourEntry::new("peer-b".to_string(), "10.0.0.2:2000".parse().unwrap(), ttl));
+        peer_list.insert(NeighbourEntry::new("peer-a".to_string(), "10.0.0.1:2000".parse().unwrap(), ttl));
+
+        let mut presence = PresenceMap::new();
+        presence.update(&Presence::new("peer-b", "grp", PresenceStatus::Busy, true, "meeting").unwrap());
+        presence.update(&Presence::new("peer-c", "grp", PresenceStatus::Away, false, "").unwrap());
+
+        let members = presence.members("grp", &peer_list);
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].id, "peer-a");
+        assert_eq!(members[0].status, PresenceStatus::Online);
+        assert_eq!(members[1].status, PresenceStatus::Busy);
+        assert_eq!(members[1].text, "meeting");
+        assert!(members[1].typing);
+
+        // Presence of peers outside the neighbour map is dropped
+        let mut peer_map = HashMap::new();
+        peer_map.insert("grp".to_string(), peer_list);
+        presence.retain(&peer_map);
+        assert_eq!(presence.peers.len(), 1);
+    }
+
+    #[test]
+    fn own_presence() {
+        let mut presence = PresenceMap::new();
+        presence.set_status(PresenceStatus::Away, "brb").unwrap();
+        assert!(presence.set_status(PresenceStatus::Away, &"x".repeat(100)).is_err());
+
+        assert!(presence.set_typing("grp", true));
+        assert!(!presence.set_typing("grp", true));
+        assert!(presence.typing_due().is_empty());
+
+        let own = presence.own("peer-a", "grp").unwrap();
+        assert_eq!(own.status(), PresenceStatus::Away);
+        assert_eq!(own.text(), "brb");
+        assert!(own.is_typing());
+        assert!(!presence.own("peer-a", "other").unwrap().is_typing());
+
+        assert!(presence.set_typing("grp", false));
+        assert!(!presence.set_typing("grp", false));
+    }
+}
@@ -0,0 +1,154 @@
+use std::{collections::BTreeMap, fmt::Display, io};
+
+use crate::{message::format::{FrameError, FormatError}, transport::common::TransportError};
+
+/// Counts the packets dropped before they could be handled, by cause
+pub struct DropStats {
+    /// Packets with a message type unknown to this version, by type
+    unknown_types: BTreeMap<u8, u64>,
+    /// Messages whose content doesn't follow the layout, e.g. an unknown enum value or a missing field
+    malformed: u64,
+    /// Datagrams that don't start with the magic byte
+    not_messages: u64,
+    /// Packets of protocol versions older than the supported ones
+    old_versions: u64,
+    /// Datagrams shorter than the sizes in their headers, and contents ending in the middle of a field
+    truncated: u64,
+    /// Datagrams with bytes after the last message
+    trailing: u64,
+    /// Messages with names or texts that aren't UTF-8
+    invalid_utf8: u64,
+    /// Messages with names, texts or lists longer than the protocol allows
+    too_long: u64,
+    /// Compressed contents that don't decompress
+    decompression: u64,
+    /// Messages with a wrong checksum or MAC
+    integrity: u64,
+    /// Failed receives, by the kind of the socket error
+    socket_errors: Vec<(io::ErrorKind, u64)>,
+    /// Description of the last drop
+    last: Option<String>,
+}
+
+impl DropStats {
+    pub fn new() -> DropStats {
+        DropStats {
+            unknown_types: BTreeMap::new(),
+            malformed: 0,
+            not_messages: 0,
+            old_versions: 0,
+            truncated: 0,
+            trailing: 0,
+            invalid_utf8: 0,
+            too_long: 0,
+            decompression: 0,
+            integrity: 0,
+            socket_errors: vec![],
+            last: None,
+        }
+    }
+
+    /// Records a datagram that couldn't be split into messages
+    pub fn record_frame(&mut self, err: FrameError) {
+        self.record_error(&FormatError::Framing(err));
+    }
+
+    /// Records a message whose header or content couldn't be decoded
+    pub fn record_error(&mut self, err: &FormatError) {
+        match err {
+            FormatError::UnknownType(msg_type) => *self.unknown_types.entry(*msg_type).or_insert(0) += 1,
+            FormatError::BadMagic(_) | FormatError::Framing(FrameError::NotMessage) => self.not_messages += 1,
+            FormatError::Truncated | FormatError::Framing(FrameError::Truncated { .. }) => self.truncated += 1,
+            FormatError::Framing(FrameError::Trailing { .. }) => self.trailing += 1,
+            FormatError::InvalidUtf8 => self.invalid_utf8 += 1,
+            FormatError::TooLong { .. } => self.too_long += 1,
+            FormatError::Decompression => self.decompression += 1,
+            FormatError::Integrity(_) => self.integrity += 1,
+            FormatError::UnknownFlags(_) | FormatError::MissingField(_) | FormatError::WrongLength { .. }
+                | FormatError::InvalidValue { .. } => self.malformed += 1,
+        }
+        self.last = Some(err.to_string());
+    }
+
+    /// Records a packet of a protocol version that is no longer supported
+    pub fn record_old_version(&mut self, version: u8) {
+        self.old_versions += 1;
+        self.last = Some(format!("message of version {}", version));
+    }
+
+    /// Records a receive that failed
+    pub fn record_socket(&mut self, err: &TransportError) {
+        match self.socket_errors.iter_mut().find(|(kind, _)| *kind == err.kind()) {
+            Some((_, count)) => *count += 1,
+            None => self.socket_errors.push((err.kind(), 1)),
+        }
+        self.last = Some(err.to_string());
+    }
+}
+
+impl Display for DropStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "dropped {} malformed packets", self.malformed)?;
+        let counts = [
+            (self.not_messages, "not peerko messages"),
+            (self.truncated, "truncated"),
+            (self.trailing, "with trailing bytes"),
+            (self.invalid_utf8, "with invalid UTF-8"),
+            (self.too_long, "with too long fields"),
+            (self.decompression, "that can't be decompressed"),
+            (self.integrity, "with a wrong checksum or MAC"),
+        ];
+        for (count, cause) in counts.iter().filter(|(count, _)| *count > 0) {
+            write!(f, ", {} {}", count, cause)?;
+        }
+        for (msg_type, count) in self.unknown_types.iter() {
+            write!(f, ", {} of unknown type 0x{:02X}", count, msg_type)?;
+        }
+        if self.old_versions > 0 {
+            write!(f, ", {} of unsupported versions", self.old_versions)?;
+        }
+        for (kind, count) in self.socket_errors.iter() {
+            write!(f, ", {} failed receives ({})", count, kind)?;
+        }
+        if let Some(last) = &self.last {
+            write!(f, "; last: {}", last)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::format::{Message, Header, MessageType, Bye, Integrity};
+
+    use super::*;
+
+    #[test]
+    fn counting() {
+        let mut stats = DropStats::new();
+        for header in [[0x9D, 0x10, 0x00, 0x00], [0x9D, 0x10, 0x00, 0x00], [0x9D, 0x1C, 0x10, 0x00], [0x01, 0x18, 0x00, 0x00]] {
+            stats.record_error(&Header::try_from(header.to_vec()).unwrap_err());
+        }
+        assert_eq!(stats.to_string(), "dropped 1 malformed packets, 1 not peerko messages, 2 of unknown type 0x00; last: Format err: not a message, it starts with 0x01");
+
+        stats.record_old_version(1);
+        assert_eq!(stats.to_string(), "dropped 1 malformed packets, 1 not peerko messages, 2 of unknown type 0x00, 1 of unsupported versions; last: message of version 1");
+
+        stats.record_frame(FrameError::NotMessage);
+        stats.record_frame(FrameError::Truncated { expected: 10, available: 6 });
+        stats.record_frame(FrameError::Trailing { bytes: 3 });
+        stats.record_frame(FrameError::Trailing { bytes: 1 });
+        assert_eq!(stats.to_string(), "dropped 1 malformed packets, 2 not peerko messages, 1 truncated, 2 with trailing bytes, 2 of unknown type 0x00, 1 of unsupported versions; last: Format err: 1 trailing bytes");
+
+        let mut corrupted = Message::new(Header::new(4, MessageType::Bye, 0), Some(Bye::new("peer-A", "grp").unwrap())).encode(&Integrity::Checksum, false).unwrap();
+        corrupted[6] ^= 0x01;
+        stats.record_error(&Message::<Bye>::decode(corrupted, &Integrity::Checksum).unwrap_err());
+        stats.record_error(&FormatError::InvalidUtf8);
+        stats.record_error(&FormatError::TooLong { field: "peer id", max: 32 });
+        stats.record_socket(&TransportError::Recv(io::ErrorKind::ConnectionReset));
+        stats.record_socket(&TransportError::Recv(io::ErrorKind::ConnectionReset));
+        assert_eq!(stats.to_string(), "dropped 1 malformed packets, 2 not peerko messages, 1 truncated, 2 with trailing bytes, 1 with invalid UTF-8, \
+            1 with too long fields, 1 with a wrong checksum or MAC, 2 of unknown type 0x00, 1 of unsupported versions, \
+            2 failed receives (connection reset); last: Transport err: can't receive: connection reset");
+    }
+}
@@ -1,160 +1,364 @@
-use std::{net::SocketAddr, time::{Instant, Duration}, ops::Add, fmt::Debug};
-
-/// ID of the peer. Needs to be unique for each peer on the group.
-pub type PeerId = String;
-
-#[derive(Clone, PartialEq, Eq)]
-pub struct NeighbourEntry {
-    id: String,
-    addr: SocketAddr,
-    ttl: Instant,
-}
-
-impl NeighbourEntry {
-    pub fn new(id: String, addr: SocketAddr, ttl: Instant) -> NeighbourEntry {
-        NeighbourEntry { id, addr, ttl }
-    }
-
-    pub fn id(&self) -> &String {
-        &self.id
-    }
-
-    pub fn addr(&self) -> &SocketAddr {
-        &self.addr
-    }
-
-    pub fn ttl_expired(&self) -> bool {
-        let now = Instant::now();
-        self.ttl < now
-    }
-
-    pub fn update_ttl(&mut self, value: Duration) {
-        self.ttl = Instant::now().add(value);
-    }
-}
-
-impl Debug for NeighbourEntry {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}@{}", self.id, self.addr)
-    }
-}
-
-#[derive(Clone, PartialEq, Eq)]
-pub struct NeighbourMap {
-    peers: Vec<NeighbourEntry>,
-}
-
-impl NeighbourMap {
-    pub fn new() -> NeighbourMap {
-        NeighbourMap { peers: vec![] }
-    }
-
-    pub fn iter(&self) -> NeighbourMapIterator {
-        NeighbourMapIterator { peers: &self.peers, index: 0 }
-    }
-
-    pub fn contains_peer(&self, peer_id: &str) -> bool {
-        self.peers.iter().any(|peer| peer.id == peer_id)
-    }
-
-    pub fn insert(&mut self, peer: NeighbourEntry) {
-        self.peers.push(peer)
-    }
-
-    pub fn find_peer_mut(&mut self, peer_id: &str) -> Option<&mut NeighbourEntry> {
-        self.peers.iter_mut().find(|p| p.id == peer_id)
-    }
-
-    pub fn remove_expired(&mut self) {
-        while let Some(peer_index) = self.peers.iter().position(|e| e.ttl_expired()) {
-            self.peers.remove(peer_index);
-        }
-    }
-
-    pub fn count(&self) -> usize {
-        self.peers.len()
-    }
-
-}
-
-impl Debug for NeighbourMap {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self.peers)
-    }
-}
-
-pub struct NeighbourMapIterator<'a> {
-    index: usize,
-    peers: &'a [NeighbourEntry],
-}
-
-impl<'a> Iterator for NeighbourMapIterator<'a> {
-    type Item = &'a NeighbourEntry;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.peers.get(self.index) {
-            Some(element) => {
-                self.index += 1;
-                Some(element)
-            },
-            None => None,
-        }
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn neighbour_entry() {
-        let ttl = Instant::now().add(Duration::from_millis(500));
-        let entry = NeighbourEntry::new("peer-a".to_string(), "127.0.0.1:2000".parse().unwrap(), ttl);
-        assert_eq!(entry.ttl_expired(), false);
-
-        std::thread::sleep(Duration::from_millis(600));
-
-        assert_eq!(entry.ttl_expired(), true);
-    }
-
-    #[test]
-    fn neighbour_map() {
-        let ttl = Instant::now().add(Duration::from_millis(500));
-        let entry1 = NeighbourEntry::new("peer-a".to_string(), "127.0.0.1:2000".parse().unwrap(), ttl);
-        let entry2 = NeighbourEntry::new("peer-b".to_string(), "127.0.0.1:2001".parse().unwrap(), ttl);
-        let entry3 = NeighbourEntry::new("peer-c".to_string(), "127.0.0.1:2002".parse().unwrap(), ttl);
-
-        let mut map = NeighbourMap::new();
-        map.insert(entry1);
-        map.insert(entry2);
-        map.insert(entry3);
-
-        assert_eq!(map.contains_peer("peer-a"), true);
-        assert_eq!(map.contains_peer("peer-123"), false);
-
-        let ids = vec!["peer-a", "peer-b", "peer-c"];
-        let mut i = 0;
-        for peer in map.iter() {
-            assert_eq!(peer.id, ids[i]);
-            i+=1;
-        }
-    }
-
-    #[test]
-    fn map_expiry() {
-        let ttl = Instant::now().add(Duration::from_millis(500));
-        let entry1 = NeighbourEntry::new("peer-a".to_string(), "127.0.0.1:2000".parse().unwrap(), ttl);
-        let entry2 = NeighbourEntry::new("peer-b".to_string(), "127.0.0.1:2001".parse().unwrap(), ttl);
-        let entry3 = NeighbourEntry::new("peer-c".to_string(), "127.0.0.1:2002".parse().unwrap(), ttl);
-
-        let mut map = NeighbourMap::new();
-        map.insert(entry1);
-        map.insert(entry2);
-        map.insert(entry3);
-
-        std::thread::sleep(Duration::from_millis(600));
-        map.remove_expired();
-        assert_eq!(map.count(), 0);
-
-    }
+use std::{net::SocketAddr, time::{Instant, Duration}, ops::Add, fmt::Debug};
+
+use crate::message::format::MemberState;
+
+/// ID of the peer. Needs to be unique for each peer on the group.
+pub type PeerId = String;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct NeighbourEntry {
+    id: String,
+    addr: SocketAddr,
+    ttl: Instant,
+    state: MemberState,
+    incarnation: u32,
+    suspected_at: Option<Instant>,
+    /// Process of the peer, known after hearing from it directly
+    session: Option<u64>,
+    /// Protocol features the peer advertised, none until hearing from it directly
+    features: u8,
+}
+
+impl NeighbourEntry {
+    pub fn new(id: String, addr: SocketAddr, ttl: Instant) -> NeighbourEntry {
+        NeighbourEntry { id, addr, ttl, state: MemberState::Alive, incarnation: 0, suspected_at: None, session: None, features: 0 }
+    }
+
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    /// Switches to the validated new address of the peer
+    pub fn set_addr(&mut self, addr: SocketAddr) {
+        self.addr = addr;
+    }
+
+    /// Returns the session of the peer, unknown until it was heard from directly
+    pub fn session(&self) -> Option<u64> {
+        self.session
+    }
+
+    pub fn set_session(&mut self, session: u64) {
+        self.session = Some(session);
+    }
+
+    pub fn features(&self) -> u8 {
+        self.features
+    }
+
+    pub fn set_features(&mut self, features: u8) {
+        self.features = features;
+    }
+
+    /// Returns true if a message from another address with the given session can't be from this peer.
+    /// A peer that stopped answering can be taken over, e.g. after restarting somewhere else.
+    /// Entries learned from member lists, gossip, the LAN, the DHT or the cache carry no session,
+    /// so a clash with them is only found once the peer is heard from directly.
+    pub fn clashes_with(&self, session: u64) -> bool {
+        self.state == MemberState::Alive && self.session.map(|s| s != session).unwrap_or(false)
+    }
+
+    pub fn ttl_expired(&self) -> bool {
+        let now = Instant::now();
+        self.ttl < now
+    }
+
+    pub fn update_ttl(&mut self, value: Duration) {
+        self.ttl = Instant::now().add(value);
+    }
+
+    pub fn state(&self) -> MemberState {
+        self.state
+    }
+
+    pub fn incarnation(&self) -> u32 {
+        self.incarnation
+    }
+
+    /// Returns true if the peer is suspected for longer than the timeout
+    pub fn suspicion_expired(&self, timeout: Duration) -> bool {
+        match self.suspected_at {
+            Some(at) => self.state == MemberState::Suspect && at.add(timeout) < Instant::now(),
+            None => false,
+        }
+    }
+
+    /// Clears the suspicion after hearing from the peer directly
+    pub fn confirm_alive(&mut self) {
+        if self.state == MemberState::Suspect {
+            self.state = MemberState::Alive;
+            self.suspected_at = None;
+        }
+    }
+
+    /// Applies a membership update following the SWIM precedence rules.
+    /// Returns true if the state of the entry changed.
+    pub fn apply(&mut self, state: MemberState, incarnation: u32) -> bool {
+        let overrides = match (state, self.state) {
+            (MemberState::Alive, _) => incarnation > self.incarnation,
+            (MemberState::Suspect, MemberState::Alive) => incarnation >= self.incarnation,
+            (MemberState::Suspect, MemberState::Suspect) => incarnation > self.incarnation,
+            (MemberState::Suspect, MemberState::Dead) => false,
+            (MemberState::Dead, MemberState::Dead) => false,
+            (MemberState::Dead, _) => incarnation >= self.incarnation,
+        };
+
+        if overrides {
+            self.state = state;
+            self.incarnation = incarnation;
+            self.suspected_at = match state {
+                MemberState::Suspect => Some(Instant::now()),
+                _ => None,
+            };
+        }
+        overrides
+    }
+}
+
+impl Debug for NeighbourEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.state {
+            MemberState::Alive => write!(f, "{}@{}", self.id, self.addr),
+            MemberState::Suspect => write!(f, "{}@{} (suspect)", self.id, self.addr),
+            MemberState::Dead => write!(f, "{}@{} (dead)", self.id, self.addr),
+        }
+    }
+}
+
+/// Maximum number of offline peers remembered in a group, the ones gone the longest are forgotten first
+const MAX_OFFLINE: usize = 32;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct NeighbourMap {
+    peers: Vec<NeighbourEntry>,
+    /// Peers that expired from the map and haven't been seen since
+    offline: Vec<PeerId>,
+}
+
+impl NeighbourMap {
+    pub fn new() -> NeighbourMap {
+        NeighbourMap { peers: vec![], offline: vec![] }
+    }
+
+    pub fn iter(&self) -> NeighbourMapIterator<'_> {
+        NeighbourMapIterator { peers: &self.peers, index: 0 }
+    }
+
+    pub fn contains_peer(&self, peer_id: &str) -> bool {
+        self.peers.iter().any(|peer| peer.id == peer_id)
+    }
+
+    pub fn insert(&mut self, peer: NeighbourEntry) {
+        self.offline.retain(|id| *id != peer.id);
+        self.peers.push(peer)
+    }
+
+    pub fn find_peer(&self, peer_id: &str) -> Option<&NeighbourEntry> {
+        self.peers.iter().find(|p| p.id == peer_id)
+    }
+
+    pub fn find_peer_mut(&mut self, peer_id: &str) -> Option<&mut NeighbourEntry> {
+        self.peers.iter_mut().find(|p| p.id == peer_id)
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, NeighbourEntry> {
+        self.peers.iter_mut()
+    }
+
+    /// Removes the peers whose TTL expired and returns them
+    pub fn remove_expired(&mut self) -> Vec<NeighbourEntry> {
+        let mut expired = vec![];
+        while let Some(peer_index) = self.peers.iter().position(|e| e.ttl_expired()) {
+            let peer = self.peers.remove(peer_index);
+            self.mark_offline(&peer.id);
+            expired.push(peer);
+        }
+        expired
+    }
+
+    /// Removes the peer that left the group
+    pub fn remove(&mut self, peer_id: &str) -> Option<NeighbourEntry> {
+        let peer_index = self.peers.iter().position(|e| e.id == peer_id)?;
+        let peer = self.peers.remove(peer_index);
+        self.mark_offline(&peer.id);
+        Some(peer)
+    }
+
+    fn mark_offline(&mut self, peer_id: &str) {
+        if !self.offline.iter().any(|id| id == peer_id) {
+            if self.offline.len() == MAX_OFFLINE {
+                self.offline.remove(0);
+            }
+            self.offline.push(peer_id.to_string());
+        }
+    }
+
+    /// Returns the ids of peers that expired from the map
+    pub fn offline(&self) -> &[PeerId] {
+        &self.offline
+    }
+
+    pub fn count(&self) -> usize {
+        self.peers.len()
+    }
+
+}
+
+impl Debug for NeighbourMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.peers)
+    }
+}
+
+pub struct NeighbourMapIterator<'a> {
+    index: usize,
+    peers: &'a [NeighbourEntry],
+}
+
+impl<'a> Iterator for NeighbourMapIterator<'a> {
+    type Item = &'a NeighbourEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.peers.get(self.index) {
+            Some(element) => {
+                self.index += 1;
+                Some(element)
+            },
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison, clippy::explicit_counter_loop, clippy::useless_vec)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neighbour_entry() {
+        let ttl = Instant::now().add(Duration::from_millis(500));
+        let entry = NeighbourEntry::new("peer-a".to_string(), "127.0.0.1:2000".parse().unwrap(), ttl);
+        assert_eq!(entry.ttl_expired(), false);
+
+        std::thread::sleep(Duration::from_millis(600));
+
+        assert_eq!(entry.ttl_expired(), true);
+    }
+
+    #[test]
+    fn neighbour_map() {
+        let ttl = Instant::now().add(Duration::from_millis(500));
+        let entry1 = NeighbourEntry::new("peer-a".to_string(), "127.0.0.1:2000".parse().unwrap(), ttl);
+        let entry2 = NeighbourEntry::new("peer-b".to_string(), "127.0.0.1:2001".parse().unwrap(), ttl);
+        let entry3 = NeighbourEntry::new("peer-c".to_string(), "127.0.0.1:2002".parse().unwrap(), ttl);
+
+        let mut map = NeighbourMap::new();
+        map.insert(entry1);
+        map.insert(entry2);
+        map.insert(entry3);
+
+        assert_eq!(map.contains_peer("peer-a"), true);
+        assert_eq!(map.contains_peer("peer-123"), false);
+
+        let ids = vec!["peer-a", "peer-b", "peer-c"];
+        let mut i = 0;
+        for peer in map.iter() {
+            assert_eq!(peer.id, ids[i]);
+            i+=1;
+        }
+    }
+
+    #[test]
+    fn map_expiry() {
+        let ttl = Instant::now().add(Duration::from_millis(500));
+        let entry1 = NeighbourEntry::new("peer-a".to_string(), "127.0.0.1:2000".parse().unwrap(), ttl);
+        let entry2 = NeighbourEntry::new("peer-b".to_string(), "127.0.0.1:2001".parse().unwrap(), ttl);
+        let entry3 = NeighbourEntry::new("peer-c".to_string(), "127.0.0.1:2002".parse().unwrap(), ttl);
+
+        let mut map = NeighbourMap::new();
+        map.insert(entry1);
+        map.insert(entry2);
+        map.insert(entry3);
+
+        std::thread::sleep(Duration::from_millis(600));
+        map.remove_expired();
+        assert_eq!(map.count(), 0);
+
+    }
+
+    #[test]
+    fn offline_peers() {
+        let ttl = Instant::now().add(Duration::from_millis(50));
+        let mut map = NeighbourMap::new();
+        for (i, id) in ["peer-a", "peer-b", "peer-c"].iter().enumerate() {
+            map.insert(NeighbourEntry::new(id.to_string(), SocketAddr::new("127.0.0.1".parse().unwrap(), 2000 + i as u16), ttl));
+        }
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(map.remove_expired().len(), 3);
+        assert_eq!(map.offline(), ["peer-a", "peer-b", "peer-c"]);
+
+        let ttl = Instant::now().add(Duration::from_millis(500));
+        map.insert(NeighbourEntry::new("peer-b".to_string(), "127.0.0.1:2001".parse().unwrap(), ttl));
+        assert_eq!(map.offline(), ["peer-a", "peer-c"]);
+
+        assert!(map.remove("peer-b").is_some());
+        assert!(map.remove("peer-b").is_none());
+        assert_eq!(map.offline(), ["peer-a", "peer-c", "peer-b"]);
+
+        for i in 0..MAX_OFFLINE {
+            let ttl = Instant::now().add(Duration::from_millis(500));
+            map.insert(NeighbourEntry::new(format!("peer-{}", i), "127.0.0.1:2001".parse().unwrap(), ttl));
+            map.remove(&format!("peer-{}", i));
+        }
+        assert_eq!(map.offline().len(), MAX_OFFLINE);
+        assert_eq!(map.offline()[0], "peer-0");
+    }
+
+    #[test]
+    fn swim_precedence() {
+        let ttl = Instant::now().add(Duration::from_millis(500));
+        let mut entry = NeighbourEntry::new("peer-a".to_string(), "127.0.0.1:2000".parse().unwrap(), ttl);
+
+        // Suspicion with the same incarnation overrides alive, but not the other way around
+        assert!(entry.apply(MemberState::Suspect, 0));
+        assert!(!entry.apply(MemberState::Alive, 0));
+        assert!(!entry.apply(MemberState::Suspect, 0));
+        assert_eq!(format!("{:?}", entry), "peer-a@127.0.0.1:2000 (suspect)");
+
+        // Refuted by a higher incarnation
+        assert!(entry.apply(MemberState::Alive, 1));
+        assert_eq!(entry.state(), MemberState::Alive);
+        assert!(!entry.suspicion_expired(Duration::ZERO));
+
+        assert!(entry.apply(MemberState::Suspect, 1));
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(entry.suspicion_expired(Duration::from_millis(5)));
+
+        assert!(entry.apply(MemberState::Dead, 1));
+        assert!(!entry.apply(MemberState::Suspect, 2));
+        assert!(!entry.apply(MemberState::Dead, 2));
+        assert!(entry.apply(MemberState::Alive, 2));
+    }
+
+    #[test]
+    fn session_clash() {
+        let ttl = Instant::now().add(Duration::from_millis(500));
+        let mut entry = NeighbourEntry::new("peer-a".to_string(), "127.0.0.1:2000".parse().unwrap(), ttl);
+
+        // Unknown session can't clash
+        assert!(!entry.clashes_with(1));
+
+        entry.set_session(1);
+        assert!(!entry.clashes_with(1));
+        assert!(entry.clashes_with(2));
+
+        // Once it stops answering, the name is free
+        entry.apply(MemberState::Suspect, 0);
+        assert!(!entry.clashes_with(2));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,170 @@
+use std::{collections::HashMap, net::SocketAddr, time::{Duration, Instant}};
+
+use crate::message::format::MemberUpdate;
+
+use super::{structures::PeerId, random_u64};
+
+/// Period between two rounds of direct pings
+pub static PROTOCOL_PERIOD: Duration = Duration::from_secs(5);
+
+/// Time to wait for an answer before asking other members to ping the target
+pub static ACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time to wait for an answer to the indirect pings before the target is suspected
+pub static INDIRECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Time a suspected member has to refute the suspicion before it's declared dead
+pub static SUSPECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Number of members asked to ping an unresponsive target
+pub const INDIRECT_PROBES: usize = 3;
+
+/// Number of times a membership update is piggybacked before it's dropped
+const MAX_TRANSMISSIONS: u8 = 4;
+
+/// Maximum number of updates piggybacked on a single message
+const MAX_PIGGYBACK: usize = 5;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Probe {
+    /// Ping sent directly to the target
+    Direct { group: String, target: PeerId },
+    /// Target didn't answer the direct ping, other members were asked to ping it
+    Indirect { group: String, target: PeerId },
+    /// Ping sent on behalf of another member, the answer is relayed back
+    Relay { requester: SocketAddr, seq: u32 },
+}
+
+struct PendingProbe {
+    probe: Probe,
+    sent: Instant,
+}
+
+/// State of the SWIM failure detector: outstanding probes and
+/// the membership updates waiting to be piggybacked.
+pub struct Swim {
+    incarnation: u32,
+    next_seq: u32,
+    probes: HashMap<u32, PendingProbe>,
+    updates: Vec<(MemberUpdate, u8)>,
+}
+
+impl Swim {
+    pub fn new() -> Swim {
+        Swim { incarnation: 0, next_seq: random_u64() as u32, probes: HashMap::new(), updates: vec![] }
+    }
+
+    pub fn incarnation(&self) -> u32 {
+        self.incarnation
+    }
+
+    /// Raises the own incarnation above the suspicion, so the refutation takes precedence
+    pub fn refute(&mut self, suspected: u32) -> u32 {
+        self.incarnation = self.incarnation.max(suspected) + 1;
+        self.incarnation
+    }
+
+    /// Registers a probe and returns the sequence number to use in the ping
+    pub fn probe(&mut self, probe: Probe) -> u32 {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.probes.insert(seq, PendingProbe { probe, sent: Instant::now() });
+        seq
+    }
+
+    /// Resolves the probe answered by the ack
+    pub fn ack(&mut self, seq: u32) -> Option<Probe> {
+        self.probes.remove(&seq).map(|p| p.probe)
+    }
+
+    /// Removes and returns the probes that didn't get an answer in time.
+    /// Direct probes time out after `ACK_TIMEOUT`, indirect ones after `INDIRECT_TIMEOUT`
+    /// and relayed ones after `PROTOCOL_PERIOD`.
+    pub fn expired_probes(&mut self) -> Vec<Probe> {
+        let now = Instant::now();
+        let expired: Vec<u32> = self.probes
+            .iter()
+            .filter(|(_, p)| {
+                let timeout = match p.probe {
+                    Probe::Direct { .. } => ACK_TIMEOUT,
+                    Probe::Indirect { .. } => INDIRECT_TIMEOUT,
+                    Probe::Relay { .. } => PROTOCOL_PERIOD,
+                };
+                now.duration_since(p.sent) > timeout
+            })
+            .map(|(seq, _)| *seq)
+            .collect();
+
+        expired.into_iter().filter_map(|seq| self.ack(seq)).collect()
+    }
+
+    /// Queues the update for dissemination, replacing older news about the same member
+    pub fn broadcast(&mut self, update: MemberUpdate) {
+        self.updates.retain(|(u, _)| u.group_name() != update.group_name() || u.peer_id() != update.peer_id());
+        self.updates.push((update, MAX_TRANSMISSIONS));
+    }
+
+    /// Returns the updates to piggyback on the next message
+    pub fn piggyback(&mut self) -> Vec<MemberUpdate> {
+        // Fresh updates first
+        self.updates.sort_by_key(|(_, remaining)| std::cmp::Reverse(*remaining));
+
+        let updates = self.updates
+            .iter_mut()
+            .take(MAX_PIGGYBACK)
+            .map(|(u, remaining)| {
+                *remaining -= 1;
+                u.clone()
+            })
+            .collect();
+
+        self.updates.retain(|(_, remaining)| *remaining > 0);
+        updates
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::format::MemberState;
+
+    use super::*;
+
+    #[test]
+    fn probes() {
+        let mut swim = Swim::new();
+        let seq1 = swim.probe(Probe::Direct { group: "grp".to_string(), target: "peer-a".to_string() });
+        let seq2 = swim.probe(Probe::Relay { requester: "127.0.0.1:2000".parse().unwrap(), seq: 12 });
+        assert_ne!(seq1, seq2);
+
+        assert_eq!(swim.ack(seq2), Some(Probe::Relay { requester: "127.0.0.1:2000".parse().unwrap(), seq: 12 }));
+        assert_eq!(swim.ack(seq2), None);
+        assert!(swim.expired_probes().is_empty());
+
+        std::thread::sleep(ACK_TIMEOUT + Duration::from_millis(50));
+        assert_eq!(swim.expired_probes(), vec![Probe::Direct { group: "grp".to_string(), target: "peer-a".to_string() }]);
+        assert_eq!(swim.ack(seq1), None);
+    }
+
+    #[test]
+    fn piggyback() {
+        let mut swim = Swim::new();
+        let addr = "127.0.0.1:2000".parse().unwrap();
+        swim.broadcast(MemberUpdate::new("grp", "peer-a", addr, MemberState::Suspect, 0).unwrap());
+        swim.broadcast(MemberUpdate::new("grp", "peer-a", addr, MemberState::Alive, 1).unwrap());
+
+        for _ in 0..MAX_TRANSMISSIONS {
+            let updates = swim.piggyback();
+            assert_eq!(updates.len(), 1);
+            assert_eq!(updates[0].incarnation(), 1);
+        }
+        assert!(swim.piggyback().is_empty());
+    }
+
+    #[test]
+    fn refute() {
+        let mut swim = Swim::new();
+        assert_eq!(swim.refute(0), 1);
+        assert_eq!(swim.refute(5), 6);
+        assert_eq!(swim.incarnation(), 6);
+    }
+}
@@ -1,25 +1,58 @@
-use std::{net::SocketAddr, fmt::Display, error::Error};
-
-#[derive(Clone, Debug)]
-pub struct TransportError {
-    pub error: String,
-}
-
-impl Error for TransportError {}
-
-impl Display for TransportError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Transport err: {}", self.error)
-    }
-}
-
-#[derive(Clone, Eq, PartialEq)]
-pub struct TransportPacket {
-    pub socket_addr: SocketAddr,
-    pub data: Vec<u8>,
-}
-
-pub trait Transport {
-    fn send(&self, packet: TransportPacket) -> Result<usize, TransportError>;
-    fn recv(&self) -> Result<TransportPacket, TransportError>;
-}
+use std::{net::SocketAddr, fmt::Display, error::Error, io};
+
+/// Failed socket operation, with the kind of the OS error
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportError {
+    /// The address can't be bound, e.g. the port is taken
+    Bind(io::ErrorKind),
+    /// The datagram can't be sent, e.g. the network is down
+    Send(io::ErrorKind),
+    /// No datagram was received, e.g. the read timed out or an earlier datagram bounced
+    Recv(io::ErrorKind),
+    /// A socket option can't be read or changed
+    Socket(io::ErrorKind),
+}
+
+impl TransportError {
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            TransportError::Bind(kind) | TransportError::Send(kind) | TransportError::Recv(kind) | TransportError::Socket(kind) => *kind,
+        }
+    }
+
+    /// Returns true if the read timeout passed before a datagram came in
+    pub fn timed_out(&self) -> bool {
+        matches!(self, TransportError::Recv(io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut))
+    }
+}
+
+impl Error for TransportError {}
+
+impl Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransportError::Bind(kind) => write!(f, "Transport err: can't bind: {}", kind),
+            TransportError::Send(kind) => write!(f, "Transport err: can't send: {}", kind),
+            TransportError::Recv(kind) => write!(f, "Transport err: can't receive: {}", kind),
+            TransportError::Socket(kind) => write!(f, "Transport err: socket option: {}", kind),
+        }
+    }
+}
+
+#[derive(Clone, Eq, PartialEq)]
+pub struct TransportPacket {
+    pub socket_addr: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+/// Received datagram that borrows the buffer it was read into
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct PacketRef<'a> {
+    pub socket_addr: SocketAddr,
+    pub data: &'a [u8],
+}
+
+pub trait Transport {
+    fn send(&self, packet: TransportPacket) -> Result<usize, TransportError>;
+    fn recv(&self) -> Result<TransportPacket, TransportError>;
+}
@@ -1,2 +1,2 @@
-pub mod udp;
+pub mod udp;
 pub mod common;
\ No newline at end of file
@@ -1,69 +1,125 @@
-use std::net::{UdpSocket, SocketAddr};
-
-use super::common::{Transport, TransportError, TransportPacket};
-
-pub struct UdpTransport {
-    socket: UdpSocket,
-}
-
-impl UdpTransport {
-    pub fn new(addr: SocketAddr) -> Result<UdpTransport, TransportError> {
-        let soc = UdpSocket::bind(addr).map_err(|err| TransportError{ error: err.to_string() })?;
-        Ok(UdpTransport{
-            socket: soc,
-        })
-    }
-
-    pub fn try_clone(&self) -> Result<UdpTransport, TransportError> {
-        let soc = self.socket.try_clone().map_err(|err| TransportError{ error: err.to_string() })?;
-        Ok(
-            UdpTransport{
-                socket: soc,
-            }
-        )
-    }
-}
-
-impl Transport for UdpTransport {
-    fn send(&self, packet: TransportPacket) -> Result<usize, TransportError> {
-        self.socket
-        .send_to(
-            packet.data.as_slice(),
-            packet.socket_addr
-        ).map_err(|err| TransportError{ error: err.to_string() })
-    }
-
-    fn recv(&self) -> Result<TransportPacket, TransportError> {
-        let mut buf: [u8; 1024] = [0; 1024];
-        let (byte_count, addr) = self.socket.recv_from(&mut buf).map_err(|err| TransportError{ error: err.to_string() })?;
-        Ok(TransportPacket{
-            data: Vec::from(&buf[..byte_count]),
-            socket_addr: addr,
-        })
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use std::thread;
-    use crate::transport::common::{Transport, TransportPacket};
-
-    use super::UdpTransport;
-
-    #[test]
-    fn basic_send_recv() {
-        let udp1 = UdpTransport::new("0.0.0.0:9232".parse().unwrap()).unwrap();
-        let udp2 = UdpTransport::new("0.0.0.0:9233".parse().unwrap()).unwrap();
-
-        thread::spawn(move || {
-            let packet = udp1.recv().unwrap();
-            assert_eq!(packet.data, vec![0x1]);
-            udp1.send(TransportPacket { data: vec![0x2, 0x3], socket_addr: "127.0.0.1:9233".parse().unwrap() }).unwrap();
-
-        });
-
-        udp2.send(TransportPacket{ data: vec![0x1], socket_addr: "127.0.0.1:9232".parse().unwrap() }).unwrap();
-        let packet = udp2.recv().unwrap();
-        assert_eq!(packet.data, vec![0x2, 0x3]);
-    }
-}
\ No newline at end of file
+use std::{net::{UdpSocket, SocketAddr}, time::Duration};
+
+use super::common::{Transport, TransportError, TransportPacket, PacketRef};
+
+/// Largest datagram that is received whole, messages coalesced into one datagram have to fit in it
+pub const MAX_DATAGRAM: usize = 1024;
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn new(addr: SocketAddr) -> Result<UdpTransport, TransportError> {
+        let soc = UdpSocket::bind(addr).map_err(|err| TransportError::Bind(err.kind()))?;
+        Ok(UdpTransport{
+            socket: soc,
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, TransportError> {
+        self.socket.local_addr().map_err(|err| TransportError::Socket(err.kind()))
+    }
+
+    /// Makes `recv` fail after waiting for the given time, None waits forever
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), TransportError> {
+        self.socket.set_read_timeout(timeout).map_err(|err| TransportError::Socket(err.kind()))
+    }
+
+    /// Returns the next packet without taking it from the socket
+    pub fn peek(&self) -> Result<TransportPacket, TransportError> {
+        let mut buf = [0; MAX_DATAGRAM];
+        let (byte_count, addr) = self.socket.peek_from(&mut buf).map_err(|err| TransportError::Recv(err.kind()))?;
+        Ok(TransportPacket{
+            data: Vec::from(&buf[..byte_count]),
+            socket_addr: addr,
+        })
+    }
+
+    /// Receives the next datagram into the buffer, which is reused between the calls
+    pub fn recv_into<'a>(&self, buf: &'a mut [u8]) -> Result<PacketRef<'a>, TransportError> {
+        let (byte_count, addr) = self.socket.recv_from(buf).map_err(|err| TransportError::Recv(err.kind()))?;
+        Ok(PacketRef{
+            data: &buf[..byte_count],
+            socket_addr: addr,
+        })
+    }
+
+    pub fn try_clone(&self) -> Result<UdpTransport, TransportError> {
+        let soc = self.socket.try_clone().map_err(|err| TransportError::Socket(err.kind()))?;
+        Ok(
+            UdpTransport{
+                socket: soc,
+            }
+        )
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&self, packet: TransportPacket) -> Result<usize, TransportError> {
+        self.socket
+        .send_to(
+            packet.data.as_slice(),
+            packet.socket_addr
+        ).map_err(|err| TransportError::Send(err.kind()))
+    }
+
+    fn recv(&self) -> Result<TransportPacket, TransportError> {
+        let mut buf = [0; MAX_DATAGRAM];
+        let (byte_count, addr) = self.socket.recv_from(&mut buf).map_err(|err| TransportError::Recv(err.kind()))?;
+        Ok(TransportPacket{
+            data: Vec::from(&buf[..byte_count]),
+            socket_addr: addr,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, io, time::Duration};
+    use crate::transport::common::{Transport, TransportError, TransportPacket};
+
+    use super::{UdpTransport, MAX_DATAGRAM};
+
+    #[test]
+    fn basic_send_recv() {
+        let udp1 = UdpTransport::new("0.0.0.0:9232".parse().unwrap()).unwrap();
+        let udp2 = UdpTransport::new("0.0.0.0:9233".parse().unwrap()).unwrap();
+
+        thread::spawn(move || {
+            let packet = udp1.recv().unwrap();
+            assert_eq!(packet.data, vec![0x1]);
+            udp1.send(TransportPacket { data: vec![0x2, 0x3], socket_addr: "127.0.0.1:9233".parse().unwrap() }).unwrap();
+
+        });
+
+        udp2.send(TransportPacket{ data: vec![0x1], socket_addr: "127.0.0.1:9232".parse().unwrap() }).unwrap();
+        let packet = udp2.recv().unwrap();
+        assert_eq!(packet.data, vec![0x2, 0x3]);
+    }
+
+    #[test]
+    fn recv_into_buffer() {
+        let udp1 = UdpTransport::new("127.0.0.1:9235".parse().unwrap()).unwrap();
+        let udp2 = UdpTransport::new("127.0.0.1:9236".parse().unwrap()).unwrap();
+
+        let mut buf = [0xFF; MAX_DATAGRAM];
+        for data in [vec![0x1, 0x2, 0x3], vec![0x4]] {
+            udp2.send(TransportPacket { data: data.clone(), socket_addr: "127.0.0.1:9235".parse().unwrap() }).unwrap();
+            let packet = udp1.recv_into(&mut buf).unwrap();
+            assert_eq!(packet.data, data.as_slice());
+            assert_eq!(packet.socket_addr, "127.0.0.1:9236".parse().unwrap());
+        }
+    }
+
+    #[test]
+    fn socket_errors() {
+        let udp = UdpTransport::new("127.0.0.1:9234".parse().unwrap()).unwrap();
+        assert_eq!(UdpTransport::new("127.0.0.1:9234".parse().unwrap()).err(), Some(TransportError::Bind(io::ErrorKind::AddrInUse)));
+
+        udp.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let err = udp.recv().err().unwrap();
+        assert!(err.timed_out());
+        assert!(!TransportError::Send(err.kind()).timed_out());
+    }
+}